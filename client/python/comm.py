@@ -65,6 +65,7 @@ class CommUsbsas(Comm):
     req_types = {
         "CopyStart": proto_usbsas.RequestCopyStart,
         "Devices": proto_usbsas.RequestDevices,
+        "DirSize": proto_usbsas.RequestDirSize,
         "End": proto_usbsas.RequestEnd,
         "GetAttr": proto_usbsas.RequestGetAttr,
         "Id": proto_usbsas.RequestId,
@@ -83,6 +84,7 @@ class CommUsbsas(Comm):
         "CopyStatus": proto_usbsas.ResponseCopyStatus,
         "CopyStatusDone": proto_usbsas.ResponseCopyStatusDone,
         "Devices": proto_usbsas.ResponseDevices,
+        "DirSize": proto_usbsas.ResponseDirSize,
         "End": proto_usbsas.ResponseEnd,
         "Error": proto_usbsas.ResponseError,
         "FinalCopyStatus": proto_usbsas.ResponseFinalCopyStatus,
@@ -134,8 +136,18 @@ class CommUsbsas(Comm):
         self.send_req(proto_usbsas.RequestOpenPartition(index=index))
         return self.recv_resp()
 
-    def read_dir(self, path):
-        self.send_req(proto_usbsas.RequestReadDir(path=path))
+    def read_dir(self, path, offset=0, limit=0, sort=proto_common.NAME,
+                 descending=False):
+        # limit=0 lists all the entries, the response has the total number of
+        # entries of the directory
+        self.send_req(proto_usbsas.RequestReadDir(
+            path=path, offset=offset, limit=limit, sort=sort,
+            descending=descending
+            ))
+        return self.recv_resp()
+
+    def dir_size(self, path):
+        self.send_req(proto_usbsas.RequestDirSize(path=path))
         return self.recv_resp()
 
     def copy_files_usb(self, selected, busnum, devnum):
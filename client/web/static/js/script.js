@@ -308,13 +308,9 @@ function set_usbsas_infos(infos) {
   usbsas_infos.innerHTML = infos.name + infos.message;
   let version = document.getElementById("sasver");
   version.innerHTML = "Version: " + infos.version;
-}
-
-function get_usbsas_infos() {
-  try {
-    parse_json_and_call("usbsas_infos", set_usbsas_infos);
-  } catch (error) {
-    console.error(error);
+  usb_passphrase_required = infos.usb_passphrase_required;
+  if (usb_passphrase_required) {
+    document.querySelector("#copy-options").setAttribute("open", "");
   }
 }
 
@@ -323,10 +319,14 @@ var fs = new FileSystem();
 var cur_path = new Path(btoa(""), "/", null);
 var selected = new Selection(fs);
 var devices = new Devices();
-var refresh_device;
-var refresh_id;
-var refresh_check_id;
+// Devices and user ID are pushed by the server on /events
+var events;
+var watch_devices = false;
+var last_devices = [];
+var last_id = "";
+var operator = false;
 var reset_timer;
+var usb_passphrase_required = false;
 
 function set_error(error_text) {
   error.classList.add("fadein100");
@@ -757,6 +757,29 @@ function do_copy() {
             tr_err.appendChild(name_td_err);
             tbody.appendChild(tr_err);
           }
+          for (let skipped_path of json.skipped_path) {
+            // Display skipped elements (links)
+            has_error = true;
+            let tr_err = document.createElement("tr");
+            let status_td_err = document.createElement("td");
+            status_td_err.innerHTML = "&nbsp;";
+            let format_status_icon_err = document.createElement("i");
+            format_status_icon_err.classList.add("fas");
+            format_status_icon_err.classList.add("fa-times");
+            format_status_icon_err.classList.add("text-danger");
+            status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
+            tr_err.appendChild(status_td_err);
+            let name_td_err = document.createElement("td");
+            let p_err = document.createElement("p");
+            p_err.classList.add("text-danger");
+            p_err.innerHTML = "<strong data-langkey=\"skippedlink\">" + langDocument["skippedlink"] + "</strong>";
+            let span_err = document.createElement("span");
+            span_err.innerText = skipped_path;
+            p_err.appendChild(span_err);
+            name_td_err.appendChild(p_err);
+            tr_err.appendChild(name_td_err);
+            tbody.appendChild(tr_err);
+          }
           set_state("WAIT_REMOVAL");
           break;
         case "terminate":
@@ -854,6 +877,66 @@ function do_copy() {
             tr_err.appendChild(name_td_err);
             tbody.appendChild(tr_err);
           }
+          for (let skipped_path of json.skipped_path) {
+            // Display failed elements
+            has_error = true;
+            let tr_err = document.createElement("tr");
+            let status_td_err = document.createElement("td");
+            status_td_err.innerHTML = "&nbsp;";
+            let format_status_icon_err = document.createElement("i");
+            format_status_icon_err.classList.add("fas");
+            format_status_icon_err.classList.add("fa-times");
+            format_status_icon_err.classList.add("text-danger");
+            status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
+            tr_err.appendChild(status_td_err);
+
+            let name_td_err = document.createElement("td");
+            let p_err = document.createElement("p");
+            p_err.classList.add("text-danger");
+            p_err.innerHTML = "<strong data-langkey=\"skippedlink\">" + langDocument["skippedlink"] + "</strong>";
+            let span_err = document.createElement("span");
+            span_err.innerText = skipped_path;
+            p_err.appendChild(span_err);
+            name_td_err.appendChild(p_err);
+            tr_err.appendChild(name_td_err);
+            tbody.appendChild(tr_err);
+          }
+          for (let damaged_path of json.damaged_path) {
+            // Display files with unreadable sectors
+            has_error = true;
+            let tr_err = document.createElement("tr");
+            let status_td_err = document.createElement("td");
+            status_td_err.innerHTML = "&nbsp;";
+            let format_status_icon_err = document.createElement("i");
+            format_status_icon_err.classList.add("fas");
+            format_status_icon_err.classList.add("fa-times");
+            format_status_icon_err.classList.add("text-danger");
+            status_td_err.insertBefore(format_status_icon_err, status_td_err.firstChild);
+            tr_err.appendChild(status_td_err);
+
+            let name_td_err = document.createElement("td");
+            let p_err = document.createElement("p");
+            p_err.classList.add("text-danger");
+            p_err.innerHTML = "<strong data-langkey=\"damagedfile\">" + langDocument["damagedfile"] + "</strong>";
+            let span_err = document.createElement("span");
+            span_err.innerText = damaged_path;
+            p_err.appendChild(span_err);
+            name_td_err.appendChild(p_err);
+            tr_err.appendChild(name_td_err);
+            tbody.appendChild(tr_err);
+          }
+          if (json.device_error) {
+            // Destination device didn't read back what was written
+            has_error = true;
+            progress.classList.add("bg-danger");
+            let device_error = document.createElement("tr");
+            device_error.innerHTML =
+              "<td><i class='fas fa-times'></i>&nbsp;</td><td><strong data-langkey=\"faultydev\">" +
+              langDocument["faultydev"] + "</strong><q id='device-error-reason'></q></td>";
+            device_error.classList.add("text-danger");
+            tbody.appendChild(device_error);
+            document.querySelector("#device-error-reason").innerText = json.device_error;
+          }
           break;
         case "fatal_error":
           elements[elements.length - 1].icon.classList.remove("spinner-border");
@@ -896,8 +979,11 @@ function do_copy() {
   };
 
   var fsfmt = document.querySelector("#fsfmt");
+  var passphrase = document.querySelector("#passphrase");
   var post_body = selected.toJSON();
   post_body.fsfmt = fsfmt.options[fsfmt.selectedIndex].value;
+  post_body.passphrase = passphrase.value;
+  passphrase.value = "";
 
   fetch("/copy", {
     method: "POST",
@@ -1054,7 +1140,10 @@ function render_device_choice() {
         };
       }
 
-      if (!((type == "in" && device.is_src) || (type == "out" && device.is_dst))) {
+      // Devices refused by the policy are shown, disabled, with the reason
+      let refused = device.dev_type == "Usb" && device.dev.Usb.refused
+        && !((type == "in" && device.is_src) || (type == "out" && device.is_dst));
+      if (!refused && !((type == "in" && device.is_src) || (type == "out" && device.is_dst))) {
         continue;
       }
       let p = document.createElement("p");
@@ -1064,6 +1153,10 @@ function render_device_choice() {
       if (device.dev_type == "Usb") {
         h4.innerText = device.dev.Usb.description;
         p.innerText = device.dev.Usb.manufacturer + " / " + device.dev.Usb.serial;
+        if (refused) {
+          p.innerText += " - " + langDocument["devicerefused"] + device.dev.Usb.refused;
+          a.classList.add("disabled");
+        }
       } else if (device.dev_type == "Net") {
         p.innerText = device.dev.Net.longdescr;
         h4.innerText = device.dev.Net.description;
@@ -1095,7 +1188,7 @@ function render_device_choice() {
 function check_render_device_choice() {
   devices.check_available();
   if (devices.device_in !== undefined && devices.device_out !== undefined) {
-    clearInterval(refresh_device);
+    watch_devices = false;
     var request = new XMLHttpRequest();
     request.open(
       "GET", "/devices/select/" + devices.device_in.id + "/" + devices.device_out.id,
@@ -1158,86 +1251,43 @@ function check_device_removal() {
 }
 
 function device_choice() {
-  var request = new XMLHttpRequest();
-  request.open("GET", "/devices", true);
-  request.onload = function () {
-    clear_error();
-    if (state == "INIT") {
-      set_state("WAIT_SOURCE");
-    }
-    if (this.status >= 200 && this.status < 400) {
-      var data = JSON.parse(this.response);
-      if (devices.update_available(data)) {
-        if (state == "WAIT_REMOVAL" || state == "WAIT_REMOVAL_RESTART") {
-          check_device_removal();
-        } else if (state == "WAIT_WIPE_KEY" || state == 'WAIT_IMAGE_KEY') {
-          confirm_key();
-        } else {
-          render_device_choice();
-        }
-      }
+  if (!watch_devices) {
+    return;
+  }
+  clear_error();
+  if (state == "INIT") {
+    set_state("WAIT_SOURCE");
+  }
+  if (devices.update_available(last_devices)) {
+    if (state == "WAIT_REMOVAL" || state == "WAIT_REMOVAL_RESTART") {
+      check_device_removal();
+    } else if (state == "WAIT_WIPE_KEY" || state == 'WAIT_IMAGE_KEY') {
+      confirm_key();
     } else {
-      throw_error(langDocument["errgetdev"]);
-    }
-  };
-  request.error = function () {
-    throw_error(langDocument["errgetdev"]);
-  };
-  request.onerror = function () {
-    document.location.reload(true);
-  };
-  request.send();
-}
-
-function check_id() {
-  var request = new XMLHttpRequest();
-  request.open("GET", "/id", true);
-  request.onload = function () {
-    clear_error();
-    if (this.status >= 400) {
-      throw_error(langDocument["errgetid"]);
+      render_device_choice();
     }
-  };
-  request.error = function () {
-    throw_error(langDocument["errgetid"]);
-  };
-  request.onerror = function () {
-    document.location.reload(true);
-  };
-  request.send();
+  }
 }
 
 function get_id() {
-  var request = new XMLHttpRequest();
-  request.open("GET", "/id", true);
-  request.onload = function () {
-    if (this.status >= 200 && this.status < 400) {
-      var data = JSON.parse(this.response);
-      if (data.length > 0) {
-        document.querySelector("#id-num div").innerHTML = "<h4 id='id-content'></h4>";
-        document.querySelector("#id-content").innerText = "ID: " + data;
-        document.querySelector("#id-num div").classList.add("alert-success");
-        document.querySelector("#id-num div").classList.remove("alert-info");
-        document.querySelector("#id-num").style.height = "60px";
-
-        clearInterval(refresh_id);
-        clearInterval(refresh_check_id);
-        do_copy();
-      }
-    } else {
-      throw_error(langDocument["errgetid"]);
-    }
-  };
-  request.error = function () {
-    throw_error(langDocument["errgetid"]);
-  };
-  request.send();
+  if (state == "WAIT_ID" && last_id.length > 0) {
+    document.querySelector("#id-num div").innerHTML = "<h4 id='id-content'></h4>";
+    document.querySelector("#id-content").innerText = "ID: " + last_id;
+    document.querySelector("#id-num div").classList.add("alert-success");
+    document.querySelector("#id-num div").classList.remove("alert-info");
+    document.querySelector("#id-num").style.height = "60px";
+    do_copy();
+  }
 }
 
 function do_id_and_copy() {
+  if (devices.device_out.dev_type == "Usb" && usb_passphrase_required &&
+      document.querySelector("#passphrase").value == "") {
+    set_error(langDocument["passrequired"]);
+    return;
+  }
   set_state("WAIT_ID");
   get_id();
-  refresh_id = setInterval(get_id, 1000);
 }
 
 function tool_device_choice(action) {
@@ -1245,6 +1295,9 @@ function tool_device_choice(action) {
     set_state("WAIT_WIPE_KEY");
     document.querySelector("#copy-options").classList.remove('d-none');
     document.querySelector("#wipe-warning").classList.remove('d-none');
+    if (wipe_type != "quick") {
+      document.querySelector("#wipe-mode").classList.remove('d-none');
+    }
     updateElementLang(document.querySelector("#usb-arrow p"), "insertwipe");
   } else if (action == 'imagedisk') {
     set_state("WAIT_IMAGE_KEY");
@@ -1252,7 +1305,6 @@ function tool_device_choice(action) {
   }
   document.querySelector("#cancel-button").innerText = langDocument["cancel"];
   document.querySelector("#usb-arrow img").src = "static/img/device_wipe.svg";
-  device_choice();
 }
 
 function confirm_key() {
@@ -1278,9 +1330,7 @@ function do_tool() {
 
 function do_wipe_key() {
   set_state("WIPE_KEY");
-  clearInterval(refresh_device);
-  clearInterval(refresh_id);
-  clearInterval(refresh_check_id);
+  watch_devices = false;
   document.querySelector("#copy-options").classList.add("d-none");
   let wipe_message = document.querySelector("#tool-message");
   updateElementLang(wipe_message, "formatting");
@@ -1331,6 +1381,9 @@ function do_wipe_key() {
         break;
       case "wipe_end":
         updateElementLang(wipe_message, "formatend");
+        if (data.method) {
+          wipe_message.innerText += " (" + langDocument["wipemethod"] + data.method + ")";
+        }
         percent = 100;
         wipe_time.setAttribute("hidden", true);
         progress.innerText = percent + "%";
@@ -1366,8 +1419,10 @@ function do_wipe_key() {
 
   var fschoice = document.querySelector("#fsfmt");
   var fsfmt = fschoice.options[fschoice.selectedIndex].value;
+  var modechoice = document.querySelector("#wipemode");
+  var mode = modechoice.options[modechoice.selectedIndex].value;
 
-  fetch("/wipe" + "/" + devices.device_in.id + "/" + fsfmt + "/" + quick, {
+  fetch("/admin/wipe" + "/" + devices.device_in.id + "/" + fsfmt + "/" + quick + "?mode=" + mode, {
     method: "GET",
     headers: {
       Accept: "application/json",
@@ -1381,14 +1436,11 @@ function do_wipe_key() {
       document.querySelector("#cancel-button").removeAttribute("disabled");
       throw_error(langDocument["formaterr"]);
     });
-  clearInterval(refresh_device);
 }
 
 function do_image_disk() {
   set_state("IMAGE_KEY");
-  clearInterval(refresh_device);
-  clearInterval(refresh_id);
-  clearInterval(refresh_check_id);
+  watch_devices = false;
 
   let message = document.querySelector("#tool-message");
   updateElementLang(message, "imaging");
@@ -1460,7 +1512,7 @@ function do_image_disk() {
     }
   };
 
-  fetch("/imagedisk" + "/" + devices.device_in.id, {
+  fetch("/admin/imagedisk" + "/" + devices.device_in.id, {
     method: "GET",
     headers: {
       Accept: "application/json",
@@ -1474,7 +1526,6 @@ function do_image_disk() {
       document.querySelector("#cancel-button").removeAttribute("disabled");
       throw_error(langDocument["imgerr"]);
     });
-  clearInterval(refresh_device);
 
 }
 
@@ -1483,15 +1534,13 @@ function reload_document() {
 }
 
 function reset_usbsas() {
-  get_usbsas_infos();
   clearInterval(reset_timer);
   var request = new XMLHttpRequest();
   request.open("GET", "/reset", true);
   request.onload = function (data) {
     if (this.status >= 200 && this.status < 400) {
+      watch_devices = true;
       device_choice();
-      refresh_device = setInterval(device_choice, 1000);
-      refresh_check_id = setInterval(check_id, 2000);
     } else {
       throw_error(langDocument["reseterr"]);
     }
@@ -1507,9 +1556,90 @@ function reset_usbsas() {
   request.send();
 }
 
+// Clients watching display the state and progress of the operator's session
+function set_watching(watching) {
+  document.querySelector("body").setAttribute("data-watching", watching);
+}
+
+function watch_progress(data) {
+  if (operator) {
+    return;
+  }
+  let text = langDocument[data.status] || data.status;
+  if (data.progress !== undefined) {
+    text += " (" + Math.min(100, data.progress).toFixed(0) + "%)";
+  } else if (data.total_size) {
+    text += " (" + (100 * data.current_size / data.total_size).toFixed(0) + "%)";
+  }
+  document.querySelector("#watch-progress").innerText = text;
+}
+
+// Take control of usbsas if no other client has it, watch otherwise
+function take_session() {
+  fetch("/session", { method: "POST" })
+    .then((response) => {
+      if (response.ok) {
+        if (!operator) {
+          operator = true;
+          set_watching(false);
+          reset_usbsas();
+        }
+      } else {
+        set_watching(true);
+      }
+    })
+    .catch((error) => {
+      console.error(error);
+      set_error(langDocument["errconnsrv"]);
+    });
+}
+
+function start_events() {
+  events = new EventSource("/events");
+  // The cookie identifying this client is set once connected
+  events.addEventListener("open", () => {
+    clear_error();
+    take_session();
+  });
+  events.addEventListener("error", () => {
+    set_error(langDocument["errconnsrv"]);
+  });
+  events.addEventListener("infos", (event) => {
+    set_usbsas_infos(JSON.parse(event.data));
+  });
+  events.addEventListener("devices", (event) => {
+    last_devices = JSON.parse(event.data);
+    device_choice();
+  });
+  events.addEventListener("id", (event) => {
+    last_id = JSON.parse(event.data);
+    get_id();
+  });
+  events.addEventListener("state", (event) => {
+    let session_state = JSON.parse(event.data);
+    document.querySelector("#watch-state").innerText =
+      langDocument["state-" + session_state] || session_state;
+    if (session_state == "idle") {
+      document.querySelector("#watch-progress").innerText = "";
+    }
+  });
+  events.addEventListener("progress", (event) => {
+    watch_progress(JSON.parse(event.data));
+  });
+  events.addEventListener("session", (event) => {
+    let session = JSON.parse(event.data);
+    if (operator && !session.operator) {
+      // Lost control of usbsas
+      document.location.reload(true);
+    } else if (!session.controlled) {
+      take_session();
+    }
+  });
+}
+
 document.addEventListener("readystatechange", (event) => {
   if (document.readyState == "interactive") {
-    reset_usbsas();
+    start_events();
   }
   document.querySelector("#schema").addEventListener("mouseenter", function () {
     document.querySelector("#schema").classList.add("anim_usb");
@@ -1603,7 +1733,7 @@ var ready = (callback) => {
 ready(() => {
   document.querySelector("#footer").addEventListener("dblclick", function (e) {
     try {
-      parse_json_and_call("server_infos", display_infos);
+      parse_json_and_call("admin/server_infos", display_infos);
       document.querySelector("#modalInfos").style.display = "block";
       document.querySelector("#modalInfos").className = "modal fade show";
     } catch (error) {
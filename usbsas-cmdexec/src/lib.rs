@@ -5,7 +5,9 @@
 
 use log::{error, info, trace};
 use std::{
-    os::unix::io::RawFd,
+    fs::{self, File},
+    io,
+    os::unix::{io::RawFd, process::CommandExt},
     process::{Command, Stdio},
 };
 use thiserror::Error;
@@ -14,6 +16,7 @@ use usbsas_config::{conf_parse, conf_read, Command as CmdConf, PostCopy};
 use usbsas_process::UsbsasProcess;
 use usbsas_proto as proto;
 use usbsas_proto::{cmdexec::request::Msg, common::OutFileType};
+use usbsas_utils::encrypt;
 
 #[derive(Error, Debug)]
 enum Error {
@@ -128,12 +131,29 @@ impl RunningState {
             .cmd
             .take()
             .ok_or_else(|| Error::Error("No command in conf".to_string()))?;
-        let args = replace_arg_source(&cmd.command_args, &self.out_tar);
+        let out_fname = match cmd.recipients {
+            Some(ref recipients) if !recipients.is_empty() => self.encrypt_tar(recipients)?,
+            _ => self.out_tar.clone(),
+        };
+        let args = replace_arg_source(&cmd.command_args, &out_fname);
         self.exec_cmd(cmd.command_bin, args)?;
         comm.exec(proto::cmdexec::ResponseExec {})?;
         Ok(())
     }
 
+    /// Encrypt the output tar for `recipients`, returns the filename of the
+    /// encrypted tar. The plaintext one is removed, only the encrypted one is
+    /// given to the command.
+    fn encrypt_tar(&self, recipients: &[String]) -> Result<String> {
+        let out_fname = format!("{}.age", self.out_tar);
+        info!("encrypting {} for {:?}", self.out_tar, recipients);
+        let mut writer = encrypt::recipients_writer(File::create(&out_fname)?, recipients)?;
+        io::copy(&mut File::open(&self.out_tar)?, &mut writer)?;
+        writer.finish()?;
+        fs::remove_file(&self.out_tar)?;
+        Ok(out_fname)
+    }
+
     fn post_copy(
         &mut self,
         comm: &mut Comm<proto::cmdexec::Request>,
@@ -152,12 +172,21 @@ impl RunningState {
 
     fn exec_cmd(&self, binpath: String, args: Vec<String>) -> Result<()> {
         info!("executing {} {:?}", binpath, args);
-        if let Ok(cmd) = Command::new(binpath)
+        let mut command = Command::new(binpath);
+        command
             .args(args)
+            .stdin(Stdio::null())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-        {
+            .stderr(Stdio::piped());
+        // The command can't be confined as much as cmdexec, only deny it
+        // syscalls it has no reason to use
+        unsafe {
+            command.pre_exec(|| {
+                usbsas_privileges::cmdexec::restrict_exec()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+            });
+        }
+        if let Ok(cmd) = command.spawn() {
             match cmd.wait_with_output() {
                 Ok(output) => {
                     if !output.status.success() {
@@ -18,6 +18,7 @@ lazy_static! {
             "RUST_BACKTRACE",
             "USBSAS_MOCK_IN_DEV",
             "USBSAS_MOCK_OUT_DEV",
+            "USBSAS_SECCOMP_AUDIT",
         ]
         .iter()
         .map(|s| s.to_string())
@@ -31,6 +32,8 @@ pub struct Network {
     pub longdescr: String,
     pub url: String,
     pub krb_service_name: Option<String>,
+    /// age recipients the uploaded archive is encrypted for
+    pub recipients: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -39,6 +42,8 @@ pub struct Command {
     pub longdescr: String,
     pub command_bin: String,
     pub command_args: Vec<String>,
+    /// age recipients the archive given to the command is encrypted for
+    pub recipients: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,6 +77,245 @@ pub struct UsbPortAccesses {
     pub ports_dst: Vec<u8>,
 }
 
+/// USB interface classes refused by default, composite mass storage devices
+/// with such interfaces being a sign of BadUSB: CDC (network, modem), HID,
+/// CDC data and wireless controller (RNDIS)
+const DEFAULT_DENIED_CLASSES: [u8; 4] = [0x02, 0x03, 0x0a, 0xe0];
+
+/// Match `text` against `pattern` where `*` matches any sequence of characters
+/// and `?` any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and of the text it matched from
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Vendor / product IDs and serial number pattern (`*` and `?` wildcards) of
+/// USB devices, unset fields match any device.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceId {
+    pub vendorid: Option<u32>,
+    pub productid: Option<u32>,
+    pub serial: Option<String>,
+}
+
+impl DeviceId {
+    pub fn matches(&self, vendorid: u32, productid: u32, serial: &str) -> bool {
+        self.vendorid.map_or(true, |id| id == vendorid)
+            && self.productid.map_or(true, |id| id == productid)
+            && self
+                .serial
+                .as_ref()
+                .map_or(true, |pattern| glob_match(pattern, serial))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DeviceList {
+    /// If set, only the devices matching one of these are allowed
+    pub allow: Option<Vec<DeviceId>>,
+    pub deny: Option<Vec<DeviceId>>,
+}
+
+impl DeviceList {
+    /// Returns the reason why the device isn't allowed, if it isn't
+    pub fn check(&self, vendorid: u32, productid: u32, serial: &str) -> Result<(), String> {
+        if let Some(allow) = &self.allow {
+            if !allow
+                .iter()
+                .any(|id| id.matches(vendorid, productid, serial))
+            {
+                return Err("not in the allowed devices".into());
+            }
+        }
+        if let Some(deny) = &self.deny {
+            if deny
+                .iter()
+                .any(|id| id.matches(vendorid, productid, serial))
+            {
+                return Err("in the denied devices".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Devices accepted as sources and destinations
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DevicePolicy {
+    pub source: Option<DeviceList>,
+    pub destination: Option<DeviceList>,
+    /// Devices with an interface (or a device class) of one of these classes
+    /// are refused, HID, CDC and wireless controllers if not set
+    pub denied_classes: Option<Vec<u8>>,
+}
+
+impl DevicePolicy {
+    /// Returns the reason why a device with these interface classes isn't
+    /// allowed, if it isn't
+    pub fn check_classes(&self, classes: &[u32]) -> Result<(), String> {
+        let denied_classes = self
+            .denied_classes
+            .as_deref()
+            .unwrap_or(&DEFAULT_DENIED_CLASSES);
+        match classes.iter().find(|class| {
+            denied_classes
+                .iter()
+                .any(|denied| u32::from(*denied) == **class)
+        }) {
+            Some(class) => Err(format!("has an interface of denied class 0x{:02x}", class)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_source(&self, vendorid: u32, productid: u32, serial: &str) -> Result<(), String> {
+        match &self.source {
+            Some(list) => list.check(vendorid, productid, serial),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_destination(
+        &self,
+        vendorid: u32,
+        productid: u32,
+        serial: &str,
+    ) -> Result<(), String> {
+        match &self.destination {
+            Some(list) => list.check(vendorid, productid, serial),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Audit mode of the seccomp filters, for debugging: denied syscalls don't
+/// kill the processes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompAudit {
+    /// Syscalls are allowed and logged by the kernel
+    Log,
+    /// Syscalls are reported on stderr by the process and fail with ENOSYS
+    Trap,
+}
+
+/// What to do with symbolic links (and NTFS junctions / symlinks) found on
+/// the source device.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Don't copy links, report them as skipped
+    #[default]
+    Skip,
+    /// Store links as links in the output tar (network and command destinations)
+    Link,
+    /// Copy the target of the link if it's a file inside the source volume
+    Dereference,
+}
+
+/// What to do with files of the source device that have unreadable sectors.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DamagedFilePolicy {
+    /// Don't write damaged files on the destination, report them as damaged
+    #[default]
+    Skip,
+    /// Copy damaged files with the unreadable sectors replaced by zeros
+    Zerofill,
+}
+
+/// Ed25519 key used to sign the transfer manifests
+#[derive(Clone, Debug, Deserialize)]
+pub struct Signature {
+    pub key_path: String,
+}
+
+/// Only destination devices listed in the signed registry can be written
+#[derive(Clone, Debug, Deserialize)]
+pub struct Enrollment {
+    pub registry_path: String,
+    /// Hexadecimal public key of the administrators, who sign the registry
+    pub public_key: String,
+}
+
+/// Credentials of the administrators of the web server
+#[derive(Clone, Debug, Deserialize)]
+pub struct Admin {
+    pub username: String,
+    /// PHC string of the password (argon2 or pbkdf2), like the output of
+    /// `usbsas-server --hash-password`
+    pub password_hash: String,
+    /// File the logs of the server are written to, downloadable by the
+    /// administrators
+    pub log_file: Option<String>,
+}
+
+/// History of the transfers, wipes and disk images kept by the web server
+#[derive(Clone, Debug, Deserialize)]
+pub struct History {
+    /// JSON lines file the records are appended to
+    pub path: String,
+    /// Size (in MB) from which the file is rotated, 64 if not set
+    pub max_size: Option<u64>,
+    /// Days the rotated files are kept, forever if not set
+    pub retention_days: Option<u64>,
+}
+
+/// Where the logs of the web server and of usbsas are sent
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    /// journald native protocol, the logs aren't written on stderr
+    Journald,
+    /// RFC 5424 messages sent to a syslog collector, also written on stderr
+    Syslog,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Logging {
+    #[serde(default)]
+    pub output: LogOutput,
+    /// "host:port" of the syslog collector
+    pub address: Option<String>,
+    #[serde(default)]
+    pub protocol: SyslogProtocol,
+    /// PEM certificates of the CAs of the syslog collector (TLS)
+    pub ca_file: Option<String>,
+    /// Directory of the messages kept while the collector is unreachable
+    pub spool_dir: Option<String>,
+    /// Size (in MB) of the spool, 64 if not set
+    pub spool_max_size: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub out_directory: String,
@@ -83,6 +327,18 @@ pub struct Config {
     pub post_copy: Option<PostCopy>,
     pub analyzer: Option<Analyzer>,
     pub usb_port_accesses: Option<UsbPortAccesses>,
+    pub device_policy: Option<DevicePolicy>,
+    pub enrollment: Option<Enrollment>,
+    pub symlink_policy: Option<SymlinkPolicy>,
+    pub damaged_file_policy: Option<DamagedFilePolicy>,
+    pub signature: Option<Signature>,
+    pub usb_passphrase_required: Option<bool>,
+    pub verify_destination: Option<bool>,
+    pub sandbox_required: Option<bool>,
+    pub seccomp_audit: Option<SeccompAudit>,
+    pub admin: Option<Admin>,
+    pub history: Option<History>,
+    pub logging: Option<Logging>,
 }
 
 impl Config {
@@ -108,3 +364,59 @@ pub fn conf_parse(conf_str: &str) -> io::Result<Config> {
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        // No wildcards
+        assert!(glob_match("ABC123", "ABC123"));
+        assert!(!glob_match("ABC123", "ABC1234"));
+        assert!(!glob_match("ABC123", "ABC12"));
+
+        // `*` at the ends
+        assert!(glob_match("ABC*", "ABC"));
+        assert!(glob_match("ABC*", "ABC123"));
+        assert!(!glob_match("ABC*", "XABC"));
+        assert!(glob_match("*123", "123"));
+        assert!(glob_match("*123", "ABC123"));
+        assert!(!glob_match("*123", "1234"));
+        assert!(glob_match("*B*", "ABC"));
+        assert!(!glob_match("*B*", "AC"));
+
+        // Consecutive `*`, backtracking
+        assert!(glob_match("**", ""));
+        assert!(glob_match("A**C", "ABBC"));
+        assert!(glob_match("A***", "A"));
+        assert!(glob_match("A*B*C", "AXBXBXC"));
+        assert!(!glob_match("A*B*C", "AXBXBX"));
+        assert!(glob_match("*AB", "AAAB"));
+
+        // `?`
+        assert!(glob_match("A?C", "ABC"));
+        assert!(!glob_match("A?C", "AC"));
+        assert!(glob_match("?*", "A"));
+        assert!(!glob_match("?*", ""));
+
+        // Empty pattern only matches empty text
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "A"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "ABC"));
+    }
+
+    #[test]
+    fn test_device_id() {
+        let id = DeviceId {
+            vendorid: Some(0x0951),
+            productid: None,
+            serial: Some("60A44C*".into()),
+        };
+        assert!(id.matches(0x0951, 0x1666, "60A44C413F6A"));
+        assert!(!id.matches(0x0951, 0x1666, "70A44C413F6A"));
+        assert!(!id.matches(0x1234, 0x1666, "60A44C413F6A"));
+        assert!(DeviceId::default().matches(0x1234, 0x5678, ""));
+    }
+}
@@ -86,6 +86,23 @@ struct PartitionsListedState<T: UsbContext> {
 
 struct WaitEndState {}
 
+// Only reads of file contents tolerate unreadable sectors (zero-filled and
+// reported in the response), reads of the partition table and of the fs
+// metadata fail on them
+fn read_sectors<T: UsbContext>(
+    usb_mass_storage: &mut MassStorage<T>,
+    req: &proto::scsi::RequestReadSectors,
+) -> std::io::Result<(Vec<u8>, Vec<u64>)> {
+    let block_size = usb_mass_storage.block_size as usize;
+    if req.tolerant {
+        usb_mass_storage.read_sectors_tolerant(req.offset, req.count, block_size)
+    } else {
+        usb_mass_storage
+            .read_sectors(req.offset, req.count, block_size)
+            .map(|data| (data, Vec::new()))
+    }
+}
+
 impl<T: UsbContext> InitState<T> {
     fn run(self, comm: &mut Comm<proto::scsi::Request>) -> Result<State<T>> {
         trace!("waiting unlock");
@@ -109,16 +126,22 @@ impl<T: UsbContext> InitState<T> {
             return Ok(State::WaitEnd(WaitEndState {}));
         }
 
-        let usb_mass_storage = match MassStorage::from_busnum_devnum(self.context, busnum, devnum) {
-            Ok(ums) => ums,
-            Err(err) => {
-                error!("Init mass storage error: {}, waiting end", err);
-                comm.error(proto::scsi::ResponseError {
-                    err: format!("{}", err),
-                })?;
-                return Ok(State::WaitEnd(WaitEndState {}));
-            }
-        };
+        // The LUN to open follows busnum and devnum
+        comm.read_exact(&mut buf[..4])?;
+        let lun = u8::try_from(LittleEndian::read_u32(&buf[..4]))?;
+        debug!("lun: {}", lun);
+
+        let usb_mass_storage =
+            match MassStorage::from_busnum_devnum(self.context, busnum, devnum, Some(lun)) {
+                Ok(ums) => ums,
+                Err(err) => {
+                    error!("Init mass storage error: {}, waiting end", err);
+                    comm.error(proto::scsi::ResponseError {
+                        err: format!("{}", err),
+                    })?;
+                    return Ok(State::WaitEnd(WaitEndState {}));
+                }
+            };
 
         #[cfg(not(feature = "mock"))]
         usbsas_privileges::dev2scsi::drop_priv(
@@ -150,21 +173,17 @@ impl<T: UsbContext> DevOpenedState<T> {
                         })?;
                     }
                 },
-                Msg::ReadSectors(req) => {
-                    match self.usb_mass_storage.read_sectors(
-                        req.offset,
-                        req.count,
-                        self.usb_mass_storage.block_size as usize,
-                    ) {
-                        Ok(data) => comm.readsectors(proto::scsi::ResponseReadSectors { data })?,
-                        Err(err) => {
-                            error!("{}", err);
-                            comm.error(proto::scsi::ResponseError {
-                                err: format!("{}", err),
-                            })?;
-                        }
+                Msg::ReadSectors(req) => match read_sectors(&mut self.usb_mass_storage, &req) {
+                    Ok((data, bad_sectors)) => {
+                        comm.readsectors(proto::scsi::ResponseReadSectors { data, bad_sectors })?
                     }
-                }
+                    Err(err) => {
+                        error!("{}", err);
+                        comm.error(proto::scsi::ResponseError {
+                            err: format!("{}", err),
+                        })?;
+                    }
+                },
                 Msg::End(_) => {
                     comm.end(proto::scsi::ResponseEnd {})?;
                     return Ok(State::End);
@@ -184,8 +203,13 @@ impl<T: UsbContext> DevOpenedState<T> {
         trace!("req partitions");
         let mut partitions = vec![];
         let block_size = self.usb_mass_storage.block_size as u64;
+        // bootsector only knows sector sizes fitting in a u16 (up to 32KiB)
+        let sector_size = match u16::try_from(block_size) {
+            Ok(size) => bootsector::SectorSize::Known(size),
+            Err(_) => bootsector::SectorSize::GuessOrAssume,
+        };
         let options = bootsector::Options {
-            sector_size: bootsector::SectorSize::Known(u16::try_from(block_size)?),
+            sector_size,
             ..Default::default()
         };
 
@@ -340,6 +364,8 @@ impl<T: UsbContext> DevOpenedState<T> {
                 sectors_to_read,
                 block_size as usize,
             )?;
+            // Blocks larger than 32KiB start before the ISO header
+            let data = &data[(0x8000 % block_size) as usize..];
             if [0x43, 0x44, 0x30, 0x30, 0x31] == data[0x1..0x6]
                 || [0x43, 0x44, 0x30, 0x30, 0x31] == data[0x801..0x806]
             {
@@ -366,21 +392,17 @@ impl<T: UsbContext> PartitionsListedState<T> {
         loop {
             let req: proto::scsi::Request = comm.recv()?;
             match req.msg.ok_or(Error::BadRequest)? {
-                Msg::ReadSectors(req) => {
-                    match self.usb_mass_storage.read_sectors(
-                        req.offset,
-                        req.count,
-                        self.usb_mass_storage.block_size as usize,
-                    ) {
-                        Ok(data) => comm.readsectors(proto::scsi::ResponseReadSectors { data })?,
-                        Err(err) => {
-                            error!("{}", err);
-                            comm.error(proto::scsi::ResponseError {
-                                err: format!("{}", err),
-                            })?;
-                        }
+                Msg::ReadSectors(req) => match read_sectors(&mut self.usb_mass_storage, &req) {
+                    Ok((data, bad_sectors)) => {
+                        comm.readsectors(proto::scsi::ResponseReadSectors { data, bad_sectors })?
                     }
-                }
+                    Err(err) => {
+                        error!("{}", err);
+                        comm.error(proto::scsi::ResponseError {
+                            err: format!("{}", err),
+                        })?;
+                    }
+                },
                 Msg::End(_) => {
                     comm.end(proto::scsi::ResponseEnd {})?;
                     break;
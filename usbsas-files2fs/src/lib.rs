@@ -65,6 +65,11 @@ protoresponse!(
     end = End[ResponseEnd]
 );
 
+// Largest sector size supported by FatFs and ntfs-3g
+const MAX_FS_SECTOR_SIZE: u64 = 4096;
+// Size field of MBR partition entries
+const MAX_MBR_SECTOR_COUNT: u64 = 0xFFFF_FFFF;
+
 enum State {
     Init(InitState),
     WaitFsInfos(WaitFsInfosState),
@@ -135,15 +140,17 @@ impl WaitFsInfosState {
         trace!("wait fs infos");
         let req: proto::writefs::Request = comm.recv()?;
         let newstate = match req.msg.ok_or(Error::BadRequest)? {
-            Msg::SetFsInfos(fsinfos) => match self.mkfs(comm, fsinfos.dev_size, fsinfos.fstype) {
-                Ok(fs) => State::WaitNewFile(WaitNewFileState { fs }),
-                Err(err) => {
-                    comm.error(proto::writefs::ResponseError {
-                        err: format!("Error mkfs: {}", err),
-                    })?;
-                    State::WaitEnd(WaitEndState {})
+            Msg::SetFsInfos(fsinfos) => {
+                match self.mkfs(comm, fsinfos.dev_size, fsinfos.sector_size, fsinfos.fstype) {
+                    Ok(fs) => State::WaitNewFile(WaitNewFileState { fs }),
+                    Err(err) => {
+                        comm.error(proto::writefs::ResponseError {
+                            err: format!("Error mkfs: {}", err),
+                        })?;
+                        State::WaitEnd(WaitEndState {})
+                    }
                 }
-            },
+            }
             Msg::ImgDisk(_) => return Ok(State::ImgDisk(ImgDiskState { fs: self.fs })),
             Msg::End(_) => {
                 comm.end(proto::writefs::ResponseEnd {})?;
@@ -164,42 +171,69 @@ impl WaitFsInfosState {
         self,
         comm: &mut Comm<proto::writefs::Request>,
         dev_size: u64,
+        sector_size: u32,
         fstype: i32,
     ) -> Result<Box<dyn FSWrite<StreamSlice<SparseFile<File>>>>> {
         let out_fs_type =
             OutFsType::from_i32(fstype).ok_or_else(|| Error::FSError("bad fstype".into()))?;
 
-        log::debug!("mkfs dev_size: {}", dev_size);
+        // Sector size of the destination, 512 if not specified
+        let sector_size = match u64::from(sector_size) {
+            0 => SECTOR_SIZE,
+            size => size,
+        };
+        if !sector_size.is_power_of_two()
+            || !(SECTOR_SIZE..=MAX_FS_SECTOR_SIZE).contains(&sector_size)
+        {
+            return Err(Error::FSError(format!(
+                "unsupported sector size: {}",
+                sector_size
+            )));
+        }
+
+        log::debug!("mkfs dev_size: {} sector_size: {}", dev_size, sector_size);
 
-        let fs_size = dev_size - (SECTOR_START * SECTOR_SIZE);
-        if fs_size % SECTOR_SIZE != 0 {
+        let fs_size = dev_size - (SECTOR_START * sector_size);
+        if fs_size % sector_size != 0 {
             return Err(Error::FSError("fs size not multiple of sector size".into()));
         }
 
-        if dev_size % SECTOR_SIZE as u64 != 0 {
+        if dev_size % sector_size != 0 {
             return Err(Error::FSError(
                 "dev size not multiple of sector size".into(),
             ));
         }
-        let sector_count = fs_size / SECTOR_SIZE;
-        let sector_count: u64 = if sector_count > 0xFFFF_FFFF {
-            return Err(Error::FSError("sector count too big".into()));
+        // MBR partitions can't be larger than 2^32 - 1 sectors, the rest of
+        // larger devices is left unused
+        let sector_count = fs_size / sector_size;
+        let sector_count: u64 = if sector_count > MAX_MBR_SECTOR_COUNT {
+            log::warn!(
+                "device too large for an mbr partition, only using {} of {} sectors",
+                MAX_MBR_SECTOR_COUNT,
+                sector_count
+            );
+            MAX_MBR_SECTOR_COUNT
         } else {
-            sector_count & 0xFFFF_FFFF
+            sector_count
         };
 
-        let mut sparse_file =
-            SparseFile::new(self.fs, SECTOR_SIZE, (SECTOR_START + sector_count) as usize)?;
+        // The bitvec of written sectors is always in 512 bytes units, fs2dev
+        // groups them by blocks of the destination
+        let mut sparse_file = SparseFile::new(
+            self.fs,
+            SECTOR_SIZE,
+            ((SECTOR_START + sector_count) * sector_size / SECTOR_SIZE) as usize,
+        )?;
 
         let fs: Box<dyn FSWrite<StreamSlice<SparseFile<File>>>> = match out_fs_type {
             OutFsType::Fat | OutFsType::Exfat => {
                 // ff handles writing mbr but still wrap in StreamSlice so we have the same type as ntfs below
                 let file_slice =
-                    StreamSlice::new(sparse_file, 0, (SECTOR_START + sector_count) * SECTOR_SIZE)?;
+                    StreamSlice::new(sparse_file, 0, (SECTOR_START + sector_count) * sector_size)?;
 
                 Box::new(ff::FatFsWriter::mkfs(
                     file_slice,
-                    SECTOR_SIZE,
+                    sector_size,
                     sector_count,
                     Some(out_fs_type),
                 )?)
@@ -225,13 +259,13 @@ impl WaitFsInfosState {
 
                 let file_slice = StreamSlice::new(
                     sparse_file,
-                    SECTOR_START * SECTOR_SIZE,
-                    (SECTOR_START + sector_count) * SECTOR_SIZE,
+                    SECTOR_START * sector_size,
+                    (SECTOR_START + sector_count) * sector_size,
                 )?;
 
                 Box::new(ntfs::NTFS3G::mkfs(
                     file_slice,
-                    SECTOR_SIZE,
+                    sector_size,
                     sector_count,
                     None,
                 )?)
@@ -10,6 +10,7 @@ use usbsas_comm::{protoresponse, Comm};
 use usbsas_process::UsbsasProcess;
 use usbsas_proto as proto;
 use usbsas_proto::{common::FileType, writetar::request::Msg};
+use usbsas_utils::sign::SigningKey;
 
 protoresponse!(
     CommWritefs,
@@ -44,6 +45,7 @@ impl State {
 
 struct InitState {
     archive_path: String,
+    key_path: Option<String>,
 }
 
 impl InitState {
@@ -54,7 +56,14 @@ impl InitState {
             .open(&self.archive_path)?;
         let outfd = archive_file.as_raw_fd();
 
-        let mut archive: Box<dyn ArchiveWriter> = Box::new(TarWriter::new(archive_file));
+        // Load the signing key before entering seccomp
+        let signing_key = match self.key_path {
+            Some(ref key_path) => Some(SigningKey::from_file(key_path)?),
+            None => None,
+        };
+
+        let mut archive: Box<dyn ArchiveWriter> =
+            Box::new(TarWriter::new(archive_file, signing_key));
 
         usbsas_privileges::files2tar::drop_priv(comm.input_fd(), comm.output_fd(), outfd)?;
 
@@ -74,10 +83,16 @@ impl WaitNewFileState {
             Msg::NewFile(req) => {
                 let fstype = FileType::from_i32(req.ftype)
                     .ok_or_else(|| Error::Error("Bad file type".to_string()))?;
-                match self
-                    .archive
-                    .newfile(&req.path, fstype, req.size, req.timestamp)
-                {
+                let res = match fstype {
+                    FileType::Symlink => {
+                        self.archive
+                            .newlink(&req.path, &req.link_target, req.timestamp)
+                    }
+                    _ => self
+                        .archive
+                        .newfile(&req.path, fstype, req.size, req.timestamp),
+                };
+                match res {
                     Ok(_) => {
                         comm.newfile(proto::writetar::ResponseNewFile {})?;
                         Ok(State::WritingFile(WritingFileState {
@@ -136,8 +151,13 @@ impl WritingFileState {
                         comm.writefile(proto::writetar::ResponseWriteFile {})?;
                     }
                 }
-                Msg::EndFile(_) => {
-                    if let Err(err) = self.archive.endfile(self.len_written) {
+                Msg::EndFile(req) => {
+                    let res = if req.discard {
+                        self.archive.discardfile()
+                    } else {
+                        self.archive.endfile(self.len_written)
+                    };
+                    if let Err(err) = res {
                         return Err(Error::Error(format!("{}", err)));
                     };
                     comm.endfile(proto::writetar::ResponseEndFile {})?;
@@ -181,8 +201,15 @@ pub struct Files2Tar {
 }
 
 impl Files2Tar {
-    fn new(comm: Comm<proto::writetar::Request>, archive_path: String) -> Result<Self> {
-        let state = State::Init(InitState { archive_path });
+    fn new(
+        comm: Comm<proto::writetar::Request>,
+        archive_path: String,
+        key_path: Option<String>,
+    ) -> Result<Self> {
+        let state = State::Init(InitState {
+            archive_path,
+            key_path,
+        });
         Ok(Files2Tar { comm, state })
     }
 
@@ -216,7 +243,8 @@ impl UsbsasProcess for Files2Tar {
                 let mut comm = Comm::from_raw_fd(read_fd, write_fd);
                 match comm.read_u8()? {
                     // 0: unlock to start writing files in a tar
-                    0 => Files2Tar::new(comm, fname.to_string())?.main_loop()?,
+                    0 => Files2Tar::new(comm, fname.to_string(), args.get(1).cloned())?
+                        .main_loop()?,
                     // 1: unlock to exit value
                     1 => Files2Tar {
                         comm,
@@ -4,6 +4,8 @@
 //! analysis. If data is uploaded to a remote server, files will be stored in
 //! the tar under a "/data/" directory and a "/infos.json" file containing
 //! information about the input device, hostname etc. will be added.
+//! If a signing key is given as second argument, a detached signature of the
+//! transfer manifest is added as well.
 //!
 
 use thiserror::Error;
@@ -34,7 +36,9 @@ type Result<T> = std::result::Result<T, Error>;
 pub(crate) trait ArchiveWriter {
     fn init(&mut self) -> Result<()>;
     fn newfile(&mut self, path: &str, ftype: FileType, size: u64, timestamp: i64) -> Result<()>;
+    fn newlink(&mut self, path: &str, target: &str, timestamp: i64) -> Result<()>;
     fn writefile(&mut self, data: &[u8]) -> Result<()>;
     fn endfile(&mut self, len_written: usize) -> Result<()>;
+    fn discardfile(&mut self) -> Result<()>;
     fn finish(self: Box<Self>, infos: usbsas_proto::writetar::RequestClose) -> Result<()>;
 }
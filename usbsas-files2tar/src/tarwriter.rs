@@ -1,27 +1,56 @@
 use crate::ArchiveWriter;
 use crate::{Error, Result};
 use serde_json::json;
-use std::{io::Write, path::Path, time::SystemTime};
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    time::SystemTime,
+};
 use usbsas_proto::common::FileType;
-use usbsas_utils::{TAR_BLOCK_SIZE, TAR_DATA_DIR};
+use usbsas_utils::{
+    sign::SigningKey, MANIFEST_FILE_NAME, SIGNATURE_FILE_NAME, TAR_BLOCK_SIZE, TAR_DATA_DIR,
+};
 
-pub(crate) struct TarWriter<W: Write> {
-    builder: tar::Builder<W>,
+pub(crate) struct TarWriter {
+    builder: tar::Builder<File>,
     data_dir: String,
     files: Vec<String>,
+    signing_key: Option<SigningKey>,
+    // Offset of the header(s) of the last entry, to discard it
+    entry_start: u64,
 }
 
-impl<W: Write> TarWriter<W> {
-    pub(crate) fn new(writer: W) -> Self {
+impl TarWriter {
+    pub(crate) fn new(writer: File, signing_key: Option<SigningKey>) -> Self {
         TarWriter {
             builder: tar::Builder::new(writer),
             data_dir: TAR_DATA_DIR.trim_end_matches('/').to_owned() + "/",
             files: Vec::new(),
+            signing_key,
+            entry_start: 0,
         }
     }
+
+    /// Append a file at the root of the archive (outside of the data dir)
+    fn append_meta_file(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_path(path)?;
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs_f64() as u64,
+        );
+        header.set_cksum();
+        self.builder.append(&header, data)?;
+        Ok(())
+    }
 }
 
-impl<W: Write> ArchiveWriter for TarWriter<W> {
+impl ArchiveWriter for TarWriter {
     fn init(&mut self) -> Result<()> {
         self.builder.follow_symlinks(false);
         let mut header = tar::Header::new_ustar();
@@ -53,6 +82,22 @@ impl<W: Write> ArchiveWriter for TarWriter<W> {
         let mut path_string: String = path.trim_start_matches('/').into();
         self.files.push(path_string.clone());
         path_string.insert_str(0, &self.data_dir);
+        self.entry_start = self.builder.get_mut().stream_position()?;
+        self.builder
+            .append_data(&mut header, Path::new(&path_string), std::io::empty())?;
+        Ok(())
+    }
+
+    fn newlink(&mut self, path: &str, target: &str, timestamp: i64) -> Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(0);
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_link_name(target)?;
+        header.set_mtime(timestamp as u64);
+        let mut path_string: String = path.trim_start_matches('/').into();
+        self.files.push(path_string.clone());
+        path_string.insert_str(0, &self.data_dir);
         self.builder
             .append_data(&mut header, Path::new(&path_string), std::io::empty())?;
         Ok(())
@@ -73,6 +118,15 @@ impl<W: Write> ArchiveWriter for TarWriter<W> {
         Ok(())
     }
 
+    fn discardfile(&mut self) -> Result<()> {
+        // Rewind to the header of the file and truncate its content
+        let file = self.builder.get_mut();
+        file.seek(SeekFrom::Start(self.entry_start))?;
+        file.set_len(self.entry_start)?;
+        self.files.pop();
+        Ok(())
+    }
+
     fn finish(mut self: Box<Self>, req: usbsas_proto::writetar::RequestClose) -> Result<()> {
         let mut name = match uname::Info::new() {
             Ok(uname) => uname.nodename,
@@ -91,18 +145,16 @@ impl<W: Write> ArchiveWriter for TarWriter<W> {
             "description": req.description
         })
         .to_string();
-        let mut header = tar::Header::new_ustar();
-        header.set_size(infos.as_bytes().len() as u64);
-        header.set_entry_type(tar::EntryType::Regular);
-        header.set_mode(0o644);
-        header.set_path("infos.json")?;
-        header.set_mtime(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs_f64() as u64,
-        );
-        header.set_cksum();
-        self.builder.append(&header, infos.as_bytes())?;
+        self.append_meta_file("infos.json", infos.as_bytes())?;
+
+        if !req.manifest.is_empty() {
+            self.append_meta_file(MANIFEST_FILE_NAME, &req.manifest)?;
+            // Detached signature of the manifest, which holds the hashes of
+            // every file of the archive
+            if let Some(signature) = self.signing_key.as_ref().map(|key| key.sign(&req.manifest)) {
+                self.append_meta_file(SIGNATURE_FILE_NAME, signature.as_bytes())?;
+            }
+        }
 
         // Make sure everything is flushed and closed
         let mut inner = self.builder.into_inner()?;
@@ -1,9 +1,11 @@
 //! usbsas process responsible for writing the file system on destination USB
-//! device. It can also wipe devices (with 0's).
+//! device. It can also wipe devices (by overwriting them or with an erase
+//! command of the device).
 
 use bitvec::prelude::*;
 use byteorder::{LittleEndian, ReadBytesExt};
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 #[cfg(not(feature = "mock"))]
 use rusb::{Context, UsbContext};
 use std::{
@@ -13,13 +15,17 @@ use std::{
 };
 use thiserror::Error;
 use usbsas_comm::{protoresponse, Comm};
+use usbsas_config::{conf_parse, conf_read};
 #[cfg(feature = "mock")]
 use usbsas_mock::mass_storage::{
     MockContext, MockMassStorage as MassStorage, MockUsbContext as UsbContext,
 };
 use usbsas_process::UsbsasProcess;
 use usbsas_proto as proto;
-use usbsas_utils::SECTOR_SIZE;
+use usbsas_utils::{
+    enrollment::{self, Registry, MARKER_SECTOR, TOKEN_SIZE},
+    SECTOR_SIZE,
+};
 #[cfg(not(feature = "mock"))]
 use {
     std::os::unix::io::AsRawFd,
@@ -53,13 +59,17 @@ protoresponse!(
     copystatus = CopyStatus[ResponseCopyStatus],
     copystatusdone = CopyStatusDone[ResponseCopyStatusDone],
     loadbitvec = LoadBitVec[ResponseLoadBitVec],
-    wipe = Wipe[ResponseWipe]
+    wipe = Wipe[ResponseWipe],
+    wipedone = WipeDone[ResponseWipeDone],
+    enroll = Enroll[ResponseEnroll]
 );
 
 // Some usb keys don't support bigger buffers
 // (Linux writes 240 sectors per scsi write(10) requests)
-const MAX_WRITE_SECTORS: usize = 240;
-const BUFFER_MAX_WRITE_SIZE: u64 = MAX_WRITE_SECTORS as u64 * SECTOR_SIZE;
+const MAX_WRITE_SECTORS: u64 = 240;
+const BUFFER_MAX_WRITE_SIZE: u64 = MAX_WRITE_SECTORS * SECTOR_SIZE;
+// Only the first mismatching sectors are listed in the copy report
+const MAX_REPORTED_BAD_SECTORS: usize = 64;
 
 enum State<T: UsbContext> {
     Init(InitState<T>),
@@ -87,42 +97,190 @@ impl<T: UsbContext> State<T> {
 
 struct InitState<T: UsbContext> {
     fs_fname: String,
+    config_path: Option<String>,
     context: T,
 }
 
 struct DevOpenedState<T: UsbContext> {
     fs: File,
     mass_storage: MassStorage<T>,
+    verify: bool,
+    enrollment: Enrollment,
 }
 
 struct BitVecLoadedState<T: UsbContext> {
     fs: File,
     fs_bv: BitVecIterOnes,
     mass_storage: MassStorage<T>,
+    verify: bool,
 }
 
 struct CopyingState<T: UsbContext> {
     fs: File,
     fs_bv: BitVecIterOnes,
     mass_storage: MassStorage<T>,
+    verify: bool,
 }
 
 struct WipingState<T: UsbContext> {
     fs: File,
     mass_storage: MassStorage<T>,
+    verify: bool,
+    enrollment: Enrollment,
+    mode: proto::common::WipeMode,
+}
+
+// Enrollment status of the device
+struct Enrollment {
+    // Only enrolled devices can be written
+    required: bool,
+    // Marker block of the enrolled device, written again after wipes
+    marker: Option<Vec<u8>>,
+}
+
+impl Enrollment {
+    /// Read the marker of the device and check that its token is the one
+    /// enrolled for the IDs and serial number of the device
+    fn new<T: UsbContext>(
+        mass_storage: &mut MassStorage<T>,
+        registry: Option<Registry>,
+    ) -> Result<Self> {
+        let registry = match registry {
+            Some(registry) => registry,
+            None => {
+                return Ok(Enrollment {
+                    required: false,
+                    marker: None,
+                })
+            }
+        };
+        let block_size = mass_storage.block_size as usize;
+        let marker = if mass_storage.max_lba > MARKER_SECTOR {
+            let block = mass_storage.read_sectors(MARKER_SECTOR, 1, block_size)?;
+            let token = enrollment::marker_token(&block);
+            match registry
+                .find(
+                    mass_storage.vendorid,
+                    mass_storage.productid,
+                    &mass_storage.serial,
+                )
+                .filter(|device| Some(&device.token) == token.as_ref())
+            {
+                Some(device) => {
+                    info!(
+                        "enrolled device: {} ({})",
+                        device.description, device.serial
+                    );
+                    Some(block)
+                }
+                None => {
+                    // The marker may have been copied from an enrolled device
+                    if let Some(device) = token.and_then(|token| registry.find_token(&token)) {
+                        warn!(
+                            "marker of enrolled device {:04x}:{:04x} ({}) found on device {:04x}:{:04x} ({})",
+                            device.vendorid,
+                            device.productid,
+                            device.serial,
+                            mass_storage.vendorid,
+                            mass_storage.productid,
+                            mass_storage.serial
+                        );
+                    }
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(Enrollment {
+            required: true,
+            marker,
+        })
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.required && self.marker.is_none() {
+            error!("destination device isn't enrolled");
+            return Err(Error::Error("destination device isn't enrolled".into()));
+        }
+        Ok(())
+    }
+}
+
+// Data written over the device by a wipe pass
+enum Fill {
+    Zeros,
+    Random,
+}
+
+// Sectors read back from the device that don't match what was written
+#[derive(Default)]
+struct BadSectors {
+    count: u64,
+    // Only the first ones are reported
+    first: Vec<u64>,
+}
+
+impl BadSectors {
+    // Read back the sectors starting at `sector_start` and compare them with
+    // `expected`. Sectors that can't be read back are as bad as wrong ones.
+    fn check<T: UsbContext>(
+        &mut self,
+        mass_storage: &mut MassStorage<T>,
+        sector_start: u64,
+        expected: &[u8],
+        block_size: u64,
+    ) {
+        let sector_count = expected.len() as u64 / block_size;
+        let data = mass_storage
+            .read_sectors(sector_start, sector_count, block_size as usize)
+            .unwrap_or_else(|err| {
+                warn!(
+                    "couldn't read back sectors {}-{}: {}",
+                    sector_start,
+                    sector_start + sector_count,
+                    err
+                );
+                Vec::new()
+            });
+        self.compare(sector_start, &data, expected, block_size);
+    }
+
+    fn compare(&mut self, sector_start: u64, data: &[u8], expected: &[u8], block_size: u64) {
+        for sector in 0..expected.len() as u64 / block_size {
+            let range = (sector * block_size) as usize..((sector + 1) * block_size) as usize;
+            if data.get(range.clone()) != Some(&expected[range]) {
+                self.count += 1;
+                if self.first.len() < MAX_REPORTED_BAD_SECTORS {
+                    self.first.push(sector_start + sector);
+                }
+            }
+        }
+    }
 }
 
 struct WaitEndState;
 
-// Wrapper around BitVec to iterate over contiguous group of ones
+// Number of blocks written per scsi write(10) request
+fn max_write_blocks(block_size: u64) -> usize {
+    (BUFFER_MAX_WRITE_SIZE / block_size).max(1) as usize
+}
+
+// Wrapper around BitVec to iterate over contiguous group of ones, by groups
+// of at most max_len bits
 struct BitVecIterOnes {
     pub bv: BitVec<u8, Lsb0>,
     pos: usize,
+    max_len: usize,
 }
 
 impl BitVecIterOnes {
-    fn new(bv: BitVec<u8, Lsb0>) -> Self {
-        BitVecIterOnes { bv, pos: 0 }
+    fn new(bv: BitVec<u8, Lsb0>, max_len: usize) -> Self {
+        BitVecIterOnes {
+            bv,
+            pos: 0,
+            max_len,
+        }
     }
     fn count_ones(&self) -> usize {
         self.bv.count_ones()
@@ -139,8 +297,8 @@ impl Iterator for BitVecIterOnes {
                 .iter()
                 .position(|bit| !*bit)
                 .unwrap_or_else(|| self.bv[index_start..].len());
-        self.pos = if index_stop - index_start > MAX_WRITE_SECTORS {
-            index_start + MAX_WRITE_SECTORS
+        self.pos = if index_stop - index_start > self.max_len {
+            index_start + self.max_len
         } else {
             index_stop
         };
@@ -148,6 +306,47 @@ impl Iterator for BitVecIterOnes {
     }
 }
 
+// The bitvec has a bit per 512 bytes sector of the fs, a block of the device
+// is written if any of its sectors is
+fn blocks_bitvec(fs_bv: BitVec<u8, Lsb0>, block_size: u64) -> BitVec<u8, Lsb0> {
+    if block_size > SECTOR_SIZE {
+        fs_bv
+            .chunks((block_size / SECTOR_SIZE) as usize)
+            .map(|sectors| sectors.any())
+            .collect()
+    } else {
+        fs_bv
+    }
+}
+
+/// Read sectors `sector_start..sector_stop` (of `sector_size` bytes) of `fs`
+/// in `buffer`, padded with zeros past the end of the fs. Returns the size of
+/// the sectors.
+fn read_fs_sectors(
+    fs: &mut File,
+    buffer: &mut [u8],
+    sector_start: u64,
+    sector_stop: u64,
+    sector_size: u64,
+    fs_size: u64,
+) -> Result<usize> {
+    let sector_start_pos = sector_start * sector_size;
+    let sectors_size = (sector_stop - sector_start) * sector_size;
+    fs.seek(SeekFrom::Start(sector_start_pos))?;
+
+    let size = if sector_start_pos + sectors_size > fs_size {
+        fs_size - sector_start_pos
+    } else {
+        sectors_size
+    };
+
+    fs.read_exact(&mut buffer[..size as usize])?;
+    buffer[size as usize..sectors_size as usize]
+        .iter_mut()
+        .for_each(|b| *b = 0);
+    Ok(sectors_size as usize)
+}
+
 impl<T: UsbContext> InitState<T> {
     fn run(self, comm: &mut Comm<proto::fs2dev::Request>) -> Result<State<T>> {
         debug!("waiting for busnum and devnum");
@@ -165,8 +364,24 @@ impl<T: UsbContext> InitState<T> {
             )?;
             Ok(State::WaitEnd(WaitEndState))
         } else {
+            let (verify, registry) = match self.config_path {
+                Some(ref config_path) => {
+                    let config = conf_parse(&conf_read(config_path)?)?;
+                    let registry = match config.enrollment {
+                        Some(ref enrollment) => Some(Registry::from_file(
+                            &enrollment.registry_path,
+                            &enrollment.public_key,
+                        )?),
+                        None => None,
+                    };
+                    (config.verify_destination.unwrap_or(false), registry)
+                }
+                None => (false, None),
+            };
             let fs = File::open(self.fs_fname)?;
-            let mass_storage = MassStorage::from_busnum_devnum(self.context, busnum, devnum)?;
+            let mut mass_storage =
+                MassStorage::from_busnum_devnum(self.context, busnum, devnum, None)?;
+            let enrollment = Enrollment::new(&mut mass_storage, registry)?;
             #[cfg(not(feature = "mock"))]
             usbsas_privileges::fs2dev::drop_priv(
                 comm.input_fd(),
@@ -174,7 +389,12 @@ impl<T: UsbContext> InitState<T> {
                 Some(fs.as_raw_fd()),
                 usbsas_privileges::get_libusb_opened_fds(busnum, devnum)?,
             )?;
-            Ok(State::DevOpened(DevOpenedState { fs, mass_storage }))
+            Ok(State::DevOpened(DevOpenedState {
+                fs,
+                mass_storage,
+                verify,
+                enrollment,
+            }))
         }
     }
 }
@@ -186,68 +406,235 @@ impl<T: UsbContext> CopyingState<T> {
 
         let fs_size = self.fs.seek(SeekFrom::End(0))?;
         self.fs.seek(SeekFrom::Start(0))?;
-
-        let total_size = self.fs_bv.count_ones() as u64 * SECTOR_SIZE;
+        let block_size = u64::from(self.mass_storage.block_size);
+
+        // Written sectors are read back in a second pass when verifying
+        let write_size = self.fs_bv.count_ones() as u64 * block_size;
+        let (total_size, verify_bv) = if self.verify {
+            (
+                write_size * 2,
+                Some(BitVecIterOnes::new(
+                    self.fs_bv.bv.clone(),
+                    self.fs_bv.max_len,
+                )),
+            )
+        } else {
+            (write_size, None)
+        };
 
         trace!("state=copying: size={} ", total_size);
 
         let mut current_size = 0u64;
-        let mut buffer = vec![0; BUFFER_MAX_WRITE_SIZE as usize];
+        let mut buffer = vec![0; self.fs_bv.max_len * block_size as usize];
 
-        for (sector_start, sector_stop) in self.fs_bv {
-            let sector_start_pos = (sector_start * SECTOR_SIZE) as u64;
-            self.fs.seek(SeekFrom::Start(sector_start_pos))?;
+        for (sector_start, sector_stop) in &mut self.fs_bv {
+            let size = read_fs_sectors(
+                &mut self.fs,
+                &mut buffer,
+                sector_start,
+                sector_stop,
+                block_size,
+                fs_size,
+            )?;
+            self.mass_storage.write_sectors(
+                &mut buffer[..size],
+                sector_start,
+                sector_stop - sector_start,
+            )?;
 
-            let sector_count = sector_stop - sector_start;
-            let sector_write_size = (sector_count * SECTOR_SIZE) as u64;
+            current_size += size as u64;
+            comm.copystatus(proto::fs2dev::ResponseCopyStatus {
+                current_size,
+                total_size,
+            })?;
+        }
 
-            let (size, pad) = if sector_start_pos + sector_write_size > fs_size {
-                let size = fs_size - sector_start_pos;
-                (size, (sector_write_size - size))
-            } else {
-                (sector_write_size, 0)
-            };
+        let bad_sectors = match verify_bv {
+            Some(fs_bv) => self.verify(comm, fs_bv, fs_size, current_size, total_size)?,
+            None => BadSectors::default(),
+        };
 
-            self.fs.read_exact(&mut buffer[..size as usize])?;
-            buffer[size as usize..]
-                .iter_mut()
-                .take(pad as usize)
-                .for_each(|b| *b = 0);
+        comm.copystatusdone(proto::fs2dev::ResponseCopyStatusDone {
+            bad_sector_count: bad_sectors.count,
+            bad_sectors: bad_sectors.first,
+        })?;
+        Ok(State::WaitEnd(WaitEndState))
+    }
 
-            self.mass_storage.scsi_write_10(
-                &mut buffer[..size as usize + pad as usize],
-                sector_start as u64,
-                sector_count as u64,
+    /// Read back the written sectors from the device and compare them with
+    /// the fs. Returns the mismatching sectors.
+    fn verify(
+        &mut self,
+        comm: &mut Comm<proto::fs2dev::Request>,
+        fs_bv: BitVecIterOnes,
+        fs_size: u64,
+        mut current_size: u64,
+        total_size: u64,
+    ) -> Result<BadSectors> {
+        trace!("verifying written sectors");
+        let block_size = u64::from(self.mass_storage.block_size);
+        let mut buffer = vec![0; fs_bv.max_len * block_size as usize];
+        let mut bad_sectors = BadSectors::default();
+
+        for (sector_start, sector_stop) in fs_bv {
+            let size = read_fs_sectors(
+                &mut self.fs,
+                &mut buffer,
+                sector_start,
+                sector_stop,
+                block_size,
+                fs_size,
             )?;
+            bad_sectors.check(
+                &mut self.mass_storage,
+                sector_start,
+                &buffer[..size],
+                block_size,
+            );
 
-            current_size += sector_write_size;
+            current_size += size as u64;
             comm.copystatus(proto::fs2dev::ResponseCopyStatus {
                 current_size,
                 total_size,
             })?;
         }
 
-        comm.copystatusdone(proto::fs2dev::ResponseCopyStatusDone {})?;
-        Ok(State::WaitEnd(WaitEndState))
+        if bad_sectors.count != 0 {
+            error!(
+                "{} sectors don't match what was written (first ones: {:?})",
+                bad_sectors.count, bad_sectors.first
+            );
+        }
+        Ok(bad_sectors)
     }
 }
 
 impl<T: UsbContext> WipingState<T> {
     fn run(mut self, comm: &mut Comm<proto::fs2dev::Request>) -> Result<State<T>> {
+        use proto::common::WipeMode;
         trace!("wiping state");
         comm.wipe(proto::fs2dev::ResponseWipe {})?;
-        let mut buffer = vec![0u8; BUFFER_MAX_WRITE_SIZE as usize];
-        let total_size = self.mass_storage.dev_size;
-        let mut todo = total_size;
-        let mut sector_index = 0;
-        let mut sector_count = buffer.len() as u64 / SECTOR_SIZE;
+        let (mode, (method, bad_sectors)) = match self.mode {
+            WipeMode::Zerofill => (self.mode, self.fill(comm, &[Fill::Zeros])?),
+            WipeMode::Randomfill => (self.mode, self.fill(comm, &[Fill::Random])?),
+            WipeMode::Multipass => (
+                self.mode,
+                self.fill(comm, &[Fill::Random, Fill::Random, Fill::Zeros])?,
+            ),
+            WipeMode::Erase => {
+                let erased = self.mass_storage.erase(&mut |current_size, total_size| {
+                    comm.copystatus(proto::fs2dev::ResponseCopyStatus {
+                        current_size,
+                        total_size,
+                    })
+                })?;
+                match erased {
+                    Some(method) => (WipeMode::Erase, (method.to_string(), BadSectors::default())),
+                    None => {
+                        warn!("device can't be erased with a command, filling it with zeros");
+                        (WipeMode::Zerofill, self.fill(comm, &[Fill::Zeros])?)
+                    }
+                }
+            }
+        };
+        if let Some(ref marker) = self.enrollment.marker {
+            self.mass_storage
+                .write_sectors(&mut marker.clone(), MARKER_SECTOR, 1)?;
+        }
+        info!("device wiped: {}", method);
+        comm.wipedone(proto::fs2dev::ResponseWipeDone {
+            mode: mode.into(),
+            method,
+            bad_sector_count: bad_sectors.count,
+            bad_sectors: bad_sectors.first,
+        })?;
+        Ok(State::DevOpened(DevOpenedState {
+            fs: self.fs,
+            mass_storage: self.mass_storage,
+            verify: self.verify,
+            enrollment: self.enrollment,
+        }))
+    }
+
+    // Overwrite the whole device once per pass, returns a description of
+    // the passes. When verifying, the last pass is read back once the device
+    // is completely written (data aliased by fake capacity devices would
+    // have been overwritten) and the mismatching sectors are returned too.
+    fn fill(
+        &mut self,
+        comm: &mut Comm<proto::fs2dev::Request>,
+        passes: &[Fill],
+    ) -> Result<(String, BadSectors)> {
+        let block_size = u64::from(self.mass_storage.block_size);
+        let dev_size = self.mass_storage.dev_size;
+        let mut total_size = dev_size * passes.len() as u64;
+        if self.verify {
+            total_size += dev_size;
+        }
         let mut current_size = 0;
+        let mut bad_sectors = BadSectors::default();
+        let mut rng = StdRng::from_entropy();
+        let mut seed = [0u8; 32];
         trace!(
-            "wipe device; size: {} total sectors: {}",
-            total_size,
-            total_size / SECTOR_SIZE as u64
+            "wipe device; size: {} total sectors: {} passes: {}",
+            dev_size,
+            dev_size / block_size,
+            passes.len()
         );
 
+        for fill in passes {
+            rng.fill_bytes(&mut seed);
+            self.fill_pass(comm, fill, seed, &mut current_size, total_size, None)?;
+        }
+        if self.verify {
+            if let Some(fill) = passes.last() {
+                trace!("verifying wiped sectors");
+                self.fill_pass(
+                    comm,
+                    fill,
+                    seed,
+                    &mut current_size,
+                    total_size,
+                    Some(&mut bad_sectors),
+                )?;
+            }
+        }
+        if bad_sectors.count != 0 {
+            error!(
+                "{} sectors don't match what was wiped (first ones: {:?})",
+                bad_sectors.count, bad_sectors.first
+            );
+        }
+        let method = passes
+            .iter()
+            .map(|fill| match fill {
+                Fill::Zeros => "zeros",
+                Fill::Random => "random",
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok((method, bad_sectors))
+    }
+
+    // Go through the whole device with data from `fill` (random data is
+    // generated from `seed` so it can be generated again to be checked).
+    // The data is written, or compared with the device if `bad_sectors` is
+    // given.
+    fn fill_pass(
+        &mut self,
+        comm: &mut Comm<proto::fs2dev::Request>,
+        fill: &Fill,
+        seed: [u8; 32],
+        current_size: &mut u64,
+        total_size: u64,
+        mut bad_sectors: Option<&mut BadSectors>,
+    ) -> Result<()> {
+        let block_size = u64::from(self.mass_storage.block_size);
+        let mut rng = StdRng::from_seed(seed);
+        let mut buffer = vec![0u8; max_write_blocks(block_size) * block_size as usize];
+        let mut todo = self.mass_storage.dev_size;
+        let mut sector_index = 0;
+        let mut sector_count = buffer.len() as u64 / block_size;
         while todo > 0 {
             trace!(
                 "wipe cur size: {}, sector index: {}, todo: {}",
@@ -256,33 +643,35 @@ impl<T: UsbContext> WipingState<T> {
                 todo
             );
             if todo < buffer.len() as u64 {
-                sector_count = todo / SECTOR_SIZE;
+                sector_count = todo / block_size;
                 buffer.truncate(todo as usize);
             }
-            self.mass_storage.scsi_write_10(
-                &mut buffer,
-                sector_index as u64,
-                sector_count as u64,
-            )?;
-            current_size += buffer.len() as u64;
+            if let Fill::Random = fill {
+                rng.fill_bytes(&mut buffer);
+            }
+            match bad_sectors {
+                Some(ref mut bad_sectors) => {
+                    bad_sectors.check(&mut self.mass_storage, sector_index, &buffer, block_size)
+                }
+                None => self
+                    .mass_storage
+                    .write_sectors(&mut buffer, sector_index, sector_count)?,
+            }
+            *current_size += buffer.len() as u64;
             comm.copystatus(proto::fs2dev::ResponseCopyStatus {
-                current_size,
+                current_size: *current_size,
                 total_size,
             })?;
 
             todo -= buffer.len() as u64;
             sector_index += sector_count;
         }
-        comm.copystatusdone(proto::fs2dev::ResponseCopyStatusDone {})?;
-        Ok(State::DevOpened(DevOpenedState {
-            fs: self.fs,
-            mass_storage: self.mass_storage,
-        }))
+        Ok(())
     }
 }
 
 impl<T: UsbContext> DevOpenedState<T> {
-    fn run(self, comm: &mut Comm<proto::fs2dev::Request>) -> Result<State<T>> {
+    fn run(mut self, comm: &mut Comm<proto::fs2dev::Request>) -> Result<State<T>> {
         trace!("dev opened state");
         use proto::fs2dev;
         use proto::fs2dev::request::Msg;
@@ -292,14 +681,29 @@ impl<T: UsbContext> DevOpenedState<T> {
             Msg::DevSize(_) => {
                 comm.devsize(fs2dev::ResponseDevSize {
                     size: self.mass_storage.dev_size,
+                    block_size: self.mass_storage.block_size,
                 })?;
                 State::DevOpened(self)
             }
-            Msg::LoadBitVec(ref mut msg) => self.load_bitvec(comm, &mut msg.chunk, msg.last)?,
-            Msg::Wipe(_) => State::Wiping(WipingState {
-                fs: self.fs,
-                mass_storage: self.mass_storage,
-            }),
+            Msg::LoadBitVec(ref mut msg) => {
+                self.enrollment.check()?;
+                self.load_bitvec(comm, &mut msg.chunk, msg.last)?
+            }
+            Msg::Wipe(req) => {
+                self.enrollment.check()?;
+                State::Wiping(WipingState {
+                    mode: req.mode(),
+                    fs: self.fs,
+                    mass_storage: self.mass_storage,
+                    verify: self.verify,
+                    enrollment: self.enrollment,
+                })
+            }
+            Msg::Enroll(req) => {
+                self.enroll(&req.token)?;
+                comm.enroll(fs2dev::ResponseEnroll {})?;
+                State::DevOpened(self)
+            }
             Msg::End(_) => {
                 comm.end(fs2dev::ResponseEnd {})?;
                 State::End
@@ -314,6 +718,22 @@ impl<T: UsbContext> DevOpenedState<T> {
         })
     }
 
+    /// Write the marker holding `token` in the gap before the partition
+    fn enroll(&mut self, token: &[u8]) -> Result<()> {
+        let token: &[u8; TOKEN_SIZE] = token
+            .try_into()
+            .map_err(|_| Error::Error("bad enrollment token size".into()))?;
+        if self.mass_storage.max_lba <= MARKER_SECTOR {
+            return Err(Error::Error("device too small to be enrolled".into()));
+        }
+        let mut marker = enrollment::marker(token, self.mass_storage.block_size as usize);
+        self.mass_storage
+            .write_sectors(&mut marker, MARKER_SECTOR, 1)?;
+        self.enrollment.marker = Some(marker);
+        info!("device enrolled");
+        Ok(())
+    }
+
     fn load_bitvec(
         self,
         comm: &mut Comm<proto::fs2dev::Request>,
@@ -345,11 +765,18 @@ impl<T: UsbContext> DevOpenedState<T> {
                 }
             }
         }
-        let fs_bv = BitVecIterOnes::new(BitVec::from_vec(fs_bv_buf));
+        let block_size = u64::from(self.mass_storage.block_size);
+        let mut fs_bv = blocks_bitvec(BitVec::from_vec(fs_bv_buf), block_size);
+        // Keep the enrollment marker
+        if self.enrollment.marker.is_some() && fs_bv.len() > MARKER_SECTOR as usize {
+            fs_bv.set(MARKER_SECTOR as usize, false);
+        }
+        let fs_bv = BitVecIterOnes::new(fs_bv, max_write_blocks(block_size));
         Ok(State::BitVecLoaded(BitVecLoadedState {
             fs: self.fs,
             fs_bv,
             mass_storage: self.mass_storage,
+            verify: self.verify,
         }))
     }
 }
@@ -364,6 +791,7 @@ impl<T: UsbContext> BitVecLoadedState<T> {
                 fs: self.fs,
                 fs_bv: self.fs_bv,
                 mass_storage: self.mass_storage,
+                verify: self.verify,
             }),
             Msg::End(_) => {
                 comm.end(fs2dev::ResponseEnd {})?;
@@ -408,8 +836,17 @@ struct Fs2DevContext<T: UsbContext> {
 }
 
 impl<T: UsbContext> Fs2DevContext<T> {
-    fn new(comm: Comm<proto::fs2dev::Request>, fs_fname: String, context: T) -> Result<Self> {
-        let state = State::Init(InitState { fs_fname, context });
+    fn new(
+        comm: Comm<proto::fs2dev::Request>,
+        fs_fname: String,
+        config_path: Option<String>,
+        context: T,
+    ) -> Result<Self> {
+        let state = State::Init(InitState {
+            fs_fname,
+            config_path,
+            context,
+        });
         Ok(Fs2DevContext { comm, state })
     }
 
@@ -450,6 +887,7 @@ impl UsbsasProcess for Fs2Dev {
                 Fs2DevContext::new(
                     Comm::from_raw_fd(read_fd, write_fd),
                     fname.to_owned(),
+                    args.get(1).cloned(),
                     #[cfg(not(feature = "mock"))]
                     Context::new()?,
                     #[cfg(feature = "mock")]
@@ -465,3 +903,63 @@ impl UsbsasProcess for Fs2Dev {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_bitvec() {
+        // Sectors 1, 8 and 9 (the last 512 bytes sector of the fs)
+        let fs_bv: BitVec<u8, Lsb0> = BitVec::from_vec(vec![0b0000_0010, 0b0000_0011]);
+
+        let blocks = blocks_bitvec(fs_bv.clone(), 512);
+        assert_eq!(blocks, fs_bv);
+
+        // 4Kn: blocks 0 and 1
+        let blocks = blocks_bitvec(fs_bv.clone(), 4096);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0] && blocks[1]);
+
+        // 64KiB: one block of 128 sectors, the last one is partial
+        let blocks = blocks_bitvec(fs_bv, 0x10000);
+        assert_eq!(blocks.len(), 1);
+        assert!(blocks[0]);
+
+        let blocks = blocks_bitvec(BitVec::from_vec(vec![0, 0b1000_0000]), 2048);
+        assert_eq!(
+            blocks.iter().map(|bit| *bit).collect::<Vec<_>>(),
+            [false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_bad_sectors() {
+        let expected: Vec<u8> = (0..4 * 512).map(|i| (i / 512) as u8 + 1).collect();
+        let mut bad_sectors = BadSectors::default();
+
+        bad_sectors.compare(10, &expected, &expected, 512);
+        assert_eq!(bad_sectors.count, 0);
+
+        // Sector 12 is wrong
+        let mut data = expected.clone();
+        data[2 * 512 + 7] = 0;
+        bad_sectors.compare(10, &data, &expected, 512);
+        assert_eq!(bad_sectors.count, 1);
+        assert_eq!(bad_sectors.first, [12]);
+
+        // Short read: the missing sectors are bad too
+        bad_sectors.compare(20, &expected[..512], &expected, 512);
+        assert_eq!(bad_sectors.count, 4);
+        assert_eq!(bad_sectors.first, [12, 21, 22, 23]);
+
+        // Only the first ones are reported
+        let mut bad_sectors = BadSectors::default();
+        for index in 0..MAX_REPORTED_BAD_SECTORS as u64 {
+            bad_sectors.compare(index * 4, &[], &expected, 512);
+        }
+        assert_eq!(bad_sectors.count, 4 * MAX_REPORTED_BAD_SECTORS as u64);
+        assert_eq!(bad_sectors.first.len(), MAX_REPORTED_BAD_SECTORS);
+        assert_eq!(bad_sectors.first[..5], [0, 1, 2, 3, 4]);
+    }
+}
@@ -8,6 +8,15 @@ pub struct Ext4<T> {
     vol: ext4::SuperBlock<T>,
 }
 
+fn ext4_file_type(file_type: &ext4::FileType) -> FileType {
+    match file_type {
+        ext4::FileType::RegularFile => FileType::Regular,
+        ext4::FileType::Directory => FileType::Directory,
+        ext4::FileType::SymbolicLink => FileType::Symlink,
+        _ => FileType::Other,
+    }
+}
+
 impl<T: ReadAt> FSRead<T> for Ext4<T> {
     fn new(reader: T, _sector_size: u32) -> Result<Self> {
         let options = ext4::Options {
@@ -20,11 +29,7 @@ impl<T: ReadAt> FSRead<T> for Ext4<T> {
     fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)> {
         let entry = self.vol.resolve_path(path)?;
         let inode = self.vol.load_inode(entry.inode)?;
-        let file_type = match entry.file_type {
-            ext4::FileType::RegularFile => FileType::Regular,
-            ext4::FileType::Directory => FileType::Directory,
-            _ => FileType::Other,
-        };
+        let file_type = ext4_file_type(&entry.file_type);
         Ok((
             file_type,
             inode.stat.size,
@@ -44,11 +49,7 @@ impl<T: ReadAt> FSRead<T> for Ext4<T> {
                         continue;
                     }
                     let inode = self.vol.load_inode(entry.inode)?;
-                    let file_type = match entry.file_type {
-                        ext4::FileType::RegularFile => FileType::Regular,
-                        ext4::FileType::Directory => FileType::Directory,
-                        _ => FileType::Other,
-                    };
+                    let file_type = ext4_file_type(&entry.file_type);
                     files_info.push(FileInfo {
                         path: format!("{}/{}", path, &entry.name),
                         ftype: file_type.into(),
@@ -95,6 +96,15 @@ impl<T: ReadAt> FSRead<T> for Ext4<T> {
         }
     }
 
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let entry = self.vol.resolve_path(path)?;
+        let inode = self.vol.load_inode(entry.inode)?;
+        match self.vol.enhance(&inode)? {
+            ext4::Enhanced::SymbolicLink(target) => Ok(target),
+            _ => Err(Error::FSError(format!("{} is not a symbolic link", path))),
+        }
+    }
+
     fn unmount_fs(self: Box<Self>) -> Result<T> {
         Ok(self.vol.into_inner())
     }
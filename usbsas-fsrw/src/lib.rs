@@ -51,6 +51,14 @@ pub trait FSRead<T> {
         offset: u64,
         bytes_to_read: u64,
     ) -> Result<u64>;
+    // Only implemented by file systems supporting symbolic links (ext4) or
+    // reparse points (NTFS).
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        Err(Error::FSError(format!(
+            "Cannot read link {}: unsupported by file system",
+            path
+        )))
+    }
     fn unmount_fs(self: Box<Self>) -> Result<T>;
 }
 
@@ -151,6 +151,74 @@ fn ntfs_file_size<T: Read + Seek>(ntfs_file: &ntfs::NtfsFile, reader: &mut T) ->
     }
 }
 
+// Reparse points handled as symbolic links, other tags (dedup, WOF, cloud
+// files etc.) are data related and their files are kept as regular files.
+const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+
+fn ntfs_link_target<T: Read + Seek>(
+    ntfs_file: &ntfs::NtfsFile,
+    reader: &mut T,
+) -> Result<Option<String>> {
+    if !ntfs_file
+        .info()?
+        .file_attributes()
+        .contains(ntfs::structured_values::NtfsFileAttributeFlags::REPARSE_POINT)
+    {
+        return Ok(None);
+    }
+
+    let mut attributes = ntfs_file.attributes();
+    while let Some(item) = attributes.next(reader) {
+        let attribute = item?.to_attribute();
+        if attribute.ty()? != ntfs::NtfsAttributeType::ReparsePoint {
+            continue;
+        }
+        let mut value = attribute.value(reader)?;
+        let mut data = vec![0; usize::try_from(value.len())?];
+        value.read_exact(reader, &mut data)?;
+
+        // REPARSE_DATA_BUFFER: tag (4 bytes), data length (2), reserved (2),
+        // substitute name offset (2) and length (2), print name offset (2) and
+        // length (2), flags (4, symlinks only) and path buffer.
+        let read_u16 = |off: usize| -> Result<usize> {
+            data.get(off..off + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                .ok_or_else(|| Error::FSError("Truncated reparse point".into()))
+        };
+        if data.len() < 16 {
+            return Err(Error::FSError("Truncated reparse point".into()));
+        }
+        let path_buffer = match u32::from_le_bytes([data[0], data[1], data[2], data[3]]) {
+            IO_REPARSE_TAG_MOUNT_POINT => 16,
+            IO_REPARSE_TAG_SYMLINK => 20,
+            _ => return Ok(None),
+        };
+        let (name_offset, name_len) = (read_u16(8)?, read_u16(10)?);
+        let name_utf16: Vec<u16> = data
+            .get(path_buffer + name_offset..path_buffer + name_offset + name_len)
+            .ok_or_else(|| Error::FSError("Truncated reparse point".into()))?
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        // Absolute targets ("\??\C:\...") start with a '/' once converted
+        return Ok(Some(
+            String::from_utf16_lossy(&name_utf16).replace('\\', "/"),
+        ));
+    }
+    Ok(None)
+}
+
+fn ntfs_file_type<T: Read + Seek>(ntfs_file: &ntfs::NtfsFile, reader: &mut T) -> Result<FileType> {
+    if ntfs_link_target(ntfs_file, reader)?.is_some() {
+        Ok(FileType::Symlink)
+    } else if ntfs_file.is_directory() {
+        Ok(FileType::Directory)
+    } else {
+        Ok(FileType::Regular)
+    }
+}
+
 impl<T: Read + Seek> FSRead<T> for NTFS<T> {
     fn new(mut reader: T, _sector_size: u32) -> Result<Self> {
         let fs = ntfs::Ntfs::new(&mut reader)?;
@@ -160,12 +228,12 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
     fn get_attr(&mut self, path: &str) -> Result<(FileType, u64, i64)> {
         log::trace!("get attr: {}", path);
         let ntfs_file = ntfs_file_from_path(&self.fs, &mut self.reader, path)?;
-        let file_type = if ntfs_file.is_directory() {
-            FileType::Directory
-        } else {
-            FileType::Regular
+        let file_type = ntfs_file_type(&ntfs_file, &mut self.reader)?;
+        // Links may not have a $DATA attribute
+        let size = match file_type {
+            FileType::Symlink => 0,
+            _ => ntfs_file_size(&ntfs_file, &mut self.reader)?,
         };
-        let size = ntfs_file_size(&ntfs_file, &mut self.reader)?;
         // Convert ntfs timestamp to unix timestamp (e.g. nano sec to sec and
         // subtract ntfs "epoch" 01.01.1601 00:00:00
         let ts = ntfs_file.info()?.creation_time().nt_timestamp() as i64 / 10000000 - 11644473600;
@@ -193,14 +261,14 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
             // See get_attr() above
             let ts =
                 ntfs_file.info()?.creation_time().nt_timestamp() as i64 / 10000000 - 11644473600;
+            let file_type = ntfs_file_type(&ntfs_file, &mut self.reader)?;
             ntfs_entries.push(FileInfo {
                 path: format!("{}/{}", path, name_string),
-                size: ntfs_file_size(&ntfs_file, &mut self.reader)?,
-                ftype: if ntfs_file.is_directory() {
-                    FileType::Directory.into()
-                } else {
-                    FileType::Regular.into()
+                size: match file_type {
+                    FileType::Symlink => 0,
+                    _ => ntfs_file_size(&ntfs_file, &mut self.reader)?,
                 },
+                ftype: file_type.into(),
                 timestamp: ts,
             });
         }
@@ -248,6 +316,12 @@ impl<T: Read + Seek> FSRead<T> for NTFS<T> {
         }
     }
 
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        let ntfs_file = ntfs_file_from_path(&self.fs, &mut self.reader, path)?;
+        ntfs_link_target(&ntfs_file, &mut self.reader)?
+            .ok_or_else(|| Error::FSError(format!("{} is not a link", path)))
+    }
+
     fn unmount_fs(self: Box<Self>) -> Result<T> {
         drop(self.fs);
         Ok(self.reader)
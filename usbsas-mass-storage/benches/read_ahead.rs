@@ -0,0 +1,133 @@
+//! Benchmark of the scsi2files read path against a mock device.
+//!
+//! A thread plays dev2scsi by serving read requests from the file pointed to by
+//! `USBSAS_MOCK_IN_DEV`, the number of round trips is reported with the
+//! elapsed time of each access pattern.
+//!
+//! `USBSAS_MOCK_IN_DEV=/tmp/mock_in_dev.img cargo bench -p usbsas-mass-storage`
+
+use positioned_io2::ReadAt;
+use std::{
+    env,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::{io::OwnedFd, net::UnixStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+use usbsas_comm::Comm;
+use usbsas_mass_storage::MassStorageComm;
+use usbsas_proto as proto;
+use usbsas_proto::scsi::{request::Msg, response};
+
+const BLOCK_SIZE: u64 = 512;
+
+fn comm_pair() -> (Comm<proto::scsi::Request>, Comm<proto::scsi::Request>) {
+    let (client, server) = UnixStream::pair().expect("socketpair");
+    let to_file = |stream: UnixStream| {
+        let file = File::from(OwnedFd::from(stream));
+        Comm::new(file.try_clone().expect("dup"), file)
+    };
+    (to_file(client), to_file(server))
+}
+
+fn serve(mut comm: Comm<proto::scsi::Request>, dev: File, round_trips: Arc<AtomicU64>) {
+    while let Ok(req) = comm.recv::<proto::scsi::Request>() {
+        match req.msg {
+            Some(Msg::ReadSectors(req)) => {
+                round_trips.fetch_add(1, Ordering::Relaxed);
+                let mut data = vec![0; (req.count * BLOCK_SIZE) as usize];
+                dev.read_exact_at(req.offset * BLOCK_SIZE, &mut data)
+                    .expect("mock read");
+                comm.send(proto::scsi::Response {
+                    msg: Some(response::Msg::ReadSectors(
+                        proto::scsi::ResponseReadSectors {
+                            data,
+                            bad_sectors: Vec::new(),
+                        },
+                    )),
+                })
+                .expect("send");
+            }
+            _ => break,
+        }
+    }
+}
+
+fn bench<F: FnMut(&mut MassStorageComm)>(name: &str, path: &str, mut run: F) {
+    let dev = File::open(path).expect("open mock device");
+    let dev_size = dev.metadata().expect("metadata").len();
+    let (client, server) = comm_pair();
+    let round_trips = Arc::new(AtomicU64::new(0));
+    let counter = round_trips.clone();
+    let handle = thread::spawn(move || serve(server, dev, counter));
+
+    let mut mass = MassStorageComm::new(client);
+    mass.block_size = BLOCK_SIZE as u32;
+    mass.dev_size = dev_size;
+    let start = Instant::now();
+    run(&mut mass);
+    let elapsed = start.elapsed();
+    drop(mass);
+    handle.join().expect("join");
+    println!(
+        "{:<24} {:>10.2?} {:>8} round trips",
+        name,
+        elapsed,
+        round_trips.load(Ordering::Relaxed)
+    );
+}
+
+fn main() {
+    let path = match env::var("USBSAS_MOCK_IN_DEV") {
+        Ok(path) => path,
+        Err(_) => {
+            eprintln!("USBSAS_MOCK_IN_DEV not set, skipping");
+            return;
+        }
+    };
+    let dev_size = File::open(&path)
+        .and_then(|dev| dev.metadata())
+        .expect("mock device")
+        .len();
+    let len = dev_size.min(0x1000_0000);
+
+    bench("sequential 4KiB reads", &path, |mass| {
+        let mut buf = vec![0; 0x1000];
+        mass.seek(SeekFrom::Start(0)).unwrap();
+        for _ in 0..len / 0x1000 {
+            mass.read_exact(&mut buf).unwrap();
+        }
+    });
+
+    bench("sequential 1MiB reads", &path, |mass| {
+        let mut buf = vec![0; 0x10_0000];
+        mass.seek(SeekFrom::Start(0)).unwrap();
+        for _ in 0..len / 0x10_0000 {
+            mass.read_exact(&mut buf).unwrap();
+        }
+    });
+
+    bench("scattered 512B reads", &path, |mass| {
+        let mut buf = vec![0; 0x200];
+        let sectors = len / BLOCK_SIZE;
+        for i in 0..0x4000u64 {
+            // Deterministic spread over the device, with some locality
+            let sector = (i.wrapping_mul(2_654_435_761) >> 4) % sectors;
+            mass.read_exact_at(sector * BLOCK_SIZE, &mut buf).unwrap();
+            mass.read_exact_at((sector + 1).min(sectors - 1) * BLOCK_SIZE, &mut buf)
+                .unwrap();
+        }
+    });
+
+    bench("repeated metadata reads", &path, |mass| {
+        let mut buf = vec![0; 0x200];
+        for i in 0..0x10000u64 {
+            mass.read_exact_at((i % 64) * BLOCK_SIZE, &mut buf).unwrap();
+        }
+    });
+}
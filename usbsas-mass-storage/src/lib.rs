@@ -2,17 +2,19 @@
 
 use positioned_io2::ReadAt;
 use std::{
+    collections::BTreeSet,
     io::{self, ErrorKind, Read, Seek, SeekFrom},
     sync::{Arc, RwLock},
 };
 use usbsas_comm::{protorequest, Comm};
 use usbsas_proto as proto;
+use usbsas_scsi::SCSI_MAX_READ_SIZE;
 #[cfg(not(feature = "mock"))]
 use {
     log::{debug, error, trace},
-    rusb::{Direction, TransferType, UsbContext},
+    rusb::{Device, DeviceHandle, Direction, TransferType, UsbContext},
     std::time::Duration,
-    usbsas_scsi::ScsiUsb,
+    usbsas_scsi::{ScsiUsb, UasPipes, USB_PR_BULK, USB_PR_UAS},
 };
 
 protorequest!(
@@ -24,28 +26,91 @@ protorequest!(
     opendev = OpenDevice[RequestOpenDevice, ResponseOpenDevice]
 );
 
-pub const MAX_SECTORS_COUNT_CACHE: u64 = 8;
+// Read-ahead window (in bytes) used for random accesses, doubled on each
+// sequential miss up to the largest single read dev2scsi sends to the device
+pub const READAHEAD_MIN_SIZE: u64 = 0x4000;
+pub const READAHEAD_MAX_SIZE: u64 = SCSI_MAX_READ_SIZE;
+// Max amount of data (in bytes) kept in the read cache
+pub const READ_CACHE_MAX_SIZE: u64 = 0x100_0000;
+pub const MIN_BLOCK_SIZE: u32 = 0x200;
+pub const MAX_BLOCK_SIZE: u32 = 0x10000;
+
+/// Block sizes are powers of two from 512 bytes to 64KiB
+pub fn is_supported_block_size(block_size: u32) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
 
 #[cfg(not(feature = "mock"))]
 enum LibusbClassCode {
     MassStorage = 0x08,
 }
 
+// Number of sectors read back after an unmap
+const UNMAP_SAMPLES: u64 = 64;
+
+/// Sectors read back to check an unmap: the first and last ones and others
+/// spread over the device
+pub fn unmap_samples(block_count: u64) -> Vec<u64> {
+    if block_count <= UNMAP_SAMPLES {
+        return (0..block_count).collect();
+    }
+    let step = (block_count - 1) / (UNMAP_SAMPLES - 1);
+    let mut samples: Vec<u64> = (0..UNMAP_SAMPLES - 1).map(|i| i * step).collect();
+    samples.push(block_count - 1);
+    samples
+}
+
+#[cfg(not(feature = "mock"))]
+fn open_interface<T: UsbContext>(
+    device: &Device<T>,
+    interface_num: u8,
+) -> Result<DeviceHandle<T>, rusb::Error> {
+    let mut handle = device.open()?;
+    handle.set_auto_detach_kernel_driver(true)?;
+    handle.claim_interface(interface_num)?;
+    Ok(handle)
+}
+
+/// Serial number of the device, read as usbdev does so that it can be
+/// compared with the one of the device policy and of the enrollment registry
+#[cfg(not(feature = "mock"))]
+fn read_serial<T: UsbContext>(handle: &DeviceHandle<T>) -> String {
+    let timeout = Duration::from_secs(1);
+    handle
+        .read_languages(timeout)
+        .ok()
+        .and_then(|languages| languages.first().copied())
+        .zip(handle.device().device_descriptor().ok())
+        .and_then(|(language, descriptor)| {
+            handle
+                .read_serial_number_string(language, &descriptor, timeout)
+                .ok()
+        })
+        .unwrap_or_else(|| "Unknown serial".to_string())
+}
+
 // Mass storage struct used by dev2scsi
 #[cfg(not(feature = "mock"))]
 pub struct MassStorage<T: UsbContext> {
     scsiusb: RwLock<ScsiUsb<T>>,
-    pub max_lba: u32,
+    pub max_lba: u64,
     pub block_size: u32,
     pub dev_size: u64,
     pub pos: u64,
+    pub vendorid: u32,
+    pub productid: u32,
+    pub serial: String,
 }
 
 #[cfg(not(feature = "mock"))]
 impl<T: UsbContext> MassStorage<T> {
-    fn new(scsiusb: ScsiUsb<T>) -> Result<Self, io::Error> {
+    fn new(
+        scsiusb: ScsiUsb<T>,
+        lun: Option<u8>,
+        (vendorid, productid, serial): (u32, u32, String),
+    ) -> Result<Self, io::Error> {
         let mut scsiusb = scsiusb;
-        let (max_lba, block_size, dev_size) = match scsiusb.init_mass_storage() {
+        let (max_lba, block_size, dev_size) = match scsiusb.init_mass_storage(lun) {
             Ok(result) => result,
             Err(e) => {
                 return Err(io::Error::new(
@@ -54,21 +119,31 @@ impl<T: UsbContext> MassStorage<T> {
                 ));
             }
         };
-        // TODO: support more sector size
-        assert!(vec![0x200, 0x800, 0x1000].contains(&block_size));
+        if !is_supported_block_size(block_size) {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!("Unsupported block size: {}", block_size),
+            ));
+        }
         Ok(MassStorage {
             scsiusb: RwLock::new(scsiusb),
             max_lba,
             block_size,
             dev_size,
             pos: 0,
+            vendorid,
+            productid,
+            serial,
         })
     }
 
+    /// Open the mass storage device `busnum`/`devnum`. If `lun` isn't
+    /// specified, the first ready direct access LUN is used.
     pub fn from_busnum_devnum(
         libusb_ctx: T,
         busnum: u32,
         devnum: u32,
+        lun: Option<u8>,
     ) -> Result<Self, rusb::Error> {
         trace!("find_and_init_dev");
         let libusb_devlist = libusb_ctx.devices()?;
@@ -78,42 +153,78 @@ impl<T: UsbContext> MassStorage<T> {
                 continue;
             }
             debug!("Found matching {{bus,dev}}num device");
-            let mut handle = device.open()?;
-            let mut endpoints: [Option<u8>; 2] = [None; 2];
+            let device_descriptor = device.device_descriptor()?;
+            let ids = |handle: &DeviceHandle<T>| {
+                (
+                    u32::from(device_descriptor.vendor_id()),
+                    u32::from(device_descriptor.product_id()),
+                    read_serial(handle),
+                )
+            };
             for interface in device.active_config_descriptor()?.interfaces() {
-                for desc in interface.descriptors() {
-                    if desc.class_code() == LibusbClassCode::MassStorage as u8
-                        && (desc.sub_class_code() == 0x01 || desc.sub_class_code() == 0x06)
-                        && desc.protocol_code() == 0x50
-                    {
-                        for endp in desc.endpoint_descriptors() {
-                            if endp.transfer_type() == TransferType::Bulk {
-                                if endp.direction() == Direction::In {
-                                    endpoints[0] = Some(endp.address());
-                                }
-                                if endp.direction() == Direction::Out {
-                                    endpoints[1] = Some(endp.address());
-                                }
+                let descs: Vec<_> = interface
+                    .descriptors()
+                    .filter(|desc| {
+                        desc.class_code() == LibusbClassCode::MassStorage as u8
+                            && (desc.sub_class_code() == 0x01 || desc.sub_class_code() == 0x06)
+                    })
+                    .collect();
+
+                // Prefer UAS when the device offers it, fall back to BOT
+                if let Some(desc) = descs.iter().find(|desc| desc.protocol_code() == USB_PR_UAS) {
+                    if let Some(pipes) = UasPipes::from_descriptor(desc) {
+                        let handle = open_interface(&device, interface.number())?;
+                        let ids = ids(&handle);
+                        match ScsiUsb::new_uas(
+                            handle,
+                            interface.number(),
+                            desc.setting_number(),
+                            pipes,
+                            Duration::from_secs(5),
+                        )
+                        .and_then(|scsiusb| MassStorage::new(scsiusb, lun, ids))
+                        {
+                            Ok(mass_storage) => {
+                                debug!("Using UAS transport");
+                                return Ok(mass_storage);
                             }
+                            Err(err) => error!("UAS init err: {}, falling back to BOT", err),
                         }
+                    }
+                }
 
-                        if let [Some(ep0), Some(ep1)] = endpoints {
-                            handle.set_auto_detach_kernel_driver(true)?;
-                            handle.claim_interface(interface.number())?;
-                            let scsiusb: ScsiUsb<T> = ScsiUsb::new(
-                                handle,
-                                interface.number(),
-                                desc.setting_number(),
-                                ep0,
-                                ep1,
-                                Duration::from_secs(5),
-                            );
-                            return MassStorage::new(scsiusb).map_err(|err| {
-                                error!("MassStorage init err: {}", err);
-                                rusb::Error::NotFound
-                            });
+                if let Some(desc) = descs
+                    .iter()
+                    .find(|desc| desc.protocol_code() == USB_PR_BULK)
+                {
+                    let mut endpoints: [Option<u8>; 2] = [None; 2];
+                    for endp in desc.endpoint_descriptors() {
+                        if endp.transfer_type() == TransferType::Bulk {
+                            if endp.direction() == Direction::In {
+                                endpoints[0] = Some(endp.address());
+                            }
+                            if endp.direction() == Direction::Out {
+                                endpoints[1] = Some(endp.address());
+                            }
                         }
                     }
+
+                    if let [Some(ep0), Some(ep1)] = endpoints {
+                        let handle = open_interface(&device, interface.number())?;
+                        let ids = ids(&handle);
+                        let scsiusb: ScsiUsb<T> = ScsiUsb::new(
+                            handle,
+                            interface.number(),
+                            desc.setting_number(),
+                            ep0,
+                            ep1,
+                            Duration::from_secs(5),
+                        );
+                        return MassStorage::new(scsiusb, lun, ids).map_err(|err| {
+                            error!("MassStorage init err: {}", err);
+                            rusb::Error::NotFound
+                        });
+                    }
                 }
             }
         }
@@ -136,7 +247,21 @@ impl<T: UsbContext> MassStorage<T> {
             .read_sectors(offset, count, block_size)
     }
 
-    pub fn scsi_write_10(
+    /// Read sectors, zero-filling the unreadable ones. Returns the data and
+    /// the unreadable sectors.
+    pub fn read_sectors_tolerant(
+        &mut self,
+        offset: u64,
+        count: u64,
+        block_size: usize,
+    ) -> Result<(Vec<u8>, Vec<u64>), io::Error> {
+        self.scsiusb
+            .write()
+            .map_err(|err| io::Error::new(ErrorKind::Other, format!("lock error: {}", err)))?
+            .read_sectors_tolerant(offset, count, block_size)
+    }
+
+    pub fn write_sectors(
         &mut self,
         buffer: &mut [u8],
         offset: u64,
@@ -146,7 +271,7 @@ impl<T: UsbContext> MassStorage<T> {
             .scsiusb
             .write()
             .map_err(|err| io::Error::new(ErrorKind::Other, format!("lock error: {}", err)))?
-            .scsi_write_10(buffer, offset, count)?;
+            .write_blocks(buffer, offset, count)?;
         // Read last sector of what we've just written and verify it's ok.
         // XXX TODO FIXME Apparently, some devices requires reads between writes
         // to avoid overwriting cache of previous write call. Read call will
@@ -156,15 +281,80 @@ impl<T: UsbContext> MassStorage<T> {
         self.scsiusb
             .write()
             .map_err(|err| io::Error::new(ErrorKind::Other, format!("lock error: {}", err)))?
-            .scsi_read_10(&mut buf_check, offset + count - 1, 1)?;
+            .read_blocks(&mut buf_check, offset + count - 1, 1)?;
         if buf_check != buffer[(buffer.len() - buf_check.len()) as usize..] {
             return Err(io::Error::new(
                 ErrorKind::InvalidData,
-                "Couldn't verify write(10)",
+                "Couldn't verify write",
             ));
         }
         Ok(ret)
     }
+
+    /// Erase the whole device with the best method it supports among
+    /// SANITIZE, ATA security erase and UNMAP. `progress` is called with the
+    /// erased and total sizes. Returns the method used, or `None` if the
+    /// device supports none of them (or if unmapped blocks don't read back as
+    /// zeros), the device must then be overwritten.
+    pub fn erase(
+        &mut self,
+        progress: &mut dyn FnMut(u64, u64) -> Result<(), io::Error>,
+    ) -> Result<Option<&'static str>, io::Error> {
+        let dev_size = self.dev_size;
+        let block_count = self.max_lba + 1;
+        let block_size = u64::from(self.block_size);
+        let mut scsiusb = self
+            .scsiusb
+            .write()
+            .map_err(|err| io::Error::new(ErrorKind::Other, format!("lock error: {}", err)))?;
+        let support = scsiusb.erase_support();
+
+        if support.sanitize_block_erase || support.sanitize_crypto_erase {
+            // Prefer block erase, crypto erase only changes the media key
+            let crypto = !support.sanitize_block_erase;
+            scsiusb.sanitize_wait(crypto, &mut |done, total| {
+                progress(
+                    (u128::from(dev_size) * u128::from(done) / u128::from(total)) as u64,
+                    dev_size,
+                )
+            })?;
+            return Ok(Some(if crypto {
+                "sanitize crypto erase"
+            } else {
+                "sanitize block erase"
+            }));
+        }
+
+        if let Some(erase_time) = support.ata_security_erase {
+            progress(0, dev_size)?;
+            scsiusb.ata_security_erase(erase_time)?;
+            progress(dev_size, dev_size)?;
+            return Ok(Some("ata security erase"));
+        }
+
+        if let Some(max_count) = support.unmap {
+            let mut offset = 0;
+            while offset < block_count {
+                let count = (block_count - offset).min(u64::from(max_count));
+                if scsiusb.unmap(offset, count as u32)? != 0 {
+                    return Err(io::Error::new(ErrorKind::Other, "unmap failed"));
+                }
+                offset += count;
+                progress(offset * block_size, dev_size)?;
+            }
+            // Unmapped blocks are supposed to read as zeros, check it
+            for sector in unmap_samples(block_count) {
+                let data = scsiusb.read_sectors(sector, 1, block_size as usize)?;
+                if data.iter().any(|byte| *byte != 0) {
+                    error!("sector {} not zeroed after unmap", sector);
+                    return Ok(None);
+                }
+            }
+            return Ok(Some("unmap"));
+        }
+
+        Ok(None)
+    }
 }
 
 #[cfg(not(feature = "mock"))]
@@ -271,8 +461,12 @@ pub struct MassStorageComm {
     pub partition_sector_start: u64,
     // RwLock because we need to impl ReadAt which takes a non mut ref
     pub comm: Arc<RwLock<Comm<proto::scsi::Request>>>,
-    // Small cache to avoid reading the same sectors multiple time
-    pub cache: RwLock<lru::LruCache<(u64, u64), Vec<u8>>>,
+    // Read-ahead cache to avoid a round trip with dev2scsi for each small read
+    pub cache: RwLock<ReadCache>,
+    // Unreadable sectors are only tolerated (zero-filled and recorded here)
+    // when this is set, other reads fail on them. Shared with the process as
+    // the fs readers own the struct.
+    pub bad_sectors: Arc<RwLock<Option<Vec<u64>>>>,
 }
 
 impl MassStorageComm {
@@ -283,9 +477,8 @@ impl MassStorageComm {
             dev_size: 0,
             partition_sector_start: 0,
             comm: Arc::new(RwLock::new(comm)),
-            cache: RwLock::new(lru::LruCache::new(
-                std::num::NonZeroUsize::new(1024).unwrap(),
-            )),
+            cache: RwLock::new(ReadCache::new()),
+            bad_sectors: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -298,34 +491,159 @@ impl MassStorageComm {
     }
 
     pub fn read_sectors(&self, offset: u64, count: u64) -> Result<Vec<u8>, io::Error> {
-        // Don't cache data if we're reading a lot of sectors,
-        // it's probably a file (only read once) and not FS stuff
-        if count <= MAX_SECTORS_COUNT_CACHE as u64 {
-            if let Some(data) = self
-                .cache
-                .write()
-                .map_err(|err| {
-                    io::Error::new(ErrorKind::Other, format!("cache lock error: {}", err))
+        let block_size = self.block_size as u64;
+        let mut cache = self.cache.write().map_err(|err| {
+            io::Error::new(ErrorKind::Other, format!("cache lock error: {}", err))
+        })?;
+        if let Some(data) = cache.get(offset, count, block_size) {
+            return Ok(data);
+        }
+        let max_sector = if self.dev_size != 0 {
+            self.dev_size / block_size
+        } else {
+            u64::MAX
+        };
+        let mut read_count = cache.read_ahead(offset, count, block_size, max_sector);
+        let mut bad_sectors = self.bad_sectors.write().map_err(|err| {
+            io::Error::new(ErrorKind::Other, format!("bad sectors lock error: {}", err))
+        })?;
+        let tolerant = bad_sectors.is_some();
+        let mut rep = match self.comm()?.readsectors(proto::scsi::RequestReadSectors {
+            offset,
+            count: read_count,
+            tolerant,
+        }) {
+            Ok(rep) => rep,
+            // The unreadable sector may only be in the read-ahead window,
+            // past the requested ones
+            Err(err) if !tolerant && read_count > count => {
+                log::debug!(
+                    "read ahead of {} failed ({}), retrying without",
+                    offset,
+                    err
+                );
+                read_count = count;
+                self.comm()?.readsectors(proto::scsi::RequestReadSectors {
+                    offset,
+                    count,
+                    tolerant,
                 })?
-                .get(&(offset, count))
-            {
-                return Ok(data.clone());
             }
+            Err(err) => return Err(err),
+        };
+        if let (Some(bad_sectors), false) = (bad_sectors.as_mut(), rep.bad_sectors.is_empty()) {
+            // Don't cache damaged data so that it's reported for every read,
+            // and only report sectors that were actually requested
+            bad_sectors.extend(
+                rep.bad_sectors
+                    .iter()
+                    .filter(|sector| (offset..offset + count).contains(sector)),
+            );
+            rep.data.truncate((count * block_size) as usize);
+            return Ok(rep.data);
         }
-        let rep = self
-            .comm
-            .write()
-            .map_err(|err| io::Error::new(ErrorKind::Other, format!("comm lock error: {}", err)))?
-            .readsectors(proto::scsi::RequestReadSectors { offset, count })?;
-        if count <= MAX_SECTORS_COUNT_CACHE as u64 {
-            self.cache
-                .write()
-                .map_err(|err| {
-                    io::Error::new(ErrorKind::Other, format!("cache lock error: {}", err))
-                })?
-                .put((offset, count), rep.data.clone());
+        if read_count == count && count * block_size >= READAHEAD_MAX_SIZE {
+            // Large reads are file contents, read only once, don't cache them
+            return Ok(rep.data);
+        }
+        let data = rep.data[..(count * block_size) as usize].to_vec();
+        cache.put(offset, rep.data);
+        Ok(data)
+    }
+}
+
+/// LRU cache of extents read from the device.
+///
+/// Sequential reads are detected to grow the read-ahead window so that
+/// filesystem parsers doing small reads don't need a round trip with dev2scsi
+/// for each of them.
+pub struct ReadCache {
+    // Extents indexed by their first sector
+    extents: lru::LruCache<u64, Vec<u8>>,
+    // First sectors of the extents, for range lookups
+    starts: BTreeSet<u64>,
+    size: u64,
+    // Sector following the last read, to detect sequential reads
+    next_sector: u64,
+    // Current read-ahead window, in bytes
+    window: u64,
+}
+
+impl Default for ReadCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadCache {
+    pub fn new() -> Self {
+        ReadCache {
+            extents: lru::LruCache::unbounded(),
+            starts: BTreeSet::new(),
+            size: 0,
+            next_sector: 0,
+            window: READAHEAD_MIN_SIZE,
+        }
+    }
+
+    /// Return cached data if `count` sectors from `offset` are in a single
+    /// extent.
+    pub fn get(&mut self, offset: u64, count: u64, block_size: u64) -> Option<Vec<u8>> {
+        // Extents aren't larger than the read-ahead window, only those
+        // starting less than a window before offset can hold it
+        let first = offset.saturating_sub(READAHEAD_MAX_SIZE / block_size);
+        let start = self
+            .starts
+            .range(first..=offset)
+            .rev()
+            .copied()
+            .find(|start| {
+                self.extents.peek(start).map_or(false, |data| {
+                    offset + count <= start + data.len() as u64 / block_size
+                })
+            })?;
+        if offset == self.next_sector {
+            self.next_sector = offset + count;
+        }
+        let begin = ((offset - start) * block_size) as usize;
+        self.extents
+            .get(&start)
+            .map(|data| data[begin..begin + (count * block_size) as usize].to_vec())
+    }
+
+    /// Update the read-ahead window on a cache miss and return the number of
+    /// sectors to read from `offset` (at least `count`, at most up to
+    /// `max_sector`).
+    pub fn read_ahead(&mut self, offset: u64, count: u64, block_size: u64, max_sector: u64) -> u64 {
+        self.window = if offset == self.next_sector {
+            (self.window * 2).min(READAHEAD_MAX_SIZE)
+        } else {
+            READAHEAD_MIN_SIZE
+        };
+        self.next_sector = offset + count;
+        if count * block_size >= READAHEAD_MAX_SIZE {
+            return count;
+        }
+        (self.window / block_size)
+            .min(max_sector.saturating_sub(offset))
+            .max(count)
+    }
+
+    pub fn put(&mut self, offset: u64, data: Vec<u8>) {
+        self.size += data.len() as u64;
+        if let Some(old) = self.extents.put(offset, data) {
+            self.size -= old.len() as u64;
+        }
+        self.starts.insert(offset);
+        while self.size > READ_CACHE_MAX_SIZE {
+            match self.extents.pop_lru() {
+                Some((start, data)) => {
+                    self.size -= data.len() as u64;
+                    self.starts.remove(&start);
+                }
+                None => break,
+            }
         }
-        Ok(rep.data)
     }
 }
 
@@ -395,3 +713,114 @@ impl ReadAt for MassStorageComm {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_cache() {
+        let mut cache = ReadCache::new();
+        cache.put(100, vec![1; 8 * 512]);
+        cache.put(104, vec![2; 2 * 512]);
+        cache.put(200, vec![3; 512]);
+
+        // Lookups in the latest extent starting before offset, or in earlier
+        // ones that still cover the range
+        assert_eq!(cache.get(100, 4, 512), Some(vec![1; 4 * 512]));
+        assert_eq!(cache.get(104, 2, 512), Some(vec![2; 2 * 512]));
+        assert_eq!(cache.get(105, 3, 512), Some(vec![1; 3 * 512]));
+        assert_eq!(cache.get(200, 1, 512), Some(vec![3; 512]));
+        assert_eq!(cache.get(99, 2, 512), None);
+        assert_eq!(cache.get(107, 2, 512), None);
+        assert_eq!(cache.get(201, 1, 512), None);
+
+        // Evicted extents aren't found anymore
+        let big = READ_CACHE_MAX_SIZE / READAHEAD_MAX_SIZE;
+        for i in 0..big {
+            cache.put(0x10000 + i * 0x1000, vec![4; READAHEAD_MAX_SIZE as usize]);
+        }
+        assert_eq!(cache.get(100, 1, 512), None);
+        assert!(cache.size <= READ_CACHE_MAX_SIZE);
+        assert_eq!(cache.starts.len(), cache.extents.len());
+    }
+
+    #[test]
+    fn test_read_ahead_bad_sector() {
+        use std::os::unix::{io::OwnedFd, net::UnixStream};
+        let comm = |stream: UnixStream| -> Comm<proto::scsi::Request> {
+            Comm::new(
+                std::fs::File::from(OwnedFd::from(stream.try_clone().unwrap())),
+                std::fs::File::from(OwnedFd::from(stream)),
+            )
+        };
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut server = comm(server);
+
+        // dev2scsi with an unreadable sector 10, strict reads covering it fail
+        let dev2scsi = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            while let Ok(req) = server.recv::<proto::scsi::Request>() {
+                let req = match req.msg {
+                    Some(proto::scsi::request::Msg::ReadSectors(req)) => req,
+                    _ => break,
+                };
+                requests.push((req.offset, req.count));
+                let msg = if (req.offset..req.offset + req.count).contains(&10) && !req.tolerant {
+                    proto::scsi::response::Msg::Error(proto::scsi::ResponseError {
+                        err: "medium error".into(),
+                    })
+                } else {
+                    proto::scsi::response::Msg::ReadSectors(proto::scsi::ResponseReadSectors {
+                        data: (req.offset..req.offset + req.count)
+                            .flat_map(|sector| vec![sector as u8; 512])
+                            .collect(),
+                        bad_sectors: vec![],
+                    })
+                };
+                server
+                    .send(proto::scsi::Response { msg: Some(msg) })
+                    .unwrap();
+            }
+            requests
+        });
+
+        let mut mass_storage = MassStorageComm::new(comm(client));
+        mass_storage.block_size = 512;
+        mass_storage.dev_size = 1024 * 512;
+        // The bad sector is only in the read-ahead window: retried without it
+        assert_eq!(mass_storage.read_sectors(0, 1).unwrap(), vec![0; 512]);
+        assert_eq!(mass_storage.read_sectors(1, 1).unwrap(), vec![1; 512]);
+        // Requested, the read still fails
+        assert!(mass_storage.read_sectors(10, 1).is_err());
+        drop(mass_storage);
+        assert_eq!(
+            dev2scsi.join().unwrap(),
+            [(0, 64), (0, 1), (1, 128), (1, 1), (10, 32), (10, 1)]
+        );
+    }
+
+    #[test]
+    fn test_unmap_samples() {
+        assert!(unmap_samples(0).is_empty());
+        assert_eq!(unmap_samples(3), [0, 1, 2]);
+        assert_eq!(unmap_samples(UNMAP_SAMPLES).len() as u64, UNMAP_SAMPLES);
+        for block_count in [UNMAP_SAMPLES + 1, 1000, 0x1_0000_0001, u64::MAX] {
+            let samples = unmap_samples(block_count);
+            assert_eq!(samples.len() as u64, UNMAP_SAMPLES);
+            assert_eq!(samples[0], 0);
+            assert_eq!(*samples.last().unwrap(), block_count - 1);
+            assert!(samples.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_supported_block_size() {
+        for block_size in [0x200, 0x400, 0x800, 0x1000, 0x2000, 0x8000, 0x10000] {
+            assert!(is_supported_block_size(block_size));
+        }
+        for block_size in [0, 0x100, 0x208, 0x600, 0x20000] {
+            assert!(!is_supported_block_size(block_size));
+        }
+    }
+}
@@ -12,9 +12,13 @@ impl MockUsbContext for MockContext {}
 
 pub struct MockMassStorage<T> {
     fakedev: File,
+    pub max_lba: u64,
     pub block_size: u32,
     pub dev_size: u64,
     pub pos: u64,
+    pub vendorid: u32,
+    pub productid: u32,
+    pub serial: String,
     ctx: PhantomData<T>,
 }
 
@@ -27,7 +31,7 @@ impl<T> MockMassStorage<T> {
                         io::Error::new(ErrorKind::InvalidInput, format!("{}", err))
                     })?,
                 )?,
-                (1, 2) => OpenOptions::new().read(false).write(true).open(
+                (1, 2) => OpenOptions::new().read(true).write(true).open(
                     env::var("USBSAS_MOCK_OUT_DEV").map_err(|err| {
                         io::Error::new(ErrorKind::InvalidInput, format!("{}", err))
                     })?,
@@ -42,14 +46,24 @@ impl<T> MockMassStorage<T> {
         let dev_size = fakedev.metadata()?.len();
         Ok(MockMassStorage {
             fakedev,
+            max_lba: (dev_size / 512).saturating_sub(1),
             block_size: 512,
             dev_size,
             pos: 0,
+            // Same as the fake devices of usbdev
+            vendorid: 1,
+            productid: 1,
+            serial: "plop".to_string(),
             ctx: PhantomData,
         })
     }
 
-    pub fn from_busnum_devnum(libusb_ctx: T, busnum: u32, devnum: u32) -> Result<Self, io::Error> {
+    pub fn from_busnum_devnum(
+        libusb_ctx: T,
+        busnum: u32,
+        devnum: u32,
+        _lun: Option<u8>,
+    ) -> Result<Self, io::Error> {
         MockMassStorage::new(libusb_ctx, busnum, devnum)
     }
 
@@ -60,13 +74,22 @@ impl<T> MockMassStorage<T> {
         block_size: usize,
     ) -> Result<Vec<u8>, io::Error> {
         self.fakedev
-            .seek(SeekFrom::Start((offset as usize * block_size) as u64))?;
+            .seek(SeekFrom::Start(offset * block_size as u64))?;
         let mut buf = vec![0; count as usize * block_size];
         self.fakedev.read_exact(&mut buf)?;
         Ok(buf)
     }
 
-    pub fn scsi_write_10(
+    pub fn read_sectors_tolerant(
+        &mut self,
+        offset: u64,
+        count: u64,
+        block_size: usize,
+    ) -> Result<(Vec<u8>, Vec<u64>), io::Error> {
+        Ok((self.read_sectors(offset, count, block_size)?, Vec::new()))
+    }
+
+    pub fn write_sectors(
         &mut self,
         buffer: &mut [u8],
         offset: u64,
@@ -77,6 +100,14 @@ impl<T> MockMassStorage<T> {
         self.fakedev.write_all(buffer)?;
         Ok(0)
     }
+
+    /// Mock devices don't support any erase command
+    pub fn erase(
+        &mut self,
+        _: &mut dyn FnMut(u64, u64) -> Result<(), io::Error>,
+    ) -> Result<Option<&'static str>, io::Error> {
+        Ok(None)
+    }
 }
 
 impl<T> Read for MockMassStorage<T> {
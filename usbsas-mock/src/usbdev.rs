@@ -4,7 +4,7 @@ use thiserror::Error;
 use usbsas_comm::{protoresponse, Comm};
 use usbsas_process::UsbsasProcess;
 use usbsas_proto as proto;
-use usbsas_proto::common::Device as UsbDevice;
+use usbsas_proto::common::{Device as UsbDevice, UsbInterface, UsbSpeed};
 
 #[derive(Error, Debug)]
 enum Error {
@@ -44,6 +44,16 @@ impl MockUsbDev {
                 serial: "plop".to_string(),
                 is_src: true,
                 is_dst: false,
+                lun: 0,
+                speed: UsbSpeed::High.into(),
+                usb_version: 0x0200,
+                interfaces: vec![UsbInterface {
+                    number: 0,
+                    class_code: 0x08,
+                    sub_class_code: 0x06,
+                    protocol_code: 0x50,
+                }],
+                ..Default::default()
             });
         }
 
@@ -59,6 +69,16 @@ impl MockUsbDev {
                 serial: "plop".to_string(),
                 is_src: false,
                 is_dst: true,
+                lun: 0,
+                speed: UsbSpeed::High.into(),
+                usb_version: 0x0200,
+                interfaces: vec![UsbInterface {
+                    number: 0,
+                    class_code: 0x08,
+                    sub_class_code: 0x06,
+                    protocol_code: 0x50,
+                }],
+                ..Default::default()
             });
         }
 
@@ -1,12 +1,12 @@
-use crate::{Error, HttpClient, Result};
-use log::{error, trace};
+use crate::{Endpoints, Error, HttpClient, Result};
+use log::{error, trace, warn};
 use reqwest::blocking::Body;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fs::File,
     io::{self, Read},
-    os::unix::io::RawFd,
+    os::unix::io::{AsRawFd, RawFd},
     thread::sleep,
     time::Duration,
 };
@@ -90,22 +90,39 @@ struct RunningState {
 struct WaitEndState {}
 
 impl InitState {
-    fn run(self, _comm: &mut Comm<proto::analyzer::Request>) -> Result<State> {
+    fn run(self, comm: &mut Comm<proto::analyzer::Request>) -> Result<State> {
         let file = File::open(&self.tarpath)?;
         let config = conf_parse(&conf_read(&self.config_path)?)?;
 
-        // XXX seccomp
-
         if let Some(conf) = config.analyzer {
+            let endpoints = Endpoints::new(&conf.url)?;
+            if usbsas_privileges::analyzer::drop_priv(
+                comm.input_fd(),
+                comm.output_fd(),
+                file.as_raw_fd(),
+                &endpoints.ports,
+                conf.krb_service_name.is_some(),
+            )? != usbsas_privileges::landlock::Status::Enforced
+            {
+                warn!("landlock rules not fully enforced, relying on seccomp");
+            }
             Ok(State::Running(RunningState {
                 file: Some(file),
                 url: conf.url,
                 http_client: HttpClient::new(
+                    &endpoints,
                     #[cfg(feature = "authkrb")]
                     conf.krb_service_name,
                 )?,
             }))
         } else {
+            usbsas_privileges::analyzer::drop_priv(
+                comm.input_fd(),
+                comm.output_fd(),
+                file.as_raw_fd(),
+                &[],
+                false,
+            )?;
             error!("No analyzer conf, parking");
             Ok(State::WaitEnd(WaitEndState {}))
         }
@@ -18,7 +18,11 @@ use reqwest::{
     header::{HeaderMap, HeaderValue},
     Method, StatusCode,
 };
-use std::time::Duration;
+use std::{
+    env,
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
 
 use thiserror::Error;
 
@@ -43,6 +47,8 @@ enum Error {
     B64(#[from] base64::DecodeError),
     #[error("{0}")]
     Error(String),
+    #[error("privileges: {0}")]
+    Privileges(#[from] usbsas_privileges::Error),
     #[error("Bad Request")]
     BadRequest,
     #[error("Remote server error")]
@@ -56,6 +62,55 @@ enum Error {
 }
 type Result<T> = std::result::Result<T, Error>;
 
+// Proxy variables read by reqwest
+const PROXY_VARS: [&str; 6] = [
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+];
+
+/// Hosts the process connects to: the server and the proxy if any. Their
+/// names are resolved before entering the sandbox, which forbids it.
+pub(crate) struct Endpoints {
+    resolved: Vec<(String, SocketAddr)>,
+    pub ports: Vec<u16>,
+}
+
+impl Endpoints {
+    fn new(url: &str) -> Result<Self> {
+        let mut urls = vec![url.to_owned()];
+        urls.extend(
+            PROXY_VARS
+                .iter()
+                .filter_map(|var| env::var(var).ok())
+                .filter(|proxy| !proxy.is_empty()),
+        );
+        let mut endpoints = Endpoints {
+            resolved: Vec::new(),
+            ports: Vec::new(),
+        };
+        for url in urls {
+            let url = reqwest::Url::parse(&url)
+                .map_err(|err| Error::Error(format!("bad url {}: {}", url, err)))?;
+            let port = url.port_or_known_default().ok_or(Error::Conf)?;
+            if !endpoints.ports.contains(&port) {
+                endpoints.ports.push(port);
+            }
+            if let Some(domain) = url.domain() {
+                let addr = (domain, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| Error::Error(format!("couldn't resolve {}", domain)))?;
+                endpoints.resolved.push((domain.to_owned(), addr));
+            }
+        }
+        Ok(endpoints)
+    }
+}
+
 // Wrapper around reqwest::Client to transparently perform kerberos authentication
 pub(crate) struct HttpClient {
     client: Client,
@@ -65,11 +120,17 @@ pub(crate) struct HttpClient {
 }
 
 impl HttpClient {
-    fn new(#[cfg(feature = "authkrb")] krb_service_name: Option<String>) -> Result<Self> {
-        let client = Client::builder()
+    fn new(
+        endpoints: &Endpoints,
+        #[cfg(feature = "authkrb")] krb_service_name: Option<String>,
+    ) -> Result<Self> {
+        let mut builder = Client::builder()
             .timeout(None)
-            .connect_timeout(Duration::from_secs(30))
-            .build()?;
+            .connect_timeout(Duration::from_secs(30));
+        for (domain, addr) in &endpoints.resolved {
+            builder = builder.resolve(domain, *addr);
+        }
+        let client = builder.build()?;
         Ok(Self {
             client,
             headers: HeaderMap::new(),
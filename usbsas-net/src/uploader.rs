@@ -1,16 +1,17 @@
-use crate::{Error, HttpClient, Result};
-use log::{error, trace};
+use crate::{Endpoints, Error, HttpClient, Result};
+use log::{error, trace, warn};
 use reqwest::blocking::Body;
 use std::{
     fs::File,
     io::{self, Read},
-    os::unix::io::RawFd,
+    os::unix::io::{AsRawFd, RawFd},
 };
 use usbsas_comm::{protoresponse, Comm};
 use usbsas_config::{conf_parse, conf_read};
 use usbsas_process::UsbsasProcess;
 use usbsas_proto as proto;
 use usbsas_proto::uploader::request::Msg;
+use usbsas_utils::encrypt::EncryptReader;
 
 protoresponse!(
     CommUploader,
@@ -31,6 +32,9 @@ struct FileReaderProgress {
 impl Read for FileReaderProgress {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let size_read = self.file.read(buf)?;
+        if size_read == 0 {
+            return Ok(0);
+        }
         self.offset += size_read as u64;
         // if we report progression with each read (of 8kb), the json status of
         // the server polled by the client will quickly become very large and
@@ -73,24 +77,39 @@ struct RunningState {
     file: Option<File>,
     url: String,
     http_client: HttpClient,
+    recipients: Vec<String>,
 }
 
 struct WaitEndState {}
 
 impl InitState {
-    fn run(self, _comm: &mut Comm<proto::uploader::Request>) -> Result<State> {
+    fn run(self, comm: &mut Comm<proto::uploader::Request>) -> Result<State> {
         let file = File::open(&self.tarpath)?;
         let config_str = conf_read(&self.config_path)?;
         let config = conf_parse(&config_str)?;
         let net_conf = config.network.ok_or(Error::Conf)?;
 
+        let endpoints = Endpoints::new(&net_conf.url)?;
+        if usbsas_privileges::uploader::drop_priv(
+            comm.input_fd(),
+            comm.output_fd(),
+            file.as_raw_fd(),
+            &endpoints.ports,
+            net_conf.krb_service_name.is_some(),
+        )? != usbsas_privileges::landlock::Status::Enforced
+        {
+            warn!("landlock rules not fully enforced, relying on seccomp");
+        }
+
         Ok(State::Running(RunningState {
             file: Some(file),
             url: net_conf.url,
             http_client: HttpClient::new(
+                &endpoints,
                 #[cfg(feature = "authkrb")]
                 net_conf.krb_service_name,
             )?,
+            recipients: net_conf.recipients.unwrap_or_default(),
         }))
     }
 }
@@ -133,7 +152,12 @@ impl RunningState {
             offset: 0,
         };
 
-        let body = Body::sized(filereaderprogress, filesize);
+        // The size of the encrypted archive isn't known in advance
+        let body = if self.recipients.is_empty() {
+            Body::sized(filereaderprogress, filesize)
+        } else {
+            Body::new(EncryptReader::new(filereaderprogress, &self.recipients)?)
+        };
 
         let resp = self.http_client.post(&self.url, body)?;
         if !resp.status().is_success() {
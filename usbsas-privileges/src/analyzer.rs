@@ -0,0 +1,21 @@
+use crate::{landlock, Result};
+use std::os::unix::io::RawFd;
+
+/// Confine the analyzer: it can only read the tar to analyze and connect to
+/// the analyzer server (`ports`, if landlock is supported). Returns what the
+/// landlock rules enforce.
+pub fn drop_priv(
+    fd_read: RawFd,
+    fd_write: RawFd,
+    tar_fd: RawFd,
+    ports: &[u16],
+    kerberos: bool,
+) -> Result<landlock::Status> {
+    let landlock = crate::net::restrict_access(ports, kerberos)?;
+
+    let mut ctx = crate::new_context_with_common_rules(vec![fd_read, tar_fd], vec![fd_write])?;
+    crate::net::apply_net_rules(&mut ctx, &[fd_read, fd_write], kerberos, landlock)?;
+    ctx.load()?;
+
+    Ok(landlock)
+}
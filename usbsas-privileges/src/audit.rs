@@ -0,0 +1,216 @@
+//! Audit mode of the seccomp filters: instead of killing the process, syscalls
+//! denied by its rules are either logged by the kernel (`log`, like
+//! `type=SECCOMP ... comm="files2fs" ... syscall=257` in the kernel log) or
+//! reported on stderr by the process itself and failed with `ENOSYS` (`trap`).
+//! This is meant to find the syscalls missing from a profile, never to run
+//! usbsas in production.
+
+use crate::{Error, Result};
+use std::{
+    ffi::CStr,
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+use syscallz::Action;
+
+/// Environment variable selecting the mode (`log` or `trap`) if it wasn't set
+/// by `set_mode()`
+pub const AUDIT_ENV_VAR: &str = "USBSAS_SECCOMP_AUDIT";
+/// Start of the lines written on stderr in `trap` mode, followed by
+/// `<process>: syscall <number> args <args>`
+pub const TRAP_PREFIX: &str = "usbsas seccomp audit:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditMode {
+    Log,
+    Trap,
+}
+
+// 0: not set, read from the environment
+const MODE_UNSET: u8 = 0;
+const MODE_OFF: u8 = 1;
+const MODE_LOG: u8 = 2;
+const MODE_TRAP: u8 = 3;
+static MODE: AtomicU8 = AtomicU8::new(MODE_UNSET);
+
+// Name of the process (16 bytes, see PR_GET_NAME) for the SIGSYS handler,
+// which can't allocate nor make syscalls other than write()
+static NAME: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Select the audit mode of the filters loaded from now on, by this process
+/// and the children it will fork
+pub fn set_mode(mode: Option<AuditMode>) {
+    let mode = match mode {
+        None => MODE_OFF,
+        Some(AuditMode::Log) => MODE_LOG,
+        Some(AuditMode::Trap) => MODE_TRAP,
+    };
+    MODE.store(mode, Ordering::Relaxed);
+}
+
+pub fn mode() -> Option<AuditMode> {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_LOG => Some(AuditMode::Log),
+        MODE_TRAP => Some(AuditMode::Trap),
+        MODE_OFF => None,
+        _ => match std::env::var(AUDIT_ENV_VAR).as_deref() {
+            Ok("log") => Some(AuditMode::Log),
+            Ok("trap") => Some(AuditMode::Trap),
+            _ => None,
+        },
+    }
+}
+
+/// Action of syscalls not allowed by the rules
+pub(crate) fn default_action() -> Result<Action> {
+    match mode() {
+        None => Ok(Action::KillProcess),
+        Some(AuditMode::Log) => {
+            log::warn!("seccomp audit mode, denied syscalls are only logged");
+            Ok(Action::Log)
+        }
+        Some(AuditMode::Trap) => {
+            log::warn!("seccomp audit mode, denied syscalls are reported and fail");
+            install_sigsys_handler()?;
+            Ok(Action::Trap)
+        }
+    }
+}
+
+/// Name of a syscall of the native architecture
+pub fn syscall_name(number: i32) -> Option<String> {
+    #[link(name = "seccomp")]
+    extern "C" {
+        fn seccomp_syscall_resolve_num_arch(arch_token: u32, num: libc::c_int)
+            -> *mut libc::c_char;
+    }
+    // SCMP_ARCH_NATIVE
+    let name = unsafe { seccomp_syscall_resolve_num_arch(0, number) };
+    if name.is_null() {
+        return None;
+    }
+    let result = unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { libc::free(name as *mut libc::c_void) };
+    Some(result)
+}
+
+fn install_sigsys_handler() -> Result<()> {
+    let mut name = [0u8; 16];
+    unsafe { libc::prctl(libc::PR_GET_NAME, name.as_mut_ptr()) };
+    for (i, chunk) in name.chunks(8).enumerate() {
+        NAME[i].store(
+            u64::from_ne_bytes(chunk.try_into().unwrap_or_default()),
+            Ordering::Relaxed,
+        );
+    }
+
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = sigsys_handler as usize;
+    action.sa_flags = libc::SA_SIGINFO;
+    if unsafe { libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) } < 0 {
+        return Err(Error::Error(format!(
+            "sigaction: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+// siginfo_t of SIGSYS (struct _sigsys)
+#[repr(C)]
+struct SigsysInfo {
+    _signo: libc::c_int,
+    _errno: libc::c_int,
+    _code: libc::c_int,
+    #[cfg(target_pointer_width = "64")]
+    _pad: libc::c_int,
+    _call_addr: *mut libc::c_void,
+    syscall: libc::c_int,
+    _arch: libc::c_uint,
+}
+
+// Line written by the handler, without allocating
+struct Line {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let bytes = s.as_bytes();
+        let len = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Arguments of the trapped syscall and pointer to its return value
+#[cfg(target_arch = "x86_64")]
+unsafe fn syscall_context(context: *mut libc::c_void) -> ([u64; 6], *mut i64) {
+    let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+    let args = [
+        libc::REG_RDI,
+        libc::REG_RSI,
+        libc::REG_RDX,
+        libc::REG_R10,
+        libc::REG_R8,
+        libc::REG_R9,
+    ]
+    .map(|reg| gregs[reg as usize] as u64);
+    (args, &mut gregs[libc::REG_RAX as usize] as *mut i64)
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn syscall_context(context: *mut libc::c_void) -> ([u64; 6], *mut i64) {
+    let regs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.regs;
+    let mut args = [0; 6];
+    args.copy_from_slice(&regs[..6]);
+    (args, &mut regs[0] as *mut u64 as *mut i64)
+}
+
+extern "C" fn sigsys_handler(
+    _signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let info = unsafe { &*(info as *const SigsysInfo) };
+    let mut name = [0u8; 16];
+    name[..8].copy_from_slice(&NAME[0].load(Ordering::Relaxed).to_ne_bytes());
+    name[8..].copy_from_slice(&NAME[1].load(Ordering::Relaxed).to_ne_bytes());
+    let name_len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+
+    let mut line = Line {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = write!(
+        line,
+        "{} {}: syscall {} args",
+        TRAP_PREFIX,
+        std::str::from_utf8(&name[..name_len]).unwrap_or("?"),
+        info.syscall
+    );
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    let ret = {
+        let (args, ret) = unsafe { syscall_context(context) };
+        for arg in args {
+            let _ = write!(line, " {:#x}", arg);
+        }
+        Some(ret)
+    };
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let ret: Option<*mut i64> = {
+        let _ = context;
+        None
+    };
+    let _ = line.write_str("\n");
+    unsafe { libc::write(2, line.buf.as_ptr() as *const libc::c_void, line.len) };
+
+    // The syscall isn't executed, make it fail
+    if let Some(ret) = ret {
+        unsafe { *ret = -(libc::ENOSYS as i64) };
+    }
+}
@@ -0,0 +1,69 @@
+use crate::Result;
+use syscallz::{Action, Context, Syscall};
+
+// Syscalls the executed command has no reason to use, they fail with EPERM
+const DENIED_SYSCALLS: &[Syscall] = &[
+    Syscall::ptrace,
+    Syscall::process_vm_readv,
+    Syscall::process_vm_writev,
+    Syscall::mount,
+    Syscall::umount2,
+    Syscall::pivot_root,
+    Syscall::chroot,
+    Syscall::unshare,
+    Syscall::setns,
+    Syscall::kexec_load,
+    Syscall::kexec_file_load,
+    Syscall::init_module,
+    Syscall::finit_module,
+    Syscall::delete_module,
+    Syscall::bpf,
+    Syscall::perf_event_open,
+    Syscall::keyctl,
+    Syscall::add_key,
+    Syscall::request_key,
+    Syscall::reboot,
+    Syscall::swapon,
+    Syscall::swapoff,
+    Syscall::acct,
+    Syscall::userfaultfd,
+    Syscall::open_by_handle_at,
+    Syscall::name_to_handle_at,
+    Syscall::settimeofday,
+    Syscall::clock_settime,
+    Syscall::adjtimex,
+    Syscall::syslog,
+    Syscall::quotactl,
+    #[cfg(target_arch = "x86_64")]
+    Syscall::iopl,
+    #[cfg(target_arch = "x86_64")]
+    Syscall::ioperm,
+];
+
+/// Restrict the command executed by cmdexec. This is meant to be called
+/// between fork and exec (`CommandExt::pre_exec`): fds other than stdio aren't
+/// inherited, core dumps are disabled and a seccomp filter denying
+/// administration and debugging syscalls is loaded (with no_new_privs, the
+/// command can't gain privileges either).
+pub fn restrict_exec() -> Result<()> {
+    // Mark the fds inherited from usbsas close-on-exec rather than closing
+    // them, std reports exec errors with one of them
+    let max_fd = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) }.clamp(0, 0x10000) as libc::c_int;
+    for fd in 3..max_fd {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core) };
+
+    let mut ctx = Context::init_with_action(Action::Allow)?;
+    for syscall in DENIED_SYSCALLS {
+        ctx.set_action_for_syscall(Action::Errno(libc::EPERM as u16), *syscall)?;
+    }
+    ctx.load()?;
+
+    Ok(())
+}
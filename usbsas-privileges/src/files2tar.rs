@@ -12,6 +12,15 @@ pub fn drop_priv(fd_read: RawFd, fd_write: RawFd, out_tar_fd: RawFd) -> Result<(
         Syscall::_llseek,
         &[Comparator::new(0, Cmp::Eq, out_tar_fd as u64, None)],
     )?;
+    // Damaged files are discarded by truncating the archive
+    ctx.set_rule_for_syscall(
+        Action::Allow,
+        #[cfg(not(target_arch = "arm"))]
+        Syscall::ftruncate,
+        #[cfg(target_arch = "arm")]
+        Syscall::ftruncate64,
+        &[Comparator::new(0, Cmp::Eq, out_tar_fd as u64, None)],
+    )?;
     ctx.allow_syscall(Syscall::uname)?;
     ctx.load()?;
 
@@ -14,6 +14,9 @@ pub fn drop_priv(
     }
     let mut ctx = crate::new_context_with_common_rules(fds_read, vec![fd_write])?;
 
+    // Random wipes
+    ctx.allow_syscall(Syscall::getrandom)?;
+
     if let Some(fd) = out_fs_fd {
         // Allow lseek on out_fs
         ctx.set_rule_for_syscall(
@@ -0,0 +1,225 @@
+//! Landlock rules limiting the files a process can open and the TCP ports it
+//! can connect to, on kernels supporting it.
+
+use crate::{Error, Result};
+use std::{ffi::CString, fs, io, mem, os::unix::ffi::OsStrExt, os::unix::io::RawFd, path::Path};
+
+// Landlock syscalls have the same number on every architecture
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_uint = 1;
+const LANDLOCK_RULE_NET_PORT: libc::c_uint = 2;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+// Every file system access right of the first ABI, from EXECUTE to MAKE_SYM
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+// ABI 2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+// ABI 3
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+// ABI 4
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C)]
+struct NetPortAttr {
+    allowed_access: u64,
+    port: u64,
+}
+
+/// What the landlock rules enforce
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Landlock isn't supported by the kernel, nothing is restricted
+    Unsupported,
+    /// Files are restricted but the kernel doesn't support TCP rules (ABI <
+    /// 4), the ports that were requested to be restricted aren't
+    FilesOnly,
+    /// Every requested restriction is enforced
+    Enforced,
+}
+
+/// Landlock ABI version supported by the kernel, 0 if it isn't supported
+pub fn abi_version() -> i32 {
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if ret < 0 {
+        0
+    } else {
+        ret as i32
+    }
+}
+
+fn os_error(what: &str) -> Error {
+    Error::Error(format!("landlock {}: {}", what, io::Error::last_os_error()))
+}
+
+fn add_path_rule(ruleset_fd: RawFd, path: &str, write: bool, handled_access: u64) -> Result<()> {
+    // Missing paths (differing between distributions) are ignored
+    let mut allowed_access = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            let mut access = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+            if write {
+                access |= ACCESS_FS_WRITE_FILE
+                    | ACCESS_FS_REMOVE_FILE
+                    | ACCESS_FS_MAKE_REG
+                    | ACCESS_FS_TRUNCATE;
+            }
+            access
+        }
+        Ok(_) if write => ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE,
+        Ok(_) => ACCESS_FS_READ_FILE,
+        Err(_) => return Ok(()),
+    };
+    // TRUNCATE isn't handled by older ABIs
+    allowed_access &= handled_access;
+    let c_path = CString::new(Path::new(path).as_os_str().as_bytes())
+        .map_err(|err| Error::Error(format!("landlock path: {}", err)))?;
+    let parent_fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if parent_fd < 0 {
+        return Err(os_error("open"));
+    }
+    let attr = PathBeneathAttr {
+        allowed_access,
+        parent_fd,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset_fd,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    unsafe { libc::close(parent_fd) };
+    if ret < 0 {
+        return Err(os_error("add path rule"));
+    }
+    Ok(())
+}
+
+fn add_port_rule(ruleset_fd: RawFd, port: u16) -> Result<()> {
+    let attr = NetPortAttr {
+        allowed_access: ACCESS_NET_CONNECT_TCP,
+        port: u64::from(port),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset_fd,
+            LANDLOCK_RULE_NET_PORT,
+            &attr as *const NetPortAttr,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(os_error("add port rule"));
+    }
+    Ok(())
+}
+
+/// Only allow the calling thread (and the threads and processes it will
+/// create) to read `read_paths`, to read and write `write_paths` and, if set,
+/// to connect to `connect_ports`. This must be done before entering seccomp.
+/// Returns what is enforced: nothing if the kernel doesn't support landlock,
+/// and ports aren't restricted if it doesn't support TCP rules (ABI < 4).
+pub fn restrict(
+    read_paths: &[&str],
+    write_paths: &[&str],
+    connect_ports: Option<&[u16]>,
+) -> Result<Status> {
+    let abi = abi_version();
+    if abi < 1 {
+        return Ok(Status::Unsupported);
+    }
+    let mut handled_access_fs = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled_access_fs |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled_access_fs |= ACCESS_FS_TRUNCATE;
+    }
+    let status = if connect_ports.is_some() && abi < 4 {
+        log::error!(
+            "landlock ABI {} doesn't support TCP rules, ports aren't restricted",
+            abi
+        );
+        Status::FilesOnly
+    } else {
+        Status::Enforced
+    };
+    let connect_ports = connect_ports.filter(|_| abi >= 4);
+    let attr = RulesetAttr {
+        handled_access_fs,
+        handled_access_net: if connect_ports.is_some() {
+            ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP
+        } else {
+            0
+        },
+    };
+    // The net field only exists from ABI 4
+    let attr_size = if abi >= 4 {
+        mem::size_of::<RulesetAttr>()
+    } else {
+        mem::size_of::<u64>()
+    };
+    let ruleset_fd = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            attr_size,
+            0,
+        )
+    };
+    if ruleset_fd < 0 {
+        return Err(os_error("create ruleset"));
+    }
+    let ruleset_fd = ruleset_fd as RawFd;
+
+    let result = (|| {
+        for path in read_paths {
+            add_path_rule(ruleset_fd, path, false, handled_access_fs)?;
+        }
+        for path in write_paths {
+            add_path_rule(ruleset_fd, path, true, handled_access_fs)?;
+        }
+        for port in connect_ports.unwrap_or_default() {
+            add_port_rule(ruleset_fd, *port)?;
+        }
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(os_error("no new privs"));
+        }
+        if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset_fd, 0) } < 0 {
+            return Err(os_error("restrict self"));
+        }
+        Ok(status)
+    })();
+    unsafe { libc::close(ruleset_fd) };
+    result
+}
@@ -1,5 +1,8 @@
-//! Seccomp rules for usbsas processes.
+//! Seccomp (and landlock) rules for usbsas processes.
 
+pub mod analyzer;
+pub mod audit;
+pub mod cmdexec;
 pub mod dev2scsi;
 pub mod files2fs;
 pub mod files2tar;
@@ -8,8 +11,11 @@ pub mod fs2dev;
 pub mod fswriter;
 pub mod identificator;
 pub mod imager;
+pub mod landlock;
+mod net;
 pub mod scsi2files;
 pub mod tar2files;
+pub mod uploader;
 pub mod usbdev;
 pub mod usbsas;
 
@@ -33,7 +39,7 @@ pub(crate) fn new_context_with_common_rules(
     fds_read: Vec<RawFd>,
     fds_write: Vec<RawFd>,
 ) -> Result<Context> {
-    let mut ctx = Context::init_with_action(Action::KillProcess)?;
+    let mut ctx = Context::init_with_action(audit::default_action()?)?;
 
     // Allow read
     for fd in &fds_read {
@@ -140,6 +146,7 @@ extern "C" {
     pub fn usbdevfs_discardurb() -> u64;
     pub fn usbdevfs_get_capabilities() -> u64;
     pub fn usbdevfs_disconnect_claim() -> u64;
+    pub fn usbdevfs_clear_halt() -> u64;
     pub fn usbdevfs_reset() -> u64;
 }
 
@@ -245,6 +252,16 @@ pub(crate) fn apply_libusb_rules(ctx: &mut Context, libusb_fds: LibusbFds) -> Re
                 Comparator::new(1, Cmp::Eq, unsafe { usbdevfs_disconnect_claim() }, None),
             ],
         )?;
+        // Recovery of stalled endpoints (SCSI commands failing, BOT reset
+        // recovery)
+        ctx.set_rule_for_syscall(
+            Action::Allow,
+            Syscall::ioctl,
+            &[
+                Comparator::new(0, Cmp::Eq, device_fd as u64, None),
+                Comparator::new(1, Cmp::Eq, unsafe { usbdevfs_clear_halt() }, None),
+            ],
+        )?;
     }
 
     // XXX poll() takes as first arg an array of struct pollfd, can we use comparators for this ?
@@ -0,0 +1,212 @@
+//! Rules shared by the processes talking to remote servers (analyzer and
+//! uploader). Their HTTP client (with its sockets, epoll and runtime thread)
+//! is created after entering seccomp, so that it is confined too.
+
+use crate::{landlock, Result};
+use procfs::process::Process;
+use std::os::unix::io::RawFd;
+use syscallz::{Action, Cmp, Comparator, Context, Syscall};
+
+// Files read by the TLS library (certificates, configuration)
+const TLS_PATHS: &[&str] = &[
+    "/etc/ssl",
+    "/etc/pki",
+    "/usr/lib/ssl",
+    "/usr/share/ca-certificates",
+    "/etc/crypto-policies",
+];
+
+// Files read by the Kerberos library and the name resolution of its KDCs
+const KRB_PATHS: &[&str] = &[
+    "/etc/krb5.conf",
+    "/etc/krb5.conf.d",
+    "/etc/gss",
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/host.conf",
+    "/etc/gai.conf",
+    "/tmp",
+];
+const KRB_PORTS: &[u16] = &[53, 88];
+
+/// Highest fd opened by the process, fds opened afterwards will be greater
+fn max_open_fd() -> Result<RawFd> {
+    let mut max_fd = 2;
+    for fd in Process::myself()?.fd()? {
+        max_fd = max_fd.max(fd?.fd as RawFd);
+    }
+    Ok(max_fd)
+}
+
+/// Only allow reading the TLS (and Kerberos) files and connecting to `ports`
+/// (and those of the KDCs), where landlock is supported
+pub(crate) fn restrict_access(ports: &[u16], kerberos: bool) -> Result<landlock::Status> {
+    let mut paths = TLS_PATHS.to_vec();
+    let mut ports = ports.to_vec();
+    if kerberos {
+        paths.extend_from_slice(KRB_PATHS);
+        ports.extend_from_slice(KRB_PORTS);
+    }
+    crate::landlock::restrict(&paths, &[], Some(&ports))
+}
+
+/// Seccomp rules of the network processes. Seccomp can't check the paths
+/// opened and the addresses connected to, files can only be opened if
+/// landlock restricts them (`landlock_status` isn't `Unsupported`).
+pub(crate) fn apply_net_rules(
+    ctx: &mut Context,
+    comm_fds: &[RawFd],
+    kerberos: bool,
+    landlock_status: landlock::Status,
+) -> Result<()> {
+    // Fds opened from now on can only be sockets, epoll / event fds of the
+    // HTTP client or files opened read-only
+    let max_fd = max_open_fd()?;
+    for syscall in [
+        Syscall::read,
+        Syscall::write,
+        Syscall::close,
+        Syscall::readv,
+        Syscall::writev,
+        Syscall::recvfrom,
+        Syscall::sendto,
+        Syscall::recvmsg,
+        Syscall::sendmsg,
+        Syscall::getsockopt,
+        Syscall::setsockopt,
+        Syscall::getsockname,
+        Syscall::getpeername,
+        Syscall::shutdown,
+        Syscall::fcntl,
+        Syscall::ioctl,
+        Syscall::epoll_ctl,
+        Syscall::epoll_pwait,
+        #[cfg(not(target_arch = "aarch64"))]
+        Syscall::epoll_wait,
+        #[cfg(not(target_arch = "arm"))]
+        Syscall::lseek,
+        #[cfg(target_arch = "arm")]
+        Syscall::_llseek,
+    ] {
+        ctx.set_rule_for_syscall(
+            Action::Allow,
+            syscall,
+            &[Comparator::new(0, Cmp::Gt, max_fd as u64, None)],
+        )?;
+    }
+
+    // Comm fds are duplicated to report the upload progress
+    for fd in comm_fds {
+        ctx.set_rule_for_syscall(
+            Action::Allow,
+            Syscall::fcntl,
+            &[
+                Comparator::new(0, Cmp::Eq, *fd as u64, None),
+                Comparator::new(1, Cmp::Eq, libc::F_DUPFD_CLOEXEC as u64, None),
+            ],
+        )?;
+    }
+
+    // IPv4 and IPv6 sockets only, connected with addresses of their size (the
+    // ports are restricted by landlock)
+    for domain in [libc::AF_INET, libc::AF_INET6] {
+        ctx.set_rule_for_syscall(
+            Action::Allow,
+            Syscall::socket,
+            &[Comparator::new(0, Cmp::Eq, domain as u64, None)],
+        )?;
+    }
+    for addr_len in [
+        std::mem::size_of::<libc::sockaddr_in>(),
+        std::mem::size_of::<libc::sockaddr_in6>(),
+    ] {
+        ctx.set_rule_for_syscall(
+            Action::Allow,
+            Syscall::connect,
+            &[
+                Comparator::new(0, Cmp::Gt, max_fd as u64, None),
+                Comparator::new(2, Cmp::Eq, addr_len as u64, None),
+            ],
+        )?;
+    }
+
+    // Read-only files (TLS certificates and configuration), their paths are
+    // restricted by landlock. Without it, opening them fails.
+    let action = if landlock_status == landlock::Status::Unsupported {
+        log::error!("landlock not supported, files can't be opened");
+        Action::Errno(libc::EACCES as u16)
+    } else {
+        Action::Allow
+    };
+    ctx.set_rule_for_syscall(
+        action,
+        Syscall::openat,
+        &[Comparator::new(
+            2,
+            Cmp::MaskedEq,
+            (libc::O_ACCMODE | libc::O_CREAT | libc::O_TRUNC) as u64,
+            Some(libc::O_RDONLY as u64),
+        )],
+    )?;
+
+    // Threads of the HTTP client, glibc falls back to clone() if clone3()
+    // isn't implemented
+    ctx.set_rule_for_syscall(
+        Action::Allow,
+        Syscall::clone,
+        &[Comparator::new(
+            0,
+            Cmp::MaskedEq,
+            libc::CLONE_THREAD as u64,
+            Some(libc::CLONE_THREAD as u64),
+        )],
+    )?;
+    ctx.set_action_for_syscall(Action::Errno(libc::ENOSYS as u16), Syscall::clone3)?;
+    ctx.allow_syscall(Syscall::mprotect)?;
+    ctx.set_rule_for_syscall(
+        Action::KillThread,
+        Syscall::mprotect,
+        &[Comparator::new(
+            2,
+            Cmp::MaskedEq,
+            libc::PROT_EXEC as u64,
+            Some(libc::PROT_EXEC as u64),
+        )],
+    )?;
+    // Thread names
+    ctx.set_rule_for_syscall(
+        Action::Allow,
+        Syscall::prctl,
+        &[Comparator::new(0, Cmp::Eq, libc::PR_SET_NAME as u64, None)],
+    )?;
+    ctx.allow_syscall(Syscall::set_robust_list)?;
+    ctx.allow_syscall(Syscall::rseq)?;
+    ctx.allow_syscall(Syscall::madvise)?;
+    ctx.allow_syscall(Syscall::rt_sigprocmask)?;
+    ctx.allow_syscall(Syscall::sched_yield)?;
+    ctx.allow_syscall(Syscall::exit)?;
+
+    ctx.allow_syscall(Syscall::epoll_create1)?;
+    ctx.allow_syscall(Syscall::eventfd2)?;
+    #[cfg(not(target_arch = "aarch64"))]
+    ctx.allow_syscall(Syscall::poll)?;
+    ctx.allow_syscall(Syscall::ppoll)?;
+    ctx.allow_syscall(Syscall::getrandom)?;
+    ctx.allow_syscall(Syscall::clock_nanosleep)?;
+    ctx.allow_syscall(Syscall::nanosleep)?;
+    ctx.allow_syscall(Syscall::statx)?;
+    #[cfg(not(target_arch = "arm"))]
+    ctx.allow_syscall(Syscall::newfstatat)?;
+    #[cfg(not(target_arch = "arm"))]
+    ctx.allow_syscall(Syscall::fstat)?;
+
+    if kerberos {
+        ctx.allow_syscall(Syscall::getuid)?;
+        ctx.allow_syscall(Syscall::geteuid)?;
+        ctx.allow_syscall(Syscall::getpid)?;
+        ctx.allow_syscall(Syscall::uname)?;
+    }
+
+    Ok(())
+}
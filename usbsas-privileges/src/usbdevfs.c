@@ -30,6 +30,10 @@ uint64_t usbdevfs_disconnect_claim() {
     return USBDEVFS_DISCONNECT_CLAIM;
 }
 
+uint64_t usbdevfs_clear_halt() {
+    return USBDEVFS_CLEAR_HALT;
+}
+
 uint64_t usbdevfs_reset() {
     return USBDEVFS_RESET;
 }
@@ -0,0 +1,190 @@
+//! Apply the profiles of the network processes, of the commands executed by
+//! cmdexec and of the processes driving USB devices in forked children and
+//! check what they can still do.
+
+use std::{
+    fs::File,
+    io::Read,
+    net::{TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, RawFd},
+    process::Command,
+};
+use usbsas_privileges::landlock;
+
+type DropPriv =
+    fn(RawFd, RawFd, RawFd, &[u16], bool) -> Result<landlock::Status, usbsas_privileges::Error>;
+
+/// Run `child` in a forked process and return its wait status
+fn fork_and_wait(child: impl FnOnce() -> i32) -> i32 {
+    match unsafe { libc::fork() } {
+        -1 => panic!("fork failed"),
+        0 => {
+            let code = child();
+            unsafe { libc::_exit(code) }
+        }
+        pid => {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            status
+        }
+    }
+}
+
+fn pipe() -> (RawFd, RawFd) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    (fds[0], fds[1])
+}
+
+fn tar_file(name: &str) -> File {
+    let path = std::env::temp_dir().join(format!("{}-{}.tar", name, std::process::id()));
+    std::fs::write(&path, b"tar").unwrap();
+    let file = File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file
+}
+
+fn check_net_profile(name: &str, drop_priv: DropPriv) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (fd_read, fd_write) = pipe();
+
+    // Reading the tar and connecting to the server is allowed
+    let mut file = tar_file(name);
+    let status = fork_and_wait(|| {
+        if drop_priv(fd_read, fd_write, file.as_raw_fd(), &[port], false).is_err() {
+            return 1;
+        }
+        let mut buf = Vec::new();
+        if file.read_to_end(&mut buf).is_err() || buf != b"tar" {
+            return 2;
+        }
+        if TcpStream::connect(("127.0.0.1", port)).is_err() {
+            return 3;
+        }
+        0
+    });
+    assert!(libc::WIFEXITED(status), "{} killed", name);
+    assert_eq!(libc::WEXITSTATUS(status), 0, "{}", name);
+
+    // Creating a file isn't
+    let file = tar_file(name);
+    let status = fork_and_wait(|| {
+        if drop_priv(fd_read, fd_write, file.as_raw_fd(), &[port], false).is_err() {
+            return 1;
+        }
+        let _ = File::create(std::env::temp_dir().join("usbsas-profile-test"));
+        0
+    });
+    assert!(libc::WIFSIGNALED(status), "{} not killed", name);
+    assert_eq!(libc::WTERMSIG(status), libc::SIGSYS, "{}", name);
+
+    // Connecting to another port isn't either, where landlock has TCP rules
+    if landlock::abi_version() < 4 {
+        eprintln!("landlock TCP rules not supported, ports not checked");
+        return;
+    }
+    let other_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let other_port = other_listener.local_addr().unwrap().port();
+    let file = tar_file(name);
+    let status = fork_and_wait(|| {
+        match drop_priv(fd_read, fd_write, file.as_raw_fd(), &[port], false) {
+            Ok(landlock::Status::Enforced) => (),
+            _ => return 1,
+        }
+        match TcpStream::connect(("127.0.0.1", other_port)) {
+            Err(err) if err.raw_os_error() == Some(libc::EACCES) => 0,
+            _ => 2,
+        }
+    });
+    assert!(libc::WIFEXITED(status), "{} killed", name);
+    assert_eq!(libc::WEXITSTATUS(status), 0, "{}", name);
+}
+
+#[test]
+fn uploader() {
+    check_net_profile("uploader", usbsas_privileges::uploader::drop_priv);
+}
+
+#[test]
+fn analyzer() {
+    check_net_profile("analyzer", usbsas_privileges::analyzer::drop_priv);
+}
+
+#[test]
+fn cmdexec() {
+    let status = fork_and_wait(|| {
+        if usbsas_privileges::cmdexec::restrict_exec().is_err() {
+            return 1;
+        }
+        // Executing commands still works
+        match Command::new("/bin/true").status() {
+            Ok(status) if status.success() => (),
+            _ => return 2,
+        }
+        // Creating namespaces doesn't
+        if unsafe { libc::unshare(libc::CLONE_NEWUSER) } != -1
+            || std::io::Error::last_os_error().raw_os_error() != Some(libc::EPERM)
+        {
+            return 3;
+        }
+        0
+    });
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+}
+
+#[test]
+fn dev2scsi() {
+    // A file stands for the device, the ioctls fail with ENOTTY instead of
+    // reaching a USB device but aren't filtered
+    let device = tar_file("dev2scsi");
+    let other = tar_file("dev2scsi-other");
+    let (fd_read, fd_write) = pipe();
+    let clear_halt = |fd: RawFd, endpoint: libc::c_uint| unsafe {
+        libc::ioctl(
+            fd,
+            usbsas_privileges::usbdevfs_clear_halt() as _,
+            &endpoint as *const libc::c_uint,
+        )
+    };
+
+    // BOT reset recovery of a stalled endpoint: clear the halt of both bulk
+    // endpoints
+    let status = fork_and_wait(|| {
+        let libusb_fds = usbsas_privileges::LibusbFds {
+            device: Some(device.as_raw_fd()),
+            timers: vec![],
+            events: vec![],
+        };
+        if usbsas_privileges::dev2scsi::drop_priv(fd_read, fd_write, libusb_fds).is_err() {
+            return 1;
+        }
+        for endpoint in [0x81, 0x02] {
+            if clear_halt(device.as_raw_fd(), endpoint) != -1
+                || std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOTTY)
+            {
+                return 2;
+            }
+        }
+        0
+    });
+    assert!(libc::WIFEXITED(status), "dev2scsi killed clearing a halt");
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+
+    // Only on the device
+    let status = fork_and_wait(|| {
+        let libusb_fds = usbsas_privileges::LibusbFds {
+            device: Some(device.as_raw_fd()),
+            timers: vec![],
+            events: vec![],
+        };
+        if usbsas_privileges::dev2scsi::drop_priv(fd_read, fd_write, libusb_fds).is_err() {
+            return 1;
+        }
+        clear_halt(other.as_raw_fd(), 0x81);
+        0
+    });
+    assert!(libc::WIFSIGNALED(status), "dev2scsi not killed");
+    assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+}
@@ -1,10 +1,14 @@
 //! Helper functions for usbsas processes spawning
 
+pub mod sandbox;
+
 use nix::{
     self,
     fcntl::{FcntlArg, FdFlag},
     unistd,
 };
+pub use nix::{sys::wait::WaitStatus, unistd::Pid};
+use sandbox::Sandbox;
 use std::{io, os::unix::io::RawFd};
 use thiserror::Error;
 use usbsas_comm::Comm;
@@ -15,6 +19,8 @@ pub enum Error {
     IO(#[from] io::Error),
     #[error("errno error")]
     Errno(#[from] nix::errno::Errno),
+    #[error("privileges: {0}")]
+    Privileges(#[from] usbsas_privileges::Error),
     #[error("{0}")]
     Error(String),
     #[error("Spawn error")]
@@ -33,6 +39,7 @@ pub trait UsbsasProcess {
 pub struct UsbsasChildSpawner {
     args: Option<Vec<String>>,
     wait_on_startup: bool,
+    sandbox: Sandbox,
 }
 
 impl UsbsasChildSpawner {
@@ -40,6 +47,7 @@ impl UsbsasChildSpawner {
         Self {
             args: None,
             wait_on_startup: false,
+            sandbox: Sandbox::default(),
         }
     }
 
@@ -58,6 +66,45 @@ impl UsbsasChildSpawner {
         self
     }
 
+    /// Path the child can read in its sandbox
+    pub fn read_path(mut self, path: &str) -> Self {
+        self.sandbox.read_paths.push(path.into());
+        self
+    }
+
+    /// Path the child can read and write in its sandbox
+    pub fn write_path(mut self, path: &str) -> Self {
+        self.sandbox.write_paths.push(path.into());
+        self
+    }
+
+    /// The child (or one of its own children) opens USB devices
+    pub fn usb(mut self) -> Self {
+        self.sandbox.usb = true;
+        self
+    }
+
+    /// The child keeps the network (remote servers, or netlink for USB
+    /// hotplug events) and can read the system files needed to use it
+    pub fn network(mut self) -> Self {
+        self.sandbox.network = true;
+        self
+    }
+
+    /// The child listens to USB hotplug events, it keeps the network and user
+    /// namespaces of the parent and is only confined by landlock
+    pub fn hotplug(mut self) -> Self {
+        self.sandbox.hotplug = true;
+        self
+    }
+
+    /// The child keeps the file system of the parent (for commands executed by
+    /// cmdexec)
+    pub fn host_fs(mut self) -> Self {
+        self.sandbox.host_fs = true;
+        self
+    }
+
     pub fn spawn<T: UsbsasProcess, R>(self) -> Result<UsbsasChild<R>> {
         let (child_to_parent_rd, child_to_parent_wr) = unistd::pipe()?;
         let (parent_to_child_rd, parent_to_child_wr) = unistd::pipe()?;
@@ -74,6 +121,14 @@ impl UsbsasChildSpawner {
             Ok(unistd::ForkResult::Child) => {
                 unistd::close(child_to_parent_rd)?;
                 unistd::close(parent_to_child_wr)?;
+                set_process_name::<T>();
+                if let Err(err) = self.sandbox.enter(
+                    std::any::type_name::<T>(),
+                    &[parent_to_child_rd, child_to_parent_wr],
+                ) {
+                    log::error!("Couldn't confine child: {}", err);
+                    std::process::exit(1);
+                }
                 T::spawn(parent_to_child_rd, child_to_parent_wr, self.args)
                     .map_err(|err| Error::Error(format!("{}", err)))?;
                 log::debug!("Child {} exiting", std::any::type_name::<T>());
@@ -112,12 +167,27 @@ pub struct UsbsasChild<R> {
 }
 
 impl<R> UsbsasChild<R> {
-    pub fn wait(&self) -> Result<()> {
-        if let Err(err) = nix::sys::wait::waitpid(self.child, None) {
-            log::error!("Couldn't wait child {}: {}", self.child, err);
-            return Err(Error::Error("waitpid() error".to_string()));
+    pub fn wait(&self) -> Result<WaitStatus> {
+        match nix::sys::wait::waitpid(self.child, None) {
+            Ok(status) => Ok(status),
+            Err(err) => {
+                log::error!("Couldn't wait child {}: {}", self.child, err);
+                Err(Error::Error("waitpid() error".to_string()))
+            }
         }
-        Ok(())
+    }
+}
+
+/// Name the child after its process (e.g. `files2fs`), as shown by `ps` and
+/// in the logs of the kernel
+fn set_process_name<T>() {
+    let name = std::any::type_name::<T>()
+        .rsplit("::")
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if let Ok(name) = std::ffi::CString::new(name) {
+        unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) };
     }
 }
 
@@ -0,0 +1,449 @@
+//! Confinement of the children, in addition to their seccomp filters: each
+//! child gets its own user, mount, IPC and PID (and, unless it needs it,
+//! network) namespaces, an empty root in which only the paths it uses are
+//! mounted, and landlock rules limiting it to those paths.
+//!
+//! Kernels may lack some of these features (or forbid unprivileged user
+//! namespaces), the child is then only partially confined unless the sandbox
+//! is required.
+
+use crate::{Error, Result};
+use nix::{
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::{
+        statvfs::{statvfs, FsFlags},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{self, chdir, pivot_root, ForkResult},
+};
+use std::{
+    env, fs,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Mount point of the new root, before pivoting to it
+const NEW_ROOT: &str = "/tmp";
+const OLD_ROOT: &str = ".oldroot";
+
+// Paths every child can use
+const COMMON_WRITE_PATHS: &[&str] = &["/dev/null"];
+// libusb enumerates devices in sysfs and asks udev about them
+const USB_READ_PATHS: &[&str] = &["/sys", "/run/udev"];
+const USB_WRITE_PATHS: &[&str] = &["/dev/bus/usb"];
+// Name resolution, TLS and Kerberos configuration and libraries
+const NET_READ_PATHS: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/tmp"];
+
+// Inherited by the children (and their own children) at fork
+static REQUIRED: AtomicBool = AtomicBool::new(false);
+// Set once the root is the sandbox's one. Landlock forbids mounting, children
+// spawned from a sandbox stay in the root of their parent (which contains
+// their paths too).
+static ROOT_CONFINED: AtomicBool = AtomicBool::new(false);
+
+/// Refuse to start children that can't be fully confined
+pub fn set_required(required: bool) {
+    REQUIRED.store(required, Ordering::Relaxed);
+}
+
+#[derive(Default)]
+pub(crate) struct Sandbox {
+    pub(crate) read_paths: Vec<String>,
+    pub(crate) write_paths: Vec<String>,
+    pub(crate) usb: bool,
+    pub(crate) network: bool,
+    pub(crate) hotplug: bool,
+    pub(crate) host_fs: bool,
+}
+
+impl Sandbox {
+    /// Confine the calling (freshly forked) child. `comm_fds` are closed by the
+    /// process left outside of the PID namespace, which only waits for the
+    /// child.
+    pub(crate) fn enter(&self, name: &str, comm_fds: &[RawFd]) -> Result<()> {
+        let cwd = env::current_dir()?;
+        let mut confined = true;
+
+        // Hotplug events are sent by root, whose id isn't mapped in a user
+        // namespace: libusb would ignore them. Without a user namespace, an
+        // unprivileged child can't create the other ones, it is only confined
+        // by landlock.
+        let namespaces = !self.hotplug && {
+            // Other namespaces can still be created without a user namespace
+            // if running as root
+            if !enter_user_namespace()? {
+                log::warn!("{}: couldn't create user namespace", name);
+                confined = false;
+            }
+            let mut flags =
+                CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWPID;
+            if !self.network {
+                flags |= CloneFlags::CLONE_NEWNET;
+            }
+            match unshare(flags) {
+                Ok(()) => {
+                    enter_pid_namespace(comm_fds)?;
+                    true
+                }
+                Err(err) => {
+                    log::warn!("{}: couldn't create namespaces: {}", name, err);
+                    confined = false;
+                    false
+                }
+            }
+        };
+
+        if !self.host_fs {
+            // Without a mount namespace, this would change the parent's mounts
+            if namespaces && !ROOT_CONFINED.load(Ordering::Relaxed) {
+                match self.setup_root(&cwd) {
+                    Ok(()) => ROOT_CONFINED.store(true, Ordering::Relaxed),
+                    Err(err) => {
+                        log::warn!("{}: couldn't set up root: {}", name, err);
+                        confined = false;
+                    }
+                }
+            }
+            let (read_paths, write_paths) = self.paths(&cwd);
+            let read_paths: Vec<&str> = read_paths.iter().map(String::as_str).collect();
+            let write_paths: Vec<&str> = write_paths.iter().map(String::as_str).collect();
+            match usbsas_privileges::landlock::restrict(&read_paths, &write_paths, None) {
+                Ok(usbsas_privileges::landlock::Status::Unsupported) => {
+                    log::warn!("{}: landlock not supported", name);
+                    confined = false;
+                }
+                Err(err) => {
+                    log::warn!("{}: couldn't apply landlock rules: {}", name, err);
+                    confined = false;
+                }
+                Ok(_) => (),
+            }
+        }
+
+        if !confined && REQUIRED.load(Ordering::Relaxed) {
+            return Err(Error::Error(format!("{}: sandbox required", name)));
+        }
+        Ok(())
+    }
+
+    /// Absolute paths the child can read and write
+    fn paths(&self, cwd: &Path) -> (Vec<String>, Vec<String>) {
+        let absolute = |path: &str| cwd.join(path).display().to_string();
+        let mut read_paths: Vec<String> = self.read_paths.iter().map(|p| absolute(p)).collect();
+        let mut write_paths: Vec<String> = self.write_paths.iter().map(|p| absolute(p)).collect();
+        read_paths.push("/proc".into());
+        write_paths.extend(COMMON_WRITE_PATHS.iter().map(|p| p.to_string()));
+        if self.usb {
+            read_paths.extend(USB_READ_PATHS.iter().map(|p| p.to_string()));
+            write_paths.extend(USB_WRITE_PATHS.iter().map(|p| p.to_string()));
+            // Devices of the mock build (tests) are files
+            for var in ["USBSAS_MOCK_IN_DEV", "USBSAS_MOCK_OUT_DEV"] {
+                if let Ok(path) = env::var(var) {
+                    write_paths.push(absolute(&path));
+                }
+            }
+        }
+        if self.network {
+            read_paths.extend(NET_READ_PATHS.iter().map(|p| p.to_string()));
+        }
+        (read_paths, write_paths)
+    }
+
+    /// Mount the paths of the child on a tmpfs and make it the root
+    fn setup_root(&self, cwd: &Path) -> Result<()> {
+        // Don't propagate anything to the parent's namespace
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )?;
+
+        // Open the sources before hiding them under the new root
+        let (read_paths, write_paths) = self.paths(cwd);
+        let mut sources = vec![];
+        for (path, writable) in read_paths
+            .iter()
+            .map(|path| (path, false))
+            .chain(write_paths.iter().map(|path| (path, true)))
+        {
+            match open_path(path) {
+                Ok(file) => sources.push((path, file, writable)),
+                // Missing paths (differing between distributions) are ignored
+                Err(_) => log::debug!("{} not mounted in sandbox", path),
+            }
+        }
+        // Mount parents before their children
+        sources.sort_by_key(|(path, _, _)| path.len());
+
+        mount(
+            Some("tmpfs"),
+            NEW_ROOT,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("mode=0755,size=1m"),
+        )?;
+        let result = (|| {
+            for (path, source, writable) in &sources {
+                let target = new_root_path(path);
+                if path.as_str() == "/proc" {
+                    fs::create_dir_all(&target)?;
+                    mount_proc(&target, source.as_raw_fd())?;
+                    continue;
+                }
+                bind(source.as_raw_fd(), &target, *writable)?;
+            }
+            fs::create_dir_all(new_root_path(&cwd.display().to_string()))?;
+            fs::create_dir_all(Path::new(NEW_ROOT).join(OLD_ROOT))?;
+            Ok::<(), Error>(())
+        })();
+        if let Err(err) = result {
+            let _ = umount2(NEW_ROOT, MntFlags::MNT_DETACH);
+            return Err(err);
+        }
+
+        chdir(NEW_ROOT)?;
+        pivot_root(".", OLD_ROOT)?;
+        chdir("/")?;
+        umount2(OLD_ROOT, MntFlags::MNT_DETACH)?;
+        fs::remove_dir(OLD_ROOT)?;
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REMOUNT
+                | MsFlags::MS_BIND
+                | MsFlags::MS_RDONLY
+                | MsFlags::MS_NOSUID
+                | MsFlags::MS_NODEV,
+            None::<&str>,
+        )?;
+        // Relative paths given to the child remain valid
+        chdir(cwd)?;
+        Ok(())
+    }
+}
+
+/// Enter a user namespace where the current user and group are mapped to
+/// themselves, returns false if it isn't supported. The child is given every
+/// capability in it, which are needed to set up the other namespaces. Failing
+/// to map the ids is an error: the child would run as an unknown user.
+fn enter_user_namespace() -> Result<bool> {
+    let uid = unistd::getuid();
+    let gid = unistd::getgid();
+    if unshare(CloneFlags::CLONE_NEWUSER).is_err() {
+        return Ok(false);
+    }
+    fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+    Ok(true)
+}
+
+/// Only children of the process calling unshare() are in the new PID
+/// namespace: fork again and wait for the child from outside of it
+fn enter_pid_namespace(comm_fds: &[RawFd]) -> Result<()> {
+    match unsafe { unistd::fork() }? {
+        ForkResult::Child => {
+            // Don't outlive the waiting process
+            if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } < 0 {
+                return Err(Error::Error("prctl(PR_SET_PDEATHSIG) failed".into()));
+            }
+            Ok(())
+        }
+        ForkResult::Parent { child } => {
+            for fd in comm_fds {
+                let _ = unistd::close(*fd);
+            }
+            exit_as(waitpid(child, None))
+        }
+    }
+}
+
+/// Exit the way the waited child did, with the same code or killed by the
+/// same signal, so that the parent sees the status of the sandboxed child
+fn exit_as(status: nix::Result<WaitStatus>) -> ! {
+    match status {
+        Ok(WaitStatus::Exited(_, code)) => unsafe { libc::_exit(code) },
+        Ok(WaitStatus::Signaled(_, signal, _)) => {
+            let signal = signal as libc::c_int;
+            // The child dumped its core if it had to, not this process
+            let rlimit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe {
+                libc::setrlimit(libc::RLIMIT_CORE, &rlimit);
+                libc::signal(signal, libc::SIG_DFL);
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, signal);
+                libc::sigprocmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+                libc::raise(signal);
+                // Only reached if the signal doesn't terminate processes
+                libc::_exit(128 + signal)
+            }
+        }
+        _ => unsafe { libc::_exit(1) },
+    }
+}
+
+fn open_path(path: &str) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(path)
+}
+
+fn new_root_path(path: &str) -> PathBuf {
+    Path::new(NEW_ROOT).join(path.trim_start_matches('/'))
+}
+
+fn fd_path(fd: RawFd) -> String {
+    format!("/proc/self/fd/{}", fd)
+}
+
+/// Bind mount `source` on `target`, read only unless `writable`
+fn bind(source: RawFd, target: &Path, writable: bool) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::metadata(fd_path(source))?.is_dir() {
+        fs::create_dir_all(target)?;
+    } else if !target.exists() {
+        fs::File::create(target)?;
+    }
+    mount(
+        Some(fd_path(source).as_str()),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    if writable {
+        return Ok(());
+    }
+    // Flags of the original mount are locked and must be kept
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+    let mount_flags = statvfs(target)?.flags();
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if mount_flags.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(None::<&str>, target, None::<&str>, flags, None::<&str>)?;
+    Ok(())
+}
+
+/// Mount a procfs of the new PID namespace, or bind the current one if that
+/// isn't permitted (for instance when some of its files are hidden)
+fn mount_proc(target: &Path, source: RawFd) -> Result<()> {
+    if mount(
+        Some("proc"),
+        target,
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )
+    .is_ok()
+    {
+        return Ok(());
+    }
+    mount(
+        Some(fd_path(source).as_str()),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Exit code of the sandboxed child when it can't be fully confined
+    const UNSUPPORTED: i32 = 77;
+
+    /// Run `check` in a forked and sandboxed child, returns its wait status or
+    /// None if the kernel can't confine it
+    fn run_sandboxed(check: impl FnOnce() -> i32) -> Option<WaitStatus> {
+        match unsafe { unistd::fork() }.unwrap() {
+            ForkResult::Child => {
+                set_required(true);
+                let code = match Sandbox::default().enter("test", &[]) {
+                    Ok(()) => check(),
+                    Err(_) => UNSUPPORTED,
+                };
+                unsafe { libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+                WaitStatus::Exited(_, UNSUPPORTED) => {
+                    eprintln!("sandbox not supported, not checked");
+                    None
+                }
+                status => Some(status),
+            },
+        }
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let host_pid = std::process::id();
+        let status = run_sandboxed(|| {
+            // Alone in its PID namespace
+            if unistd::getpid().as_raw() != 1 || Path::new(&format!("/proc/{}", host_pid)).exists()
+            {
+                return 1;
+            }
+            // Only the paths of the child are in its root
+            if Path::new("/etc").exists() || Path::new("/usr").exists() {
+                return 2;
+            }
+            if !Path::new("/dev/null").exists() {
+                return 3;
+            }
+            0
+        });
+        if let Some(status) = status {
+            assert!(matches!(status, WaitStatus::Exited(_, 0)), "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn test_exit_as() {
+        for (signal, code) in [(None, 3), (Some(libc::SIGTERM), 0)] {
+            let status = match unsafe { unistd::fork() }.unwrap() {
+                ForkResult::Child => match unsafe { unistd::fork() }.unwrap() {
+                    ForkResult::Child => {
+                        if let Some(signal) = signal {
+                            unsafe { libc::raise(signal) };
+                        }
+                        unsafe { libc::_exit(code) }
+                    }
+                    ForkResult::Parent { child } => exit_as(waitpid(child, None)),
+                },
+                ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+            };
+            match (signal, status) {
+                (None, WaitStatus::Exited(_, status_code)) => assert_eq!(status_code, code),
+                (Some(signal), WaitStatus::Signaled(_, status_signal, _)) => {
+                    assert_eq!(status_signal as libc::c_int, signal)
+                }
+                _ => panic!("unexpected status {:?}", status),
+            }
+        }
+    }
+}
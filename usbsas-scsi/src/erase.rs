@@ -0,0 +1,385 @@
+//! Device-side erase commands: SANITIZE, ATA security erase (through the SCSI
+//! / ATA Translation pass-through) and UNMAP.
+//!
+//! Devices don't all advertise what they support the same way, each method
+//! is probed before being used and `EraseSupport` lists what was found.
+
+use crate::{sense_key, ScsiUsb, ASC_NOT_READY, SCSI_INQUIRY, SENSE_KEY_NOT_READY};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::{debug, error};
+use rusb::UsbContext;
+use std::{
+    io::{self, ErrorKind},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SCSI_UNMAP: u8 = 0x42;
+const SCSI_SANITIZE: u8 = 0x48;
+const SCSI_ATA_PASS_THROUGH_16: u8 = 0x85;
+const SCSI_MAINTENANCE_IN: u8 = 0xA3;
+const SA_REPORT_SUPPORTED_OPCODES: u8 = 0x0C;
+const SA_SANITIZE_BLOCK_ERASE: u8 = 0x02;
+const SA_SANITIZE_CRYPTO_ERASE: u8 = 0x03;
+const SANITIZE_IMMED: u8 = 0x20;
+const VPD_BLOCK_LIMITS: u8 = 0xB0;
+
+const ATA_IDENTIFY_DEVICE: u8 = 0xEC;
+const ATA_SECURITY_SET_PASSWORD: u8 = 0xF1;
+const ATA_SECURITY_ERASE_PREPARE: u8 = 0xF3;
+const ATA_SECURITY_ERASE_UNIT: u8 = 0xF4;
+const ATA_SECURITY_DISABLE_PASSWORD: u8 = 0xF6;
+// ATA PASS-THROUGH protocols
+const ATA_PROTO_NON_DATA: u8 = 3;
+const ATA_PROTO_PIO_IN: u8 = 4;
+const ATA_PROTO_PIO_OUT: u8 = 5;
+// Temporary user password set to be able to erase, removed by the erase
+const ATA_ERASE_PASSWORD: &[u8] = b"usbsas";
+// Used when the drive doesn't give a (short enough) estimation of the erase
+// time
+const ATA_ERASE_DEFAULT_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+const ATA_ERASE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+const ASCQ_SANITIZE_IN_PROGRESS: u8 = 0x1B;
+const SANITIZE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Erase methods supported by a device
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EraseSupport {
+    pub sanitize_crypto_erase: bool,
+    pub sanitize_block_erase: bool,
+    /// Normal erase time estimation of the drive, if ATA security erase can
+    /// be used
+    pub ata_security_erase: Option<Duration>,
+    /// Max number of blocks of an UNMAP command, if supported and if unmapped
+    /// blocks read as zeros
+    pub unmap: Option<u32>,
+}
+
+// Max LBA count of UNMAP commands, from READ CAPACITY(16) data and the block
+// limits VPD page. Only devices with logical block provisioning management
+// enabled (LBPME) whose unmapped blocks read as zeros (LBPRZ) are considered,
+// others may keep returning the old data.
+fn unmap_support(capacity: &[u8], limits: &[u8]) -> Option<u32> {
+    if capacity.len() < 15 || capacity[14] & 0xc0 != 0xc0 || limits.len() < 28 {
+        return None;
+    }
+    let max_lba_count = BigEndian::read_u32(&limits[20..24]);
+    let max_descriptors = BigEndian::read_u32(&limits[24..28]);
+    if limits[1] == VPD_BLOCK_LIMITS && max_lba_count != 0 && max_descriptors != 0 {
+        Some(max_lba_count)
+    } else {
+        None
+    }
+}
+
+impl<T: UsbContext> ScsiUsb<T> {
+    fn scsi_inquiry_vpd(&mut self, page: u8, buffer: &mut [u8]) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_INQUIRY;
+        command_data[1] = 1;
+        command_data[2] = page;
+        BigEndian::write_u16(&mut command_data[3..5], buffer.len() as u16);
+        self.bulk_transfer_read(command_data, buffer)
+    }
+
+    // REPORT SUPPORTED OPERATION CODES for a single command
+    fn supports_command(&mut self, opcode: u8, service_action: u8) -> bool {
+        let mut command_data: [u8; 16] = [0; 16];
+        let mut buffer: [u8; 20] = [0; 20];
+        command_data[0] = SCSI_MAINTENANCE_IN;
+        command_data[1] = SA_REPORT_SUPPORTED_OPCODES;
+        // Reporting options: opcode and service action
+        command_data[2] = 0x03;
+        command_data[3] = opcode;
+        BigEndian::write_u16(&mut command_data[4..6], service_action as u16);
+        BigEndian::write_u32(&mut command_data[6..10], buffer.len() as u32);
+        match self.bulk_transfer_read(command_data, &mut buffer) {
+            // Supported in conformance with a SCSI standard or in a vendor
+            // specific manner
+            Ok(0) => matches!(buffer[1] & 0x7, 0x3 | 0x5),
+            _ => false,
+        }
+    }
+
+    fn ata_pass_through(
+        &mut self,
+        command: u8,
+        protocol: u8,
+        buffer: &mut [u8],
+    ) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_ATA_PASS_THROUGH_16;
+        command_data[1] = protocol << 1;
+        command_data[2] = match protocol {
+            // Transfer length in the sector count field, in blocks
+            ATA_PROTO_PIO_IN => 0x0E,
+            ATA_PROTO_PIO_OUT => 0x06,
+            _ => 0x00,
+        };
+        command_data[6] = (buffer.len() / 512) as u8;
+        command_data[14] = command;
+        if protocol == ATA_PROTO_PIO_OUT {
+            self.bulk_transfer_write(command_data, buffer)
+        } else {
+            self.bulk_transfer_read(command_data, buffer)
+        }
+    }
+
+    // Security word of IDENTIFY DEVICE and erase time estimation, if the
+    // security feature set is supported
+    fn ata_security_status(&mut self) -> Option<(u16, Duration)> {
+        let mut buffer = [0u8; 512];
+        match self.ata_pass_through(ATA_IDENTIFY_DEVICE, ATA_PROTO_PIO_IN, &mut buffer) {
+            Ok(0) => (),
+            _ => return None,
+        }
+        let word = |index: usize| LittleEndian::read_u16(&buffer[index * 2..index * 2 + 2]);
+        // Word 82 bit 1: security feature set supported
+        if word(82) & 0x2 == 0 {
+            return None;
+        }
+        // Word 89: normal erase time, in units of 2 minutes
+        // (0: not given, 0xff: more than 508 minutes)
+        let erase_time = match word(89) & 0xff {
+            0 | 0xff => ATA_ERASE_DEFAULT_TIMEOUT,
+            units => Duration::from_secs(u64::from(units) * 2 * 60),
+        };
+        Some((word(128), erase_time))
+    }
+
+    /// Probe the erase methods supported by the device
+    pub fn erase_support(&mut self) -> EraseSupport {
+        let mut support = EraseSupport {
+            sanitize_crypto_erase: self.supports_command(SCSI_SANITIZE, SA_SANITIZE_CRYPTO_ERASE),
+            sanitize_block_erase: self.supports_command(SCSI_SANITIZE, SA_SANITIZE_BLOCK_ERASE),
+            ..Default::default()
+        };
+
+        // Security supported (bit 0), not enabled (bit 1), locked (bit 2) or
+        // frozen (bit 3). Most BIOSes freeze the security of internal drives,
+        // external ones usually aren't.
+        if let Some((security, erase_time)) = self.ata_security_status() {
+            debug!("ata security: 0x{:x}", security);
+            if security & 0xf == 0x1 {
+                support.ata_security_erase = Some(erase_time);
+            }
+        }
+
+        let mut capacity: [u8; 32] = [0; 32];
+        if let Ok(0) = self.scsi_read_capacity_16(&mut capacity) {
+            let mut limits: [u8; 64] = [0; 64];
+            if let Ok(0) = self.scsi_inquiry_vpd(VPD_BLOCK_LIMITS, &mut limits) {
+                support.unmap = unmap_support(&capacity, &limits);
+            }
+        }
+        debug!("erase support: {:?}", support);
+        support
+    }
+
+    /// Start a SANITIZE (block or crypto erase) in the background, its
+    /// progress is polled with `sanitize_progress()`
+    pub fn sanitize(&mut self, crypto: bool) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_SANITIZE;
+        command_data[1] = SANITIZE_IMMED
+            | if crypto {
+                SA_SANITIZE_CRYPTO_ERASE
+            } else {
+                SA_SANITIZE_BLOCK_ERASE
+            };
+        // No parameter list
+        self.bulk_transfer_write(command_data, &mut [])
+    }
+
+    /// Progress of a running sanitize (out of 0x10000), `None` once done
+    pub fn sanitize_progress(&mut self) -> Result<Option<u32>, io::Error> {
+        match self.scsi_test_unit_ready(&mut [])? {
+            0 => return Ok(None),
+            status => debug!("test unit ready status: {}", status),
+        }
+        let mut sense: [u8; 18] = [0; 18];
+        self.scsi_request_sense(&mut sense)?;
+        match sense_key(&sense) {
+            Some((SENSE_KEY_NOT_READY, ASC_NOT_READY))
+                if sense[13] == ASCQ_SANITIZE_IN_PROGRESS =>
+            {
+                // Progress indication in the sense key specific bytes (fixed
+                // format only)
+                if sense[0] & 0x7f == 0x70 && sense[15] & 0x80 != 0 {
+                    Ok(Some(u32::from(BigEndian::read_u16(&sense[16..18]))))
+                } else {
+                    Ok(Some(0))
+                }
+            }
+            Some((SENSE_KEY_NOT_READY, ASC_NOT_READY)) => Ok(Some(0)),
+            sense => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("sanitize failed, sense: {:?}", sense),
+            )),
+        }
+    }
+
+    /// Run a sanitize and wait for its completion, `progress` is called with
+    /// the completion out of 0x10000
+    pub fn sanitize_wait(
+        &mut self,
+        crypto: bool,
+        progress: &mut dyn FnMut(u64, u64) -> Result<(), io::Error>,
+    ) -> Result<(), io::Error> {
+        if self.sanitize(crypto)? != 0 {
+            return Err(io::Error::new(ErrorKind::Other, "sanitize refused"));
+        }
+        while let Some(done) = self.sanitize_progress()? {
+            progress(u64::from(done), 0x10000)?;
+            thread::sleep(SANITIZE_POLL_INTERVAL);
+        }
+        progress(0x10000, 0x10000)
+    }
+
+    // Security password data of SET PASSWORD / ERASE UNIT / DISABLE PASSWORD:
+    // user password, high security level, normal erase
+    fn ata_password_buffer() -> [u8; 512] {
+        let mut buffer = [0u8; 512];
+        buffer[2..2 + ATA_ERASE_PASSWORD.len()].copy_from_slice(ATA_ERASE_PASSWORD);
+        buffer
+    }
+
+    /// ATA security erase: set a temporary user password and erase the unit,
+    /// which also removes the password. The command only completes once the
+    /// drive is erased, `erase_time` is its estimation.
+    pub fn ata_security_erase(&mut self, erase_time: Duration) -> Result<(), io::Error> {
+        let mut buffer = Self::ata_password_buffer();
+        if self.ata_pass_through(ATA_SECURITY_SET_PASSWORD, ATA_PROTO_PIO_OUT, &mut buffer)? != 0 {
+            return Err(io::Error::new(ErrorKind::Other, "ata set password failed"));
+        }
+        let result = match self.ata_erase_unit(erase_time) {
+            // The erase may still be running, the password can't be removed
+            // before it's done
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                error!("ata erase unit timed out, waiting for its completion");
+                self.ata_erase_wait(erase_time)
+            }
+            result => result,
+        };
+        if let Err(err) = result {
+            // Don't leave the drive with a password
+            let mut buffer = Self::ata_password_buffer();
+            match self.ata_pass_through(
+                ATA_SECURITY_DISABLE_PASSWORD,
+                ATA_PROTO_PIO_OUT,
+                &mut buffer,
+            ) {
+                Ok(0) => return Err(err),
+                Ok(_) | Err(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "{}, couldn't remove the temporary ata password: \
+                             the device is still security locked with the user password {:?}",
+                            err,
+                            String::from_utf8_lossy(ATA_ERASE_PASSWORD)
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Poll the security state of the drive until a timed out erase completes,
+    // for at least the estimated erase time. The drive doesn't answer while
+    // erasing, a successful erase disables security.
+    fn ata_erase_wait(&mut self, erase_time: Duration) -> Result<(), io::Error> {
+        let deadline = Instant::now() + erase_time;
+        while Instant::now() < deadline {
+            thread::sleep(ATA_ERASE_POLL_INTERVAL);
+            match self.ata_security_status() {
+                Some((security, _)) if security & 0x2 == 0 => return Ok(()),
+                Some((security, _)) => {
+                    debug!("ata security: 0x{:x}", security);
+                    return Err(io::Error::new(ErrorKind::Other, "ata erase unit failed"));
+                }
+                None => continue,
+            }
+        }
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "ata erase unit didn't complete",
+        ))
+    }
+
+    fn ata_erase_unit(&mut self, erase_time: Duration) -> Result<(), io::Error> {
+        if self.ata_pass_through(ATA_SECURITY_ERASE_PREPARE, ATA_PROTO_NON_DATA, &mut [])? != 0 {
+            return Err(io::Error::new(ErrorKind::Other, "ata erase prepare failed"));
+        }
+        let mut buffer = Self::ata_password_buffer();
+        // Wait for the whole erase (with some margin) instead of the usual
+        // command timeout
+        let timeout = self.timeout;
+        self.timeout = erase_time + erase_time / 2;
+        let result = self.ata_pass_through(ATA_SECURITY_ERASE_UNIT, ATA_PROTO_PIO_OUT, &mut buffer);
+        self.timeout = timeout;
+        match result? {
+            0 => Ok(()),
+            _ => Err(io::Error::new(ErrorKind::Other, "ata erase unit failed")),
+        }
+    }
+
+    /// UNMAP `count` blocks at `offset`
+    pub fn unmap(&mut self, offset: u64, count: u32) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        // Header and a single block descriptor
+        let mut parameters: [u8; 24] = [0; 24];
+        BigEndian::write_u16(&mut parameters[0..2], 22);
+        BigEndian::write_u16(&mut parameters[2..4], 16);
+        BigEndian::write_u64(&mut parameters[8..16], offset);
+        BigEndian::write_u32(&mut parameters[16..20], count);
+        command_data[0] = SCSI_UNMAP;
+        BigEndian::write_u16(&mut command_data[7..9], parameters.len() as u16);
+        self.bulk_transfer_write(command_data, &mut parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_limits(max_lba_count: u32, max_descriptors: u32) -> [u8; 64] {
+        let mut limits = [0u8; 64];
+        limits[1] = VPD_BLOCK_LIMITS;
+        BigEndian::write_u32(&mut limits[20..24], max_lba_count);
+        BigEndian::write_u32(&mut limits[24..28], max_descriptors);
+        limits
+    }
+
+    #[test]
+    fn test_unmap_support() {
+        let mut capacity = [0u8; 32];
+        let limits = block_limits(0x10000, 1);
+
+        // LBPME and LBPRZ
+        capacity[14] = 0xc0;
+        assert_eq!(unmap_support(&capacity, &limits), Some(0x10000));
+
+        // Unmapped blocks may not read as zeros
+        capacity[14] = 0x80;
+        assert_eq!(unmap_support(&capacity, &limits), None);
+
+        // No logical block provisioning
+        capacity[14] = 0x40;
+        assert_eq!(unmap_support(&capacity, &limits), None);
+
+        // Bad block limits page
+        capacity[14] = 0xc0;
+        assert_eq!(unmap_support(&capacity, &block_limits(0, 1)), None);
+        assert_eq!(unmap_support(&capacity, &block_limits(0x10000, 0)), None);
+        let mut limits = block_limits(0x10000, 1);
+        limits[1] = 0x80;
+        assert_eq!(unmap_support(&capacity, &limits), None);
+        assert_eq!(
+            unmap_support(&capacity[..8], &block_limits(0x10000, 1)),
+            None
+        );
+    }
+}
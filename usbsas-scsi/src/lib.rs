@@ -1,4 +1,5 @@
-//! SCSI implementation
+//! SCSI implementation, over the Bulk-Only Transport (BOT) or USB Attached
+//! SCSI (UAS).
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use log::{debug, error};
@@ -10,6 +11,15 @@ use std::{
     {thread, time},
 };
 
+mod erase;
+mod uas;
+pub use erase::EraseSupport;
+pub use uas::{UasPipes, UasTransport};
+
+/// Protocol codes of mass storage interfaces
+pub const USB_PR_BULK: u8 = 0x50;
+pub const USB_PR_UAS: u8 = 0x62;
+
 const LIBUSB_ENDPOINT_IN: u8 = 0x80;
 const LIBUSB_ENDPOINT_OUT: u8 = 0x00;
 
@@ -17,18 +27,67 @@ const SCSI_TEST_UNIT_READY: u8 = 0x00;
 const SCSI_READ_CAPACITY_10: u8 = 0x25;
 const SCSI_READ_10: u8 = 0x28;
 const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_READ_16: u8 = 0x88;
+const SCSI_WRITE_16: u8 = 0x8A;
+const SCSI_SERVICE_ACTION_IN_16: u8 = 0x9E;
+const SA_READ_CAPACITY_16: u8 = 0x10;
 const SCSI_INQUIRY: u8 = 0x12;
 const SCSI_REQUEST_SENSE: u8 = 0x3;
-const SCSI_MAX_READ_SECTORS: u64 = 0x800;
+// Max size of a single read command, in bytes
+pub const SCSI_MAX_READ_SIZE: u64 = 0x100000;
+// Attempts of a read before splitting it, or giving up on a single sector
+const SCSI_READ_ATTEMPTS: usize = 3;
+
+const USB_BOT_RESET: u8 = 0xff;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_STATUS_PHASE_ERROR: u8 = 2;
+
+const SENSE_KEY_NOT_READY: u8 = 0x2;
+const SENSE_KEY_UNIT_ATTENTION: u8 = 0x6;
+const ASC_NOT_READY: u8 = 0x4;
+const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3a;
+
+/// USB transport of the SCSI commands
+pub enum Transport {
+    /// CBW, data and CSW on a pair of bulk endpoints
+    Bot {
+        endpoint_in: u8,
+        endpoint_out: u8,
+    },
+    Uas(UasTransport),
+}
+
+fn usb_error(err: rusb::Error) -> io::Error {
+    let kind = match err {
+        rusb::Error::Pipe => ErrorKind::BrokenPipe,
+        rusb::Error::NoDevice => ErrorKind::NotConnected,
+        rusb::Error::Timeout => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+/// Sense key and additional sense code of fixed or descriptor format sense
+/// data
+fn sense_key(sense: &[u8]) -> Option<(u8, u8)> {
+    match sense.first()? & 0x7f {
+        0x70 | 0x71 if sense.len() > 12 => Some((sense[2] & 0xf, sense[12])),
+        0x72 | 0x73 if sense.len() > 2 => Some((sense[1] & 0xf, sense[2])),
+        _ => None,
+    }
+}
 
 //#[derive(Debug, Default)]
 pub struct ScsiUsb<T: UsbContext> {
     pub handle: DeviceHandle<T>,
+    pub interface_num: u8,
     pub tag: u32,
     pub lun: Option<u8>,
-    pub endpoint_in: u8,
-    pub endpoint_out: u8,
+    pub transport: Transport,
     pub timeout: Duration,
+    /// Whether the device has LBAs that don't fit in 32 bits and must be
+    /// accessed with 16 bytes READ / WRITE commands
+    pub long_lba: bool,
 }
 
 impl<T: UsbContext> ScsiUsb<T> {
@@ -42,16 +101,58 @@ impl<T: UsbContext> ScsiUsb<T> {
     ) -> ScsiUsb<T> {
         let mut scsi = ScsiUsb {
             handle,
+            interface_num,
             tag: 1,
             lun: None,
-            endpoint_in,
-            endpoint_out,
+            transport: Transport::Bot {
+                endpoint_in,
+                endpoint_out,
+            },
             timeout,
+            long_lba: false,
         };
         scsi.set_active_interface(interface_num, interface_alt);
         scsi
     }
 
+    pub fn new_uas(
+        handle: DeviceHandle<T>,
+        interface_num: u8,
+        interface_alt: u8,
+        pipes: UasPipes,
+        timeout: Duration,
+    ) -> Result<ScsiUsb<T>, io::Error> {
+        let mut scsi = ScsiUsb {
+            handle,
+            interface_num,
+            tag: 1,
+            lun: None,
+            transport: Transport::Bot {
+                endpoint_in: 0,
+                endpoint_out: 0,
+            },
+            timeout,
+            long_lba: false,
+        };
+        // Streams can only be allocated once the UAS setting is active
+        scsi.set_active_interface(interface_num, interface_alt);
+        scsi.transport = Transport::Uas(UasTransport::new(&scsi.handle, pipes)?);
+        Ok(scsi)
+    }
+
+    fn bot_endpoints(&self) -> Result<(u8, u8), io::Error> {
+        match self.transport {
+            Transport::Bot {
+                endpoint_in,
+                endpoint_out,
+            } => Ok((endpoint_in, endpoint_out)),
+            Transport::Uas(_) => Err(io::Error::new(
+                ErrorKind::Other,
+                "not a bulk-only transport",
+            )),
+        }
+    }
+
     fn command_to_size(&self, cmd: u8) -> Option<u8> {
         match cmd {
             0x00..=0x1F => Some(6),
@@ -91,24 +192,55 @@ impl<T: UsbContext> ScsiUsb<T> {
 
     fn ack_data(&mut self) -> Result<u8, io::Error> {
         let mut csw: [u8; 13] = [0; 13];
-        if self
-            .handle
-            .read_bulk(self.endpoint_in, &mut csw, self.timeout)
-            .is_err()
-        {
-            return Err(io::Error::new(ErrorKind::Other, "Usb ack error"));
+        let (endpoint_in, _) = self.bot_endpoints()?;
+        let mut result = self.handle.read_bulk(endpoint_in, &mut csw, self.timeout);
+        // A stalled CSW is read again once the halt is cleared
+        if let Err(rusb::Error::Pipe) = result {
+            self.handle.clear_halt(endpoint_in).map_err(usb_error)?;
+            result = self.handle.read_bulk(endpoint_in, &mut csw, self.timeout);
         }
+        let tag = self.tag;
         self.tag += 1;
+        match result {
+            Ok(13) => (),
+            Ok(_) | Err(_) => return Err(io::Error::new(ErrorKind::Other, "Usb ack error")),
+        }
+        if LittleEndian::read_u32(&csw[0..4]) != CSW_SIGNATURE
+            || LittleEndian::read_u32(&csw[4..8]) != tag
+        {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid CSW"));
+        }
+        if csw[12] == CSW_STATUS_PHASE_ERROR {
+            return Err(io::Error::new(ErrorKind::Other, "CSW phase error"));
+        }
         Ok(csw[12])
     }
 
+    /// Bulk-Only reset recovery: reset the mass storage interface and clear
+    /// the halt of both bulk endpoints
+    pub fn reset_recovery(&mut self) -> Result<(), io::Error> {
+        let (endpoint_in, endpoint_out) = self.bot_endpoints()?;
+        debug!("bulk-only reset recovery");
+        self.handle
+            .write_control(
+                request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+                USB_BOT_RESET,
+                0,
+                self.interface_num as u16,
+                &[],
+                self.timeout,
+            )
+            .map_err(usb_error)?;
+        self.handle.clear_halt(endpoint_in).map_err(usb_error)?;
+        self.handle.clear_halt(endpoint_out).map_err(usb_error)?;
+        Ok(())
+    }
+
     fn read_bulk(&mut self, data: &mut [u8]) -> Result<(), io::Error> {
+        let (endpoint_in, _) = self.bot_endpoints()?;
         let mut buffer = data;
         while !buffer.is_empty() {
-            let size = match self
-                .handle
-                .read_bulk(self.endpoint_in, buffer, self.timeout)
-            {
+            let size = match self.handle.read_bulk(endpoint_in, buffer, self.timeout) {
                 Ok(size) => {
                     if size == 0 {
                         return Err(io::Error::new(
@@ -125,7 +257,7 @@ impl<T: UsbContext> ScsiUsb<T> {
                 }
                 Err(err) => {
                     return Err(io::Error::new(
-                        ErrorKind::Other,
+                        usb_error(err).kind(),
                         format!("Usb read_bulk error: {}", err),
                     ));
                 }
@@ -136,12 +268,10 @@ impl<T: UsbContext> ScsiUsb<T> {
     }
 
     fn write_bulk(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let (_, endpoint_out) = self.bot_endpoints()?;
         let mut buffer = data;
         while !buffer.is_empty() {
-            let size = match self
-                .handle
-                .write_bulk(self.endpoint_out, buffer, self.timeout)
-            {
+            let size = match self.handle.write_bulk(endpoint_out, buffer, self.timeout) {
                 Ok(size) => {
                     if size == 0 {
                         return Err(io::Error::new(
@@ -158,7 +288,7 @@ impl<T: UsbContext> ScsiUsb<T> {
                 }
                 Err(err) => {
                     return Err(io::Error::new(
-                        ErrorKind::Other,
+                        usb_error(err).kind(),
                         format!("Usb write_bulk error: {}", err),
                     ));
                 }
@@ -173,12 +303,20 @@ impl<T: UsbContext> ScsiUsb<T> {
         command_data: [u8; 16],
         buffer: &mut [u8],
     ) -> Result<u8, io::Error> {
-        let cbw = self.cbw_init(buffer, LIBUSB_ENDPOINT_IN, command_data)?;
-        self.write_bulk(&cbw)?;
-        if !buffer.is_empty() {
-            self.read_bulk(buffer)?;
+        if let Transport::Uas(ref mut uas) = self.transport {
+            let lun = self
+                .lun
+                .ok_or_else(|| io::Error::new(ErrorKind::Other, "lun must be set"))?;
+            return uas.command(
+                &self.handle,
+                lun,
+                command_data,
+                Direction::In,
+                buffer,
+                self.timeout,
+            );
         }
-        self.ack_data()
+        self.bot_transfer(command_data, buffer, LIBUSB_ENDPOINT_IN)
     }
 
     fn bulk_transfer_write(
@@ -186,18 +324,76 @@ impl<T: UsbContext> ScsiUsb<T> {
         command_data: [u8; 16],
         buffer: &mut [u8],
     ) -> Result<u8, io::Error> {
-        let cbw = self.cbw_init(buffer, LIBUSB_ENDPOINT_OUT, command_data)?;
+        if let Transport::Uas(ref mut uas) = self.transport {
+            let lun = self
+                .lun
+                .ok_or_else(|| io::Error::new(ErrorKind::Other, "lun must be set"))?;
+            return uas.command(
+                &self.handle,
+                lun,
+                command_data,
+                Direction::Out,
+                buffer,
+                self.timeout,
+            );
+        }
+        self.bot_transfer(command_data, buffer, LIBUSB_ENDPOINT_OUT)
+    }
+
+    // Run a command over the Bulk-Only Transport, recovering the transport
+    // if the command failed
+    fn bot_transfer(
+        &mut self,
+        command_data: [u8; 16],
+        buffer: &mut [u8],
+        dir: u8,
+    ) -> Result<u8, io::Error> {
+        let result = self.bot_command(command_data, buffer, dir);
+        if let Err(ref err) = result {
+            if err.kind() != ErrorKind::NotConnected {
+                error!("bot transfer error: {}", err);
+                if let Err(err) = self.reset_recovery() {
+                    error!("reset recovery failed: {}", err);
+                }
+            }
+        }
+        result
+    }
+
+    fn bot_command(
+        &mut self,
+        command_data: [u8; 16],
+        buffer: &mut [u8],
+        dir: u8,
+    ) -> Result<u8, io::Error> {
+        let cbw = self.cbw_init(buffer, dir, command_data)?;
         self.write_bulk(&cbw)?;
-        self.write_bulk(buffer)?;
+        if !buffer.is_empty() {
+            let (endpoint_in, endpoint_out) = self.bot_endpoints()?;
+            let (result, endpoint) = if dir == LIBUSB_ENDPOINT_IN {
+                (self.read_bulk(buffer), endpoint_in)
+            } else {
+                (self.write_bulk(buffer), endpoint_out)
+            };
+            match result {
+                Ok(_) => (),
+                // The device stalls the data phase of a command it can't
+                // complete, its status still comes with the CSW
+                Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                    debug!("data phase stalled");
+                    self.handle.clear_halt(endpoint).map_err(usb_error)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
         self.ack_data()
     }
 
     fn scsi_test_unit_ready(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
         let mut command_data: [u8; 16] = [0; 16];
         command_data[0] = SCSI_TEST_UNIT_READY;
-        let cbw = self.cbw_init(buffer, LIBUSB_ENDPOINT_OUT, command_data)?;
-        self.write_bulk(&cbw)?;
-        self.ack_data()
+        // No data phase
+        self.bulk_transfer_write(command_data, &mut buffer[..0])
     }
 
     fn scsi_read_capacity_10(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
@@ -206,6 +402,19 @@ impl<T: UsbContext> ScsiUsb<T> {
         self.bulk_transfer_read(command_data, buffer)
     }
 
+    fn scsi_read_capacity_16(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_SERVICE_ACTION_IN_16;
+        command_data[1] = SA_READ_CAPACITY_16;
+        BigEndian::write_u32(
+            &mut command_data[10..14],
+            u32::try_from(buffer.len()).map_err(|_| {
+                io::Error::new(ErrorKind::InvalidData, "Couldn't convert usize to u32")
+            })?,
+        );
+        self.bulk_transfer_read(command_data, buffer)
+    }
+
     pub fn scsi_read_10(
         &mut self,
         buffer: &mut [u8],
@@ -252,6 +461,72 @@ impl<T: UsbContext> ScsiUsb<T> {
         self.bulk_transfer_write(command_data, buffer)
     }
 
+    pub fn scsi_read_16(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_READ_16;
+        BigEndian::write_u64(&mut command_data[2..10], offset);
+        BigEndian::write_u32(
+            &mut command_data[10..14],
+            u32::try_from(count).map_err(|_| {
+                io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u32")
+            })?,
+        );
+        self.bulk_transfer_read(command_data, buffer)
+    }
+
+    pub fn scsi_write_16(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        let mut command_data: [u8; 16] = [0; 16];
+        command_data[0] = SCSI_WRITE_16;
+        BigEndian::write_u64(&mut command_data[2..10], offset);
+        BigEndian::write_u32(
+            &mut command_data[10..14],
+            u32::try_from(count).map_err(|_| {
+                io::Error::new(ErrorKind::InvalidData, "Couldn't convert u64 to u32")
+            })?,
+        );
+        self.bulk_transfer_write(command_data, buffer)
+    }
+
+    /// Read `count` blocks at `offset` with READ(10), or READ(16) if the
+    /// device needs it
+    pub fn read_blocks(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        if self.long_lba {
+            self.scsi_read_16(buffer, offset, count)
+        } else {
+            self.scsi_read_10(buffer, offset, count)
+        }
+    }
+
+    /// Write `count` blocks at `offset` with WRITE(10), or WRITE(16) if the
+    /// device needs it
+    pub fn write_blocks(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<u8, io::Error> {
+        if self.long_lba {
+            self.scsi_write_16(buffer, offset, count)
+        } else {
+            self.scsi_write_10(buffer, offset, count)
+        }
+    }
+
     fn scsi_inquiry(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
         let mut command_data: [u8; 16] = [0; 16];
         command_data[0] = SCSI_INQUIRY;
@@ -260,6 +535,15 @@ impl<T: UsbContext> ScsiUsb<T> {
     }
 
     fn scsi_request_sense(&mut self, buffer: &mut [u8]) -> Result<u8, io::Error> {
+        // UAS returns the sense data along with the status of the failed
+        // command
+        if let Transport::Uas(ref mut uas) = self.transport {
+            if let Some(sense) = uas.take_sense() {
+                let len = buffer.len().min(sense.len());
+                buffer[..len].copy_from_slice(&sense[..len]);
+                return Ok(0);
+            }
+        }
         let mut command_data: [u8; 16] = [0; 16];
         command_data[0] = SCSI_REQUEST_SENSE;
         BigEndian::write_u32(&mut command_data[1..5], buffer.len() as u32);
@@ -267,6 +551,10 @@ impl<T: UsbContext> ScsiUsb<T> {
     }
 
     fn get_max_lun(&mut self) -> u8 {
+        // GET MAX LUN is a BOT class request
+        if let Transport::Uas(_) = self.transport {
+            return 0;
+        }
         let mut buffer: [u8; 1] = [0; 1];
         let _len = self.handle.read_control(
             request_type(Direction::In, RequestType::Class, Recipient::Interface),
@@ -309,38 +597,150 @@ impl<T: UsbContext> ScsiUsb<T> {
         count: u64,
         block_size: usize,
     ) -> Result<Vec<u8>, io::Error> {
-        let mut buffer = Vec::new();
-        let mut offset = offset;
-        let mut remaining_sectors = count;
-        while remaining_sectors != 0 {
-            let sectors_to_read = std::cmp::min(remaining_sectors, SCSI_MAX_READ_SECTORS);
-            let mut tmp = vec![0; block_size * sectors_to_read as usize];
-            self.scsi_read_10(&mut tmp, offset, sectors_to_read)?;
-            offset += sectors_to_read;
-            buffer.append(&mut tmp);
-            remaining_sectors -= sectors_to_read;
+        let (buffer, bad_sectors) = self.read_sectors_tolerant(offset, count, block_size)?;
+        if !bad_sectors.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!("Unreadable sectors: {:?}", bad_sectors),
+            ));
         }
         Ok(buffer)
     }
 
-    pub fn init_mass_storage(&mut self) -> Result<(u32, u32, u64), io::Error> {
-        let max_lun = self.get_max_lun();
-        debug!("init mass storage. Luns: {}", max_lun);
+    /// Read sectors like `read_sectors()` but zero-fill the ones that can't
+    /// be read. Returns the data and the unreadable sectors.
+    pub fn read_sectors_tolerant(
+        &mut self,
+        offset: u64,
+        count: u64,
+        block_size: usize,
+    ) -> Result<(Vec<u8>, Vec<u64>), io::Error> {
+        let mut buffer = vec![0; block_size * count as usize];
+        let mut bad_sectors = Vec::new();
+        let max_sectors = (SCSI_MAX_READ_SIZE / block_size as u64).max(1);
+        let mut done = 0;
+        while done != count {
+            let sectors_to_read = std::cmp::min(count - done, max_sectors);
+            let start = done as usize * block_size;
+            let stop = (done + sectors_to_read) as usize * block_size;
+            self.read_or_split(
+                &mut buffer[start..stop],
+                offset + done,
+                sectors_to_read,
+                block_size,
+                &mut bad_sectors,
+            )?;
+            done += sectors_to_read;
+        }
+        Ok((buffer, bad_sectors))
+    }
+
+    // Read sectors, retrying with smaller transfers if it fails, down to
+    // single sectors that are marked bad if they still can't be read
+    fn read_or_split(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+        block_size: usize,
+        bad_sectors: &mut Vec<u64>,
+    ) -> Result<(), io::Error> {
+        if self.read_blocks_retry(buffer, offset, count)? {
+            return Ok(());
+        }
+        if count == 1 {
+            error!("unreadable sector: {}", offset);
+            buffer.iter_mut().for_each(|b| *b = 0);
+            bad_sectors.push(offset);
+            return Ok(());
+        }
+        debug!("read of {} sectors at {} failed, splitting", count, offset);
+        let half = count / 2;
+        let (first, second) = buffer.split_at_mut(half as usize * block_size);
+        self.read_or_split(first, offset, half, block_size, bad_sectors)?;
+        self.read_or_split(second, offset + half, count - half, block_size, bad_sectors)
+    }
+
+    // Returns whether the read succeeded. Transient errors (unit attention,
+    // not ready, transport errors) are retried, a medium error isn't.
+    fn read_blocks_retry(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        count: u64,
+    ) -> Result<bool, io::Error> {
+        for _ in 0..SCSI_READ_ATTEMPTS {
+            match self.read_blocks(buffer, offset, count) {
+                Ok(0) => return Ok(true),
+                Ok(_) => {
+                    let mut sense: [u8; 18] = [0; 18];
+                    match self.scsi_request_sense(&mut sense) {
+                        Ok(_) => (),
+                        Err(err) if err.kind() == ErrorKind::NotConnected => return Err(err),
+                        Err(err) => {
+                            debug!("request sense error: {}", err);
+                            continue;
+                        }
+                    }
+                    match sense_key(&sense) {
+                        Some((SENSE_KEY_UNIT_ATTENTION, _))
+                        | Some((SENSE_KEY_NOT_READY, ASC_NOT_READY)) => {
+                            thread::sleep(time::Duration::from_millis(200));
+                        }
+                        sense => {
+                            debug!("read failed, sense: {:?}", sense);
+                            return Ok(false);
+                        }
+                    }
+                }
+                // Nothing to retry if the device is gone
+                Err(err) if err.kind() == ErrorKind::NotConnected => return Err(err),
+                Err(err) => debug!("read error: {}", err),
+            }
+        }
+        Ok(false)
+    }
+
+    /// List the LUNs up to `max_lun` whose peripheral device type (from
+    /// INQUIRY) is direct access block device, with a device connected
+    /// (peripheral qualifier 0). Card readers and some enclosures also expose
+    /// CD-ROM or enclosure services LUNs.
+    pub fn direct_access_luns(&mut self, max_lun: u8) -> Result<Vec<u8>, io::Error> {
         // Store luns which Direct access device set
         let mut lun_dad = vec![];
         for lun in 0..=max_lun {
             self.lun = Some(lun);
             let mut buffer: [u8; 36] = [0; 36];
             self.scsi_inquiry(&mut buffer)?;
+            let qualifier = buffer[0] >> 5;
             let lun_type = buffer[0] & 0x1f;
-            debug!("Lun {} of type {}", lun, lun_type);
-            if lun_type == 0 {
+            debug!("Lun {} of type {} (qualifier {})", lun, lun_type, qualifier);
+            if qualifier == 0 && lun_type == 0 {
                 // Device is Direct access decice
                 lun_dad.push(lun);
             }
         }
-
+        self.lun = None;
         debug!("Direct access devices luns: {:?}", lun_dad);
+        Ok(lun_dad)
+    }
+
+    /// Init the mass storage on `lun`, or on the first ready direct access
+    /// LUN if not specified
+    pub fn init_mass_storage(&mut self, lun: Option<u8>) -> Result<(u64, u32, u64), io::Error> {
+        let max_lun = self.get_max_lun();
+        debug!("init mass storage. Luns: {}", max_lun);
+        let mut lun_dad = self.direct_access_luns(max_lun)?;
+
+        if let Some(lun) = lun {
+            if !lun_dad.contains(&lun) {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("lun {} isn't a direct access device", lun),
+                ));
+            }
+            lun_dad = vec![lun];
+        }
 
         /* For each lun, test if ready or not present  */
 
@@ -394,18 +794,22 @@ impl<T: UsbContext> ScsiUsb<T> {
                                                     thread::sleep(ten_millis);
                                                     continue;
                                                 }
-                                                _ => {
-                                                    /* XXX TODO: All others code signal a fail? */
-                                                    error!(
-                                                        "{}",
-                                                        &format!(
-                                                            "Sense sub error code: {:?}",
-                                                            &buffer[12..13]
-                                                        )
-                                                    );
+                                                ASC_MEDIUM_NOT_PRESENT => {
+                                                    error!("Medium not present");
                                                     is_ok = false;
                                                     break;
                                                 }
+                                                _ => {
+                                                    /* Becoming ready, retry */
+                                                    debug!(
+                                                        "Not ready, ASC: 0x{:x} ASCQ: 0x{:x}",
+                                                        buffer[12], buffer[13]
+                                                    );
+                                                    let ten_millis =
+                                                        time::Duration::from_millis(200);
+                                                    thread::sleep(ten_millis);
+                                                    continue;
+                                                }
                                             }
                                         }
                                         3 | 4 | 5 => {
@@ -449,6 +853,9 @@ impl<T: UsbContext> ScsiUsb<T> {
                     break;
                 }
             }
+            if is_ok {
+                break;
+            }
         }
 
         if !is_ok {
@@ -464,11 +871,28 @@ impl<T: UsbContext> ScsiUsb<T> {
             }
         }
 
-        assert!(buffer[4] == 0 && buffer[5] == 0);
+        let mut max_lba = u64::from(BigEndian::read_u32(&buffer[0..4]));
+        let mut block_size: u32 = BigEndian::read_u32(&buffer[4..8]);
+
+        // Devices larger than 2TiB (with 512 bytes blocks) report the max
+        // LBA with READ CAPACITY(16) only
+        if max_lba == u64::from(u32::MAX) {
+            let mut buffer: [u8; 32] = [0; 32];
+            match self.scsi_read_capacity_16(&mut buffer) {
+                Ok(0) => {}
+                _ => {
+                    return Err(io::Error::new(ErrorKind::Other, "Cannot read capacity(16)"));
+                }
+            }
+            max_lba = BigEndian::read_u64(&buffer[0..8]);
+            block_size = BigEndian::read_u32(&buffer[8..12]);
+            debug!("read capacity(16): max lba: {}", max_lba);
+        }
+        self.long_lba = max_lba > u64::from(u32::MAX);
 
-        let max_lba: u32 = BigEndian::read_u32(&buffer[0..4]);
-        let block_size: u32 = BigEndian::read_u32(&buffer[4..8]);
-        let dev_size: u64 = (u64::from(max_lba) + 1) * (u64::from(block_size));
+        let dev_size = (max_lba + 1)
+            .checked_mul(u64::from(block_size))
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Bad device capacity"))?;
 
         Ok((max_lba, block_size, dev_size))
     }
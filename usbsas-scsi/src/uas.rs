@@ -0,0 +1,446 @@
+//! USB Attached SCSI (UAS) transport.
+//!
+//! Commands are sent as information units (IU) on the command pipe, their
+//! status is received on the status pipe and data goes through the data-in
+//! and data-out pipes. Super speed devices require bulk streams (the stream
+//! ID being the tag of the command), high speed devices announce the data
+//! phase with a read or write ready IU instead.
+//!
+//! Only one command is in flight at a time, like with the Bulk-Only
+//! Transport.
+
+use byteorder::{BigEndian, ByteOrder};
+use log::debug;
+use rusb::{
+    ffi::{self, constants::*},
+    DeviceHandle, Direction, InterfaceDescriptor, Speed, TransferType, UsbContext,
+};
+use std::{
+    io::{self, ErrorKind},
+    os::raw::{c_int, c_uint, c_void},
+    time::Duration,
+};
+
+const USB_DT_PIPE_USAGE: u8 = 0x24;
+const PIPE_ID_COMMAND: u8 = 1;
+const PIPE_ID_STATUS: u8 = 2;
+const PIPE_ID_DATA_IN: u8 = 3;
+const PIPE_ID_DATA_OUT: u8 = 4;
+
+const IU_ID_COMMAND: u8 = 0x01;
+const IU_ID_SENSE: u8 = 0x03;
+const IU_ID_RESPONSE: u8 = 0x04;
+const IU_ID_READ_READY: u8 = 0x06;
+const IU_ID_WRITE_READY: u8 = 0x07;
+
+const COMMAND_IU_LEN: usize = 32;
+const SENSE_IU_HEADER_LEN: usize = 16;
+const STATUS_IU_MAX_LEN: usize = SENSE_IU_HEADER_LEN + 96;
+
+// Tag of the (only) command in flight, also its stream ID
+const UAS_TAG: u16 = 1;
+
+/// Endpoints of the four pipes of a UAS interface
+#[derive(Clone, Copy, Debug)]
+pub struct UasPipes {
+    pub command: u8,
+    pub status: u8,
+    pub data_in: u8,
+    pub data_out: u8,
+}
+
+// Pipe ID of the pipe usage descriptor found in the extra descriptors of an
+// endpoint (preceded by the super speed companion descriptor if any)
+fn pipe_id(mut extra: &[u8]) -> Option<u8> {
+    while extra.len() >= 2 && extra[0] as usize <= extra.len() && extra[0] != 0 {
+        if extra[1] == USB_DT_PIPE_USAGE && extra[0] >= 3 {
+            return Some(extra[2]);
+        }
+        extra = &extra[extra[0] as usize..];
+    }
+    None
+}
+
+impl UasPipes {
+    pub fn from_descriptor(desc: &InterfaceDescriptor) -> Option<Self> {
+        let (mut command, mut status, mut data_in, mut data_out) = (None, None, None, None);
+        for endp in desc.endpoint_descriptors() {
+            if endp.transfer_type() != TransferType::Bulk {
+                continue;
+            }
+            match endp.extra().and_then(pipe_id) {
+                Some(PIPE_ID_COMMAND) => command = Some(endp.address()),
+                Some(PIPE_ID_STATUS) => status = Some(endp.address()),
+                Some(PIPE_ID_DATA_IN) => data_in = Some(endp.address()),
+                Some(PIPE_ID_DATA_OUT) => data_out = Some(endp.address()),
+                _ => continue,
+            }
+        }
+        Some(UasPipes {
+            command: command?,
+            status: status?,
+            data_in: data_in?,
+            data_out: data_out?,
+        })
+    }
+}
+
+pub struct UasTransport {
+    pipes: UasPipes,
+    streams: bool,
+    // Sense data of the last failed command (UAS has no separate
+    // REQUEST SENSE, sense data come with the status)
+    sense: Option<Vec<u8>>,
+}
+
+impl UasTransport {
+    /// The UAS alternate setting of the interface must be active
+    pub fn new<T: UsbContext>(handle: &DeviceHandle<T>, pipes: UasPipes) -> io::Result<Self> {
+        let streams = matches!(handle.device().speed(), Speed::Super | Speed::SuperPlus);
+        if streams {
+            let mut endpoints = [pipes.status, pipes.data_in, pipes.data_out];
+            let ret = unsafe {
+                ffi::libusb_alloc_streams(
+                    handle.as_raw(),
+                    UAS_TAG as u32,
+                    endpoints.as_mut_ptr(),
+                    endpoints.len() as c_int,
+                )
+            };
+            if ret < UAS_TAG as c_int {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("couldn't allocate bulk streams: {}", ret),
+                ));
+            }
+        }
+        debug!("uas transport: {:?} (streams: {})", pipes, streams);
+        Ok(UasTransport {
+            pipes,
+            streams,
+            sense: None,
+        })
+    }
+
+    pub fn take_sense(&mut self) -> Option<Vec<u8>> {
+        self.sense.take()
+    }
+
+    /// Run a command and its data phase. Returns 0 if the command succeeded
+    /// and 1 otherwise, like the status of a BOT CSW.
+    pub fn command<T: UsbContext>(
+        &mut self,
+        handle: &DeviceHandle<T>,
+        lun: u8,
+        command_data: [u8; 16],
+        direction: Direction,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<u8> {
+        let mut iu = command_iu(lun, command_data);
+        self.transfer(handle, self.pipes.command, &mut iu, timeout)?;
+
+        if !buffer.is_empty() {
+            if !self.streams {
+                if let Some(status) = self.wait_ready(handle, direction, timeout)? {
+                    return Ok(status);
+                }
+            }
+            let endpoint = match direction {
+                Direction::In => self.pipes.data_in,
+                Direction::Out => self.pipes.data_out,
+            };
+            let size = self.transfer(handle, endpoint, buffer, timeout)?;
+            if size != buffer.len() {
+                debug!("uas short transfer: {}/{}", size, buffer.len());
+            }
+        }
+
+        let mut iu = [0u8; STATUS_IU_MAX_LEN];
+        let size = self.transfer(handle, self.pipes.status, &mut iu, timeout)?;
+        self.status(&iu[..size])
+    }
+
+    // Without streams, the device sends a read / write ready IU when it's
+    // ready for the data phase, or directly the sense IU if the command failed
+    fn wait_ready<T: UsbContext>(
+        &mut self,
+        handle: &DeviceHandle<T>,
+        direction: Direction,
+        timeout: Duration,
+    ) -> io::Result<Option<u8>> {
+        let mut iu = [0u8; STATUS_IU_MAX_LEN];
+        let size = self.transfer(handle, self.pipes.status, &mut iu, timeout)?;
+        match (iu[0], direction) {
+            (IU_ID_READ_READY, Direction::In) | (IU_ID_WRITE_READY, Direction::Out) => {
+                check_tag(&iu[..size])?;
+                Ok(None)
+            }
+            _ => self.status(&iu[..size]).map(Some),
+        }
+    }
+
+    fn status(&mut self, iu: &[u8]) -> io::Result<u8> {
+        check_tag(iu)?;
+        match iu[0] {
+            IU_ID_SENSE if iu.len() >= SENSE_IU_HEADER_LEN => {
+                // SCSI status, GOOD is 0
+                if iu[6] == 0 {
+                    self.sense = None;
+                    return Ok(0);
+                }
+                let sense_len = BigEndian::read_u16(&iu[14..16]) as usize;
+                let sense = iu[SENSE_IU_HEADER_LEN..]
+                    .iter()
+                    .take(sense_len)
+                    .copied()
+                    .collect();
+                debug!("uas status 0x{:x}, sense: {:?}", iu[6], sense);
+                self.sense = Some(sense);
+                Ok(1)
+            }
+            IU_ID_RESPONSE if iu.len() >= 8 => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("uas response IU, code: 0x{:x}", iu[7]),
+            )),
+            id => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("unexpected uas IU: 0x{:x}", id),
+            )),
+        }
+    }
+
+    fn transfer<T: UsbContext>(
+        &self,
+        handle: &DeviceHandle<T>,
+        endpoint: u8,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        // The command pipe never uses streams
+        let result = if self.streams && endpoint != self.pipes.command {
+            stream_transfer(handle, endpoint, UAS_TAG as u32, buffer, timeout)
+        } else if endpoint & LIBUSB_ENDPOINT_IN != 0 {
+            handle.read_bulk(endpoint, buffer, timeout)
+        } else {
+            handle.write_bulk(endpoint, buffer, timeout)
+        };
+        result.map_err(|err| {
+            io::Error::new(
+                ErrorKind::Other,
+                format!("uas transfer error (ep 0x{:x}): {}", endpoint, err),
+            )
+        })
+    }
+}
+
+fn command_iu(lun: u8, command_data: [u8; 16]) -> [u8; COMMAND_IU_LEN] {
+    let mut iu = [0u8; COMMAND_IU_LEN];
+    iu[0] = IU_ID_COMMAND;
+    BigEndian::write_u16(&mut iu[2..4], UAS_TAG);
+    // Single level LUN, peripheral device addressing
+    iu[9] = lun;
+    iu[16..32].copy_from_slice(&command_data);
+    iu
+}
+
+fn check_tag(iu: &[u8]) -> io::Result<()> {
+    if iu.len() < 4 || BigEndian::read_u16(&iu[2..4]) != UAS_TAG {
+        return Err(io::Error::new(ErrorKind::Other, "bad uas IU tag"));
+    }
+    Ok(())
+}
+
+// State of a stream transfer, owned by the transfer: it outlives the call if
+// the transfer has to be abandoned while still in flight
+struct StreamTransfer {
+    completed: c_int,
+    // The caller is gone, the callback frees the transfer and its state
+    abandoned: bool,
+    data: Vec<u8>,
+}
+
+extern "system" fn transfer_done(transfer: *mut ffi::libusb_transfer) {
+    unsafe {
+        let state = (*transfer).user_data as *mut StreamTransfer;
+        if (*state).abandoned {
+            drop(Box::from_raw(state));
+            ffi::libusb_free_transfer(transfer);
+        } else {
+            (*state).completed = 1;
+        }
+    }
+}
+
+// libusb only offers asynchronous stream transfers, submit one and wait for
+// its completion
+fn stream_transfer<T: UsbContext>(
+    handle: &DeviceHandle<T>,
+    endpoint: u8,
+    stream_id: u32,
+    buffer: &mut [u8],
+    timeout: Duration,
+) -> rusb::Result<usize> {
+    let state = Box::into_raw(Box::new(StreamTransfer {
+        completed: 0,
+        abandoned: false,
+        data: buffer.to_vec(),
+    }));
+    unsafe {
+        let transfer = ffi::libusb_alloc_transfer(0);
+        if transfer.is_null() {
+            drop(Box::from_raw(state));
+            return Err(rusb::Error::NoMem);
+        }
+        ffi::libusb_fill_bulk_stream_transfer(
+            transfer,
+            handle.as_raw(),
+            endpoint,
+            stream_id,
+            (*state).data.as_mut_ptr(),
+            (*state).data.len() as c_int,
+            transfer_done,
+            state as *mut c_void,
+            timeout.as_millis() as c_uint,
+        );
+        if ffi::libusb_submit_transfer(transfer) != 0 {
+            ffi::libusb_free_transfer(transfer);
+            drop(Box::from_raw(state));
+            return Err(rusb::Error::Io);
+        }
+        let context = handle.context().as_raw();
+        while (*state).completed == 0 {
+            let ret = ffi::libusb_handle_events_completed(context, &mut (*state).completed);
+            if ret < 0 && ret != LIBUSB_ERROR_INTERRUPTED && (*state).completed == 0 {
+                // The transfer may complete later, its callback frees it
+                ffi::libusb_cancel_transfer(transfer);
+                (*state).abandoned = true;
+                return Err(rusb::Error::Io);
+            }
+        }
+        let (status, length) = ((*transfer).status, (*transfer).actual_length);
+        ffi::libusb_free_transfer(transfer);
+        let state = Box::from_raw(state);
+        match status {
+            LIBUSB_TRANSFER_COMPLETED => {
+                let length = length as usize;
+                buffer[..length].copy_from_slice(&state.data[..length]);
+                Ok(length)
+            }
+            LIBUSB_TRANSFER_TIMED_OUT => Err(rusb::Error::Timeout),
+            LIBUSB_TRANSFER_STALL => Err(rusb::Error::Pipe),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(rusb::Error::NoDevice),
+            LIBUSB_TRANSFER_OVERFLOW => Err(rusb::Error::Overflow),
+            _ => Err(rusb::Error::Io),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport() -> UasTransport {
+        UasTransport {
+            pipes: UasPipes {
+                command: 0x01,
+                status: 0x82,
+                data_in: 0x83,
+                data_out: 0x04,
+            },
+            streams: false,
+            sense: None,
+        }
+    }
+
+    fn sense_iu(status: u8, sense: &[u8]) -> Vec<u8> {
+        let mut iu = vec![0u8; SENSE_IU_HEADER_LEN];
+        iu[0] = IU_ID_SENSE;
+        BigEndian::write_u16(&mut iu[2..4], UAS_TAG);
+        iu[6] = status;
+        BigEndian::write_u16(&mut iu[14..16], sense.len() as u16);
+        iu.extend_from_slice(sense);
+        iu
+    }
+
+    #[test]
+    fn test_pipe_id() {
+        // Super speed companion descriptor followed by the pipe usage one
+        assert_eq!(pipe_id(&[6, 0x30, 0, 0, 0, 0, 4, 0x24, 2, 0]), Some(2));
+        assert_eq!(pipe_id(&[4, 0x24, 3, 0]), Some(3));
+        assert_eq!(pipe_id(&[6, 0x30, 0, 0, 0, 0]), None);
+        // Truncated or zero length descriptors
+        assert_eq!(pipe_id(&[8, 0x24, 1]), None);
+        assert_eq!(pipe_id(&[0, 0x24, 1, 0]), None);
+        assert_eq!(pipe_id(&[]), None);
+    }
+
+    #[test]
+    fn test_command_iu() {
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x28;
+        cdb[15] = 0xff;
+        let iu = command_iu(3, cdb);
+        assert_eq!(iu[0], IU_ID_COMMAND);
+        assert_eq!(BigEndian::read_u16(&iu[2..4]), UAS_TAG);
+        assert_eq!(iu[9], 3);
+        assert_eq!(iu[16..32], cdb);
+        assert!(check_tag(&iu).is_ok());
+    }
+
+    #[test]
+    fn test_status() {
+        let mut uas = transport();
+        assert_eq!(uas.status(&sense_iu(0, &[])).unwrap(), 0);
+        assert!(uas.take_sense().is_none());
+
+        // Check condition, the sense data is kept and its length honored
+        let sense = [0x70, 0, 0x03, 0, 0, 0, 0, 0x0a, 0, 0, 0, 0, 0x11, 0];
+        let mut iu = sense_iu(0x02, &sense);
+        iu.extend_from_slice(&[0xaa; 4]);
+        assert_eq!(uas.status(&iu).unwrap(), 1);
+        assert_eq!(uas.take_sense().unwrap(), sense);
+
+        // Response IU (task management failure) and unknown IUs are errors
+        let mut iu = [0u8; 8];
+        iu[0] = IU_ID_RESPONSE;
+        BigEndian::write_u16(&mut iu[2..4], UAS_TAG);
+        assert!(uas.status(&iu).is_err());
+        iu[0] = 0x42;
+        assert!(uas.status(&iu).is_err());
+
+        // Wrong tag and truncated IUs
+        let mut iu = sense_iu(0, &[]);
+        BigEndian::write_u16(&mut iu[2..4], UAS_TAG + 1);
+        assert!(uas.status(&iu).is_err());
+        assert!(uas.status(&[IU_ID_SENSE, 0]).is_err());
+        assert!(uas.status(&sense_iu(0, &[])[..8]).is_err());
+    }
+
+    #[test]
+    fn test_transfer_done() {
+        unsafe {
+            // Waited transfer: only flagged as completed
+            let transfer = ffi::libusb_alloc_transfer(0);
+            let mut state = StreamTransfer {
+                completed: 0,
+                abandoned: false,
+                data: vec![0; 4],
+            };
+            (*transfer).user_data = &mut state as *mut StreamTransfer as *mut c_void;
+            transfer_done(transfer);
+            assert_eq!(state.completed, 1);
+            ffi::libusb_free_transfer(transfer);
+
+            // Abandoned transfer: the callback frees it and its state
+            let transfer = ffi::libusb_alloc_transfer(0);
+            let state = Box::into_raw(Box::new(StreamTransfer {
+                completed: 0,
+                abandoned: true,
+                data: vec![0; 4],
+            }));
+            (*transfer).user_data = state as *mut c_void;
+            transfer_done(transfer);
+        }
+    }
+}
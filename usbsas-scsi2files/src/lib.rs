@@ -14,9 +14,19 @@ use usbsas_fsrw::{ext4fs, ff, iso9660fs, ntfs, FSRead};
 use usbsas_mass_storage::MassStorageComm;
 use usbsas_process::{UsbsasChild, UsbsasChildSpawner, UsbsasProcess};
 use usbsas_proto as proto;
-use usbsas_proto::{common::PartitionInfo, files::request::Msg};
+use usbsas_proto::{
+    common::{FileInfo, FileType, PartitionInfo, SortKey},
+    files::request::Msg,
+};
 use usbsas_utils::READ_FILE_MAX_SIZE;
 
+// Deeper directories aren't walked when computing recursive sizes, a corrupted
+// file system could have directories containing their ancestors
+const MAX_DIR_DEPTH: usize = 256;
+// Entries visited when computing recursive sizes, usbsas can't serve other
+// requests meanwhile
+const MAX_DIR_ENTRIES: u64 = 100_000;
+
 #[derive(Error, Debug)]
 enum Error {
     #[error("io error: {0}")]
@@ -48,7 +58,9 @@ protoresponse!(
     openpartition = OpenPartition[ResponseOpenPartition],
     getattr = GetAttr[ResponseGetAttr],
     readdir = ReadDir[ResponseReadDir],
+    dirsize = DirSize[ResponseDirSize],
     readfile = ReadFile[ResponseReadFile],
+    readlink = ReadLink[ResponseReadLink],
     readsectors = ReadSectors[ResponseReadSectors],
     error = Error[ResponseError],
     end = End[ResponseEnd]
@@ -92,6 +104,7 @@ struct InitState {}
 impl InitState {
     fn run(self, comm_parent: &mut Comm<proto::files::Request>) -> Result<State> {
         let dev2scsi = UsbsasChildSpawner::new()
+            .usb()
             .wait_on_startup()
             .spawn::<usbsas_dev2scsi::Dev2Scsi, proto::scsi::Request>()?;
         let UsbsasChild { comm, .. } = dev2scsi;
@@ -122,6 +135,7 @@ struct PartitionsListedState {
 
 struct PartitionOpenedState {
     fs: Box<dyn FSRead<MassStorageComm>>,
+    bad_sectors: Arc<RwLock<Option<Vec<u64>>>>,
 }
 
 struct WaitEndState {
@@ -133,7 +147,7 @@ impl ChildStartedState {
         let req: proto::files::Request = comm.recv()?;
         match req.msg.ok_or(Error::BadRequest)? {
             Msg::OpenDevice(req) => {
-                if let Err(err) = self.opendevice(comm, req.busnum, req.devnum) {
+                if let Err(err) = self.opendevice(comm, req.busnum, req.devnum, req.lun) {
                     error!("err open device: {}, waiting end", err);
                     comm.error(proto::files::ResponseError {
                         err: format!("{}", err),
@@ -162,11 +176,13 @@ impl ChildStartedState {
         comm: &mut Comm<proto::files::Request>,
         busnum: u32,
         devnum: u32,
+        lun: u32,
     ) -> Result<()> {
         trace!("req opendevice");
         let buf = (u64::from(devnum)) << 32 | u64::from(busnum);
         // unlock dev2scsi
         self.usb_mass.comm()?.write_all(&buf.to_le_bytes())?;
+        self.usb_mass.comm()?.write_all(&lun.to_le_bytes())?;
         let rep: proto::scsi::Response = self.usb_mass.comm()?.recv()?;
         match rep.msg.ok_or(Error::BadRequest)? {
             proto::scsi::response::Msg::OpenDevice(rep) => {
@@ -231,7 +247,11 @@ impl DevOpenedState {
         let rep = self
             .usb_mass
             .comm()?
-            .readsectors(proto::scsi::RequestReadSectors { offset, count })?;
+            .readsectors(proto::scsi::RequestReadSectors {
+                offset,
+                count,
+                tolerant: false,
+            })?;
         comm.readsectors(proto::files::ResponseReadSectors { data: rep.data })?;
         Ok(())
     }
@@ -244,8 +264,12 @@ impl PartitionsListedState {
             Msg::OpenPartition(req) => {
                 // Keep comm in case of error so we can end dev2scsi properly
                 let comm_bk = self.usb_mass.comm.clone();
+                let bad_sectors = self.usb_mass.bad_sectors.clone();
                 match self.open_partition(comm, req.index) {
-                    Ok(fs) => Ok(State::PartitionOpened(PartitionOpenedState { fs })),
+                    Ok(fs) => Ok(State::PartitionOpened(PartitionOpenedState {
+                        fs,
+                        bad_sectors,
+                    })),
                     Err(err) => {
                         comm.error(proto::files::ResponseError {
                             err: format!("{}", err),
@@ -297,8 +321,10 @@ impl PartitionOpenedState {
             let req: proto::files::Request = comm.recv()?;
             let res = match req.msg.ok_or(Error::BadRequest)? {
                 Msg::GetAttr(req) => self.getattr(comm, req.path),
-                Msg::ReadDir(req) => self.readdir(comm, req.path),
+                Msg::ReadDir(req) => self.readdir(comm, req),
+                Msg::DirSize(req) => self.dirsize(comm, req.path),
                 Msg::ReadFile(req) => self.readfile(comm, req.path, req.offset, req.size),
+                Msg::ReadLink(req) => self.readlink(comm, req.path),
                 Msg::End(_) => break,
                 _ => Err(Error::BadRequest),
             };
@@ -325,14 +351,89 @@ impl PartitionOpenedState {
         Ok(())
     }
 
-    fn readdir(&mut self, comm: &mut Comm<proto::files::Request>, path: String) -> Result<()> {
-        trace!("req readdir {}", path);
+    fn readdir(
+        &mut self,
+        comm: &mut Comm<proto::files::Request>,
+        req: proto::files::RequestReadDir,
+    ) -> Result<()> {
+        trace!(
+            "req readdir {} (offset {}, limit {})",
+            req.path,
+            req.offset,
+            req.limit
+        );
+        let mut filesinfo = self.fs.read_dir(&req.path)?;
+        let total = filesinfo.len() as u64;
+        sort_files(
+            &mut filesinfo,
+            SortKey::from_i32(req.sort).ok_or(Error::BadRequest)?,
+            req.descending,
+        );
+        let limit = if req.limit == 0 {
+            usize::MAX
+        } else {
+            usize::try_from(req.limit)?
+        };
         comm.readdir(proto::files::ResponseReadDir {
-            filesinfo: self.fs.read_dir(&path)?,
+            filesinfo: filesinfo
+                .into_iter()
+                .skip(usize::try_from(req.offset)?)
+                .take(limit)
+                .collect(),
+            total,
         })?;
         Ok(())
     }
 
+    fn dirsize(&mut self, comm: &mut Comm<proto::files::Request>, path: String) -> Result<()> {
+        trace!("req dirsize {}", path);
+        let mut rep = proto::files::ResponseDirSize::default();
+        // Attributes of the root directory can't be read on all file systems
+        let (ftype, size) = if path.trim_start_matches('/').is_empty() {
+            (FileType::Directory, 0)
+        } else {
+            let (ftype, size, _) = self.fs.get_attr(&path)?;
+            (ftype, size)
+        };
+        match ftype {
+            FileType::Regular => {
+                rep.size = size;
+                rep.files = 1;
+            }
+            FileType::Directory => {
+                let mut todo = vec![(path, 0)];
+                let mut entries = 0;
+                while let Some((dir, depth)) = todo.pop() {
+                    if entries >= MAX_DIR_ENTRIES {
+                        rep.truncated = true;
+                        break;
+                    }
+                    rep.directories += 1;
+                    for file in self.fs.read_dir(&dir)? {
+                        entries += 1;
+                        match FileType::from_i32(file.ftype) {
+                            Some(FileType::Regular) => {
+                                rep.size += file.size;
+                                rep.files += 1;
+                            }
+                            Some(FileType::Directory) if depth < MAX_DIR_DEPTH => {
+                                todo.push((file.path, depth + 1))
+                            }
+                            Some(FileType::Directory) => rep.truncated = true,
+                            // Links aren't followed
+                            _ => (),
+                        }
+                    }
+                }
+                // The requested directory isn't counted
+                rep.directories = rep.directories.saturating_sub(1);
+            }
+            _ => (),
+        }
+        comm.dirsize(rep)?;
+        Ok(())
+    }
+
     fn readfile(
         &mut self,
         comm: &mut Comm<proto::files::Request>,
@@ -344,12 +445,61 @@ impl PartitionOpenedState {
         if size > READ_FILE_MAX_SIZE {
             return Err(Error::Error("max read size exceeded".to_string()));
         }
-        self.fs.read_file(&path, &mut data, offset, size)?;
-        comm.readfile(proto::files::ResponseReadFile { data: *data })?;
+        // Unreadable sectors are only tolerated while reading file contents,
+        // they're zero-filled and reported with the data
+        self.set_bad_sectors(Some(Vec::new()))?;
+        let result = self.fs.read_file(&path, &mut data, offset, size);
+        let bad_sectors = self.set_bad_sectors(None)?.unwrap_or_default();
+        result?;
+        if !bad_sectors.is_empty() {
+            error!("{} has unreadable sectors: {:?}", path, bad_sectors);
+        }
+        comm.readfile(proto::files::ResponseReadFile {
+            data: *data,
+            bad_sectors,
+        })?;
+        Ok(())
+    }
+
+    // Enable (Some) or disable (None) the tolerance to unreadable sectors of
+    // the fs reader, returns the sectors met since it was enabled
+    fn set_bad_sectors(&self, bad_sectors: Option<Vec<u64>>) -> Result<Option<Vec<u64>>> {
+        Ok(std::mem::replace(
+            &mut *self
+                .bad_sectors
+                .write()
+                .map_err(|err| Error::Error(format!("bad sectors lock error: {}", err)))?,
+            bad_sectors,
+        ))
+    }
+
+    fn readlink(&mut self, comm: &mut Comm<proto::files::Request>, path: String) -> Result<()> {
+        trace!("req readlink {}", path);
+        comm.readlink(proto::files::ResponseReadLink {
+            target: self.fs.read_link(&path)?,
+        })?;
         Ok(())
     }
 }
 
+/// Sort a directory listing, entries with the same key are sorted by path
+fn sort_files(files: &mut [FileInfo], key: SortKey, descending: bool) {
+    files.sort_by(|a, b| {
+        let ordering = match key {
+            SortKey::Name => std::cmp::Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Timestamp => a.timestamp.cmp(&b.timestamp),
+            SortKey::Ftype => a.ftype.cmp(&b.ftype),
+        }
+        .then_with(|| a.path.cmp(&b.path));
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+}
+
 impl WaitEndState {
     fn run(self, comm: &mut Comm<proto::files::Request>) -> Result<State> {
         trace!("wait end state");
@@ -419,3 +569,44 @@ impl UsbsasProcess for Scsi2Files {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, ftype: FileType, size: u64, timestamp: i64) -> FileInfo {
+        FileInfo {
+            path: path.to_string(),
+            ftype: ftype.into(),
+            size,
+            timestamp,
+        }
+    }
+
+    fn paths(files: &[FileInfo]) -> Vec<&str> {
+        files.iter().map(|file| file.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_files() {
+        let mut files = vec![
+            file("/b", FileType::Regular, 10, 3),
+            file("/c", FileType::Directory, 0, 1),
+            file("/a", FileType::Regular, 10, 2),
+            file("/d", FileType::Regular, 20, 4),
+        ];
+        sort_files(&mut files, SortKey::Name, false);
+        assert_eq!(paths(&files), ["/a", "/b", "/c", "/d"]);
+        sort_files(&mut files, SortKey::Name, true);
+        assert_eq!(paths(&files), ["/d", "/c", "/b", "/a"]);
+        // Same size, sorted by path
+        sort_files(&mut files, SortKey::Size, false);
+        assert_eq!(paths(&files), ["/c", "/a", "/b", "/d"]);
+        sort_files(&mut files, SortKey::Size, true);
+        assert_eq!(paths(&files), ["/d", "/b", "/a", "/c"]);
+        sort_files(&mut files, SortKey::Timestamp, false);
+        assert_eq!(paths(&files), ["/c", "/a", "/b", "/d"]);
+        sort_files(&mut files, SortKey::Ftype, false);
+        assert_eq!(paths(&files), ["/a", "/b", "/d", "/c"]);
+    }
+}
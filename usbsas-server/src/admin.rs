@@ -0,0 +1,82 @@
+//! Administrators of the web server, authenticated with HTTP basic
+//! authentication against the credentials of the configuration. Routes with an
+//! `Admin` argument are only served to them, and disabled if no administrator
+//! is configured.
+
+use crate::appstate::AppState;
+use crate::error::ServiceError;
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use futures::future::{ready, Ready};
+use log::{error, warn};
+use pbkdf2::Pbkdf2;
+
+/// Extractor of authenticated administrators
+pub(crate) struct Admin;
+
+impl FromRequest for Admin {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> Result<Admin, ServiceError> {
+    let data = req
+        .app_data::<web::Data<AppState>>()
+        .ok_or(ServiceError::InternalServerError)?;
+    let admin = data.admin()?.ok_or(ServiceError::Forbidden)?;
+    let (username, password) = basic_credentials(req).ok_or(ServiceError::AdminRequired)?;
+    if !verify(&admin, &username, &password) {
+        warn!("admin authentication failed for user \"{}\"", username);
+        return Err(ServiceError::AdminRequired);
+    }
+    Ok(Admin)
+}
+
+/// Username and password of the `Authorization: Basic` header
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+fn verify(admin: &usbsas_config::Admin, username: &str, password: &str) -> bool {
+    let hash = match PasswordHash::new(&admin.password_hash) {
+        Ok(hash) => hash,
+        Err(err) => {
+            error!("invalid admin password hash: {}", err);
+            return false;
+        }
+    };
+    // The password is checked even if the username is wrong, not to tell
+    // which one is
+    let password_ok = hash
+        .verify_password(&[&Argon2::default(), &Pbkdf2], password)
+        .is_ok();
+    password_ok && username == admin.username
+}
+
+/// Check that the password hash of the configuration can be used
+pub(crate) fn check_password_hash(password_hash: &str) -> Result<(), String> {
+    let hash = PasswordHash::new(password_hash).map_err(|err| err.to_string())?;
+    match hash.algorithm.as_str() {
+        "argon2i" | "argon2d" | "argon2id" | "pbkdf2-sha256" | "pbkdf2-sha512" => Ok(()),
+        algorithm => Err(format!("unsupported algorithm {}", algorithm)),
+    }
+}
+
+/// PHC string (argon2id) of a password, for the configuration
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
@@ -1,26 +1,36 @@
 use crate::error::{AuthentError, ServiceError};
+use crate::events::Events;
+use crate::history::{History, HistoryQuery, Operation, Record};
+use crate::metrics::Metrics;
 use crate::tmpfiles::TmpFiles;
 use actix_web::web;
 use futures::task::{Context, Poll, Waker};
 use hmac::{Hmac, Mac};
-use log::{debug, error};
+use log::{debug, error, info};
+use nix::sys::wait::WaitStatus;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    fs, path,
+    fs,
+    io::{BufRead, BufReader, Write},
+    path,
     pin::Pin,
-    process::Command,
+    process::{ChildStderr, Command, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, TryLockError,
     },
+    thread,
 };
+use tempfile::NamedTempFile;
 use usbsas_comm::{protorequest, Comm};
 use usbsas_config::{conf_parse, conf_read, Config};
 use usbsas_proto as proto;
 use usbsas_proto::common::OutFileType;
-use usbsas_utils::{INPUT_PIPE_FD_VAR, OUTPUT_PIPE_FD_VAR, USBSAS_BIN_PATH};
+use usbsas_utils::{
+    enrollment::Registry, sign::SigningKey, INPUT_PIPE_FD_VAR, OUTPUT_PIPE_FD_VAR, USBSAS_BIN_PATH,
+};
 
 protorequest!(
     CommUsbsas,
@@ -32,6 +42,7 @@ protorequest!(
     partitions = Partitions[RequestPartitions, ResponsePartitions],
     openpartition = OpenPartition[RequestOpenPartition, ResponseOpenPartition],
     readdir = ReadDir[RequestReadDir, ResponseReadDir],
+    dirsize = DirSize[RequestDirSize, ResponseDirSize],
     getattr = GetAttr[RequestGetAttr, ResponseGetAttr],
     wipe = Wipe[RequestWipe, ResponseWipe],
     imgdisk = ImgDisk[RequestImgDisk, ResponseImgDisk],
@@ -66,11 +77,34 @@ enum CopyDestination {
 
 /// Public device structures we can send to web clients.
 
+/// State of the session sent to the web clients
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SessionState {
+    Idle,
+    DeviceSelected,
+    Copying,
+    Wiping,
+    Imaging,
+    Done,
+    Error,
+}
+
+/// Status of the configuration reloads, for the administrators
+#[derive(Serialize, Debug)]
+pub(crate) struct ConfigStatus {
+    /// A reloaded configuration will be used from the next session
+    pending: bool,
+    /// Error of the last reload
+    error: Option<String>,
+}
+
 #[derive(Serialize, Debug)]
 pub(crate) struct UsbsasInfos {
     pub(crate) name: String,
     pub(crate) message: String,
     pub(crate) version: String,
+    pub(crate) usb_passphrase_required: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -93,7 +127,7 @@ pub struct Partition {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ReadDir {
     pub ftype: i32,
-    size: u64,
+    pub size: u64,
     timestamp: i64,
     pub path: String,
     pub path_display: String,
@@ -101,6 +135,15 @@ pub struct ReadDir {
     path_parent_display: String,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DirSize {
+    pub size: u64,
+    pub files: u64,
+    pub directories: u64,
+    /// Too many entries to walk, the totals are lower bounds
+    pub truncated: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct USBDeviceDesc {
     vendorid: u32,
@@ -110,6 +153,10 @@ pub struct USBDeviceDesc {
     description: String,
     is_src: bool,
     is_dst: bool,
+    #[serde(default)]
+    lun: u32,
+    #[serde(default)]
+    refused: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -180,6 +227,8 @@ impl From<&TargetDevice> for DeviceDesc {
                     description: usb.description.to_owned(),
                     is_src: target.is_src,
                     is_dst: target.is_dst,
+                    lun: usb.lun,
+                    refused: usb.refused.to_owned(),
                 };
 
                 let desc_json = Desc::Usb(net_json);
@@ -195,6 +244,43 @@ impl From<&TargetDevice> for DeviceDesc {
     }
 }
 
+impl DeviceDesc {
+    /// Whether `text` is part of the description, manufacturer or serial
+    /// number of the device
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        match &self.dev {
+            Desc::Usb(usb) => [&usb.description, &usb.manufacturer, &usb.serial]
+                .iter()
+                .any(|field| field.contains(text)),
+            Desc::Net(NetDesc { description, .. }) | Desc::Cmd(CmdDesc { description, .. }) => {
+                description.contains(text)
+            }
+        }
+    }
+}
+
+/// Add the IDs of the source or destination device to the fields of the logs
+fn set_device_log_fields(source: bool, usb: &UsbDevice) {
+    let (vendorid, productid, serial) = if source {
+        ("src_vendorid", "src_productid", "src_serial")
+    } else {
+        ("dst_vendorid", "dst_productid", "dst_serial")
+    };
+    usbsas_utils::log::set_field(vendorid, Some(format!("{:04x}", usb.vendorid)));
+    usbsas_utils::log::set_field(productid, Some(format!("{:04x}", usb.productid)));
+    usbsas_utils::log::set_field(serial, Some(usb.serial.clone()));
+}
+
+impl From<&UsbDevice> for DeviceDesc {
+    fn from(usb: &UsbDevice) -> DeviceDesc {
+        DeviceDesc::from(&TargetDevice {
+            device: Device::Usb(usb.clone()),
+            is_src: usb.is_src,
+            is_dst: usb.is_dst,
+        })
+    }
+}
+
 trait Fingerprinter {
     fn fingerprint(&self) -> String;
 }
@@ -205,6 +291,7 @@ impl Fingerprinter for UsbDevice {
         hasher.update(b"Usb:");
         hasher.update(self.busnum.to_le_bytes());
         hasher.update(self.devnum.to_le_bytes());
+        hasher.update(self.lun.to_le_bytes());
         hasher.update(&self.manufacturer);
         hasher.update(&self.description);
         hasher.update(&self.serial);
@@ -247,15 +334,44 @@ impl Device {
     }
 }
 
+/// Path of the source device from a path given to the clients (base64 of the
+/// path and its HMAC, empty for the root directory)
+fn verified_path(path_b64: &str, hmac: &mut Hmac<Sha256>) -> Result<String, ServiceError> {
+    let mut path = base64::decode(path_b64)?;
+    if !path.is_empty() {
+        path = path.verify(hmac)?.to_vec();
+    }
+    Ok(String::from_utf8(path)?)
+}
+
 #[derive(Deserialize)]
 pub(crate) struct ReadDirQuery {
     pub(crate) path: String,
+    pub(crate) offset: Option<u64>,
+    /// All the entries if missing
+    pub(crate) limit: Option<u64>,
+    /// "name" (default), "size", "timestamp" or "type"
+    pub(crate) sort: Option<String>,
+    /// "asc" (default) or "desc"
+    pub(crate) order: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DirSizeQuery {
+    pub(crate) path: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
+pub(crate) struct WipeQuery {
+    pub(crate) mode: Option<String>,
+}
+
+#[derive(Deserialize)]
 pub(crate) struct CopyIn {
     pub(crate) selected: Vec<String>,
     pub(crate) fsfmt: String,
+    #[serde(default)]
+    pub(crate) passphrase: String,
 }
 
 #[derive(Serialize, Debug)]
@@ -277,6 +393,12 @@ pub struct ReportCopy<'a> {
     pub error_path: Vec<String>,
     pub filtered_path: Vec<String>,
     pub dirty_path: Vec<String>,
+    #[serde(default)]
+    pub skipped_path: Vec<String>,
+    #[serde(default)]
+    pub device_error: String,
+    #[serde(default)]
+    pub damaged_path: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -285,6 +407,13 @@ struct ReportProgress<'a> {
     progress: f32,
 }
 
+#[derive(Serialize, Debug)]
+struct ReportWipe<'a> {
+    status: &'a str,
+    mode: &'a str,
+    method: &'a str,
+}
+
 #[derive(Serialize, Debug)]
 struct ReportError<'a> {
     status: &'a str,
@@ -324,48 +453,308 @@ impl ReqAuthentication for Vec<u8> {
 pub(crate) struct AppState {
     config: Mutex<Config>,
     pub config_path: Mutex<String>,
+    /// Copy of the configuration in use, given to usbsas so that it uses the
+    /// validated one even if the file is modified
+    config_snapshot: Mutex<NamedTempFile>,
+    /// Configuration reloaded during the session, and its text
+    pending_config: Mutex<Option<(String, Config)>>,
+    reload_error: Mutex<Option<String>>,
     comm: Mutex<Comm<proto::usbsas::Request>>,
     out_dev: Mutex<Option<CopyDestination>>,
+    /// Source and destination selected, for the history
+    selected_devices: Mutex<Option<(DeviceDesc, DeviceDesc)>>,
+    history: Mutex<Option<History>>,
+    pub(crate) metrics: Metrics,
     hmac: Mutex<Hmac<Sha256>>,
     tmpfiles: Mutex<TmpFiles>,
-    #[cfg(feature = "log-json")]
+    state: Mutex<SessionState>,
+    pub(crate) events: Arc<Events>,
     pub session_id: Arc<std::sync::RwLock<String>>,
 }
 
+/// Fields of the logs set during a session
+const SESSION_LOG_FIELDS: [&str; 7] = [
+    "user_id",
+    "src_vendorid",
+    "src_productid",
+    "src_serial",
+    "dst_vendorid",
+    "dst_productid",
+    "dst_serial",
+];
+
+/// Log the lines written on stderr by usbsas and its children with the logger
+/// of the server
+fn forward_child_logs(stderr: ChildStderr) {
+    for line in BufReader::new(stderr).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        #[cfg(feature = "log-json")]
+        if let Some(record) = parse_json_log_line(&line) {
+            log::log!(target: &record.target, record.level, "{}", record.msg);
+            continue;
+        }
+        let (level, target, message) = parse_log_line(&line);
+        log::log!(target: target, level, "{}", message);
+    }
+}
+
+/// Record written by the JSON logger of the `log-json` feature
+#[cfg(feature = "log-json")]
+struct JsonLogRecord {
+    level: log::Level,
+    target: String,
+    msg: String,
+}
+
+#[cfg(feature = "log-json")]
+fn parse_json_log_line(line: &str) -> Option<JsonLogRecord> {
+    let record: serde_json::Value = serde_json::from_str(line).ok()?;
+    Some(JsonLogRecord {
+        level: record["level"].as_str()?.parse().ok()?,
+        target: record["target"].as_str()?.to_string(),
+        msg: record["msg"].as_str()?.to_string(),
+    })
+}
+
+/// Level, target and message of a line formatted by env_logger:
+/// `[<time> <level> <target>] <message>`. Other lines (panics etc.) are
+/// warnings.
+fn parse_log_line(line: &str) -> (log::Level, &str, &str) {
+    line.strip_prefix('[')
+        .and_then(|line| line.split_once("] "))
+        .and_then(|(header, message)| {
+            let mut header = header.split_whitespace();
+            let _time = header.next()?;
+            let level = header.next()?.parse().ok()?;
+            let target = header.next()?;
+            Some((level, target, message))
+        })
+        .unwrap_or((log::Level::Warn, "usbsas", line))
+}
+
+/// Identifier of a usbsas session, in the logs
+#[cfg(feature = "log-json")]
+pub(crate) fn new_session_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+#[cfg(not(feature = "log-json"))]
+pub(crate) fn new_session_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
 impl AppState {
-    pub(crate) fn new(config_path: String) -> Result<Self, ServiceError> {
-        let config = conf_parse(&conf_read(&config_path)?)?;
+    pub(crate) fn new(
+        config_path: String,
+        session_id: Arc<std::sync::RwLock<String>>,
+    ) -> Result<Self, ServiceError> {
+        let config_str = conf_read(&config_path)?;
+        let config = conf_parse(&config_str)?;
+        let config_snapshot = AppState::write_config_snapshot(&config_str)?;
 
         let tmpfiles = TmpFiles::new(config.out_directory.clone())?;
 
-        #[cfg(feature = "log-json")]
-        let session_id = uuid::Uuid::new_v4().simple().to_string();
+        usbsas_utils::log::set_field("session_id", Some(session_id.read()?.clone()));
 
         debug!("Out tar file name: {:?}", tmpfiles.out_tar);
         debug!("Out fs file name: {:?}", tmpfiles.out_fs);
 
         let comm = AppState::start_usbsas(
             &config,
-            &config_path,
+            &AppState::snapshot_path(&config_snapshot)?,
             &tmpfiles,
             #[cfg(feature = "log-json")]
-            &session_id,
+            &session_id.read()?,
         )?;
 
-        Ok(AppState {
+        let history = config.history.as_ref().map(History::new);
+        let appstate = AppState {
             config: Mutex::new(config),
             config_path: Mutex::new(config_path),
+            config_snapshot: Mutex::new(config_snapshot),
+            pending_config: Mutex::new(None),
+            reload_error: Mutex::new(None),
             tmpfiles: Mutex::new(tmpfiles),
             comm: Mutex::new(comm),
             out_dev: Mutex::new(None),
+            selected_devices: Mutex::new(None),
+            history: Mutex::new(history),
+            metrics: Metrics::new(),
             hmac: Mutex::new(Hmac::new_from_slice(
                 &rand::thread_rng().gen::<[u8; 0x10]>(),
             )?),
-            #[cfg(feature = "log-json")]
-            session_id: Arc::new(std::sync::RwLock::new(session_id)),
+            state: Mutex::new(SessionState::Idle),
+            events: Arc::new(Events::default()),
+            session_id,
+        };
+        appstate.events.send("infos", &appstate.infos()?)?;
+        appstate.events.send("state", &SessionState::Idle)?;
+
+        Ok(appstate)
+    }
+
+    /// Infos of the station, with the message (banner) of the configuration in
+    /// use: a reloaded one is sent to the clients once it is applied
+    pub(crate) fn infos(&self) -> Result<UsbsasInfos, ServiceError> {
+        let node_name = match uname::Info::new() {
+            Ok(infos) => infos.nodename,
+            _ => "Unknown".to_string(),
+        };
+        let config = self.config.lock()?;
+
+        Ok(UsbsasInfos {
+            name: node_name,
+            message: config.message.clone().unwrap_or_default(),
+            version: usbsas_utils::USBSAS_VERSION.into(),
+            usb_passphrase_required: config.usb_passphrase_required.unwrap_or(false),
+        })
+    }
+
+    fn write_config_snapshot(config_str: &str) -> Result<NamedTempFile, ServiceError> {
+        let mut snapshot = tempfile::Builder::new()
+            .prefix("usbsas_config_")
+            .suffix(".toml")
+            .tempfile()?;
+        snapshot.write_all(config_str.as_bytes())?;
+        Ok(snapshot)
+    }
+
+    fn snapshot_path(snapshot: &NamedTempFile) -> Result<String, ServiceError> {
+        snapshot
+            .path()
+            .to_str()
+            .map(String::from)
+            .ok_or(ServiceError::InternalServerError)
+    }
+
+    /// Read and validate the configuration file. It is used from the next
+    /// session, the current one (and a transfer in progress) isn't affected.
+    pub(crate) fn reload_config(&self) -> Result<(), ServiceError> {
+        let result = self.read_new_config();
+        *self.reload_error.lock()? = result.as_ref().err().map(|err| err.to_string());
+        let (config_str, config) = result?;
+        *self.pending_config.lock()? = Some((config_str, config));
+        info!("configuration reloaded, it will be used from the next session");
+        Ok(())
+    }
+
+    fn read_new_config(&self) -> Result<(String, Config), ServiceError> {
+        let config_str = conf_read(&self.config_path.lock()?)
+            .map_err(|err| ServiceError::Error(format!("couldn't read config: {}", err)))?;
+        let config = conf_parse(&config_str).map_err(|err| ServiceError::Error(err.to_string()))?;
+        if !path::Path::new(&config.out_directory).is_dir() {
+            return Err(ServiceError::Error(format!(
+                "out_directory {} isn't a directory",
+                config.out_directory
+            )));
+        }
+        if let Some(admin) = &config.admin {
+            crate::admin::check_password_hash(&admin.password_hash).map_err(|err| {
+                ServiceError::Error(format!("invalid admin password_hash: {}", err))
+            })?;
+        }
+        if let Some(history) = &config.history {
+            crate::history::check_path(&history.path)
+                .map_err(|err| ServiceError::Error(format!("invalid history path: {}", err)))?;
+        }
+        if let Some(signature) = &config.signature {
+            SigningKey::from_file(&signature.key_path).map_err(|err| {
+                ServiceError::Error(format!(
+                    "invalid signing key {}: {}",
+                    signature.key_path, err
+                ))
+            })?;
+        }
+        if let Some(enrollment) = &config.enrollment {
+            Registry::from_file(&enrollment.registry_path, &enrollment.public_key).map_err(
+                |err| {
+                    ServiceError::Error(format!(
+                        "invalid enrollment registry {}: {}",
+                        enrollment.registry_path, err
+                    ))
+                },
+            )?;
+        }
+        // Only used once the server is restarted, but checked now not to
+        // find it broken then
+        crate::server::log_output(config.logging.as_ref())
+            .and_then(|output| usbsas_utils::log::check_output(&output))
+            .map_err(|err| ServiceError::Error(format!("invalid logging: {}", err)))?;
+        Ok((config_str, config))
+    }
+
+    pub(crate) fn config_status(&self) -> Result<ConfigStatus, ServiceError> {
+        Ok(ConfigStatus {
+            pending: self.pending_config.lock()?.is_some(),
+            error: self.reload_error.lock()?.clone(),
         })
     }
 
+    /// Use the configuration reloaded during the session, if any
+    fn apply_pending_config(&self) -> Result<(), ServiceError> {
+        let (config_str, config) = match self.pending_config.lock()?.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        *self.config_snapshot.lock()? = AppState::write_config_snapshot(&config_str)?;
+        let mut tmpfiles = self.tmpfiles.lock()?;
+        if tmpfiles.out_directory != config.out_directory {
+            *tmpfiles = TmpFiles::new(config.out_directory.clone())?;
+        }
+        *self.history.lock()? = config.history.as_ref().map(History::new);
+        *self.config.lock()? = config;
+        self.events.send_changed("infos", &self.infos()?)?;
+        info!("using the reloaded configuration");
+        Ok(())
+    }
+
+    pub(crate) fn admin(&self) -> Result<Option<usbsas_config::Admin>, ServiceError> {
+        Ok(self.config.lock()?.admin.clone())
+    }
+
+    /// Most recent records of the history matching the query
+    pub(crate) fn search_history(&self, query: &HistoryQuery) -> Result<Vec<Record>, ServiceError> {
+        self.history
+            .lock()?
+            .as_ref()
+            .ok_or_else(|| ServiceError::Error("no history configured".into()))?
+            .search(query)
+    }
+
+    /// Records of the history matching the query, as JSON lines
+    pub(crate) fn export_history(&self, query: &HistoryQuery) -> Result<Vec<u8>, ServiceError> {
+        self.history
+            .lock()?
+            .as_ref()
+            .ok_or_else(|| ServiceError::Error("no history configured".into()))?
+            .export(query)
+    }
+
+    /// Account an operation in the logs and metrics, and add its record to
+    /// the history if it is enabled
+    fn add_record<T>(
+        &self,
+        mut record: Record,
+        result: &Result<T, ServiceError>,
+        resp_stream: &ResponseStream,
+    ) -> Result<(), ServiceError> {
+        record.finish(result, resp_stream.error()?);
+        record.log_events();
+        self.metrics.record(&record)?;
+        if let Some(history) = self.history.lock()?.as_ref() {
+            history.add(&record)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_state(&self, state: SessionState) -> Result<(), ServiceError> {
+        *self.state.lock()? = state;
+        self.events.send("state", &state)
+    }
+
     fn start_usbsas(
         config: &Config,
         config_path: &str,
@@ -404,7 +793,17 @@ impl AppState {
         command.env(INPUT_PIPE_FD_VAR, parent_to_child_rd.to_string());
         command.env(OUTPUT_PIPE_FD_VAR, child_to_parent_wr.to_string());
 
-        let _ = command.spawn()?;
+        // usbsas and its children can't reach journald or the syslog
+        // collector, the server forwards their logs (with the fields of the
+        // session) if its logger does
+        if usbsas_utils::log::forwarding() {
+            command.stderr(Stdio::piped());
+        }
+
+        let mut child = command.spawn()?;
+        if let Some(stderr) = child.stderr.take() {
+            thread::spawn(move || forward_child_logs(stderr));
+        }
         usbsas_process::close(parent_to_child_rd)?;
         usbsas_process::close(child_to_parent_wr)?;
 
@@ -413,34 +812,86 @@ impl AppState {
 
     pub(crate) fn reset(&self) -> Result<(), ServiceError> {
         let mut comm = self.comm.lock()?;
-        let _ = comm.end(proto::usbsas::RequestEnd {})?;
-        nix::sys::wait::wait()?;
+        // usbsas may be gone already if it crashed
+        match comm.end(proto::usbsas::RequestEnd {}) {
+            Ok(end) => {
+                for process in end.crashed {
+                    error!("{} ended abnormally", process);
+                    self.metrics.child_crashed(&process)?;
+                }
+            }
+            Err(err) => error!("couldn't end usbsas: {}", err),
+        }
+        match nix::sys::wait::wait()? {
+            WaitStatus::Exited(_, 0) => (),
+            status => {
+                error!("usbsas ended abnormally: {:?}", status);
+                self.metrics.usbsas_crashed()?;
+            }
+        }
 
         self.tmpfiles.lock()?.reset()?;
+        self.apply_pending_config()?;
+        *self.selected_devices.lock()? = None;
 
-        #[cfg(feature = "log-json")]
-        let new_session_id = uuid::Uuid::new_v4().simple().to_string();
+        let new_session_id = new_session_id();
 
         let new_comm = AppState::start_usbsas(
             &*self.config.lock()?,
-            &self.config_path.lock()?,
+            &AppState::snapshot_path(&*self.config_snapshot.lock()?)?,
             &*self.tmpfiles.lock()?,
             #[cfg(feature = "log-json")]
             &new_session_id,
         )?;
 
-        #[cfg(feature = "log-json")]
-        {
-            *self.session_id.write()? = new_session_id;
+        usbsas_utils::log::set_field("session_id", Some(new_session_id.clone()));
+        for field in SESSION_LOG_FIELDS {
+            usbsas_utils::log::set_field(field, None);
         }
+        *self.session_id.write()? = new_session_id;
 
         *comm = new_comm;
+        self.set_state(SessionState::Idle)?;
+        // The new usbsas has no user ID yet
+        self.events.send("id", &String::new())?;
+        drop(comm);
+
+        self.events.send("infos", &self.infos()?)?;
 
         Ok(())
     }
 
+    /// Send the devices and the user ID to the web clients if they changed.
+    /// usbsas only answers these requests before a transfer, nothing is done
+    /// while it is busy or done.
+    pub(crate) fn refresh(&self) -> Result<(), ServiceError> {
+        let mut comm = match self.comm.try_lock() {
+            Ok(comm) => comm,
+            Err(TryLockError::WouldBlock) => return Ok(()),
+            Err(TryLockError::Poisoned(err)) => return Err(err.into()),
+        };
+        let state = *self.state.lock()?;
+        if state == SessionState::Idle {
+            let mut devices = AppState::usb_devices(&mut comm)?;
+            devices.append(&mut self.list_alt_targets()?);
+            let devices: Vec<DeviceDesc> = devices.iter().map(DeviceDesc::from).collect();
+            self.events.send_changed("devices", &devices)?;
+        }
+        if state == SessionState::Idle || state == SessionState::DeviceSelected {
+            let id = comm.id(proto::usbsas::RequestId {})?.id;
+            usbsas_utils::log::set_field("user_id", Some(id.clone()).filter(|id| !id.is_empty()));
+            self.events.send_changed("id", &id)?;
+        }
+        Ok(())
+    }
+
     pub fn list_usb_devices(&self) -> Result<Vec<TargetDevice>, ServiceError> {
-        let mut comm = self.comm.lock()?;
+        AppState::usb_devices(&mut self.comm.lock()?)
+    }
+
+    fn usb_devices(
+        comm: &mut Comm<proto::usbsas::Request>,
+    ) -> Result<Vec<TargetDevice>, ServiceError> {
         let mut devices = vec![];
         for device in comm.devices(proto::usbsas::RequestDevices {})?.devices {
             devices.push(TargetDevice {
@@ -511,6 +962,8 @@ impl AppState {
 
         let mut dirty = None;
         let mut out_dev = None;
+        let mut dirty_desc = None;
+        let mut out_desc = None;
         for dev in devices {
             let fingerprint = dev.device.fingerprint();
             if fingerprint_dirty == fingerprint {
@@ -518,11 +971,15 @@ impl AppState {
                     dirty = Some(proto::usbsas::RequestOpenDevice {
                         device: Some(usb.to_owned()),
                     });
+                    dirty_desc = Some(DeviceDesc::from(&dev));
+                    set_device_log_fields(true, usb);
                 }
             }
             if fingerprint_out == fingerprint {
+                out_desc = Some(DeviceDesc::from(&dev));
                 match &dev.device {
                     Device::Usb(ref usb) => {
+                        set_device_log_fields(false, usb);
                         out_dev = Some(CopyDestination::Usb {
                             busnum: usb.busnum,
                             devnum: usb.devnum,
@@ -544,11 +1001,12 @@ impl AppState {
             }
         };
 
-        self.comm
-            .lock()?
-            .opendev(dirty)
+        let mut comm = self.comm.lock()?;
+        comm.opendev(dirty)
             .map_err(|err| ServiceError::Error(format!("couldn't open input device: {}", err)))?;
         *self.out_dev.lock()? = out_dev;
+        *self.selected_devices.lock()? = dirty_desc.zip(out_desc);
+        self.set_state(SessionState::DeviceSelected)?;
 
         Ok(())
     }
@@ -594,19 +1052,34 @@ impl AppState {
         Ok(())
     }
 
-    pub(crate) fn read_dir(&self, path: &str) -> Result<Vec<ReadDir>, ServiceError> {
-        let parent_path_b64 = path.replace(' ', "+");
-        let mut parent_path = base64::decode(&parent_path_b64)?;
+    /// Entries of a directory of the source device and the number of entries
+    /// of the directory
+    pub(crate) fn read_dir(
+        &self,
+        query: &ReadDirQuery,
+    ) -> Result<(Vec<ReadDir>, u64), ServiceError> {
+        let sort = match query.sort.as_deref() {
+            None | Some("name") => proto::common::SortKey::Name,
+            Some("size") => proto::common::SortKey::Size,
+            Some("timestamp") => proto::common::SortKey::Timestamp,
+            Some("type") => proto::common::SortKey::Ftype,
+            Some(sort) => return Err(ServiceError::BadRequest(format!("Bad sort key: {}", sort))),
+        };
+        let descending = match query.order.as_deref() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(order) => return Err(ServiceError::BadRequest(format!("Bad order: {}", order))),
+        };
+        let parent_path_b64 = query.path.replace(' ', "+");
         let mut hmac = self.hmac.lock()?;
-
-        if !parent_path.is_empty() {
-            parent_path = parent_path.verify(&mut hmac)?.to_vec();
-        }
-
-        let parent_path_str = String::from_utf8(parent_path)?;
+        let parent_path_str = verified_path(&parent_path_b64, &mut hmac)?;
 
         let dir_info = self.comm.lock()?.readdir(proto::usbsas::RequestReadDir {
             path: parent_path_str.clone(),
+            offset: query.offset.unwrap_or(0),
+            limit: query.limit.unwrap_or(0),
+            sort: sort.into(),
+            descending,
         })?;
 
         // Build information for each element in current path
@@ -624,14 +1097,64 @@ impl AppState {
                 path_parent_display: parent_path_str.clone(),
             })
         }
-        Ok(files)
+        Ok((files, dir_info.total))
+    }
+
+    /// Recursive size and number of files of a path of the source device
+    pub(crate) fn dir_size(&self, path: &str) -> Result<DirSize, ServiceError> {
+        let path = {
+            let mut hmac = self.hmac.lock()?;
+            verified_path(&path.replace(' ', "+"), &mut hmac)?
+        };
+        let rep = self
+            .comm
+            .lock()?
+            .dirsize(proto::usbsas::RequestDirSize { path })?;
+        Ok(DirSize {
+            size: rep.size,
+            files: rep.files,
+            directories: rep.directories,
+            truncated: rep.truncated,
+        })
     }
 
     pub(crate) fn copy(
         &self,
         req_selected: Vec<String>,
         fsfmt: String,
+        passphrase: String,
+        resp_stream: ResponseStream,
+    ) -> Result<(), ServiceError> {
+        let (source, destination) = match self.selected_devices.lock()?.take() {
+            Some((source, destination)) => (Some(source), Some(destination)),
+            None => (None, None),
+        };
+        let mut record = Record::new(
+            Operation::Copy,
+            self.id().unwrap_or_default(),
+            source,
+            destination,
+        );
+        let result = self.copy_files(
+            req_selected,
+            fsfmt,
+            passphrase,
+            resp_stream.clone(),
+            &mut record,
+        );
+        if let Err(err) = self.add_record(record, &result, &resp_stream) {
+            error!("couldn't add the copy to the history: {}", err);
+        }
+        result
+    }
+
+    fn copy_files(
+        &self,
+        req_selected: Vec<String>,
+        fsfmt: String,
+        passphrase: String,
         resp_stream: ResponseStream,
+        record: &mut Record,
     ) -> Result<(), ServiceError> {
         use proto::usbsas::response::Msg;
 
@@ -648,6 +1171,7 @@ impl AppState {
         drop(hmac);
 
         let mut comm = self.comm.lock()?;
+        self.set_state(SessionState::Copying)?;
         resp_stream.report_progress("copy_start", progress)?;
 
         let out_dev = self.out_dev.lock()?;
@@ -660,10 +1184,18 @@ impl AppState {
                     "fat32" => proto::common::OutFsType::Fat,
                     _ => return Err(ServiceError::InternalServerError),
                 };
+                if passphrase.is_empty()
+                    && self.config.lock()?.usb_passphrase_required.unwrap_or(false)
+                {
+                    error!("passphrase required for usb destination");
+                    resp_stream.report_error("passphrase required")?;
+                    return Err(ServiceError::Error("passphrase required".into()));
+                }
                 proto::usbsas::request_copy_start::Destination::Usb(proto::usbsas::DestUsb {
                     busnum: *busnum,
                     devnum: *devnum,
                     fstype: fstype.into(),
+                    passphrase,
                 })
             }
             CopyDestination::Net { .. } => {
@@ -704,11 +1236,13 @@ impl AppState {
                 }
                 Msg::CopyStatus(msg) => {
                     size_read += msg.current_size;
+                    record.read_size = size_read;
                     progress = current_progress + (size_read as f32 / total_size as f32 * 30.0);
                     resp_stream.report_progress("copy_usb_tar_update", progress)?;
                 }
                 Msg::CopyStatusDone(_) => break,
                 Msg::NotEnoughSpace(msg) => {
+                    record.outcome = "not_enough_space".into();
                     resp_stream.report_progress("copy_usb_tar_start", progress)?;
                     resp_stream.add_message(ReportCopySize {
                         status: "copy_not_enough_space",
@@ -718,12 +1252,18 @@ impl AppState {
                     return Ok(());
                 }
                 Msg::NothingToCopy(msg) => {
-                    resp_stream.add_message(ReportCopy {
+                    let report = ReportCopy {
                         status: "nothing_to_copy",
                         filtered_path: msg.rejected_filter,
                         dirty_path: msg.rejected_dirty,
                         error_path: vec![],
-                    })?;
+                        skipped_path: msg.rejected_skipped,
+                        device_error: String::new(),
+                        damaged_path: vec![],
+                    };
+                    record.outcome = "nothing_to_copy".into();
+                    record.report = Some(serde_json::to_value(&report)?);
+                    resp_stream.add_message(report)?;
                     resp_stream.done()?;
                     return Ok(());
                 }
@@ -740,6 +1280,7 @@ impl AppState {
             resp = comm.recv()?;
         }
         progress = current_progress + 30.0;
+        record.step_done("tar");
 
         if self.config.lock()?.analyzer.is_some() {
             if let Some(CopyDestination::Usb { .. }) = *out_dev {
@@ -766,6 +1307,7 @@ impl AppState {
                     }
                 }
                 progress = current_progress + 5.0;
+                record.step_done("analyze");
             };
         };
 
@@ -787,12 +1329,18 @@ impl AppState {
                         }
                         Msg::CopyStatusDone(_) => break,
                         Msg::NothingToCopy(msg) => {
-                            resp_stream.add_message(ReportCopy {
+                            let report = ReportCopy {
                                 status: "nothing_to_copy",
                                 filtered_path: msg.rejected_filter,
                                 dirty_path: msg.rejected_dirty,
                                 error_path: vec![],
-                            })?;
+                                skipped_path: msg.rejected_skipped,
+                                device_error: String::new(),
+                                damaged_path: vec![],
+                            };
+                            record.outcome = "nothing_to_copy".into();
+                            record.report = Some(serde_json::to_value(&report)?);
+                            resp_stream.add_message(report)?;
                             resp_stream.done()?;
                             return Ok(());
                         }
@@ -808,6 +1356,7 @@ impl AppState {
                     }
                 }
                 progress = current_progress + 30.0;
+                record.step_done("fs");
                 resp_stream.report_progress("copy_fs2dev_start", progress)?
             }
             CopyDestination::Net { .. } => {
@@ -828,6 +1377,7 @@ impl AppState {
             resp = comm.recv()?;
             match resp.msg.ok_or(ServiceError::InternalServerError)? {
                 Msg::FinalCopyStatus(msg) => {
+                    record.written_size = msg.current_size;
                     if msg.total_size != 0 && msg.current_size != 0 {
                         progress = current_progress
                             + (msg.current_size as f32 / msg.total_size as f32 * 30.0);
@@ -841,11 +1391,19 @@ impl AppState {
                 Msg::CopyDone(info) => {
                     progress = current_progress + 30.0;
                     resp_stream.report_progress("terminate", progress)?;
+                    if !info.manifest.is_empty() {
+                        self.save_manifest(&info.manifest)?;
+                        record.manifest = serde_json::from_slice(&info.manifest).ok();
+                    }
+                    record.step_done("write");
                     break ReportCopy {
                         status: "final_report",
                         error_path: info.error_path,
                         filtered_path: info.filtered_path,
                         dirty_path: info.dirty_path,
+                        skipped_path: info.skipped_path,
+                        device_error: info.device_error,
+                        damaged_path: info.damaged_path,
                     };
                 }
                 Msg::Error(err) => {
@@ -872,31 +1430,107 @@ impl AppState {
             })?;
         };
 
+        record.report = Some(serde_json::to_value(&final_report)?);
         resp_stream.add_message(final_report)?;
         resp_stream.done()?;
         Ok(())
     }
 
+    /// Keep a copy of the transfer manifest in the out directory for audit
+    fn save_manifest(&self, manifest: &[u8]) -> Result<(), ServiceError> {
+        let datetime = time::OffsetDateTime::now_utc();
+        fs::write(
+            format!(
+                "{}/manifest_{}{}{}{}{}{}.json",
+                self.config.lock()?.out_directory,
+                datetime.year(),
+                datetime.month(),
+                datetime.day(),
+                datetime.hour(),
+                datetime.minute(),
+                datetime.second(),
+            ),
+            manifest,
+        )?;
+        Ok(())
+    }
+
+    /// File system and mode of a wipe request
+    pub(crate) fn wipe_params(
+        fsfmt: &str,
+        mode: Option<&str>,
+    ) -> Result<(proto::common::OutFsType, proto::common::WipeMode), ServiceError> {
+        let fstype = match fsfmt {
+            "ntfs" => proto::common::OutFsType::Ntfs,
+            "exfat" => proto::common::OutFsType::Exfat,
+            "fat32" => proto::common::OutFsType::Fat,
+            _ => {
+                return Err(ServiceError::BadRequest(format!(
+                    "unknown file system: {}",
+                    fsfmt
+                )))
+            }
+        };
+        let mode = match mode {
+            None | Some("zerofill") => proto::common::WipeMode::Zerofill,
+            Some("randomfill") => proto::common::WipeMode::Randomfill,
+            Some("multipass") => proto::common::WipeMode::Multipass,
+            Some("erase") => proto::common::WipeMode::Erase,
+            Some(mode) => {
+                return Err(ServiceError::BadRequest(format!(
+                    "unknown wipe mode: {}",
+                    mode
+                )))
+            }
+        };
+        Ok((fstype, mode))
+    }
+
     pub(crate) fn wipe(
         &self,
         device: UsbDevice,
-        fsfmt: String,
+        fstype: proto::common::OutFsType,
         quick: bool,
+        mode: proto::common::WipeMode,
         resp_stream: ResponseStream,
+    ) -> Result<(), ServiceError> {
+        set_device_log_fields(false, &device);
+        let mut record = Record::new(
+            Operation::Wipe,
+            self.id().unwrap_or_default(),
+            None,
+            Some(DeviceDesc::from(&device)),
+        );
+        let result = self.wipe_device(
+            device,
+            fstype,
+            quick,
+            mode,
+            resp_stream.clone(),
+            &mut record,
+        );
+        if let Err(err) = self.add_record(record, &result, &resp_stream) {
+            error!("couldn't add the wipe to the history: {}", err);
+        }
+        result
+    }
+
+    fn wipe_device(
+        &self,
+        device: UsbDevice,
+        fstype: proto::common::OutFsType,
+        quick: bool,
+        mode: proto::common::WipeMode,
+        resp_stream: ResponseStream,
+        record: &mut Record,
     ) -> Result<(), ServiceError> {
         use proto::usbsas::response::Msg;
 
         let mut resp_stream = resp_stream;
         resp_stream.report_progress("wipe_start", 0.0)?;
 
-        let fstype = match fsfmt.as_str() {
-            "ntfs" => proto::common::OutFsType::Ntfs,
-            "exfat" => proto::common::OutFsType::Exfat,
-            "fat32" => proto::common::OutFsType::Fat,
-            _ => return Err(ServiceError::InternalServerError),
-        };
-
         let mut comm = self.comm.lock()?;
+        self.set_state(SessionState::Wiping)?;
         comm.send(proto::usbsas::Request {
             msg: Some(proto::usbsas::request::Msg::Wipe(
                 proto::usbsas::RequestWipe {
@@ -904,6 +1538,7 @@ impl AppState {
                     devnum: device.devnum,
                     fstype: fstype.into(),
                     quick,
+                    mode: mode.into(),
                 },
             )),
         })?;
@@ -911,11 +1546,14 @@ impl AppState {
         loop {
             let resp: proto::usbsas::Response = comm.recv()?;
             match resp.msg.ok_or(ServiceError::InternalServerError)? {
-                Msg::FinalCopyStatus(ref msg) => resp_stream.add_message(ReportDeviceSize {
-                    status: "wipe_status",
-                    current_size: msg.current_size,
-                    total_size: msg.total_size,
-                })?,
+                Msg::FinalCopyStatus(ref msg) => {
+                    record.written_size = msg.current_size;
+                    resp_stream.add_message(ReportDeviceSize {
+                        status: "wipe_status",
+                        current_size: msg.current_size,
+                        total_size: msg.total_size,
+                    })?
+                }
                 Msg::FinalCopyStatusDone(_) => resp_stream.add_message(ReportDeviceSize {
                     status: "format_status",
                     current_size: 0,
@@ -926,8 +1564,21 @@ impl AppState {
                     resp_stream.report_error(&err.err)?;
                     return Err(ServiceError::InternalServerError);
                 }
-                Msg::Wipe(_) => {
-                    resp_stream.report_progress("wipe_end", 0.0)?;
+                Msg::Wipe(rep) => {
+                    // The mode actually used, the device may not support
+                    // the requested one
+                    let mode = match rep.mode() {
+                        _ if quick => "quick",
+                        proto::common::WipeMode::Zerofill => "zerofill",
+                        proto::common::WipeMode::Randomfill => "randomfill",
+                        proto::common::WipeMode::Multipass => "multipass",
+                        proto::common::WipeMode::Erase => "erase",
+                    };
+                    resp_stream.add_message(ReportWipe {
+                        status: "wipe_end",
+                        mode,
+                        method: &rep.method,
+                    })?;
                     resp_stream.done()?;
                     break;
                 }
@@ -946,6 +1597,26 @@ impl AppState {
         &self,
         device: UsbDevice,
         resp_stream: ResponseStream,
+    ) -> Result<(), ServiceError> {
+        set_device_log_fields(true, &device);
+        let mut record = Record::new(
+            Operation::Imagedisk,
+            self.id().unwrap_or_default(),
+            Some(DeviceDesc::from(&device)),
+            None,
+        );
+        let result = self.image_device(device, resp_stream.clone(), &mut record);
+        if let Err(err) = self.add_record(record, &result, &resp_stream) {
+            error!("couldn't add the disk image to the history: {}", err);
+        }
+        result
+    }
+
+    fn image_device(
+        &self,
+        device: UsbDevice,
+        resp_stream: ResponseStream,
+        record: &mut Record,
     ) -> Result<(), ServiceError> {
         use proto::usbsas::response::Msg;
 
@@ -953,6 +1624,7 @@ impl AppState {
         resp_stream.report_progress("imgdisk_start", 0.0)?;
 
         let mut comm = self.comm.lock()?;
+        self.set_state(SessionState::Imaging)?;
 
         comm.send(proto::usbsas::Request {
             msg: Some(proto::usbsas::request::Msg::ImgDisk(
@@ -966,11 +1638,14 @@ impl AppState {
             let resp: proto::usbsas::Response = comm.recv()?;
             match resp.msg.ok_or(ServiceError::InternalServerError)? {
                 Msg::OpenDevice(_) => continue,
-                Msg::FinalCopyStatus(msg) => resp_stream.add_message(ReportDeviceSize {
-                    status: "imgdisk_update",
-                    current_size: msg.current_size,
-                    total_size: msg.total_size,
-                })?,
+                Msg::FinalCopyStatus(msg) => {
+                    record.read_size = msg.current_size;
+                    resp_stream.add_message(ReportDeviceSize {
+                        status: "imgdisk_update",
+                        current_size: msg.current_size,
+                        total_size: msg.total_size,
+                    })?
+                }
                 Msg::ImgDisk(_) => {
                     // Keep out fs
                     let datetime = time::OffsetDateTime::now_utc();
@@ -1020,25 +1695,33 @@ impl Drop for AppState {
     }
 }
 
-/// Struct that impl futures::Stream to report progress to the client
+/// Struct that impl futures::Stream to report progress to the client, the
+/// messages are also sent to the clients watching as "progress" events
 #[derive(Clone)]
 pub(crate) struct ResponseStream {
     /// Contains serialized messages to send
     messages: Arc<Mutex<Vec<u8>>>,
     done: Arc<AtomicBool>,
     waker: Arc<Mutex<Option<Waker>>>,
+    events: Arc<Events>,
+    /// Fatal error reported to the client
+    error: Arc<Mutex<Option<String>>>,
 }
 
 impl ResponseStream {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(events: Arc<Events>) -> Self {
         ResponseStream {
             messages: Arc::new(Mutex::new(Vec::new())),
             done: Arc::new(AtomicBool::new(false)),
             waker: Arc::new(Mutex::new(None)),
+            events,
+            error: Arc::new(Mutex::new(None)),
         }
     }
 
     fn add_serialized_message(&mut self, message: &mut Vec<u8>) -> Result<(), ServiceError> {
+        self.events
+            .send_transient("progress", &String::from_utf8_lossy(message))?;
         let mut messages = self.messages.lock()?;
         messages.append(message);
         // Also append "\r\n" in case multiple json messages are added between 2 polls
@@ -1059,6 +1742,7 @@ impl ResponseStream {
     }
 
     fn report_error(&mut self, msg: &str) -> Result<(), ServiceError> {
+        *self.error.lock()? = Some(msg.to_string());
         self.add_message(ReportError {
             status: "fatal_error",
             msg,
@@ -1066,6 +1750,10 @@ impl ResponseStream {
         self.done()
     }
 
+    fn error(&self) -> Result<Option<String>, ServiceError> {
+        Ok(self.error.lock()?.clone())
+    }
+
     fn done(&mut self) -> Result<(), ServiceError> {
         self.done.store(true, Ordering::Relaxed);
         if let Some(waker) = self.waker.lock()?.take() {
@@ -1091,3 +1779,41 @@ impl futures::Stream for ResponseStream {
         ))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_line() {
+        assert_eq!(
+            parse_log_line("[2022-11-21T10:00:00Z ERROR usbsas_files2fs] couldn't write: ENOSPC"),
+            (
+                log::Level::Error,
+                "usbsas_files2fs",
+                "couldn't write: ENOSPC"
+            )
+        );
+        assert_eq!(
+            parse_log_line("thread 'main' panicked at 'oops'"),
+            (
+                log::Level::Warn,
+                "usbsas",
+                "thread 'main' panicked at 'oops'"
+            )
+        );
+    }
+
+    #[cfg(feature = "log-json")]
+    #[test]
+    fn test_parse_json_log_line() {
+        let record = parse_json_log_line(
+            r#"{"ts":"2022-11-21T10:00:00Z", "level":"WARN", "target":"usbsas_usbdev", "msg":"refused", "transfer_id":"0"}"#,
+        )
+        .unwrap();
+        assert_eq!(record.level, log::Level::Warn);
+        assert_eq!(record.target, "usbsas_usbdev");
+        assert_eq!(record.msg, "refused");
+        assert!(parse_json_log_line("[2022-11-21T10:00:00Z INFO usbsas] text").is_none());
+    }
+}
@@ -11,8 +11,17 @@ pub(crate) enum ServiceError {
     #[error(display = "{}", _0)]
     Error(String),
 
+    #[error(display = "{}", _0)]
+    BadRequest(String),
+
     #[error(display = "Unauthorized")]
     Unauthorized,
+
+    #[error(display = "Forbidden")]
+    Forbidden,
+
+    #[error(display = "Administrator authentication required")]
+    AdminRequired,
 }
 
 #[derive(Debug)]
@@ -29,7 +38,12 @@ impl ResponseError for ServiceError {
                 HttpResponse::InternalServerError().json("Internal Server Error, Please try later")
             }
             ServiceError::Error(ref message) => HttpResponse::InternalServerError().json(message),
+            ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
             ServiceError::Unauthorized => HttpResponse::Unauthorized().json("Unauthorized"),
+            ServiceError::Forbidden => HttpResponse::Forbidden().json("Forbidden"),
+            ServiceError::AdminRequired => HttpResponse::Unauthorized()
+                .insert_header(("WWW-Authenticate", "Basic realm=\"usbsas admin\""))
+                .json("Administrator authentication required"),
         }
     }
 }
@@ -0,0 +1,342 @@
+//! Events pushed to the web clients with Server-Sent Events (device hotplug,
+//! user ID, state of the session and progress of the operations) and
+//! ownership of the session. Clients are identified by a cookie: one of them,
+//! the operator, controls usbsas while the others only watch.
+
+use crate::error::ServiceError;
+use actix_web::web;
+use futures::task::{Context, Poll, Waker};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+/// Cookie identifying the web clients
+pub(crate) const CLIENT_COOKIE: &str = "usbsas_client";
+
+// Max size of the events waiting to be sent to a client, slower clients are
+// disconnected (and get the last events again when they reconnect)
+const MAX_PENDING_SIZE: usize = 0x10_0000;
+
+// An operator that didn't subscribe to the events (API client) loses the
+// session after this long without acting on usbsas
+const OPERATOR_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+struct Subscriber {
+    client: String,
+    /// Serialized events not sent yet
+    messages: Mutex<Vec<u8>>,
+    waker: Mutex<Option<Waker>>,
+    /// The client didn't keep up with the events, its stream is ended
+    dropped: AtomicBool,
+}
+
+impl Subscriber {
+    fn push(&self, message: &[u8]) -> Result<(), ServiceError> {
+        if self.dropped.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut messages = self.messages.lock()?;
+        if messages.len() + message.len() > MAX_PENDING_SIZE {
+            log::warn!("client too slow to receive the events, disconnecting it");
+            self.dropped.store(true, Ordering::Relaxed);
+            *messages = Vec::new();
+        } else {
+            messages.extend_from_slice(message);
+        }
+        if let Some(waker) = self.waker.lock()?.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// Struct that impl futures::Stream to send the events to a client, the
+/// client unsubscribes when it is dropped (connection closed)
+pub(crate) struct EventStream(Arc<Subscriber>);
+
+impl futures::Stream for EventStream {
+    type Item = Result<web::Bytes, actix_web::Error>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // End the stream if the client was dropped or a lock is poisoned
+        if self.0.dropped.load(Ordering::Relaxed) {
+            return Poll::Ready(None);
+        }
+        let mut messages = match self.0.messages.lock() {
+            Ok(messages) => messages,
+            Err(_) => return Poll::Ready(None),
+        };
+        if messages.is_empty() {
+            match self.0.waker.lock() {
+                Ok(mut waker) => *waker = Some(cx.waker().clone()),
+                Err(_) => return Poll::Ready(None),
+            }
+            return Poll::Pending;
+        }
+        Poll::Ready(Some(Ok(web::Bytes::from(std::mem::take(&mut *messages)))))
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct SessionInfos {
+    /// This client controls usbsas
+    operator: bool,
+    /// A client controls usbsas
+    controlled: bool,
+}
+
+struct Operator {
+    client: String,
+    /// The operator subscribed to the events, it is gone once it has no
+    /// subscription left
+    subscribed: bool,
+    /// Last time the operator acted on usbsas, an operator that didn't
+    /// subscribe is gone once it has been idle for too long
+    last_active: Instant,
+}
+
+impl Operator {
+    fn idle(&self, timeout: Duration) -> bool {
+        !self.subscribed && self.last_active.elapsed() > timeout
+    }
+}
+
+pub(crate) struct Events {
+    subscribers: Mutex<Vec<Weak<Subscriber>>>,
+    /// Last data of the events replayed to new subscribers
+    last: Mutex<HashMap<&'static str, String>>,
+    operator: Mutex<Option<Operator>>,
+    operator_idle_timeout: Duration,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            subscribers: Mutex::new(Vec::new()),
+            last: Mutex::new(HashMap::new()),
+            operator: Mutex::new(None),
+            operator_idle_timeout: OPERATOR_IDLE_TIMEOUT,
+        }
+    }
+}
+
+fn format_event(event: &str, data: &str) -> Vec<u8> {
+    format!("event: {}\ndata: {}\n\n", event, data).into_bytes()
+}
+
+fn session_event(operator: Option<&str>, client: &str) -> Result<Vec<u8>, ServiceError> {
+    let infos = SessionInfos {
+        operator: operator == Some(client),
+        controlled: operator.is_some(),
+    };
+    Ok(format_event("session", &serde_json::to_string(&infos)?))
+}
+
+impl Events {
+    pub(crate) fn subscribe(&self, client: &str) -> Result<EventStream, ServiceError> {
+        let subscriber = Arc::new(Subscriber {
+            client: client.to_string(),
+            messages: Mutex::new(Vec::new()),
+            waker: Mutex::new(None),
+            dropped: AtomicBool::new(false),
+        });
+        for (event, data) in self.last.lock()?.iter() {
+            subscriber.push(&format_event(event, data))?;
+        }
+        self.subscribers.lock()?.push(Arc::downgrade(&subscriber));
+        if let Some(operator) = self.operator.lock()?.as_mut() {
+            if operator.client == client {
+                operator.subscribed = true;
+            }
+        }
+        subscriber.push(&session_event(self.operator()?.as_deref(), client)?)?;
+        Ok(EventStream(subscriber))
+    }
+
+    fn for_each(
+        &self,
+        func: impl Fn(&Subscriber) -> Result<(), ServiceError>,
+    ) -> Result<(), ServiceError> {
+        let mut subscribers = self.subscribers.lock()?;
+        subscribers.retain(|subscriber| {
+            subscriber.upgrade().map_or(false, |subscriber| {
+                !subscriber.dropped.load(Ordering::Relaxed)
+            })
+        });
+        for subscriber in subscribers.iter().filter_map(Weak::upgrade) {
+            func(&subscriber)?;
+        }
+        Ok(())
+    }
+
+    /// Send an event to all clients, and to the ones subscribing later
+    pub(crate) fn send<T: Serialize>(
+        &self,
+        event: &'static str,
+        data: &T,
+    ) -> Result<(), ServiceError> {
+        let data = serde_json::to_string(data)?;
+        let message = format_event(event, &data);
+        self.last.lock()?.insert(event, data);
+        self.for_each(|subscriber| subscriber.push(&message))
+    }
+
+    /// Send an event only if its data changed since it was last sent
+    pub(crate) fn send_changed<T: Serialize>(
+        &self,
+        event: &'static str,
+        data: &T,
+    ) -> Result<(), ServiceError> {
+        let data_str = serde_json::to_string(data)?;
+        if self.last.lock()?.get(event) == Some(&data_str) {
+            return Ok(());
+        }
+        self.send(event, data)
+    }
+
+    /// Send an event to the current subscribers only (progress of an
+    /// operation)
+    pub(crate) fn send_transient(&self, event: &str, data: &str) -> Result<(), ServiceError> {
+        let message = format_event(event, data);
+        self.for_each(|subscriber| subscriber.push(&message))
+    }
+
+    /// Keep the connections alive, closed ones are noticed when writing.
+    /// Release the session of an operator whose clients are all gone, or that
+    /// never subscribed and is idle.
+    pub(crate) fn heartbeat(&self) -> Result<(), ServiceError> {
+        self.for_each(|subscriber| subscriber.push(b": heartbeat\n\n"))?;
+        let mut operator = self.operator.lock()?;
+        let gone = match *operator {
+            Some(ref op) if op.subscribed => !self.is_connected(&op.client)?,
+            Some(ref op) => op.idle(self.operator_idle_timeout),
+            None => false,
+        };
+        if gone {
+            log::info!("operator gone, releasing the session");
+            *operator = None;
+            drop(operator);
+            self.send_session()?;
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self, client: &str) -> Result<bool, ServiceError> {
+        Ok(self
+            .subscribers
+            .lock()?
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|subscriber| {
+                subscriber.client == client && !subscriber.dropped.load(Ordering::Relaxed)
+            }))
+    }
+
+    fn operator(&self) -> Result<Option<String>, ServiceError> {
+        Ok(self.operator.lock()?.as_ref().map(|op| op.client.clone()))
+    }
+
+    fn send_session(&self) -> Result<(), ServiceError> {
+        let operator = self.operator()?;
+        self.for_each(|subscriber| {
+            subscriber.push(&session_event(operator.as_deref(), &subscriber.client)?)
+        })
+    }
+
+    /// Make `client` the operator if no other client is. Return whether it is.
+    pub(crate) fn take_control(&self, client: &str) -> Result<bool, ServiceError> {
+        let mut operator = self.operator.lock()?;
+        if let Some(ref mut op) = *operator {
+            if op.client == client {
+                op.last_active = Instant::now();
+                return Ok(true);
+            }
+            if (!op.subscribed && !op.idle(self.operator_idle_timeout))
+                || self.is_connected(&op.client)?
+            {
+                return Ok(false);
+            }
+        }
+        log::info!("new operator");
+        *operator = Some(Operator {
+            client: client.to_string(),
+            subscribed: self.is_connected(client)?,
+            last_active: Instant::now(),
+        });
+        drop(operator);
+        self.send_session()?;
+        Ok(true)
+    }
+
+    pub(crate) fn release_control(&self, client: &str) -> Result<(), ServiceError> {
+        let mut operator = self.operator.lock()?;
+        if matches!(*operator, Some(ref op) if op.client == client) {
+            *operator = None;
+            drop(operator);
+            self.send_session()?;
+        }
+        Ok(())
+    }
+
+    /// Only the operator can act on usbsas
+    pub(crate) fn check_operator(&self, client: Option<&str>) -> Result<(), ServiceError> {
+        match (self.operator.lock()?.as_mut(), client) {
+            (Some(op), Some(client)) if op.client == client => {
+                op.last_active = Instant::now();
+                Ok(())
+            }
+            _ => Err(ServiceError::Forbidden),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_operator() {
+        let mut events = Events::default();
+        assert!(events.take_control("api").unwrap());
+        assert!(!events.take_control("other").unwrap());
+        events.heartbeat().unwrap();
+        assert!(events.check_operator(Some("api")).is_ok());
+
+        // An operator that never subscribed is released once idle
+        events.operator_idle_timeout = Duration::ZERO;
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(events.take_control("other").unwrap());
+        assert!(events.check_operator(Some("api")).is_err());
+        std::thread::sleep(Duration::from_millis(10));
+        events.heartbeat().unwrap();
+        assert!(events.operator().unwrap().is_none());
+
+        // Not if it subscribed
+        let _stream = events.subscribe("web").unwrap();
+        assert!(events.take_control("web").unwrap());
+        std::thread::sleep(Duration::from_millis(10));
+        events.heartbeat().unwrap();
+        assert!(!events.take_control("other").unwrap());
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        let events = Events::default();
+        let stream = events.subscribe("slow").unwrap();
+        let data = "x".repeat(0x1000);
+        for _ in 0..=MAX_PENDING_SIZE / data.len() {
+            events.send_transient("progress", &data).unwrap();
+        }
+        assert!(stream.0.dropped.load(Ordering::Relaxed));
+        assert!(stream.0.messages.lock().unwrap().is_empty());
+        assert!(!events.is_connected("slow").unwrap());
+        events.heartbeat().unwrap();
+        assert!(events.subscribers.lock().unwrap().is_empty());
+    }
+}
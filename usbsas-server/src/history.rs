@@ -0,0 +1,459 @@
+//! History of the transfers, wipes and disk images. Each operation is recorded
+//! as a JSON line appended to a file, which is rotated when it gets too big.
+//! Rotated files are deleted after the retention period.
+
+use crate::appstate::DeviceDesc;
+use crate::error::ServiceError;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MAX_SIZE: u64 = 64;
+// Records returned by a search if no limit is given
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Operation {
+    Copy,
+    Wipe,
+    Imagedisk,
+}
+
+impl Operation {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Copy => "copy",
+            Operation::Wipe => "wipe",
+            Operation::Imagedisk => "imagedisk",
+        }
+    }
+}
+
+/// Verdicts of the rejected files, by field of the copy report
+pub(crate) const REPORT_VERDICTS: [(&str, &str); 5] = [
+    ("filtered_path", "filtered"),
+    ("dirty_path", "dirty"),
+    ("skipped_path", "skipped"),
+    ("damaged_path", "damaged"),
+    ("error_path", "error"),
+];
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Record {
+    /// Unix time of the start of the operation
+    pub(crate) time: i64,
+    pub(crate) operation: Operation,
+    /// "done", "nothing_to_copy", "not_enough_space" or "error"
+    pub(crate) outcome: String,
+    #[serde(default)]
+    pub(crate) error: Option<String>,
+    pub(crate) user_id: String,
+    pub(crate) source: Option<DeviceDesc>,
+    pub(crate) destination: Option<DeviceDesc>,
+    /// Report of the copy (rejected files with the reason: filtered, dirty
+    /// according to the analyzer, skipped, damaged or in error)
+    #[serde(default)]
+    pub(crate) report: Option<serde_json::Value>,
+    /// Manifest of the copy (written files with their hashes)
+    #[serde(default)]
+    pub(crate) manifest: Option<serde_json::Value>,
+    /// Bytes read from the source device
+    #[serde(default)]
+    pub(crate) read_size: u64,
+    /// Bytes written on the destination
+    #[serde(default)]
+    pub(crate) written_size: u64,
+    /// Duration (in seconds) of the operation and of its steps
+    pub(crate) duration: f64,
+    #[serde(default)]
+    pub(crate) timings: BTreeMap<String, f64>,
+    #[serde(skip)]
+    started: Option<Instant>,
+    #[serde(skip)]
+    last_step: Option<Instant>,
+}
+
+impl Record {
+    pub(crate) fn new(
+        operation: Operation,
+        user_id: String,
+        source: Option<DeviceDesc>,
+        destination: Option<DeviceDesc>,
+    ) -> Self {
+        let now = Instant::now();
+        Record {
+            time: time::OffsetDateTime::now_utc().unix_timestamp(),
+            operation,
+            outcome: String::new(),
+            error: None,
+            user_id,
+            source,
+            destination,
+            report: None,
+            manifest: None,
+            read_size: 0,
+            written_size: 0,
+            duration: 0.0,
+            timings: BTreeMap::new(),
+            started: Some(now),
+            last_step: Some(now),
+        }
+    }
+
+    /// Record the duration of a step, since the end of the previous one
+    pub(crate) fn step_done(&mut self, step: &str) {
+        let now = Instant::now();
+        if let Some(last_step) = self.last_step.replace(now) {
+            self.timings
+                .insert(step.to_string(), (now - last_step).as_secs_f64());
+        }
+    }
+
+    pub(crate) fn finish<T>(&mut self, result: &Result<T, ServiceError>, error: Option<String>) {
+        if let Some(started) = self.started {
+            self.duration = started.elapsed().as_secs_f64();
+        }
+        if result.is_err() {
+            self.outcome = "error".into();
+            self.error = error.or_else(|| result.as_ref().err().map(|err| err.to_string()));
+        } else if self.outcome.is_empty() {
+            self.outcome = "done".into();
+        }
+    }
+
+    /// Number of files of the manifest
+    pub(crate) fn copied_files(&self) -> usize {
+        self.manifest
+            .as_ref()
+            .and_then(|manifest| manifest["files"].as_object())
+            .map(|files| files.len())
+            .unwrap_or(0)
+    }
+
+    /// Paths rejected with a verdict
+    pub(crate) fn rejected_files<'a>(&'a self, field: &str) -> impl Iterator<Item = &'a str> {
+        self.report
+            .as_ref()
+            .and_then(|report| report[field].as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|path| path.as_str())
+    }
+
+    /// Log the outcome of the operation and the verdict of each rejected
+    /// file, as events with structured fields
+    pub(crate) fn log_events(&self) {
+        let operation = self.operation.as_str();
+        let duration = format!("{:.3}", self.duration);
+        let files = self.copied_files().to_string();
+        let mut fields = vec![
+            ("operation", operation),
+            ("outcome", self.outcome.as_str()),
+            ("duration", duration.as_str()),
+            ("files", files.as_str()),
+        ];
+        if let Some(error) = &self.error {
+            fields.push(("error", error.as_str()));
+        }
+        let level = match self.outcome.as_str() {
+            "error" => log::Level::Error,
+            _ => log::Level::Info,
+        };
+        usbsas_utils::log::event(
+            level,
+            module_path!(),
+            &format!("{} {}", operation, self.outcome),
+            &fields,
+        );
+        for (field, verdict) in REPORT_VERDICTS {
+            for path in self.rejected_files(field) {
+                let level = match verdict {
+                    "dirty" | "error" => log::Level::Warn,
+                    _ => log::Level::Info,
+                };
+                usbsas_utils::log::event(
+                    level,
+                    module_path!(),
+                    &format!("file {} rejected: {}", path, verdict),
+                    &[
+                        ("operation", operation),
+                        ("path", path),
+                        ("verdict", verdict),
+                    ],
+                );
+            }
+        }
+    }
+
+    fn matches_file(&self, file: &str) -> bool {
+        let in_manifest = self
+            .manifest
+            .as_ref()
+            .and_then(|manifest| manifest["files"].as_object())
+            .map(|files| files.keys().any(|path| path.contains(file)))
+            .unwrap_or(false);
+        let in_report = self
+            .report
+            .as_ref()
+            .and_then(|report| report.as_object())
+            .map(|report| {
+                report
+                    .values()
+                    .filter_map(|value| value.as_array())
+                    .flatten()
+                    .filter_map(|path| path.as_str())
+                    .any(|path| path.contains(file))
+            })
+            .unwrap_or(false);
+        in_manifest || in_report
+    }
+}
+
+/// Search parameters of the history, records must match all given ones
+#[derive(Default, Deserialize)]
+pub(crate) struct HistoryQuery {
+    user_id: Option<String>,
+    /// Unix time, inclusive
+    since: Option<i64>,
+    /// Unix time, exclusive
+    until: Option<i64>,
+    operation: Option<Operation>,
+    outcome: Option<String>,
+    /// Part of the description, manufacturer or serial number of the source
+    /// or destination device
+    device: Option<String>,
+    /// Part of the path of a copied or rejected file
+    file: Option<String>,
+    /// Maximum number of records returned by a search, the most recent ones
+    limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn matches(&self, record: &Record) -> bool {
+        self.user_id
+            .as_ref()
+            .map_or(true, |id| &record.user_id == id)
+            && self.since.map_or(true, |since| record.time >= since)
+            && self.until.map_or(true, |until| record.time < until)
+            && self.operation.map_or(true, |op| record.operation == op)
+            && self
+                .outcome
+                .as_ref()
+                .map_or(true, |outcome| &record.outcome == outcome)
+            && self.device.as_ref().map_or(true, |device| {
+                [&record.source, &record.destination]
+                    .into_iter()
+                    .any(|desc| desc.as_ref().map_or(false, |desc| desc.matches(device)))
+            })
+            && self
+                .file
+                .as_ref()
+                .map_or(true, |file| record.matches_file(file))
+    }
+}
+
+pub(crate) struct History {
+    path: PathBuf,
+    max_size: u64,
+    retention: Option<Duration>,
+}
+
+impl History {
+    pub(crate) fn new(config: &usbsas_config::History) -> Self {
+        History {
+            path: PathBuf::from(&config.path),
+            max_size: config.max_size.unwrap_or(DEFAULT_MAX_SIZE) * 1024 * 1024,
+            retention: config
+                .retention_days
+                .map(|days| Duration::from_secs(days * 24 * 3600)),
+        }
+    }
+
+    pub(crate) fn add(&self, record: &Record) -> Result<(), ServiceError> {
+        self.rotate()?;
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)?
+            .write_all(&line)?;
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<(), ServiceError> {
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        if size > 0 && size >= self.max_size {
+            // <path>.<unix time>, with a counter if rotated more than once in
+            // the same second
+            let time = time::OffsetDateTime::now_utc().unix_timestamp();
+            let mut rotated = format!("{}.{}", self.path.display(), time);
+            let mut counter = 0;
+            while Path::new(&rotated).exists() {
+                counter += 1;
+                rotated = format!("{}.{}.{}", self.path.display(), time, counter);
+            }
+            info!("rotating history to {}", rotated);
+            fs::rename(&self.path, rotated)?;
+        }
+        if let Some(retention) = self.retention {
+            for file in self.rotated_files()? {
+                let expired = fs::metadata(&file)?
+                    .modified()?
+                    .elapsed()
+                    .map(|age| age > retention)
+                    .unwrap_or(false);
+                if expired {
+                    info!("removing expired history {}", file.display());
+                    if let Err(err) = fs::remove_file(&file) {
+                        error!("couldn't remove {}: {}", file.display(), err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rotated files, the oldest first
+    fn rotated_files(&self) -> Result<Vec<PathBuf>, ServiceError> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = match self.path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!("{}.", name),
+            None => return Ok(vec![]),
+        };
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let rotated_time = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|suffix| match suffix.split_once('.') {
+                    Some((time, counter)) => {
+                        Some((time.parse::<i64>().ok()?, counter.parse().ok()?))
+                    }
+                    None => Some((suffix.parse::<i64>().ok()?, 0u32)),
+                });
+            if let Some(rotated_time) = rotated_time {
+                files.push((rotated_time, entry.path()));
+            }
+        }
+        files.sort();
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Call `func` on the records matching the query, the oldest first
+    fn for_each(
+        &self,
+        query: &HistoryQuery,
+        mut func: impl FnMut(Record, &str) -> Result<(), ServiceError>,
+    ) -> Result<(), ServiceError> {
+        let mut files = self.rotated_files()?;
+        files.push(self.path.clone());
+        for file in files {
+            let reader = match fs::File::open(&file) {
+                Ok(reader) => BufReader::new(reader),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for line in reader.lines() {
+                let line = line?;
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) if query.matches(&record) => func(record, &line)?,
+                    Ok(_) => (),
+                    Err(err) => warn!("invalid record in {}: {}", file.display(), err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records matching the query, the most recent first
+    pub(crate) fn search(&self, query: &HistoryQuery) -> Result<Vec<Record>, ServiceError> {
+        let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+        let mut records = std::collections::VecDeque::new();
+        self.for_each(query, |record, _| {
+            if records.len() == limit {
+                records.pop_front();
+            }
+            if limit > 0 {
+                records.push_back(record);
+            }
+            Ok(())
+        })?;
+        Ok(records.into_iter().rev().collect())
+    }
+
+    /// Records matching the query as JSON lines, the oldest first
+    pub(crate) fn export(&self, query: &HistoryQuery) -> Result<Vec<u8>, ServiceError> {
+        let mut lines = vec![];
+        self.for_each(query, |_, line| {
+            lines.extend_from_slice(line.as_bytes());
+            lines.push(b'\n');
+            Ok(())
+        })?;
+        Ok(lines)
+    }
+}
+
+/// Check that the directory of the history exists
+pub(crate) fn check_path(path: &str) -> Result<(), String> {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        return Err(format!("{} isn't a directory", dir.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        // Every record is rotated when the next one is added
+        let history = History {
+            path: path.clone(),
+            max_size: 1,
+            retention: None,
+        };
+        for user_id in 0..5 {
+            history
+                .add(&Record::new(
+                    Operation::Wipe,
+                    user_id.to_string(),
+                    None,
+                    None,
+                ))
+                .unwrap();
+        }
+        assert_eq!(history.rotated_files().unwrap().len(), 4);
+        let user_ids: Vec<String> = history
+            .search(&HistoryQuery::default())
+            .unwrap()
+            .into_iter()
+            .map(|record| record.user_id)
+            .collect();
+        assert_eq!(user_ids, ["4", "3", "2", "1", "0"]);
+    }
+}
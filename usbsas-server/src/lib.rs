@@ -2,8 +2,12 @@
 //! (starting and resetting) and exposes an API on which clients can perform
 //! transfers.
 
+pub mod admin;
 pub mod appstate;
 pub(crate) mod error;
+pub(crate) mod events;
+pub(crate) mod history;
+pub(crate) mod metrics;
 pub mod server;
 pub(crate) mod srv_infos;
 pub(crate) mod tmpfiles;
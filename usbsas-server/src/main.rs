@@ -1,4 +1,4 @@
-use std::io;
+use std::io::{self, BufRead};
 
 fn main() -> io::Result<()> {
     let matches = clap::Command::new("usbsas-server")
@@ -29,8 +29,26 @@ fn main() -> io::Result<()> {
                 .required(false)
                 .default_value("8080"),
         )
+        .arg(
+            clap::Arg::new("hash_password")
+                .long("hash-password")
+                .help("Read a password on stdin and print its hash for the admin configuration")
+                .action(clap::ArgAction::SetTrue),
+        )
         .get_matches();
 
+    if matches.get_flag("hash_password") {
+        eprintln!("Password:");
+        let mut password = String::new();
+        io::stdin().lock().read_line(&mut password)?;
+        let hash = usbsas_server::admin::hash_password(
+            password.trim_end_matches(|c| c == '\r' || c == '\n'),
+        )
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        println!("{}", hash);
+        return Ok(());
+    }
+
     let config_path = matches.get_one::<String>("config").unwrap().to_string();
     let ip = matches.get_one("bind_addr").unwrap();
     let port = matches.get_one("bind_port").unwrap();
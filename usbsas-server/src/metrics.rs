@@ -0,0 +1,312 @@
+//! Metrics of the server in the text format of Prometheus, served on
+//! `/metrics`: operations by outcome and destination, bytes read and written,
+//! rejected files, durations of the analyses and read throughput of the
+//! devices, and crashes of usbsas and of its children.
+
+use crate::appstate::DevType;
+use crate::error::ServiceError;
+use crate::history::{Record, REPORT_VERDICTS};
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+const ANALYZE_BUCKETS: [f64; 10] = [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+const DURATION_BUCKETS: [f64; 10] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+const THROUGHPUT_BUCKETS: [f64; 8] = [1e6, 2.5e6, 5e6, 10e6, 25e6, 50e6, 100e6, 250e6];
+
+struct Histogram {
+    buckets: &'static [f64],
+    /// Observations of each bucket, not cumulated
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = self.buckets.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulated = 0;
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            cumulated += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, cumulated
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+struct Values {
+    /// Operations by operation, outcome and destination
+    operations: BTreeMap<(&'static str, String, &'static str), u64>,
+    read_bytes: u64,
+    written_bytes: u64,
+    copied_files: u64,
+    rejected_files: BTreeMap<&'static str, u64>,
+    operation_duration: BTreeMap<&'static str, Histogram>,
+    analyze_duration: Histogram,
+    read_throughput: Histogram,
+    usbsas_crashes: u64,
+    /// Abnormal ends of the children of usbsas, by process
+    child_crashes: BTreeMap<String, u64>,
+}
+
+pub(crate) struct Metrics(Mutex<Values>);
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Metrics(Mutex::new(Values {
+            operations: BTreeMap::new(),
+            read_bytes: 0,
+            written_bytes: 0,
+            copied_files: 0,
+            rejected_files: BTreeMap::new(),
+            operation_duration: BTreeMap::new(),
+            analyze_duration: Histogram::new(&ANALYZE_BUCKETS),
+            read_throughput: Histogram::new(&THROUGHPUT_BUCKETS),
+            usbsas_crashes: 0,
+            child_crashes: BTreeMap::new(),
+        }))
+    }
+
+    /// Account an operation
+    pub(crate) fn record(&self, record: &Record) -> Result<(), ServiceError> {
+        let mut values = self.0.lock()?;
+        let operation = record.operation.as_str();
+        let destination = match record.destination.as_ref().map(|desc| &desc.dev_type) {
+            Some(DevType::Usb) => "usb",
+            Some(DevType::Net) => "net",
+            Some(DevType::Cmd) => "cmd",
+            None => "none",
+        };
+        *values
+            .operations
+            .entry((operation, record.outcome.clone(), destination))
+            .or_default() += 1;
+        values.read_bytes += record.read_size;
+        values.written_bytes += record.written_size;
+        values.copied_files += record.copied_files() as u64;
+        for (field, verdict) in REPORT_VERDICTS {
+            *values.rejected_files.entry(verdict).or_default() +=
+                record.rejected_files(field).count() as u64;
+        }
+        values
+            .operation_duration
+            .entry(operation)
+            .or_insert_with(|| Histogram::new(&DURATION_BUCKETS))
+            .observe(record.duration);
+        if let Some(duration) = record.timings.get("analyze") {
+            values.analyze_duration.observe(*duration);
+        }
+        // Copies read the source device while they build the archive, the
+        // whole operation for disk images
+        let read_duration = match record.timings.get("tar") {
+            Some(duration) => *duration,
+            None => record.duration,
+        };
+        if record.read_size > 0 && read_duration > 0.0 {
+            values
+                .read_throughput
+                .observe(record.read_size as f64 / read_duration);
+        }
+        Ok(())
+    }
+
+    /// usbsas ended abnormally
+    pub(crate) fn usbsas_crashed(&self) -> Result<(), ServiceError> {
+        self.0.lock()?.usbsas_crashes += 1;
+        Ok(())
+    }
+
+    /// A child of usbsas (e.g. `scsi2files`) ended abnormally
+    pub(crate) fn child_crashed(&self, process: &str) -> Result<(), ServiceError> {
+        *self
+            .0
+            .lock()?
+            .child_crashes
+            .entry(process.to_string())
+            .or_default() += 1;
+        Ok(())
+    }
+
+    pub(crate) fn render(&self) -> Result<String, ServiceError> {
+        let values = self.0.lock()?;
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "usbsas_operations_total",
+            "counter",
+            "Transfers, wipes and disk images by outcome and destination type",
+        );
+        for ((operation, outcome, destination), count) in &values.operations {
+            let _ = writeln!(
+                out,
+                "usbsas_operations_total{{operation=\"{}\",outcome=\"{}\",destination=\"{}\"}} {}",
+                operation, outcome, destination, count
+            );
+        }
+
+        write_header(
+            &mut out,
+            "usbsas_read_bytes_total",
+            "counter",
+            "Bytes read from the source devices",
+        );
+        let _ = writeln!(out, "usbsas_read_bytes_total {}", values.read_bytes);
+        write_header(
+            &mut out,
+            "usbsas_written_bytes_total",
+            "counter",
+            "Bytes written on the destinations",
+        );
+        let _ = writeln!(out, "usbsas_written_bytes_total {}", values.written_bytes);
+
+        write_header(
+            &mut out,
+            "usbsas_copied_files_total",
+            "counter",
+            "Files copied on the destinations",
+        );
+        let _ = writeln!(out, "usbsas_copied_files_total {}", values.copied_files);
+        write_header(
+            &mut out,
+            "usbsas_rejected_files_total",
+            "counter",
+            "Files rejected by verdict (filtered, dirty, skipped, damaged, error)",
+        );
+        for (verdict, count) in &values.rejected_files {
+            let _ = writeln!(
+                out,
+                "usbsas_rejected_files_total{{verdict=\"{}\"}} {}",
+                verdict, count
+            );
+        }
+
+        write_header(
+            &mut out,
+            "usbsas_operation_duration_seconds",
+            "histogram",
+            "Duration of the operations",
+        );
+        for (operation, histogram) in &values.operation_duration {
+            histogram.write(
+                &mut out,
+                "usbsas_operation_duration_seconds",
+                &format!("operation=\"{}\",", operation),
+            );
+        }
+        write_header(
+            &mut out,
+            "usbsas_analyze_duration_seconds",
+            "histogram",
+            "Duration of the analyses of the files",
+        );
+        values
+            .analyze_duration
+            .write(&mut out, "usbsas_analyze_duration_seconds", "");
+        write_header(
+            &mut out,
+            "usbsas_read_throughput_bytes_per_second",
+            "histogram",
+            "Read throughput of the source devices",
+        );
+        values
+            .read_throughput
+            .write(&mut out, "usbsas_read_throughput_bytes_per_second", "");
+
+        write_header(
+            &mut out,
+            "usbsas_crashes_total",
+            "counter",
+            "Abnormal ends of usbsas",
+        );
+        let _ = writeln!(out, "usbsas_crashes_total {}", values.usbsas_crashes);
+        write_header(
+            &mut out,
+            "usbsas_child_crashes_total",
+            "counter",
+            "Abnormal ends of the children of usbsas by process",
+        );
+        for (process, count) in &values.child_crashes {
+            let _ = writeln!(
+                out,
+                "usbsas_child_crashes_total{{process=\"{}\"}} {}",
+                process, count
+            );
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_write() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        histogram.observe(10.0);
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test", "");
+        assert_eq!(
+            out,
+            "test_bucket{le=\"1\"} 2\n\
+             test_bucket{le=\"5\"} 3\n\
+             test_bucket{le=\"+Inf\"} 4\n\
+             test_sum 14.5\n\
+             test_count 4\n"
+        );
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test", "operation=\"copy\",");
+        assert_eq!(
+            out,
+            "test_bucket{operation=\"copy\",le=\"1\"} 2\n\
+             test_bucket{operation=\"copy\",le=\"5\"} 3\n\
+             test_bucket{operation=\"copy\",le=\"+Inf\"} 4\n\
+             test_sum{operation=\"copy\"} 14.5\n\
+             test_count{operation=\"copy\"} 4\n"
+        );
+    }
+}
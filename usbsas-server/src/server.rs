@@ -1,14 +1,171 @@
-use crate::appstate::{AppState, CopyIn, DeviceDesc, ReadDirQuery, ResponseStream, UsbsasInfos};
+use crate::admin::Admin;
+use crate::appstate::{
+    new_session_id, AppState, CopyIn, DeviceDesc, DirSizeQuery, ReadDir, ReadDirQuery,
+    ResponseStream, SessionState, WipeQuery,
+};
 use crate::error::ServiceError;
+use crate::events::CLIENT_COOKIE;
+use crate::history::HistoryQuery;
 use crate::srv_infos::get_server_infos;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
-use log::info;
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    delete, get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use log::{error, info};
+use nix::sys::signal::{SigSet, Signal};
+use rand::Rng;
+use serde::{Serialize, Serializer};
 use std::{
-    collections::HashMap,
     io::{self, ErrorKind},
+    path::PathBuf,
+    sync::{Arc, RwLock},
     thread,
+    time::Duration,
 };
-use usbsas_config::{conf_parse, conf_read};
+use usbsas_config::{conf_parse, conf_read, LogOutput, Logging, SyslogProtocol};
+use usbsas_utils::log::{Output, Protocol, SyslogConfig};
+
+// Size (in MB) of the log spool if not configured
+const DEFAULT_LOG_SPOOL_SIZE: u64 = 64;
+
+// Seconds between two heartbeats on the event streams
+const HEARTBEAT_INTERVAL: u64 = 15;
+
+/// Identifier of the client, and the cookie to set if it's a new one
+fn client_id(req: &HttpRequest) -> (String, Option<Cookie<'static>>) {
+    if let Some(cookie) = req.cookie(CLIENT_COOKIE) {
+        return (cookie.value().to_string(), None);
+    }
+    let client = format!("{:032x}", rand::thread_rng().gen::<u128>());
+    let cookie = Cookie::build(CLIENT_COOKIE, client.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish();
+    (client, Some(cookie))
+}
+
+/// Only the operator can act on usbsas, other clients watch
+fn check_operator(req: &HttpRequest, data: &AppState) -> Result<(), ServiceError> {
+    let client = req.cookie(CLIENT_COOKIE);
+    data.events
+        .check_operator(client.as_ref().map(|cookie| cookie.value()))
+}
+
+fn end_state<T>(result: &Result<T, ServiceError>) -> SessionState {
+    match result {
+        Ok(_) => SessionState::Done,
+        Err(_) => SessionState::Error,
+    }
+}
+
+/// Output of the logs according to the configuration
+pub(crate) fn log_output(logging: Option<&Logging>) -> io::Result<Output> {
+    let logging = match logging {
+        Some(logging) => logging.clone(),
+        None => return Ok(Output::Stderr),
+    };
+    Ok(match logging.output {
+        LogOutput::Stderr => Output::Stderr,
+        LogOutput::Journald => Output::Journald,
+        LogOutput::Syslog => Output::Syslog(SyslogConfig {
+            address: logging.address.ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "no address for the syslog output")
+            })?,
+            protocol: match logging.protocol {
+                SyslogProtocol::Udp => Protocol::Udp,
+                SyslogProtocol::Tcp => Protocol::Tcp,
+                SyslogProtocol::Tls => Protocol::Tls,
+            },
+            ca_file: logging.ca_file.map(PathBuf::from),
+            spool_dir: logging.spool_dir.map(PathBuf::from),
+            spool_max_size: logging.spool_max_size.unwrap_or(DEFAULT_LOG_SPOOL_SIZE) * 1024 * 1024,
+        }),
+    })
+}
+
+/// Send the devices and user ID to the clients when they change, and keep
+/// their event streams alive
+fn watch(data: web::Data<AppState>) {
+    let mut ticks = 0;
+    loop {
+        if let Err(err) = data.refresh() {
+            error!("couldn't refresh devices: {}", err);
+        }
+        ticks += 1;
+        if ticks % HEARTBEAT_INTERVAL == 0 {
+            if let Err(err) = data.events.heartbeat() {
+                error!("couldn't send heartbeat: {}", err);
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Reload the configuration when SIGHUP is received
+fn reload_on_sighup(data: web::Data<AppState>, sigset: SigSet) {
+    loop {
+        match sigset.wait() {
+            Ok(_) => {
+                info!("SIGHUP received, reloading configuration");
+                if let Err(err) = data.reload_config() {
+                    error!("couldn't reload configuration: {}", err);
+                }
+            }
+            Err(err) => {
+                error!("couldn't wait for SIGHUP: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+#[get("/events")]
+async fn events(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    let (client, cookie) = client_id(&req);
+    let stream = data.events.subscribe(&client)?;
+    let mut resp = HttpResponse::Ok();
+    resp.content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"));
+    if let Some(cookie) = cookie {
+        resp.cookie(cookie);
+    }
+    Ok(resp.streaming(stream))
+}
+
+/// Take control of usbsas, refused if another client has it
+#[post("/session")]
+async fn take_session(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    let (client, cookie) = client_id(&req);
+    let mut resp = if data.events.take_control(&client)? {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::Conflict()
+    };
+    if let Some(cookie) = cookie {
+        resp.cookie(cookie);
+    }
+    Ok(resp.finish())
+}
+
+#[delete("/session")]
+async fn release_session(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    if let Some(cookie) = req.cookie(CLIENT_COOKIE) {
+        data.events.release_control(cookie.value())?;
+    }
+    Ok(HttpResponse::Ok())
+}
 
 #[get("/id")]
 async fn id(data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
@@ -18,23 +175,11 @@ async fn id(data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
 
 #[get("/usbsas_infos")]
 async fn usbsas_infos(data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
-    let node_name = match uname::Info::new() {
-        Ok(infos) => infos.nodename,
-        _ => "Unknown".to_string(),
-    };
-    /* Re-read conf to get message updates */
-    let config_str = conf_read(&data.config_path.lock()?)?;
-    let config = conf_parse(&config_str)?;
-
-    Ok(HttpResponse::Ok().json(UsbsasInfos {
-        name: node_name,
-        message: config.message.unwrap_or_else(|| "".into()),
-        version: usbsas_utils::USBSAS_VERSION.into(),
-    }))
+    Ok(HttpResponse::Ok().json(data.infos()?))
 }
 
 #[get("/server_infos")]
-async fn server_infos() -> Result<impl Responder, ServiceError> {
+async fn server_infos(_admin: Admin) -> Result<impl Responder, ServiceError> {
     Ok(HttpResponse::Ok().json(get_server_infos()))
 }
 
@@ -47,90 +192,206 @@ async fn devices(data: web::Data<AppState>) -> Result<impl Responder, ServiceErr
 
 #[get("/devices/select/{fingerprint_dirty}/{fingerprint_out}")]
 async fn device_select(
+    req: HttpRequest,
     params: web::Path<(String, String)>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
     let (fingerprint_dirty, fingerprint_out) = params.into_inner();
     data.device_select(fingerprint_dirty, fingerprint_out)?;
     Ok(HttpResponse::Ok())
 }
 
 #[get("/devices/dirty")]
-async fn read_partitions(data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
+async fn read_partitions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
     let partitions = data.read_partitions()?;
     Ok(HttpResponse::Ok().json(partitions))
 }
 
 #[get("/devices/dirty/open/{num}")]
 async fn open_partition(
+    req: HttpRequest,
     params: web::Path<u32>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
     let num = params.into_inner();
     data.open_partition(num)?;
     Ok(HttpResponse::Ok())
 }
 
+/// Entries of a directory as a dict keyed by their path, in the order of the
+/// listing
+struct ReadDirDict(Vec<ReadDir>);
+
+impl Serialize for ReadDirDict {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|rdj| (&rdj.path, rdj)))
+    }
+}
+
 #[get("/devices/dirty/read_dir/")]
 async fn read_dir(
+    req: HttpRequest,
     query: web::Query<ReadDirQuery>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
-    let ret = data.read_dir(&query.path)?;
-    // Create a dict of it, avoiding a copy of ReadDir.path for key
-    let dict: HashMap<_, _> = ret.iter().map(|rdj| (&rdj.path, rdj)).collect();
-    Ok(HttpResponse::Ok().json(dict))
+    check_operator(&req, &data)?;
+    let (ret, total) = data.read_dir(&query)?;
+    // Number of entries of the directory, for clients reading it by pages
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Total-Count", total.to_string()))
+        .json(ReadDirDict(ret)))
+}
+
+#[get("/devices/dirty/dir_size/")]
+async fn dir_size(
+    req: HttpRequest,
+    query: web::Query<DirSizeQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
+    Ok(HttpResponse::Ok().json(data.dir_size(&query.path)?))
 }
 
 #[post("/copy")]
 async fn copy(
+    req: HttpRequest,
     files: web::Json<CopyIn>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
-    let resp_stream = ResponseStream::new();
+    check_operator(&req, &data)?;
+    let resp_stream = ResponseStream::new(data.events.clone());
     let resp_stream_clone = resp_stream.clone();
     thread::spawn(move || {
-        let _ = data.copy(
+        let result = data.copy(
             files.selected.to_owned(),
             files.fsfmt.to_owned(),
+            files.passphrase.to_owned(),
             resp_stream_clone,
         );
+        let _ = data.set_state(end_state(&result));
     });
     Ok(HttpResponse::Ok().streaming(resp_stream))
 }
 
 #[get("/wipe/{fingertprint}/{fsfmt}/{quick}")]
 async fn wipe(
+    _admin: Admin,
+    req: HttpRequest,
     params: web::Path<(String, String, bool)>,
+    query: web::Query<WipeQuery>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
     let (fingerprint, fsfmt, quick) = params.into_inner();
+    let (fstype, mode) = AppState::wipe_params(&fsfmt, query.mode.as_deref())?;
     let device = data.dev_from_fingerprint(fingerprint)?;
-    let resp_stream = ResponseStream::new();
+    let resp_stream = ResponseStream::new(data.events.clone());
     let resp_stream_clone = resp_stream.clone();
     thread::spawn(move || {
-        let _ = data.wipe(device, fsfmt, quick, resp_stream_clone);
+        let result = data.wipe(device, fstype, quick, mode, resp_stream_clone);
+        let _ = data.set_state(end_state(&result));
     });
     Ok(HttpResponse::Ok().streaming(resp_stream))
 }
 
 #[get("/imagedisk/{fingerprint}")]
 async fn imagedisk(
+    _admin: Admin,
+    req: HttpRequest,
     params: web::Path<String>,
     data: web::Data<AppState>,
 ) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
     let fingerprint = params.into_inner();
     let device = data.dev_from_fingerprint(fingerprint)?;
-    let resp_stream = ResponseStream::new();
+    let resp_stream = ResponseStream::new(data.events.clone());
     let resp_stream_clone = resp_stream.clone();
     thread::spawn(move || {
-        let _ = data.imagedisk(device, resp_stream_clone);
+        let result = data.imagedisk(device, resp_stream_clone);
+        let _ = data.set_state(end_state(&result));
     });
     Ok(HttpResponse::Ok().streaming(resp_stream))
 }
 
+/// Reload the configuration, it is used from the next session
+#[post("/config/reload")]
+async fn reload_config(
+    _admin: Admin,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    data.reload_config()?;
+    Ok(HttpResponse::Ok().json(data.config_status()?))
+}
+
+#[get("/config/status")]
+async fn config_status(
+    _admin: Admin,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    Ok(HttpResponse::Ok().json(data.config_status()?))
+}
+
+/// Logs of the server, if they are redirected to a file
+#[get("/logs")]
+async fn logs(_admin: Admin, data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
+    let log_file = data
+        .admin()?
+        .and_then(|admin| admin.log_file)
+        .ok_or_else(|| ServiceError::Error("no log file configured".into()))?;
+    Ok(actix_files::NamedFile::open(log_file)?
+        .set_content_type(actix_web::mime::TEXT_PLAIN)
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("usbsas-server.log".into())],
+        }))
+}
+
+/// Search the history, the most recent records first
+#[get("/history")]
+async fn history(
+    _admin: Admin,
+    query: web::Query<HistoryQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    Ok(HttpResponse::Ok().json(data.search_history(&query)?))
+}
+
+/// Export the records of the history matching the query, as JSON lines
+#[get("/history/export")]
+async fn export_history(
+    _admin: Admin,
+    query: web::Query<HistoryQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("usbsas-history.jsonl".into())],
+        })
+        .body(data.export_history(&query)?))
+}
+
+/// Metrics in the text format of Prometheus
+#[get("/metrics")]
+async fn metrics(data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.metrics.render()?))
+}
+
 #[get("/reset")]
-async fn reset(data: web::Data<AppState>) -> Result<impl Responder, ServiceError> {
+async fn reset(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, ServiceError> {
+    check_operator(&req, &data)?;
     info!("** Resetting server **");
     data.reset()?;
     Ok(HttpResponse::Ok())
@@ -150,39 +411,69 @@ pub async fn start_server(
     bind_addr: &String,
     bind_port: &String,
 ) -> io::Result<()> {
-    let app_data = web::Data::new(AppState::new(config_path).map_err(|err| {
+    // SIGHUP is handled by a dedicated thread, block it before the other ones
+    // are spawned
+    let mut sighup = SigSet::empty();
+    sighup.add(Signal::SIGHUP);
+    sighup.thread_block()?;
+
+    // The logger is initialized before usbsas is started, its logs are
+    // forwarded by the server
+    let session_id = Arc::new(RwLock::new(new_session_id()));
+    usbsas_utils::log::init_logger_with_output(
+        #[cfg(feature = "log-json")]
+        session_id.clone(),
+        "usbsas-server",
+        log_output(conf_parse(&conf_read(&config_path)?)?.logging.as_ref())?,
+    )?;
+    let app_data = web::Data::new(AppState::new(config_path, session_id).map_err(|err| {
         io::Error::new(
             ErrorKind::Other,
             format!("couldn't init server data: {}", err),
         )
     })?);
-    #[cfg(feature = "log-json")]
-    usbsas_utils::log::init_logger(app_data.session_id.clone());
-    #[cfg(not(feature = "log-json"))]
-    usbsas_utils::log::init_logger();
+    let watch_data = app_data.clone();
+    thread::spawn(move || watch(watch_data));
+    let sighup_data = app_data.clone();
+    thread::spawn(move || reload_on_sighup(sighup_data, sighup));
     HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
             .wrap(
-                // Polled regularly by client, too noisy
+                // Too noisy
                 actix_web::middleware::Logger::default()
                     .exclude("/id")
                     .exclude("/devices")
                     .exclude("/status")
+                    .exclude("/metrics")
                     .exclude("/static"),
             )
             .service(id)
+            .service(events)
+            .service(take_session)
+            .service(release_session)
             .service(usbsas_infos)
-            .service(server_infos)
             .service(devices)
             .service(device_select)
             .service(read_partitions)
             .service(open_partition)
             .service(read_dir)
+            .service(dir_size)
             .service(copy)
-            .service(wipe)
-            .service(imagedisk)
             .service(reset)
+            .service(metrics)
+            // Only served to the administrators, the kiosk can browse and copy
+            .service(
+                web::scope("/admin")
+                    .service(server_infos)
+                    .service(wipe)
+                    .service(imagedisk)
+                    .service(reload_config)
+                    .service(config_status)
+                    .service(logs)
+                    .service(history)
+                    .service(export_history),
+            )
             .service(actix_files::Files::new(
                 "/static/",
                 format!("{}/static/", env!("USBSAS_WEBFILES_DIR")),
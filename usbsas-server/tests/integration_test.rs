@@ -92,6 +92,7 @@ impl IntegrationTester {
         let client = Client::builder()
             .timeout(Duration::from_secs(60))
             .connect_timeout(Duration::from_secs(30))
+            .cookie_store(true)
             .build()
             .expect("couldn't build reqwest client");
 
@@ -105,6 +106,13 @@ impl IntegrationTester {
             }
         }
 
+        // Take control of usbsas
+        let resp = client
+            .post("http://localhost:8080/session")
+            .send()
+            .expect("Couldn't take control of usbsas");
+        assert!(resp.status().is_success());
+
         IntegrationTester {
             api: "http://localhost:8080/".into(),
             client: client,
@@ -137,6 +145,64 @@ impl IntegrationTester {
         }
     }
 
+    fn watcher(&self) {
+        // Another client can't take control nor act on usbsas
+        let watcher = Client::new();
+        let resp = watcher
+            .post(&format!("{}{}", self.api, "session"))
+            .send()
+            .expect("Couldn't request session");
+        assert_eq!(resp.status(), reqwest::StatusCode::CONFLICT);
+        let resp = watcher
+            .get(&format!("{}{}", self.api, "reset"))
+            .send()
+            .expect("Couldn't request reset");
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+
+    fn admin(&self) {
+        // Admin routes require the credentials of the config
+        let url = format!("{}{}", self.api, "admin/server_infos");
+        let resp = self.client.get(&url).send().expect("Couldn't get infos");
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = self
+            .client
+            .get(&url)
+            .basic_auth("admin", Some("wrong"))
+            .send()
+            .expect("Couldn't get infos");
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = self
+            .client
+            .get(&url)
+            .basic_auth("admin", Some("usbsas"))
+            .send()
+            .expect("Couldn't get infos");
+        assert!(resp.status().is_success());
+    }
+
+    fn history(&self, operation: &str) -> Vec<HistoryRecordJson> {
+        self.client
+            .get(&format!(
+                "{}admin/history?operation={}&limit=1",
+                self.api, operation
+            ))
+            .basic_auth("admin", Some("usbsas"))
+            .send()
+            .expect("Couldn't search history")
+            .json()
+            .expect("Couldn't parse history")
+    }
+
+    fn metrics(&self) -> String {
+        self.client
+            .get(&format!("{}metrics", self.api))
+            .send()
+            .expect("Couldn't get metrics")
+            .text()
+            .expect("Couldn't read metrics")
+    }
+
     fn list_files_recursive(
         &self,
         files: &mut HashMap<String, appstate::ReadDir>,
@@ -159,6 +225,83 @@ impl IntegrationTester {
         Ok(())
     }
 
+    fn check_listing(
+        &self,
+        files: &HashMap<String, appstate::ReadDir>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let root: HashMap<String, appstate::ReadDir> = self
+            .client
+            .get(&format!("{}devices/dirty/read_dir/?path=", self.api))
+            .send()?
+            .json()?;
+
+        // First page of the root directory, by decreasing size
+        let resp = self
+            .client
+            .get(&format!(
+                "{}devices/dirty/read_dir/?path=&limit=1&sort=size&order=desc",
+                self.api
+            ))
+            .send()?;
+        assert!(resp.status().is_success());
+        let total: usize = resp.headers()["X-Total-Count"].to_str()?.parse()?;
+        let page: HashMap<String, appstate::ReadDir> = resp.json()?;
+        assert_eq!(page.len(), 1);
+        assert_eq!(total, root.len());
+        assert!(total >= 1 && total <= files.len());
+        assert_eq!(
+            page.values().next().unwrap().size,
+            root.values().map(|file| file.size).max().unwrap()
+        );
+
+        // Every entry of the root directory once, by pages of 2
+        let mut paged = HashMap::new();
+        for offset in (0..total).step_by(2) {
+            let page: HashMap<String, appstate::ReadDir> = self
+                .client
+                .get(&format!(
+                    "{}devices/dirty/read_dir/?path=&offset={}&limit=2",
+                    self.api, offset
+                ))
+                .send()?
+                .json()?;
+            assert_eq!(page.len(), std::cmp::min(2, total - offset));
+            paged.extend(page);
+        }
+        assert_eq!(
+            paged.keys().collect::<std::collections::HashSet<_>>(),
+            root.keys().collect()
+        );
+
+        // Unknown sort keys are refused
+        let resp = self
+            .client
+            .get(&format!(
+                "{}devices/dirty/read_dir/?path=&sort=color",
+                self.api
+            ))
+            .send()?;
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // Recursive size of the root directory
+        let dir_size: appstate::DirSize = self
+            .client
+            .get(&format!("{}devices/dirty/dir_size/?path=", self.api))
+            .send()?
+            .json()?;
+        let regular_files = files.values().filter(|file| file.ftype == 1);
+        assert_eq!(dir_size.files, regular_files.clone().count() as u64);
+        assert_eq!(
+            dir_size.size,
+            regular_files.map(|file| file.size).sum::<u64>()
+        );
+        assert_eq!(
+            dir_size.directories,
+            files.values().filter(|file| file.ftype == 2).count() as u64
+        );
+        Ok(())
+    }
+
     fn do_copy(
         &self,
         output_type: &appstate::DevType,
@@ -231,6 +374,7 @@ impl IntegrationTester {
 
         // "" for listing root dir
         self.list_files_recursive(&mut files, "")?;
+        self.check_listing(&files)?;
 
         let all_path: Vec<&&str> = ok_path
             .iter()
@@ -326,18 +470,15 @@ impl IntegrationTester {
         }
     }
 
-    fn wipe(
-        &self,
-        fsfmt: &str,
-        quick: bool,
-        expected_sha1sum: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    fn wipe_url(&self, fsfmt: &str, quick: bool, mode: Option<&str>) -> String {
         // Get devices
         let devices: Vec<appstate::DeviceDesc> = self
             .client
             .get(&format!("{}{}", self.api, "devices"))
-            .send()?
-            .json()?;
+            .send()
+            .expect("couldn't get devices")
+            .json()
+            .expect("bad devices");
 
         // Find input dev (first USB)
         let input_dev = devices
@@ -345,17 +486,33 @@ impl IntegrationTester {
             .find(|dev| dev.dev_type == appstate::DevType::Usb)
             .unwrap();
 
+        let mut url = format!(
+            "{}{}/{}/{}/{}",
+            self.api,
+            "admin/wipe",
+            input_dev.id,
+            fsfmt,
+            if quick { "true" } else { "false" }
+        );
+        if let Some(mode) = mode {
+            url.push_str(&format!("?mode={}", mode));
+        }
+        url
+    }
+
+    /// Wipe the device, returns the mode reported at the end
+    fn wipe(
+        &self,
+        fsfmt: &str,
+        quick: bool,
+        mode: Option<&str>,
+        expected_sha1sum: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         // Wipe dev
         let resp = self
             .client
-            .get(&format!(
-                "{}{}/{}/{}/{}",
-                self.api,
-                "wipe",
-                input_dev.id,
-                fsfmt,
-                if quick { "true" } else { "false" }
-            ))
+            .get(&self.wipe_url(fsfmt, quick, mode))
+            .basic_auth("admin", Some("usbsas"))
             .send()?;
         assert!(resp.status().is_success());
 
@@ -374,12 +531,24 @@ impl IntegrationTester {
                     sha1sum.split_whitespace().nth(0).unwrap().to_string(),
                     expected_sha1sum
                 );
-                return Ok(());
+                return Ok(status.mode.unwrap_or_default());
             }
         }
         Err(io::Error::new(io::ErrorKind::Other, "test failed").into())
     }
 
+    fn wipe_bad_request(&self) {
+        for (fsfmt, mode) in [("ext9", None), ("fat32", Some("shred"))] {
+            let resp = self
+                .client
+                .get(&self.wipe_url(fsfmt, false, mode))
+                .basic_auth("admin", Some("usbsas"))
+                .send()
+                .expect("couldn't send wipe request");
+            assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        }
+    }
+
     fn dev_too_small(
         &self,
         output_type: appstate::DevType,
@@ -453,12 +622,20 @@ impl Drop for IntegrationTester {
 #[derive(Debug, Deserialize)]
 struct StatusJson {
     status: String,
+    mode: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryRecordJson {
+    outcome: String,
 }
 
 #[test]
 fn integration_test() {
     let tester = IntegrationTester::new();
     tester.reset();
+    tester.watcher();
+    tester.admin();
 
     // Files in all 3 partitions of test_data/mock_input_dev.img
     let dirty_path = ["/eicar.com"];
@@ -538,18 +715,65 @@ fn integration_test() {
     );
     tester.reset();
 
+    // The last copy is recorded in the history
+    let records = tester.history("copy");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].outcome, "not_enough_space");
+
+    // Unknown file systems and modes are refused
+    tester.wipe_bad_request();
+
     // Test quick wipe & mkfs fat32
-    tester
-        .wipe("fat32", true, "a38a4728650cce9a8314aaa322f8c8dd576d3e44")
+    let mode = tester
+        .wipe(
+            "fat32",
+            true,
+            None,
+            "a38a4728650cce9a8314aaa322f8c8dd576d3e44",
+        )
         .expect("wipe failed");
+    assert_eq!(mode, "quick");
+    tester.reset();
+
+    // Mock devices support no erase command, they're filled with zeros
+    let mode = tester
+        .wipe(
+            "ntfs",
+            false,
+            Some("erase"),
+            "94c03f01de25aa834a2c1572de1efb672e6ebdb8",
+        )
+        .expect("wipe failed");
+    assert_eq!(mode, "zerofill");
     tester.reset();
 
     // Test secure wipe & mkfs ntfs
-    tester
-        .wipe("ntfs", false, "94c03f01de25aa834a2c1572de1efb672e6ebdb8")
+    let mode = tester
+        .wipe(
+            "ntfs",
+            false,
+            None,
+            "94c03f01de25aa834a2c1572de1efb672e6ebdb8",
+        )
         .expect("wipe failed");
+    assert_eq!(mode, "zerofill");
     tester.reset();
 
+    let records = tester.history("wipe");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].outcome, "done");
+
+    let metrics = tester.metrics();
+    for line in [
+        "usbsas_operations_total{operation=\"copy\",outcome=\"done\",destination=\"usb\"} 3",
+        "usbsas_operations_total{operation=\"copy\",outcome=\"done\",destination=\"net\"} 1",
+        "usbsas_operations_total{operation=\"copy\",outcome=\"not_enough_space\",destination=\"usb\"} 1",
+        "usbsas_operations_total{operation=\"wipe\",outcome=\"done\",destination=\"usb\"} 3",
+        "usbsas_crashes_total 0",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "missing metric {}", line);
+    }
+
     drop(tester);
     // Time to stop properly
     sleep(Duration::from_secs(2));
@@ -20,7 +20,7 @@ use usbsas_proto::{
     common::{FileInfo, FileType},
     files::request::Msg,
 };
-use usbsas_utils::{READ_FILE_MAX_SIZE, TAR_DATA_DIR};
+use usbsas_utils::{MANIFEST_FILE_NAME, READ_FILE_MAX_SIZE, SIGNATURE_FILE_NAME, TAR_DATA_DIR};
 
 #[derive(Error, Debug)]
 enum Error {
@@ -45,6 +45,7 @@ protoresponse!(
     getattr = GetAttr[ResponseGetAttr],
     readdir = ReadDir[ResponseReadDir],
     readfile = ReadFile[ResponseReadFile],
+    readlink = ReadLink[ResponseReadLink],
     error = Error[ResponseError],
     end = End[ResponseEnd]
 );
@@ -75,6 +76,7 @@ struct Attrs {
     size: u64,
     timestamp: i64,
     offset: u64,
+    link_target: Option<String>,
 }
 
 struct InitState {
@@ -123,14 +125,19 @@ impl LoadMetadataState {
         for entry in archive.entries()? {
             let entry = entry?;
             let path_name = entry.path()?.to_path_buf().to_string_lossy().to_string();
-            if path_name == "infos.json" {
+            if ["infos.json", MANIFEST_FILE_NAME, SIGNATURE_FILE_NAME].contains(&path_name.as_str())
+            {
                 continue;
             }
             let ftype = match entry.header().entry_type() {
                 tar::EntryType::Directory => FileType::Directory,
                 tar::EntryType::Regular => FileType::Regular,
+                tar::EntryType::Symlink => FileType::Symlink,
                 _ => continue,
             };
+            let link_target = entry
+                .link_name()?
+                .map(|target| target.to_string_lossy().to_string());
             metadata.insert(
                 path_name.trim_start_matches(&data_dir).to_owned(),
                 Attrs {
@@ -138,6 +145,7 @@ impl LoadMetadataState {
                     size: entry.header().size()?,
                     timestamp: i64::try_from(entry.header().mtime()?)?,
                     offset: entry.raw_file_position(),
+                    link_target,
                 },
             );
         }
@@ -159,6 +167,7 @@ impl MainLoopState {
                 Msg::GetAttr(req) => self.getattr(comm, &req.path),
                 Msg::ReadFile(req) => self.readfile(comm, &req.path, req.offset, req.size as usize),
                 Msg::ReadDir(req) => self.readdir(comm, &req.path),
+                Msg::ReadLink(req) => self.readlink(comm, &req.path),
                 Msg::End(_) => {
                     comm.end(proto::files::ResponseEnd {})?;
                     return Ok(State::End);
@@ -218,7 +227,20 @@ impl MainLoopState {
         self.archive
             .seek(SeekFrom::Start(entry_offset + file_offset))?;
         self.archive.read_exact(&mut data)?;
-        Ok(comm.readfile(proto::files::ResponseReadFile { data })?)
+        Ok(comm.readfile(proto::files::ResponseReadFile {
+            data,
+            bad_sectors: Vec::new(),
+        })?)
+    }
+
+    fn readlink(&mut self, comm: &mut Comm<proto::files::Request>, path: &str) -> Result<()> {
+        trace!("req_readlink {}", path);
+        let target = self
+            .get_entry(path)?
+            .link_target
+            .clone()
+            .ok_or_else(|| Error::Error(format!("{} is not a link", path)))?;
+        Ok(comm.readlink(proto::files::ResponseReadLink { target })?)
     }
 
     fn readdir(&mut self, comm: &mut Comm<proto::files::Request>, path: &str) -> Result<()> {
@@ -0,0 +1,282 @@
+//! Enroll destination devices: write a marker on the device and add it to the
+//! signed registry referenced by the configuration. Only the administrators,
+//! who hold the key signing the registry, can enroll devices.
+
+use clap::{Arg, Command};
+use std::{
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use usbsas_comm::{protorequest, Comm};
+use usbsas_config::{conf_parse, conf_read, DevicePolicy, Enrollment};
+use usbsas_process::{UsbsasChild, UsbsasChildSpawner};
+use usbsas_proto as proto;
+use usbsas_utils::{
+    enrollment::{EnrolledDevice, Registry, TOKEN_SIZE},
+    sign::SigningKey,
+};
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("{0}")]
+    Error(String),
+    #[error("process error: {0}")]
+    Process(#[from] usbsas_process::Error),
+}
+type Result<T> = std::result::Result<T, Error>;
+
+protorequest!(
+    CommUsbdev,
+    usbdev,
+    devices = Devices[RequestDevices, ResponseDevices],
+    end = End[RequestEnd, ResponseEnd]
+);
+
+protorequest!(
+    CommFs2dev,
+    fs2dev,
+    enroll = Enroll[RequestEnroll, ResponseEnroll],
+    end = End[RequestEnd, ResponseEnd]
+);
+
+/// Read the registry, or start a new one if it doesn't exist yet
+fn read_registry(enrollment: &Enrollment) -> Result<Registry> {
+    if !Path::new(&enrollment.registry_path).exists() {
+        log::warn!(
+            "registry {} doesn't exist, creating it",
+            enrollment.registry_path
+        );
+        return Ok(Registry::default());
+    }
+    Ok(Registry::from_file(
+        &enrollment.registry_path,
+        &enrollment.public_key,
+    )?)
+}
+
+/// Read the signing key, it must match the public key of the configuration
+fn read_key(enrollment: &Enrollment, key_path: &str) -> Result<SigningKey> {
+    let key = SigningKey::from_file(key_path)?;
+    if key.public_key() != enrollment.public_key.trim() {
+        return Err(Error::Error(
+            "key doesn't match the public key of the configuration".into(),
+        ));
+    }
+    Ok(key)
+}
+
+/// Check the device against the device policy like usbdev does, except for the
+/// registry the device can't be in yet
+fn check_policy(policy: &DevicePolicy, device: &proto::common::Device) -> Result<()> {
+    let classes: Vec<u32> = std::iter::once(device.class_code)
+        .chain(
+            device
+                .interfaces
+                .iter()
+                .map(|interface| interface.class_code),
+        )
+        .collect();
+    policy
+        .check_classes(&classes)
+        .and_then(|_| policy.check_destination(device.vendorid, device.productid, &device.serial))
+        .map_err(|reason| {
+            Error::Error(format!(
+                "device {:04x}:{:04x} ({}) refused: {}",
+                device.vendorid, device.productid, device.serial, reason
+            ))
+        })
+}
+
+fn enroll(
+    config_path: &str,
+    enrollment: &Enrollment,
+    policy: &DevicePolicy,
+    key: &SigningKey,
+    busnum: u32,
+    devnum: u32,
+) -> Result<()> {
+    let mut registry = read_registry(enrollment)?;
+
+    let mut usbdev = UsbsasChildSpawner::new()
+        .arg(config_path)
+        .read_path(config_path)
+        .usb()
+        .hotplug()
+        .spawn::<usbsas_usbdev::UsbDev, proto::usbdev::Request>()?;
+    let devices = usbdev
+        .comm
+        .devices(proto::usbdev::RequestDevices {})?
+        .devices;
+    usbdev.comm.end(proto::usbdev::RequestEnd {})?;
+    // The first device listed is the one with the first disk LUN
+    let device = devices
+        .into_iter()
+        .find(|dev| dev.busnum == busnum && dev.devnum == devnum)
+        .ok_or_else(|| Error::Error(format!("device {}/{} not found", busnum, devnum)))?;
+    check_policy(policy, &device)?;
+    if registry
+        .find(device.vendorid, device.productid, &device.serial)
+        .is_some()
+    {
+        return Err(Error::Error(format!(
+            "device {} is already enrolled",
+            device.serial
+        )));
+    }
+
+    // fs2dev isn't given the configuration, it would refuse to write on a
+    // device that isn't enrolled yet
+    let token: [u8; TOKEN_SIZE] = rand::random();
+    let mut fs2dev: UsbsasChild<proto::fs2dev::Request> = UsbsasChildSpawner::new()
+        .arg("/dev/null")
+        .usb()
+        .wait_on_startup()
+        .spawn::<usbsas_fs2dev::Fs2Dev, proto::fs2dev::Request>()?;
+    fs2dev
+        .comm
+        .write_all(&((u64::from(devnum) << 32) | u64::from(busnum)).to_ne_bytes())?;
+    fs2dev.locked = false;
+    fs2dev.comm.enroll(proto::fs2dev::RequestEnroll {
+        token: token.to_vec(),
+    })?;
+    fs2dev.comm.end(proto::fs2dev::RequestEnd {})?;
+
+    registry.devices.push(EnrolledDevice {
+        vendorid: device.vendorid,
+        productid: device.productid,
+        serial: device.serial.clone(),
+        description: format!("{} - {}", device.manufacturer, device.description),
+        token: token.iter().map(|x| format!("{:02x}", x)).collect(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0),
+    });
+    registry.to_file(&enrollment.registry_path, key)?;
+    eprintln!(
+        "Device {:04x}:{:04x} {} enrolled",
+        device.vendorid, device.productid, device.serial
+    );
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::builder().format_timestamp(None).init();
+    let matches = Command::new("usbsas-enroll")
+        .about("Enroll destination devices")
+        .version("1.0")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .help("Path of the configuration file")
+                .num_args(1)
+                .default_value(usbsas_utils::USBSAS_CONFIG)
+                .required(false),
+        )
+        .subcommand(
+            Command::new("add")
+                .about("Enroll a device")
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .value_name("KEY")
+                        .help("Path of the key signing the registry")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("busnum")
+                        .short('b')
+                        .long("busnum")
+                        .value_name("BUSNUM")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Bus number of the device to enroll")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("devnum")
+                        .short('d')
+                        .long("devnum")
+                        .value_name("DEVNUM")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Device number of the device to enroll")
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("remove")
+                .about("Remove a device from the registry")
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .value_name("KEY")
+                        .help("Path of the key signing the registry")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("serial")
+                        .value_name("SERIAL")
+                        .index(1)
+                        .help("Serial number of the device")
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(Command::new("list").about("List the enrolled devices"))
+        .get_matches();
+
+    let config_path = matches.get_one::<String>("config").unwrap();
+    let config = conf_parse(&conf_read(config_path)?)?;
+    let policy = config.device_policy.unwrap_or_default();
+    let enrollment = config
+        .enrollment
+        .ok_or_else(|| Error::Error("enrollment isn't configured".into()))?;
+
+    match matches.subcommand() {
+        Some(("add", matches)) => {
+            let key = read_key(&enrollment, matches.get_one::<String>("key").unwrap())?;
+            enroll(
+                config_path,
+                &enrollment,
+                &policy,
+                &key,
+                *matches.get_one::<u32>("busnum").unwrap(),
+                *matches.get_one::<u32>("devnum").unwrap(),
+            )
+        }
+        Some(("remove", matches)) => {
+            let key = read_key(&enrollment, matches.get_one::<String>("key").unwrap())?;
+            let serial = matches.get_one::<String>("serial").unwrap();
+            let mut registry = read_registry(&enrollment)?;
+            let count = registry.devices.len();
+            registry.devices.retain(|device| &device.serial != serial);
+            if registry.devices.len() == count {
+                return Err(Error::Error(format!("device {} isn't enrolled", serial)));
+            }
+            registry.to_file(&enrollment.registry_path, &key)?;
+            eprintln!("Device {} removed", serial);
+            Ok(())
+        }
+        Some(("list", _)) => {
+            for device in read_registry(&enrollment)?.devices {
+                println!(
+                    "{:04x}:{:04x} {} ({})",
+                    device.vendorid, device.productid, device.serial, device.description
+                );
+            }
+            Ok(())
+        }
+        _ => unreachable!("subcommand required"),
+    }
+}
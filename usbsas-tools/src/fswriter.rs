@@ -48,6 +48,8 @@ impl FsWriter {
         let fs = File::open(&fs_path)?;
         let mut fs2dev = UsbsasChildSpawner::new()
             .arg(&fs_path)
+            .read_path(&fs_path)
+            .usb()
             .wait_on_startup()
             .spawn::<usbsas_fs2dev::Fs2Dev, proto::fs2dev::Request>()?;
 
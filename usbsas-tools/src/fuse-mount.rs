@@ -9,7 +9,7 @@ use usbsas_process::{UsbsasChild, UsbsasChildSpawner};
 use usbsas_proto as proto;
 
 use fuse_mt::{
-    CallbackResult, DirectoryEntry, RequestInfo, ResultEmpty, ResultEntry, ResultOpen,
+    CallbackResult, DirectoryEntry, RequestInfo, ResultData, ResultEmpty, ResultEntry, ResultOpen,
     ResultReaddir, ResultSlice,
 };
 
@@ -37,6 +37,7 @@ protorequest!(
     getattr = GetAttr[RequestGetAttr, ResponseGetAttr],
     readdir = ReadDir[RequestReadDir, ResponseReadDir],
     readfile = ReadFile[RequestReadFile, ResponseReadFile],
+    readlink = ReadLink[RequestReadLink, ResponseReadLink],
     readsectors = ReadSectors[RequestReadSectors, ResponseReadSectors],
     end = End[RequestEnd, ResponseEnd]
 );
@@ -89,10 +90,15 @@ impl UsbsasFS {
     fn new(busnum: u32, devnum: u32, partnum: u32) -> Result<Self> {
         log::debug!("Opening device {} {}", busnum, devnum);
         let mut scsi2files = UsbsasChildSpawner::new()
+            .usb()
             .spawn::<usbsas_scsi2files::Scsi2Files, proto::files::Request>()?;
         let _ = scsi2files
             .comm
-            .opendevice(proto::files::RequestOpenDevice { busnum, devnum })?;
+            .opendevice(proto::files::RequestOpenDevice {
+                busnum,
+                devnum,
+                lun: 0,
+            })?;
         let parts = scsi2files
             .comm
             .partitions(proto::files::RequestPartitions {})?;
@@ -158,6 +164,7 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
         };
         let ftype = match usbsas_proto::common::FileType::from_i32(rep.ftype) {
             Some(usbsas_proto::common::FileType::Directory) => fuse_mt::FileType::Directory,
+            Some(usbsas_proto::common::FileType::Symlink) => fuse_mt::FileType::Symlink,
             _ => fuse_mt::FileType::RegularFile,
         };
         let entry = Entry {
@@ -197,6 +204,24 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
         }
     }
 
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        log::trace!("readlink: {:?}", path);
+        match self
+            .scsi2files
+            .write()
+            .unwrap()
+            .comm
+            .readlink(proto::files::RequestReadLink {
+                path: path.to_string_lossy().to_string(),
+            }) {
+            Ok(rep) => Ok(rep.target.into_bytes()),
+            Err(err) => {
+                log::error!("readlink error {:?}: {:?}", &path, err);
+                Err(libc::EIO)
+            }
+        }
+    }
+
     fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
         log::trace!("opendir: {:?}", path);
         Ok((0, 0))
@@ -213,11 +238,13 @@ impl fuse_mt::FilesystemMT for UsbsasFS {
             .comm
             .readdir(proto::files::RequestReadDir {
                 path: dir_str.trim_start_matches('/').to_string(),
+                ..Default::default()
             })
             .unwrap();
         for attrs in rep.filesinfo {
             let ftype = match usbsas_proto::common::FileType::from_i32(attrs.ftype) {
                 Some(usbsas_proto::common::FileType::Directory) => fuse_mt::FileType::Directory,
+                Some(usbsas_proto::common::FileType::Symlink) => fuse_mt::FileType::Symlink,
                 _ => fuse_mt::FileType::RegularFile,
             };
             result_entries.push(DirectoryEntry {
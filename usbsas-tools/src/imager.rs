@@ -65,6 +65,7 @@ protorequest!(
 struct BusDevNum {
     busnum: u32,
     devnum: u32,
+    lun: u32,
 }
 
 struct Imager {
@@ -85,6 +86,7 @@ impl Imager {
 
         log::debug!("Starting usbsas children");
         let dev2scsi = UsbsasChildSpawner::new()
+            .usb()
             .wait_on_startup()
             .spawn::<usbsas_dev2scsi::Dev2Scsi, proto::scsi::Request>()?;
         pipes_read.push(dev2scsi.comm.input_fd());
@@ -102,6 +104,9 @@ impl Imager {
         let usbdev = if busdevnum.is_none() {
             let usbdev = UsbsasChildSpawner::new()
                 .arg(config_path)
+                .read_path(config_path)
+                .usb()
+                .hotplug()
                 .spawn::<usbsas_usbdev::UsbDev, proto::usbdev::Request>()?;
             pipes_read.push(usbdev.comm.input_fd());
             pipes_write.push(usbdev.comm.output_fd());
@@ -144,13 +149,14 @@ impl Imager {
             eprintln!("Multiple devices found, which one should be imaged ?",);
             for (index, dev) in devices.iter().enumerate() {
                 eprintln!(
-                    "{}: {} - {} (Serial: {}, VID/PID: {}/{})",
+                    "{}: {} - {} (Serial: {}, VID/PID: {}/{}, LUN: {})",
                     index + 1,
                     dev.manufacturer,
                     dev.description,
                     dev.serial,
                     dev.vendorid,
-                    dev.productid
+                    dev.productid,
+                    dev.lun
                 );
             }
             loop {
@@ -191,15 +197,21 @@ impl Imager {
         self.busdevnum = Some(BusDevNum {
             busnum: devices[index].busnum,
             devnum: devices[index].devnum,
+            lun: devices[index].lun,
         });
         Ok(())
     }
 
     fn image_device(&mut self) -> Result<()> {
-        let BusDevNum { busnum, devnum } = self.busdevnum.take().expect("shouldn't happen");
+        let BusDevNum {
+            busnum,
+            devnum,
+            lun,
+        } = self.busdevnum.take().expect("shouldn't happen");
         // Unlock dev2scsi
         let buf = (u64::from(devnum)) << 32 | u64::from(busnum);
         self.dev2scsi.comm.write_all(&buf.to_le_bytes())?;
+        self.dev2scsi.comm.write_all(&lun.to_le_bytes())?;
         self.dev2scsi.locked = false;
 
         let rep: proto::scsi::Response = self.dev2scsi.comm.recv()?;
@@ -233,6 +245,7 @@ impl Imager {
                 .readsectors(proto::scsi::RequestReadSectors {
                     offset,
                     count: sector_count,
+                    tolerant: false,
                 })?;
 
             self.writer.write_all(&rep.data)?;
@@ -322,6 +335,16 @@ fn main() -> Result<()> {
                 .help("Device number of the device to clone")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("lun")
+                .short('l')
+                .long("lun")
+                .requires("busnum")
+                .value_name("LUN")
+                .value_parser(clap::value_parser!(u32))
+                .help("Logical unit of the device to clone (default: 0)")
+                .num_args(1),
+        )
         .arg(
             Arg::new("stdout")
                 .short('O')
@@ -363,6 +386,7 @@ fn main() -> Result<()> {
         (Some(busnum), Some(devnum)) => Some(BusDevNum {
             busnum: busnum.to_owned(),
             devnum: devnum.to_owned(),
+            lun: matches.get_one::<u32>("lun").copied().unwrap_or(0),
         }),
         (None, Some(_)) | (Some(_), None) => {
             return Err(Error::Error(
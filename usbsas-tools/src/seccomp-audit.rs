@@ -0,0 +1,181 @@
+//! Run the integration tests (with the mock feature) with the seccomp filters
+//! in audit mode and print, for each process, the syscalls its profile is
+//! missing. Must be run from the root of the workspace.
+//!
+//! In `log` mode (the default), syscalls are executed and read back from the
+//! kernel log, which requires the permission to read it (CAP_SYSLOG or
+//! kernel.dmesg_restrict=0). In `trap` mode, processes report the syscalls
+//! themselves but they fail, following syscalls may thus be missed: run it
+//! again once the profiles are updated.
+
+use clap::{Arg, ArgAction, Command};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    os::unix::fs::OpenOptionsExt,
+    process::{self, Stdio},
+};
+use thiserror::Error;
+use usbsas_privileges::audit::{syscall_name, AUDIT_ENV_VAR, TRAP_PREFIX};
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("{0}")]
+    Error(String),
+}
+type Result<T> = std::result::Result<T, Error>;
+
+// Audit record type of seccomp in the kernel log
+const AUDIT_SECCOMP: &str = "type=1326";
+
+/// Denied syscalls by process name
+type Report = BTreeMap<String, BTreeSet<i32>>;
+
+/// Open the kernel log, positioned after the existing records
+fn open_kmsg() -> Result<File> {
+    let mut kmsg = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")
+        .map_err(|err| {
+            Error::Error(format!(
+                "couldn't read the kernel log ({}), try the trap mode",
+                err
+            ))
+        })?;
+    kmsg.seek(SeekFrom::End(0))?;
+    Ok(kmsg)
+}
+
+/// `audit: type=1326 ... comm="files2fs" ... syscall=257 ...`
+fn parse_log_record(record: &str, report: &mut Report) {
+    if !record.contains(AUDIT_SECCOMP) {
+        return;
+    }
+    let comm = record
+        .split_once("comm=\"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(comm, _)| comm);
+    let syscall = record
+        .split_once(" syscall=")
+        .and_then(|(_, rest)| rest.split(' ').next())
+        .and_then(|nr| nr.parse().ok());
+    if let (Some(comm), Some(syscall)) = (comm, syscall) {
+        report.entry(comm.to_string()).or_default().insert(syscall);
+    }
+}
+
+fn read_kmsg(kmsg: &mut File, report: &mut Report) -> Result<()> {
+    // Each read returns one record
+    let mut buf = vec![0; 8192];
+    loop {
+        match kmsg.read(&mut buf) {
+            Ok(0) => break,
+            Ok(size) => parse_log_record(&String::from_utf8_lossy(&buf[..size]), report),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            // Records overwritten while reading
+            Err(err) if err.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// `usbsas seccomp audit: files2fs: syscall 257 args ...`
+fn parse_trap_line(line: &str, report: &mut Report) {
+    let entry = line
+        .split_once(TRAP_PREFIX)
+        .and_then(|(_, rest)| rest.trim_start().split_once(": syscall "))
+        .and_then(|(name, rest)| {
+            rest.split(' ')
+                .next()
+                .and_then(|nr| nr.parse().ok())
+                .map(|syscall| (name, syscall))
+        });
+    if let Some((name, syscall)) = entry {
+        report.entry(name.to_string()).or_default().insert(syscall);
+    }
+}
+
+fn cargo(args: &[&str]) -> process::Command {
+    let mut command =
+        process::Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()));
+    command.args(args);
+    command
+}
+
+fn main() -> Result<()> {
+    env_logger::builder().format_timestamp(None).init();
+    let matches = Command::new("usbsas-seccomp-audit")
+        .about("Find the syscalls missing from the seccomp profiles with the integration tests")
+        .version("1.0")
+        .arg(
+            Arg::new("mode")
+                .short('m')
+                .long("mode")
+                .value_name("MODE")
+                .value_parser(["log", "trap"])
+                .help("Audit mode of the filters")
+                .num_args(1)
+                .default_value("log"),
+        )
+        .arg(
+            Arg::new("no-build")
+                .long("no-build")
+                .help("Don't build the workspace with the mock feature first")
+                .action(ArgAction::SetTrue),
+        )
+        .get_matches();
+    let mode = matches.get_one::<String>("mode").unwrap();
+
+    let mut kmsg = if mode == "log" {
+        Some(open_kmsg()?)
+    } else {
+        None
+    };
+
+    if !matches.get_flag("no-build")
+        && !cargo(&["build", "--all", "--features", "mock"])
+            .status()?
+            .success()
+    {
+        return Err(Error::Error("build failed".into()));
+    }
+
+    let mut report = Report::new();
+    let mut tests = cargo(&["test", "-p", "usbsas-server"])
+        .env(AUDIT_ENV_VAR, mode)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(stderr) = tests.stderr.take() {
+        for line in BufReader::new(stderr).lines() {
+            let line = line?;
+            eprintln!("{}", line);
+            parse_trap_line(&line, &mut report);
+        }
+    }
+    if !tests.wait()?.success() {
+        log::warn!("integration tests failed, the report may be incomplete");
+    }
+    if let Some(ref mut kmsg) = kmsg {
+        read_kmsg(kmsg, &mut report)?;
+    }
+
+    if report.is_empty() {
+        println!("No denied syscalls");
+        return Ok(());
+    }
+    for (process, syscalls) in report {
+        println!("{}:", process);
+        for syscall in syscalls {
+            match syscall_name(syscall) {
+                Some(name) => println!("    ctx.allow_syscall(Syscall::{})?;", name),
+                None => println!("    // unknown syscall {}", syscall),
+            }
+        }
+    }
+    Ok(())
+}
@@ -44,6 +44,9 @@ fn upload(config_path: &str, bundle_path: &str, id: &str) -> Result<()> {
     let mut uploader = UsbsasChildSpawner::new()
         .arg(bundle_path)
         .arg(config_path)
+        .read_path(bundle_path)
+        .read_path(config_path)
+        .network()
         .spawn::<usbsas_net::Uploader, proto::uploader::Request>()?;
 
     log::info!("Uploading bundle");
@@ -88,6 +91,9 @@ fn analyze(config_path: &str, bundle_path: &str, id: &str) -> Result<()> {
     let mut analyzer = UsbsasChildSpawner::new()
         .arg(bundle_path)
         .arg(config_path)
+        .read_path(bundle_path)
+        .read_path(config_path)
+        .network()
         .spawn::<usbsas_net::Analyzer, proto::analyzer::Request>()?;
 
     analyzer.comm.send(proto::analyzer::Request {
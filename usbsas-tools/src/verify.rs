@@ -0,0 +1,280 @@
+//! Tool to verify the signature of a usbsas transfer and the hashes of the
+//! files listed in its manifest, files that aren't listed are rejected. The
+//! transfer can be an archive uploaded by usbsas or the root directory of a
+//! mounted destination device.
+
+use clap::{Arg, Command};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+use thiserror::Error;
+use usbsas_utils::{sign, MANIFEST_FILE_NAME, SIGNATURE_FILE_NAME, TAR_DATA_DIR};
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("{0}")]
+    Error(String),
+}
+type Result<T> = std::result::Result<T, Error>;
+
+/// Files written by usbsas next to the transferred ones, not in the manifest
+const META_FILES: [&str; 3] = [MANIFEST_FILE_NAME, SIGNATURE_FILE_NAME, "infos.json"];
+
+fn sha256<R: Read>(mut reader: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Signed manifest of a transfer and a way to hash the files it lists
+trait Transfer {
+    fn manifest(&self) -> &[u8];
+    fn signature(&self) -> &str;
+    fn hash(&mut self, path: &str) -> Result<Option<String>>;
+    /// Every regular file of the transfer but the metadata files, the
+    /// transferred ones as absolute paths
+    fn files(&mut self) -> Result<Vec<String>>;
+}
+
+struct TarTransfer {
+    manifest: Vec<u8>,
+    signature: String,
+    hashes: HashMap<String, String>,
+    files: Vec<String>,
+}
+
+impl TarTransfer {
+    fn new(path: &Path) -> Result<Self> {
+        let data_dir = TAR_DATA_DIR.trim_end_matches('/').to_owned() + "/";
+        let mut manifest = Vec::new();
+        let mut signature = String::new();
+        let mut hashes = HashMap::new();
+        let mut files = Vec::new();
+        let mut archive = tar::Archive::new(fs::File::open(path)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = entry.path()?.to_string_lossy().to_string();
+            if entry_path == MANIFEST_FILE_NAME {
+                entry.read_to_end(&mut manifest)?;
+            } else if entry_path == SIGNATURE_FILE_NAME {
+                entry.read_to_string(&mut signature)?;
+            } else if META_FILES.contains(&entry_path.as_str()) {
+                continue;
+            } else if entry.header().entry_type() == tar::EntryType::Regular {
+                // Files outside of the data directory are kept relative, they
+                // can't be in the manifest
+                let file_path = match entry_path.strip_prefix(&data_dir) {
+                    Some(file_path) => format!("/{}", file_path),
+                    None => entry_path,
+                };
+                hashes.insert(file_path.clone(), sha256(&mut entry)?);
+                files.push(file_path);
+            }
+        }
+        Ok(TarTransfer {
+            manifest,
+            signature,
+            hashes,
+            files,
+        })
+    }
+}
+
+impl Transfer for TarTransfer {
+    fn manifest(&self) -> &[u8] {
+        &self.manifest
+    }
+
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+
+    fn hash(&mut self, path: &str) -> Result<Option<String>> {
+        Ok(self.hashes.get(path).cloned())
+    }
+
+    fn files(&mut self) -> Result<Vec<String>> {
+        Ok(self.files.clone())
+    }
+}
+
+struct DirTransfer {
+    root: String,
+    manifest: Vec<u8>,
+    signature: String,
+}
+
+impl DirTransfer {
+    fn new(root: &Path) -> Result<Self> {
+        Ok(DirTransfer {
+            root: root.to_string_lossy().to_string(),
+            manifest: fs::read(root.join(MANIFEST_FILE_NAME))?,
+            signature: fs::read_to_string(root.join(SIGNATURE_FILE_NAME))?,
+        })
+    }
+}
+
+impl Transfer for DirTransfer {
+    fn manifest(&self) -> &[u8] {
+        &self.manifest
+    }
+
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+
+    fn hash(&mut self, path: &str) -> Result<Option<String>> {
+        match fs::File::open(Path::new(&self.root).join(path.trim_start_matches('/'))) {
+            Ok(file) => Ok(Some(sha256(file)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn files(&mut self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut todo = vec![String::new()];
+        while let Some(dir) = todo.pop() {
+            for entry in fs::read_dir(Path::new(&self.root).join(dir.trim_start_matches('/')))? {
+                let entry = entry?;
+                let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    todo.push(path);
+                } else if file_type.is_file()
+                    && !(dir.is_empty() && META_FILES.contains(&&path[1..]))
+                {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn verify(public_key: &str, transfer: &mut dyn Transfer) -> Result<()> {
+    if transfer.manifest().is_empty() || transfer.signature().is_empty() {
+        return Err(Error::Error("manifest or signature not found".into()));
+    }
+    sign::verify(public_key, transfer.manifest(), transfer.signature())
+        .map_err(|err| Error::Error(format!("bad manifest signature: {}", err)))?;
+    eprintln!("Manifest signature OK");
+
+    let manifest: serde_json::Value = serde_json::from_slice(transfer.manifest())?;
+    let files = manifest["files"]
+        .as_object()
+        .ok_or_else(|| Error::Error("no files in manifest".into()))?;
+    let mut failed = 0;
+    for (path, attrs) in files {
+        // Links have no content to check
+        let expected = match attrs["sha256"].as_str() {
+            Some(hash) if !hash.is_empty() => hash,
+            _ => continue,
+        };
+        match transfer.hash(path)? {
+            Some(hash) if hash == expected => (),
+            Some(_) => {
+                eprintln!("Hash mismatch: {}", path);
+                failed += 1;
+            }
+            None => {
+                eprintln!("Missing file: {}", path);
+                failed += 1;
+            }
+        }
+    }
+    // Files added to the transfer
+    let mut seen = HashSet::new();
+    for path in transfer.files()? {
+        // Links are listed without hash, a file can't replace them
+        let listed = files
+            .get(&path)
+            .and_then(|attrs| attrs["sha256"].as_str())
+            .map_or(false, |hash| !hash.is_empty());
+        if !listed {
+            eprintln!("Unexpected file: {}", path);
+            failed += 1;
+        } else if !seen.insert(path.clone()) {
+            eprintln!("Duplicate file: {}", path);
+            failed += 1;
+        }
+    }
+    if failed != 0 {
+        return Err(Error::Error(format!(
+            "{} errors, {} files in manifest",
+            failed,
+            files.len()
+        )));
+    }
+    eprintln!("{} files verified", files.len());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::builder().format_timestamp(None).init();
+    let matches = Command::new("usbsas-verify")
+        .about("Verify the signature and the files of a usbsas transfer")
+        .version("1.0")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("check")
+                .about("Check a transfer (uploaded archive or destination device root)")
+                .arg(
+                    Arg::new("pubkey")
+                        .short('k')
+                        .long("pubkey")
+                        .value_name("PUBKEY")
+                        .help("Hexadecimal public key of the usbsas station")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .index(1)
+                        .help("Path of the archive or of the mounted device")
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("pubkey")
+                .about("Print the public key of a signing key")
+                .arg(
+                    Arg::new("key")
+                        .value_name("KEY")
+                        .index(1)
+                        .help("Path of the signing key")
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        Some(("check", matches)) => {
+            let public_key = matches.get_one::<String>("pubkey").unwrap();
+            let path = Path::new(matches.get_one::<String>("path").unwrap());
+            let mut transfer: Box<dyn Transfer> = if path.is_dir() {
+                Box::new(DirTransfer::new(path)?)
+            } else {
+                Box::new(TarTransfer::new(path)?)
+            };
+            verify(public_key, transfer.as_mut())
+        }
+        Some(("pubkey", matches)) => {
+            let key = sign::SigningKey::from_file(matches.get_one::<String>("key").unwrap())?;
+            println!("{}", key.public_key());
+            Ok(())
+        }
+        _ => unreachable!("subcommand required"),
+    }
+}
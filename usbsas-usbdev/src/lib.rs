@@ -3,9 +3,9 @@
 //!
 //! It uses libusb's hot-plug handler.
 
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use rusb::constants::LIBUSB_CLASS_MASS_STORAGE;
-use rusb::UsbContext;
+use rusb::{Direction, Recipient, RequestType, UsbContext};
 use std::{
     os::unix::io::RawFd,
     sync::{Arc, Mutex},
@@ -14,10 +14,14 @@ use std::{
 };
 use thiserror::Error;
 use usbsas_comm::{protoresponse, Comm};
-use usbsas_config::{conf_parse, conf_read, UsbPortAccesses};
+use usbsas_config::{conf_parse, conf_read, DevicePolicy, UsbPortAccesses};
 use usbsas_process::UsbsasProcess;
 use usbsas_proto as proto;
-use usbsas_proto::{common::Device as UsbDevice, usbdev::request::Msg};
+use usbsas_proto::{
+    common::{Device as UsbDevice, UsbInterface, UsbSpeed},
+    usbdev::request::Msg,
+};
+use usbsas_utils::enrollment::Registry;
 
 #[derive(Error, Debug)]
 enum Error {
@@ -88,19 +92,20 @@ fn handle_events_loop(
 ) -> Result<()> {
     usbsas_privileges::usbdev::thread_drop_priv(usbsas_privileges::get_libusb_opened_fds(0, 0)?)?;
     loop {
+        // Wake up to retry devices whose descriptors couldn't be read yet
+        let timeout = match current_devices.lock() {
+            Ok(cur_dev_guard) if cur_dev_guard.need_update() => Some(DESC_RETRY_DELAY),
+            _ => None,
+        };
         trace!("waiting libusb event");
-        if let Err(err) = context.handle_events(None) {
+        if let Err(err) = context.handle_events(timeout) {
             error!("Couldn't handle libusb events: {}", err);
             continue;
         }
         trace!("handled libusb event");
 
         match current_devices.lock() {
-            Ok(ref mut cur_dev_guard) => {
-                if cur_dev_guard.need_update {
-                    cur_dev_guard.update_desc_last(&context);
-                }
-            }
+            Ok(ref mut cur_dev_guard) => cur_dev_guard.update_desc(&context),
             Err(err) => {
                 error!("Couldn't get devices lock {}", err);
                 continue;
@@ -109,32 +114,161 @@ fn handle_events_loop(
     }
 }
 
-/// Compare vectors (usb ports)
-fn cmp_vec(vec1: &[u32], vec2: &[u8]) -> bool {
-    if vec1.len() != vec2.len() {
-        return false;
+// Attempts to read the string descriptors of a device, some aren't ready to
+// answer right after being plugged
+const DESC_ATTEMPTS: u32 = 5;
+const DESC_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// GET MAX LUN class request, the spec allows up to 16 LUNs
+const BOT_GET_MAX_LUN: u8 = 0xfe;
+const BOT_MAX_LUN: u8 = 15;
+
+// Bulk-Only Transport mass storage protocol
+const USB_PR_BULK: u8 = 0x50;
+
+/// Get the last LUN of a (bulk-only) mass storage device. Card readers with
+/// multiple slots have one LUN per slot. The LUNs aren't inquired here, this
+/// thread is sandboxed and the interface isn't claimed (the kernel driver
+/// isn't detached by a listing): dev2scsi refuses the LUNs that aren't direct
+/// access block devices when they are opened.
+fn max_lun<T: rusb::UsbContext>(handle: &rusb::DeviceHandle<T>, timeout: Duration) -> u8 {
+    let config_desc = match handle.device().active_config_descriptor() {
+        Ok(cfd) => cfd,
+        Err(err) => {
+            error!("couldn't get config descriptor: {}", err);
+            return 0;
+        }
+    };
+    let interface_number = match config_desc
+        .interfaces()
+        .flat_map(|interface| interface.descriptors())
+        .find(|desc| {
+            desc.class_code() == LIBUSB_CLASS_MASS_STORAGE && desc.protocol_code() == USB_PR_BULK
+        }) {
+        Some(desc) => desc.interface_number(),
+        None => return 0,
+    };
+    let mut buffer = [0u8; 1];
+    // Devices with a single LUN may stall the request
+    match handle.read_control(
+        rusb::request_type(Direction::In, RequestType::Class, Recipient::Interface),
+        BOT_GET_MAX_LUN,
+        0,
+        u16::from(interface_number),
+        &mut buffer,
+        timeout,
+    ) {
+        Ok(1) => buffer[0].min(BOT_MAX_LUN),
+        _ => 0,
     }
-    vec1.iter()
-        .zip(vec2.iter())
-        .all(|(&elt1, &elt2)| elt1 == elt2 as u32)
 }
 
-pub struct CurrentDevices {
-    devices: Vec<UsbDevice>,
-    need_update: bool,
-    usb_port_accesses: Option<UsbPortAccesses>,
+/// Read the manufacturer, product and serial strings of a device, along with
+/// its last LUN
+fn read_string_descriptors<T: rusb::UsbContext>(
+    device: &rusb::Device<T>,
+) -> Result<(String, String, String, u8)> {
+    let mut handle = device.open()?;
+    handle.reset()?;
+    let timeout = Duration::from_secs(1);
+    let languages = handle.read_languages(timeout)?;
+    let language = *languages
+        .first()
+        .ok_or_else(|| Error::Error("no string descriptor language".into()))?;
+    let device_descriptor = handle.device().device_descriptor()?;
+    let manufacturer = handle
+        .read_manufacturer_string(language, &device_descriptor, timeout)
+        .unwrap_or_else(|_| "Unknown manufacturer".to_string());
+    let description = handle
+        .read_product_string(language, &device_descriptor, timeout)
+        .unwrap_or_else(|_| "Unknown description".to_string());
+    let serial = handle
+        .read_serial_number_string(language, &device_descriptor, timeout)
+        .unwrap_or_else(|_| "Unknown serial".to_string());
+    Ok((manufacturer, description, serial, max_lun(&handle, timeout)))
+}
+
+/// Check a device against the policy (and the enrollment registry for
+/// destinations), clearing `is_src` / `is_dst` if it isn't allowed as such and
+/// recording why in `refused`
+fn apply_policy(policy: &DevicePolicy, registry: Option<&Registry>, device: &mut UsbDevice) {
+    let mut reasons = Vec::new();
+    let classes: Vec<u32> = std::iter::once(device.class_code)
+        .chain(
+            device
+                .interfaces
+                .iter()
+                .map(|interface| interface.class_code),
+        )
+        .collect();
+    if let Err(reason) = policy.check_classes(&classes) {
+        device.is_src = false;
+        device.is_dst = false;
+        reasons.push(reason);
+    }
+    if device.is_src {
+        if let Err(reason) = policy.check_source(device.vendorid, device.productid, &device.serial)
+        {
+            device.is_src = false;
+            reasons.push(format!("source {}", reason));
+        }
+    }
+    if device.is_dst {
+        if let Err(reason) =
+            policy.check_destination(device.vendorid, device.productid, &device.serial)
+        {
+            device.is_dst = false;
+            reasons.push(format!("destination {}", reason));
+        }
+    }
+    if let (true, Some(registry)) = (device.is_dst, registry) {
+        if registry
+            .find(device.vendorid, device.productid, &device.serial)
+            .is_none()
+        {
+            device.is_dst = false;
+            reasons.push("destination not enrolled".into());
+        }
+    }
+    if !reasons.is_empty() {
+        device.refused = reasons.join(", ");
+        warn!(
+            "Device {:04x}:{:04x} ({}) refused: {}",
+            device.vendorid, device.productid, device.serial, device.refused
+        );
+    }
 }
 
 impl CurrentDevices {
-    fn new(usb_port_accesses: Option<UsbPortAccesses>) -> Self {
+    fn new(
+        usb_port_accesses: Option<UsbPortAccesses>,
+        device_policy: DevicePolicy,
+        registry: Option<Registry>,
+    ) -> Self {
         CurrentDevices {
             devices: Vec::new(),
-            need_update: false,
             usb_port_accesses,
+            device_policy,
+            registry,
         }
     }
 
+    /// Devices whose descriptors have been read
+    fn devices(&self) -> Vec<UsbDevice> {
+        self.devices
+            .iter()
+            .filter(|plugged| !plugged.need_update)
+            .map(|plugged| plugged.device.clone())
+            .collect()
+    }
+
+    fn need_update(&self) -> bool {
+        self.devices.iter().any(|plugged| plugged.need_update)
+    }
+
     /// Function called when a device is plugged. It will save its information
+    /// from the descriptors cached by libusb, its string descriptors are read
+    /// later by `update_desc()` as the hotplug callback can't do I/O.
     fn add_dev<T: rusb::UsbContext>(&mut self, device: rusb::Device<T>) -> Result<bool> {
         let ports: Vec<u32> = match device.port_numbers() {
             Ok(ports) => ports.iter().map(|&val| val as u32).collect(),
@@ -173,48 +307,70 @@ impl CurrentDevices {
                     return Err(err.into());
                 }
             };
-            for interface in config_desc.interfaces() {
-                for interface_desc in interface.descriptors() {
-                    if interface_desc.class_code() == LIBUSB_CLASS_MASS_STORAGE {
-                        self.devices.push(UsbDevice {
-                            busnum: device.bus_number() as u32,
-                            devnum: device.address() as u32,
-                            vendorid: descriptor.vendor_id() as u32,
-                            productid: descriptor.product_id() as u32,
-                            manufacturer: "unknown".into(),
-                            description: "unknown".into(),
-                            serial: "unknown".into(),
-                            is_src,
-                            is_dst,
-                        });
-                        self.need_update = true;
-                        return Ok(true);
-                    }
-                }
+            let interfaces: Vec<UsbInterface> = config_desc
+                .interfaces()
+                .flat_map(|interface| interface.descriptors())
+                .map(|desc| UsbInterface {
+                    number: u32::from(desc.interface_number()),
+                    class_code: u32::from(desc.class_code()),
+                    sub_class_code: u32::from(desc.sub_class_code()),
+                    protocol_code: u32::from(desc.protocol_code()),
+                })
+                .collect();
+            if interfaces
+                .iter()
+                .any(|interface| interface.class_code == u32::from(LIBUSB_CLASS_MASS_STORAGE))
+            {
+                let version = descriptor.usb_version();
+                self.devices.push(PluggedDevice {
+                    device: UsbDevice {
+                        busnum: device.bus_number() as u32,
+                        devnum: device.address() as u32,
+                        vendorid: descriptor.vendor_id() as u32,
+                        productid: descriptor.product_id() as u32,
+                        manufacturer: "unknown".into(),
+                        description: "unknown".into(),
+                        serial: "unknown".into(),
+                        is_src,
+                        is_dst,
+                        lun: 0,
+                        class_code: u32::from(descriptor.class_code()),
+                        sub_class_code: u32::from(descriptor.sub_class_code()),
+                        protocol_code: u32::from(descriptor.protocol_code()),
+                        speed: usb_speed(device.speed()).into(),
+                        max_power: u32::from(config_desc.max_power()),
+                        usb_version: u32::from(version.major()) << 8
+                            | u32::from(version.minor()) << 4
+                            | u32::from(version.sub_minor()),
+                        interfaces,
+                        refused: String::new(),
+                    },
+                    need_update: true,
+                    attempts: 0,
+                });
+                return Ok(true);
             }
         }
 
         Ok(false)
     }
 
-    /// Remove device from current list
+    /// Remove device (and all its LUNs) from current list
     fn rm_dev<T: rusb::UsbContext>(&mut self, device: rusb::Device<T>) {
-        if let Some(index) = self.devices.iter().position(|x| {
-            (x.busnum, x.devnum) == (device.bus_number() as u32, device.address() as u32)
-        }) {
-            self.devices.remove(index);
-        }
+        self.devices.retain(|x| {
+            (x.device.busnum, x.device.devnum)
+                != (device.bus_number() as u32, device.address() as u32)
+        });
     }
 
-    /// Get more information on last plugged device like its description and
-    /// manufacturer etc.
-    fn update_desc_last(&mut self, context: &rusb::Context) {
-        let last_device = match self.devices.last_mut() {
-            Some(dev) => dev,
-            None => return,
-        };
-
-        let devices = match context.devices() {
+    /// Read the string descriptors (description, manufacturer etc.) of the
+    /// devices that need it. Devices that aren't ready yet are retried on the
+    /// next call, until `DESC_ATTEMPTS` is reached.
+    fn update_desc(&mut self, context: &rusb::Context) {
+        if !self.need_update() {
+            return;
+        }
+        let usb_devices = match context.devices() {
             Ok(devs) => devs,
             Err(err) => {
                 error!("Couldn't get devices: {}", err);
@@ -222,84 +378,77 @@ impl CurrentDevices {
             }
         };
 
-        for device in devices.iter() {
-            if device.bus_number() == last_device.busnum as u8
-                && device.address() == last_device.devnum as u8
-            {
-                match device.open() {
-                    Ok(mut handle) => {
-                        if let Err(err) = handle.reset() {
-                            error!("Couldn't reset device: {}", err);
-                            return;
-                        };
-                        let timeout = Duration::from_secs(1);
-                        let languages = match handle.read_languages(timeout) {
-                            Ok(lang) => lang,
-                            Err(err) => {
-                                error!("Couldn't read languages: {}", err);
-                                return;
-                            }
-                        };
-
-                        let device_descriptor = match handle.device().device_descriptor() {
-                            Ok(dd) => dd,
-                            Err(err) => {
-                                error!("couldn't get device descriptor: {}", err);
-                                return;
-                            }
-                        };
-                        let (manufacturer, description, serial) = if !languages.is_empty() {
-                            (
-                                handle
-                                    .read_manufacturer_string(
-                                        languages[0],
-                                        &device_descriptor,
-                                        timeout,
-                                    )
-                                    .unwrap_or_else(|_| "Unknown manufacturer".to_string()),
-                                handle
-                                    .read_product_string(languages[0], &device_descriptor, timeout)
-                                    .unwrap_or_else(|_| "Unknown description".to_string()),
-                                handle
-                                    .read_serial_number_string(
-                                        languages[0],
-                                        &device_descriptor,
-                                        timeout,
-                                    )
-                                    .unwrap_or_else(|_| "Unknown serial".to_string()),
-                            )
-                        } else {
-                            return;
-                        };
-                        info!(
-                            "Device plugged: {} - {} - {}",
-                            manufacturer, description, serial
+        let mut other_luns = Vec::new();
+        for plugged in self
+            .devices
+            .iter_mut()
+            .filter(|plugged| plugged.need_update)
+        {
+            let result = match usb_devices.iter().find(|device| {
+                device.bus_number() == plugged.device.busnum as u8
+                    && device.address() == plugged.device.devnum as u8
+            }) {
+                Some(device) => read_string_descriptors(&device),
+                None => Err(Error::Error("device not found".into())),
+            };
+            let (manufacturer, description, serial, max_lun) = match result {
+                Ok(strings) => strings,
+                Err(err) => {
+                    plugged.attempts += 1;
+                    if plugged.attempts < DESC_ATTEMPTS {
+                        debug!(
+                            "couldn't read descriptors of {}/{} (attempt {}): {}",
+                            plugged.device.busnum, plugged.device.devnum, plugged.attempts, err
+                        );
+                    } else {
+                        error!(
+                            "couldn't read descriptors of {}/{}, giving up: {}",
+                            plugged.device.busnum, plugged.device.devnum, err
+                        );
+                        plugged.need_update = false;
+                        apply_policy(
+                            &self.device_policy,
+                            self.registry.as_ref(),
+                            &mut plugged.device,
                         );
-                        last_device.manufacturer = manufacturer;
-                        last_device.description = description;
-                        last_device.serial = serial;
-                        self.need_update = false;
-                    }
-                    Err(err) => {
-                        error!("Can't open dev: {}", err);
                     }
+                    continue;
                 }
+            };
+            info!(
+                "Device plugged: {} - {} - {}",
+                manufacturer, description, serial
+            );
+            plugged.device.manufacturer = manufacturer;
+            plugged.device.serial = serial;
+            plugged.need_update = false;
+            apply_policy(
+                &self.device_policy,
+                self.registry.as_ref(),
+                &mut plugged.device,
+            );
+
+            // Other LUNs are listed as separate sources, the destination is
+            // always written on the first one
+            if max_lun > 0 {
+                plugged.device.description = format!("{} (LUN 0)", description);
+            } else {
+                plugged.device.description = description.clone();
             }
-        }
-    }
-
-    fn add_dev_with_desc<T: rusb::UsbContext>(
-        &mut self,
-        device: rusb::Device<T>,
-        context: &rusb::Context,
-    ) {
-        match self.add_dev(device) {
-            Ok(true) => self.update_desc_last(context),
-            Ok(false) => (),
-            Err(err) => {
-                error!("Couldn't add dev: {}", err);
+            for lun in 1..=max_lun {
+                other_luns.push(PluggedDevice {
+                    device: UsbDevice {
+                        description: format!("{} (LUN {})", description, lun),
+                        is_dst: false,
+                        lun: u32::from(lun),
+                        ..plugged.device.clone()
+                    },
+                    need_update: false,
+                    attempts: 0,
+                });
             }
         }
+        self.devices.extend(other_luns);
     }
 }
 
@@ -338,12 +487,32 @@ impl InitState {
         trace!("init state");
         let config = conf_parse(&conf_read(&self.config_path)?)?;
 
+        // No destination is allowed if the registry can't be trusted
+        let registry = config.enrollment.map(|enrollment| {
+            Registry::from_file(&enrollment.registry_path, &enrollment.public_key).unwrap_or_else(
+                |err| {
+                    error!("couldn't read enrollment registry: {}", err);
+                    Registry::default()
+                },
+            )
+        });
+
         let context = rusb::Context::new()?;
-        let current_devices = Arc::new(Mutex::new(CurrentDevices::new(config.usb_port_accesses)));
+        let current_devices = Arc::new(Mutex::new(CurrentDevices::new(
+            config.usb_port_accesses,
+            config.device_policy.unwrap_or_default(),
+            registry,
+        )));
 
         // Poll devices
-        for device in context.devices()?.iter() {
-            current_devices.lock()?.add_dev_with_desc(device, &context);
+        {
+            let mut cur_dev_guard = current_devices.lock()?;
+            for device in context.devices()?.iter() {
+                if let Err(err) = cur_dev_guard.add_dev(device) {
+                    error!("Couldn't add dev: {}", err);
+                }
+            }
+            cur_dev_guard.update_desc(&context);
         }
 
         let registration = rusb::HotplugBuilder::new()
@@ -380,7 +549,7 @@ impl RunningState {
             let req: proto::usbdev::Request = comm.recv()?;
             let res = match req.msg.ok_or(Error::BadRequest)? {
                 Msg::Devices(_) => comm.devices(proto::usbdev::ResponseDevices {
-                    devices: self.current_devices.lock()?.devices.clone(),
+                    devices: self.current_devices.lock()?.devices(),
                 }),
                 Msg::End(_) => {
                     self.context.unregister_callback(self.registration);
@@ -3,20 +3,26 @@
 //! to its children. It doesn't do much by itself and he as well waits for
 //! requests from the final application.
 
+use crate::manifest::{Manifest, RejectStatus};
 use log::{debug, error, info, trace, warn};
+use sha2::{Digest, Sha256};
 #[cfg(feature = "log-json")]
 use std::sync::{Arc, RwLock};
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     convert::TryFrom,
-    io::Write,
+    io::{self, Write},
+    time::SystemTime,
 };
 use thiserror::Error;
 use usbsas_comm::{protorequest, protoresponse, Comm};
+use usbsas_config::{
+    conf_parse, conf_read, Config, DamagedFilePolicy, SeccompAudit, SymlinkPolicy,
+};
 use usbsas_mass_storage::UsbDevice;
 #[cfg(feature = "mock")]
 use usbsas_mock::usbdev::MockUsbDev as UsbDev;
-use usbsas_process::{UsbsasChild, UsbsasChildSpawner};
+use usbsas_process::{UsbsasChild, UsbsasChildSpawner, WaitStatus};
 use usbsas_proto as proto;
 use usbsas_proto::{
     common::*,
@@ -24,7 +30,12 @@ use usbsas_proto::{
 };
 #[cfg(not(feature = "mock"))]
 use usbsas_usbdev::UsbDev;
-use usbsas_utils::READ_FILE_MAX_SIZE;
+use usbsas_utils::{
+    encrypt, sign::SigningKey, ENCRYPTED_FILE_NAME, MANIFEST_FILE_NAME, READ_FILE_MAX_SIZE,
+    SIGNATURE_FILE_NAME, TAR_BLOCK_SIZE,
+};
+
+mod manifest;
 
 #[derive(Error, Debug)]
 enum Error {
@@ -38,6 +49,8 @@ enum Error {
     Upload(String),
     #[error("int error: {0}")]
     Tryfromint(#[from] std::num::TryFromIntError),
+    #[error("serde error: {0}")]
+    Serde(#[from] serde_json::Error),
     #[error("privileges: {0}")]
     Privileges(#[from] usbsas_privileges::Error),
     #[error("process error: {0}")]
@@ -61,6 +74,7 @@ protoresponse!(
     partitions = Partitions[ResponsePartitions],
     getattr = GetAttr[ResponseGetAttr],
     readdir = ReadDir[ResponseReadDir],
+    dirsize = DirSize[ResponseDirSize],
     copystart = CopyStart[ResponseCopyStart],
     copydone = CopyDone[ResponseCopyDone],
     copystatus = CopyStatus[ResponseCopyStatus],
@@ -115,7 +129,9 @@ protorequest!(
     openpartition = OpenPartition[RequestOpenPartition, ResponseOpenPartition],
     getattr = GetAttr[RequestGetAttr, ResponseGetAttr],
     readdir = ReadDir[RequestReadDir, ResponseReadDir],
+    dirsize = DirSize[RequestDirSize, ResponseDirSize],
     readfile = ReadFile[RequestReadFile, ResponseReadFile],
+    readlink = ReadLink[RequestReadLink, ResponseReadLink],
     readsectors = ReadSectors[RequestReadSectors, ResponseReadSectors],
     end = End[RequestEnd, ResponseEnd]
 );
@@ -166,6 +182,76 @@ protorequest!(
     end = End[RequestEnd, ResponseEnd]
 );
 
+// Max number of chained links followed when dereferencing
+const MAX_LINK_HOPS: usize = 8;
+
+/// Resolve the target of a link relatively to the directory of the link.
+/// Returns None if the target is absolute or outside of the volume.
+fn resolve_link_target(link_path: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut parts: Vec<&str> = link_path.trim_start_matches('/').split('/').collect();
+    // Remove link basename
+    let _ = parts.pop();
+    for part in target.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part),
+        }
+    }
+    Some(format!("/{}", parts.join("/")))
+}
+
+/// Links and file types of the source file system
+trait SourceLinks {
+    fn read_link(&mut self, path: &str) -> Result<String>;
+    fn file_attrs(&mut self, path: &str) -> Result<(Option<FileType>, u64)>;
+}
+
+impl SourceLinks for Comm<proto::files::Request> {
+    fn read_link(&mut self, path: &str) -> Result<String> {
+        Ok(self
+            .readlink(proto::files::RequestReadLink { path: path.into() })?
+            .target)
+    }
+
+    fn file_attrs(&mut self, path: &str) -> Result<(Option<FileType>, u64)> {
+        let attrs = self.getattr(proto::files::RequestGetAttr { path: path.into() })?;
+        Ok((FileType::from_i32(attrs.ftype), attrs.size))
+    }
+}
+
+/// Follow a link and return the file it points to (with its size) if it's a
+/// regular file inside the source volume, reached in less than MAX_LINK_HOPS
+/// links.
+fn follow_links(source: &mut impl SourceLinks, path: &str) -> Result<Option<(String, u64)>> {
+    let mut link = path.to_string();
+    for _ in 0..MAX_LINK_HOPS {
+        let target = source.read_link(&link)?;
+        let resolved = match resolve_link_target(&link, &target) {
+            Some(resolved) => resolved,
+            None => {
+                warn!("Link {} points outside of the source volume", path);
+                return Ok(None);
+            }
+        };
+        match source.file_attrs(&resolved)? {
+            (Some(FileType::Regular), size) => return Ok(Some((resolved, size))),
+            (Some(FileType::Symlink), _) => link = resolved,
+            _ => {
+                warn!("Link {} doesn't point to a regular file", path);
+                return Ok(None);
+            }
+        }
+    }
+    warn!("Too many levels of links for {}", path);
+    Ok(None)
+}
+
 enum State {
     Init(InitState),
     DevOpened(DevOpenedState),
@@ -198,7 +284,11 @@ impl State {
     }
 }
 
-struct InitState {}
+struct InitState {
+    signing_key: Option<SigningKey>,
+    symlink_policy: SymlinkPolicy,
+    damaged_file_policy: DamagedFilePolicy,
+}
 
 impl InitState {
     fn run(
@@ -214,8 +304,21 @@ impl InitState {
                 Msg::Id(_) => children.id(comm, &mut id),
                 Msg::Devices(_) => self.devices(comm, children),
                 Msg::OpenDevice(req) => {
-                    match self.open_device(comm, children, req.device.ok_or(Error::BadRequest)?) {
-                        Ok(device) => return Ok(State::DevOpened(DevOpenedState { device, id })),
+                    match self.open_device(
+                        comm,
+                        children,
+                        req.device.ok_or(Error::BadRequest)?,
+                        false,
+                    ) {
+                        Ok(device) => {
+                            return Ok(State::DevOpened(DevOpenedState {
+                                device,
+                                id,
+                                signing_key: self.signing_key,
+                                symlink_policy: self.symlink_policy,
+                                damaged_file_policy: self.damaged_file_policy,
+                            }))
+                        }
                         Err(err) => Err(err),
                     }
                 }
@@ -225,10 +328,16 @@ impl InitState {
                         devnum: req.devnum as u64,
                         quick: req.quick,
                         fstype: req.fstype,
+                        mode: req.mode,
                     }))
                 }
                 Msg::ImgDisk(req) => {
-                    match self.open_device(comm, children, req.device.ok_or(Error::BadRequest)?) {
+                    match self.open_device(
+                        comm,
+                        children,
+                        req.device.ok_or(Error::BadRequest)?,
+                        true,
+                    ) {
                         Ok(device) => return Ok(State::ImgDisk(ImgDiskState { device })),
                         Err(err) => Err(err),
                     }
@@ -270,14 +379,22 @@ impl InitState {
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
         dev_req: Device,
+        img_disk: bool,
     ) -> Result<UsbDevice> {
         trace!("req opendevice");
+        // Don't trust the client, use the device as listed (and checked
+        // against the device policy) by usbdev. Destinations can be imaged.
+        let dev_req = children.listed_device(dev_req.busnum, dev_req.devnum, Some(dev_req.lun))?;
+        if !(dev_req.is_src || (img_disk && dev_req.is_dst)) {
+            return Err(device_refused(&dev_req, "source"));
+        }
         let device = children
             .scsi2files
             .comm
             .opendevice(proto::files::RequestOpenDevice {
                 busnum: dev_req.busnum,
                 devnum: dev_req.devnum,
+                lun: dev_req.lun,
             })?;
         comm.opendevice(proto::usbsas::ResponseOpenDevice {
             sector_size: device.block_size,
@@ -300,6 +417,9 @@ impl InitState {
 struct DevOpenedState {
     device: UsbDevice,
     id: Option<String>,
+    signing_key: Option<SigningKey>,
+    symlink_policy: SymlinkPolicy,
+    damaged_file_policy: DamagedFilePolicy,
 }
 
 impl DevOpenedState {
@@ -318,6 +438,9 @@ impl DevOpenedState {
                         return Ok(State::PartitionOpened(PartitionOpenedState {
                             device: self.device,
                             id: self.id,
+                            signing_key: self.signing_key,
+                            symlink_policy: self.symlink_policy,
+                            damaged_file_policy: self.damaged_file_policy,
                         }))
                     }
                     Err(err) => {
@@ -376,6 +499,9 @@ impl DevOpenedState {
 struct PartitionOpenedState {
     device: UsbDevice,
     id: Option<String>,
+    signing_key: Option<SigningKey>,
+    symlink_policy: SymlinkPolicy,
+    damaged_file_policy: DamagedFilePolicy,
 }
 
 impl PartitionOpenedState {
@@ -389,14 +515,30 @@ impl PartitionOpenedState {
             let res = match req.msg.ok_or(Error::BadRequest)? {
                 Msg::Id(_) => children.id(comm, &mut self.id),
                 Msg::GetAttr(req) => self.get_attr(comm, children, req.path),
-                Msg::ReadDir(req) => self.read_dir(comm, children, req.path),
+                Msg::ReadDir(req) => self.read_dir(comm, children, req),
+                Msg::DirSize(req) => self.dir_size(comm, children, req.path),
                 Msg::CopyStart(req) => {
+                    let destination = req.destination.ok_or(Error::BadRequest)?;
+                    if let Destination::Usb(ref usb) = destination {
+                        let device = children.listed_device(usb.busnum, usb.devnum, None)?;
+                        if !device.is_dst {
+                            let err = device_refused(&device, "destination");
+                            error!("{}", err);
+                            comm.error(proto::usbsas::ResponseError {
+                                err: format!("{}", err),
+                            })?;
+                            continue;
+                        }
+                    }
                     if let Some(id) = self.id {
                         return Ok(State::CopyFiles(CopyFilesState {
                             device: self.device,
                             id,
                             selected: req.selected,
-                            destination: req.destination.ok_or(Error::BadRequest)?,
+                            destination,
+                            signing_key: self.signing_key,
+                            symlink_policy: self.symlink_policy,
+                            damaged_file_policy: self.damaged_file_policy,
                         }));
                     }
                     error!("empty id");
@@ -441,15 +583,42 @@ impl PartitionOpenedState {
         &mut self,
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
-        path: String,
+        req: proto::usbsas::RequestReadDir,
     ) -> Result<()> {
-        trace!("req read dir attrs: {}", &path);
+        trace!("req read dir attrs: {}", &req.path);
+        let rep = children
+            .scsi2files
+            .comm
+            .readdir(proto::files::RequestReadDir {
+                path: req.path,
+                offset: req.offset,
+                limit: req.limit,
+                sort: req.sort,
+                descending: req.descending,
+            })?;
         comm.readdir(proto::usbsas::ResponseReadDir {
-            filesinfo: children
-                .scsi2files
-                .comm
-                .readdir(proto::files::RequestReadDir { path })?
-                .filesinfo,
+            filesinfo: rep.filesinfo,
+            total: rep.total,
+        })?;
+        Ok(())
+    }
+
+    fn dir_size(
+        &mut self,
+        comm: &mut Comm<proto::usbsas::Request>,
+        children: &mut Children,
+        path: String,
+    ) -> Result<()> {
+        trace!("req dir size: {}", &path);
+        let rep = children
+            .scsi2files
+            .comm
+            .dirsize(proto::files::RequestDirSize { path })?;
+        comm.dirsize(proto::usbsas::ResponseDirSize {
+            size: rep.size,
+            files: rep.files,
+            directories: rep.directories,
+            truncated: rep.truncated,
         })?;
         Ok(())
     }
@@ -460,6 +629,9 @@ struct CopyFilesState {
     device: UsbDevice,
     id: String,
     selected: Vec<String>,
+    signing_key: Option<SigningKey>,
+    symlink_policy: SymlinkPolicy,
+    damaged_file_policy: DamagedFilePolicy,
 }
 
 impl CopyFilesState {
@@ -471,20 +643,28 @@ impl CopyFilesState {
         trace!("req copy");
         info!("Usbsas transfer for user: {}", self.id);
 
-        let mut errors = vec![];
+        // File systems of USB destinations can't hold links
+        if let (SymlinkPolicy::Link, Destination::Usb(_)) = (self.symlink_policy, &self.destination)
+        {
+            self.symlink_policy = SymlinkPolicy::Skip;
+        }
+
+        let destination = self.manifest_destination(children)?;
+        let mut manifest = Manifest::new(&self.id, &self.device, destination);
         let mut all_directories = vec![];
         let mut all_files = vec![];
+        let mut links = HashMap::new();
         let total_files_size = self.selected_to_files_list(
             children,
-            &mut errors,
+            &mut manifest,
             &mut all_files,
             &mut all_directories,
+            &mut links,
         )?;
-        let mut filtered: Vec<String> = Vec::new();
 
-        let all_files_filtered = self.filter_files(children, all_files, &mut filtered)?;
+        let mut all_files_filtered = self.filter_files(children, all_files, &mut manifest)?;
         let all_directories_filtered =
-            self.filter_files(children, all_directories, &mut filtered)?;
+            self.filter_files(children, all_directories, &mut manifest)?;
 
         let mut all_entries_filtered = vec![];
         all_entries_filtered.append(&mut all_directories_filtered.clone());
@@ -493,8 +673,9 @@ impl CopyFilesState {
         // Abort if no files passed name filtering
         if all_entries_filtered.is_empty() {
             comm.nothingtocopy(proto::usbsas::ResponseNothingToCopy {
-                rejected_filter: filtered,
+                rejected_filter: manifest.rejected(RejectStatus::Filtered),
                 rejected_dirty: vec![],
+                rejected_skipped: manifest.rejected(RejectStatus::Skipped),
             })?;
             warn!("Aborting copy, no files survived filter");
             return Ok(State::WaitEnd(WaitEndState {}));
@@ -513,9 +694,19 @@ impl CopyFilesState {
                     .comm
                     .size(proto::fs2dev::RequestDevSize {})?
                     .size;
+                // Encrypted copies are a tar archive with a header and
+                // padding per entry, and encryption adds its own overhead
+                let needed_size = if usb.passphrase.is_empty() {
+                    total_files_size
+                } else {
+                    encrypt::encrypted_size(
+                        total_files_size
+                            + (all_entries_filtered.len() as u64 + 1) * 2 * TAR_BLOCK_SIZE as u64,
+                    )
+                };
                 // Check dest dev is large enough
                 // XXX try to be more precise about this
-                if total_files_size > (dev_size * 98 / 100) {
+                if needed_size > (dev_size * 98 / 100) {
                     comm.notenoughspace(proto::usbsas::ResponseNotEnoughSpace {
                         max_size: dev_size,
                     })?;
@@ -542,21 +733,25 @@ impl CopyFilesState {
             comm,
             children,
             &all_entries_filtered,
-            &mut errors,
+            &links,
+            &mut manifest,
             max_file_size,
         )?;
 
+        // Skipped damaged files aren't in the tar
+        let damaged = manifest.rejected(RejectStatus::Damaged);
+        all_files_filtered.retain(|path| !damaged.contains(path));
+
         match self.destination {
             Destination::Usb(usb) => {
                 children.tar2files.comm.write_all(&[1_u8])?;
                 children.tar2files.locked = false;
                 Ok(State::WriteFiles(WriteFilesState {
                     directories: all_directories_filtered,
-                    dirty: Vec::new(),
-                    errors,
                     files: all_files_filtered,
-                    filtered,
                     id: self.id,
+                    manifest,
+                    signing_key: self.signing_key,
                     usb,
                 }))
             }
@@ -564,22 +759,43 @@ impl CopyFilesState {
                 children.tar2files.comm.write_all(&[0_u8])?;
                 children.tar2files.locked = false;
                 Ok(State::UploadOrCmd(UploadOrCmdState {
-                    errors,
-                    filtered,
                     id: self.id,
+                    manifest,
                     destination: self.destination,
                 }))
             }
         }
     }
 
-    /// Expand tree of selected files and directories and compute total files size
+    fn manifest_destination(&mut self, children: &mut Children) -> Result<manifest::Destination> {
+        Ok(match self.destination {
+            Destination::Usb(ref usb) => {
+                let devices = children
+                    .usbdev
+                    .comm
+                    .devices(proto::usbdev::RequestDevices {})?
+                    .devices;
+                Manifest::usb_destination(
+                    devices
+                        .iter()
+                        .find(|dev| dev.busnum == usb.busnum && dev.devnum == usb.devnum),
+                )
+            }
+            Destination::Net(_) => manifest::Destination::Net,
+            Destination::Cmd(_) => manifest::Destination::Cmd,
+        })
+    }
+
+    /// Expand tree of selected files and directories and compute total files size.
+    /// Links that aren't skipped are added to `files` and to `links` (see
+    /// link_source()).
     fn selected_to_files_list(
         &mut self,
         children: &mut Children,
-        errors: &mut Vec<String>,
+        manifest: &mut Manifest,
         files: &mut Vec<String>,
         directories: &mut Vec<String>,
+        links: &mut HashMap<String, String>,
     ) -> Result<u64> {
         let mut total_size: u64 = 0;
         let mut todo = VecDeque::from(self.selected.to_vec());
@@ -604,8 +820,8 @@ impl CopyFilesState {
                     path: entry.clone(),
                 }) {
                 Ok(rep) => rep,
-                Err(_) => {
-                    errors.push(entry);
+                Err(err) => {
+                    manifest.reject(&entry, RejectStatus::Error, &format!("{}", err));
                     continue;
                 }
             };
@@ -625,22 +841,68 @@ impl CopyFilesState {
                     let rep = children
                         .scsi2files
                         .comm
-                        .readdir(proto::files::RequestReadDir { path: entry })?;
+                        .readdir(proto::files::RequestReadDir {
+                            path: entry,
+                            ..Default::default()
+                        })?;
                     for file in rep.filesinfo.iter() {
                         todo.push_back(file.path.clone());
                     }
                 }
-                _ => errors.push(entry),
+                Some(FileType::Symlink) => {
+                    if all_entries.contains(&entry) {
+                        continue;
+                    }
+                    match self.link_source(children, &entry) {
+                        Ok(Some((source, size))) => {
+                            links.insert(entry.clone(), source);
+                            files.push(entry.clone());
+                            all_entries.insert(entry);
+                            total_size += size;
+                        }
+                        Ok(None) => {
+                            warn!("Skipping link {}", &entry);
+                            manifest.reject(&entry, RejectStatus::Skipped, "symbolic link");
+                        }
+                        Err(err) => {
+                            error!("Couldn't resolve link {}: {}", &entry, err);
+                            manifest.reject(&entry, RejectStatus::Error, &format!("{}", err));
+                        }
+                    }
+                }
+                _ => manifest.reject(&entry, RejectStatus::Error, "unsupported file type"),
             }
         }
         Ok(total_size)
     }
 
+    /// Apply the symlink policy to a link, returns its target if it's kept as
+    /// a link or the file to copy (and its size) if it's dereferenced. None
+    /// means the link is skipped.
+    fn link_source(
+        &mut self,
+        children: &mut Children,
+        path: &str,
+    ) -> Result<Option<(String, u64)>> {
+        match self.symlink_policy {
+            SymlinkPolicy::Skip => Ok(None),
+            SymlinkPolicy::Link => Ok(Some((
+                children
+                    .scsi2files
+                    .comm
+                    .readlink(proto::files::RequestReadLink { path: path.into() })?
+                    .target,
+                0,
+            ))),
+            SymlinkPolicy::Dereference => follow_links(&mut children.scsi2files.comm, path),
+        }
+    }
+
     fn filter_files(
         &mut self,
         children: &mut Children,
         files: Vec<String>,
-        filtered: &mut Vec<String>,
+        manifest: &mut Manifest,
     ) -> Result<Vec<String>> {
         trace!("filter files");
         let mut filtered_files: Vec<String> = Vec::new();
@@ -655,10 +917,14 @@ impl CopyFilesState {
             return Err(Error::Error("filter error".to_string()));
         }
         for (i, f) in files.iter().enumerate().take(files_count) {
-            if rep.results[i] == proto::filter::FilterResult::PathOk as i32 {
+            if [ENCRYPTED_FILE_NAME, MANIFEST_FILE_NAME, SIGNATURE_FILE_NAME]
+                .contains(&f.trim_start_matches('/'))
+            {
+                manifest.reject(f, RejectStatus::Filtered, "reserved file name");
+            } else if rep.results[i] == proto::filter::FilterResult::PathOk as i32 {
                 filtered_files.push(f.clone());
             } else {
-                filtered.push(f.clone());
+                manifest.reject(f, RejectStatus::Filtered, "file name filter");
             }
         }
         Ok(filtered_files)
@@ -669,16 +935,32 @@ impl CopyFilesState {
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
         selected: &[String],
-        errors: &mut Vec<String>,
+        links: &HashMap<String, String>,
+        manifest: &mut Manifest,
         max_file_size: Option<u64>,
     ) -> Result<()> {
         trace!("tar src files");
         for path in selected {
-            if let Err(err) = self.file_to_tar(comm, children, path, max_file_size) {
+            let res = match (links.get(path), self.symlink_policy) {
+                (Some(target), SymlinkPolicy::Link) => {
+                    self.link_to_tar(children, path, target, manifest)
+                }
+                (Some(src_path), _) => {
+                    self.file_to_tar(comm, children, path, src_path, max_file_size, manifest)
+                }
+                (None, _) => self.file_to_tar(comm, children, path, path, max_file_size, manifest),
+            };
+            if let Err(err) = res {
                 error!("Couldn't copy file {}: {}", &path, err);
-                errors.push(path.clone());
+                manifest.reject(path, RejectStatus::Error, &format!("{}", err));
             };
         }
+        // The manifest of USB transfers is written on the destination fs
+        // after analysis
+        let manifest_json = match self.destination {
+            Destination::Usb(_) => Vec::new(),
+            Destination::Net(_) | Destination::Cmd(_) => manifest.to_json()?,
+        };
         children
             .files2tar
             .comm
@@ -689,22 +971,61 @@ impl CopyFilesState {
                 manufacturer: self.device.manufacturer.clone(),
                 serial: self.device.serial.clone(),
                 description: self.device.description.clone(),
+                manifest: manifest_json,
             })?;
         comm.copystatusdone(proto::usbsas::ResponseCopyStatusDone {})?;
         Ok(())
     }
 
+    fn link_to_tar(
+        &mut self,
+        children: &mut Children,
+        path: &str,
+        target: &str,
+        manifest: &mut Manifest,
+    ) -> Result<()> {
+        let attrs = children
+            .scsi2files
+            .comm
+            .getattr(proto::files::RequestGetAttr { path: path.into() })?;
+        children
+            .files2tar
+            .comm
+            .newfile(proto::writetar::RequestNewFile {
+                path: path.to_string(),
+                size: 0,
+                ftype: FileType::Symlink.into(),
+                timestamp: attrs.timestamp,
+                link_target: target.to_string(),
+            })?;
+        children
+            .files2tar
+            .comm
+            .endfile(proto::writetar::RequestEndFile {
+                path: path.to_string(),
+                discard: false,
+            })?;
+        manifest.add_link(path, target);
+        Ok(())
+    }
+
+    /// Write file `src_path` of the source in the tar as `path`, they only
+    /// differ for dereferenced links.
     fn file_to_tar(
         &mut self,
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
         path: &str,
+        src_path: &str,
         max_file_size: Option<u64>,
+        manifest: &mut Manifest,
     ) -> Result<()> {
         let mut attrs = children
             .scsi2files
             .comm
-            .getattr(proto::files::RequestGetAttr { path: path.into() })?;
+            .getattr(proto::files::RequestGetAttr {
+                path: src_path.into(),
+            })?;
 
         if let Some(max_size) = max_file_size {
             if attrs.size > max_size {
@@ -729,9 +1050,14 @@ impl CopyFilesState {
                 size: attrs.size,
                 ftype: attrs.ftype,
                 timestamp: attrs.timestamp,
+                link_target: String::new(),
             })?;
 
+        let file_size = attrs.size;
+        let mut hasher = Sha256::new();
         let mut offset: u64 = 0;
+        // Unreadable sectors are zero-filled by dev2scsi
+        let mut bad_sectors = 0;
         while attrs.size > 0 {
             let size_todo = if attrs.size < READ_FILE_MAX_SIZE {
                 attrs.size
@@ -742,10 +1068,12 @@ impl CopyFilesState {
                 .scsi2files
                 .comm
                 .readfile(proto::files::RequestReadFile {
-                    path: path.to_string(),
+                    path: src_path.to_string(),
                     offset,
                     size: size_todo,
                 })?;
+            bad_sectors += rep.bad_sectors.len() as u64;
+            hasher.update(&rep.data);
             children
                 .files2tar
                 .comm
@@ -761,24 +1089,91 @@ impl CopyFilesState {
             })?;
         }
 
+        // Skipped damaged files are removed from the archive, whatever the
+        // destination
+        let discard =
+            bad_sectors != 0 && matches!(self.damaged_file_policy, DamagedFilePolicy::Skip);
         children
             .files2tar
             .comm
             .endfile(proto::writetar::RequestEndFile {
                 path: path.to_string(),
+                discard,
             })?;
 
+        if let Some(FileType::Regular) = FileType::from_i32(attrs.ftype) {
+            manifest.add_file(
+                path,
+                file_size,
+                format!("{:x}", hasher.finalize()),
+                if path != src_path {
+                    Some(src_path)
+                } else {
+                    None
+                },
+            );
+        }
+
+        if bad_sectors != 0 {
+            warn!("File '{}' has {} unreadable sectors", path, bad_sectors);
+            match self.damaged_file_policy {
+                DamagedFilePolicy::Skip => manifest.reject(
+                    path,
+                    RejectStatus::Damaged,
+                    &format!("{} unreadable sectors", bad_sectors),
+                ),
+                DamagedFilePolicy::Zerofill => manifest.set_damaged(path, bad_sectors),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn now_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn faulty_device_error(bad_sector_count: u64) -> String {
+    format!(
+        "destination device is faulty or fake: {} sectors read back don't match what was written",
+        bad_sector_count
+    )
+}
+
+/// Writer of a file of the destination fs, through files2fs
+struct FsFileWriter<'a> {
+    comm: &'a mut Comm<proto::writefs::Request>,
+    path: String,
+    offset: u64,
+}
+
+impl Write for FsFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), READ_FILE_MAX_SIZE as usize);
+        self.comm.writefile(proto::writefs::RequestWriteFile {
+            path: self.path.clone(),
+            offset: self.offset,
+            data: buf[..len].to_vec(),
+        })?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
 
 struct WriteFilesState {
     directories: Vec<String>,
-    dirty: Vec<String>,
-    errors: Vec<String>,
     files: Vec<String>,
-    filtered: Vec<String>,
     id: String,
+    manifest: Manifest,
+    signing_key: Option<SigningKey>,
     usb: proto::usbsas::DestUsb,
 }
 
@@ -793,8 +1188,9 @@ impl WriteFilesState {
         // Abort if no files survived antivirus
         if self.files.is_empty() {
             comm.nothingtocopy(proto::usbsas::ResponseNothingToCopy {
-                rejected_filter: self.filtered,
-                rejected_dirty: self.dirty,
+                rejected_filter: self.manifest.rejected(RejectStatus::Filtered),
+                rejected_dirty: self.manifest.rejected(RejectStatus::Dirty),
+                rejected_skipped: self.manifest.rejected(RejectStatus::Skipped),
             })?;
             warn!("Aborting copy, no files survived antivirus");
             return Ok(State::WaitEnd(WaitEndState {}));
@@ -802,6 +1198,59 @@ impl WriteFilesState {
 
         self.init_fs(children)?;
 
+        let manifest = if self.usb.passphrase.is_empty() {
+            self.write_files(comm, children)?;
+            let manifest = self.manifest.to_json()?;
+            for (name, data) in self.meta_files(&manifest) {
+                self.write_meta_file(children, name, &data)?;
+            }
+            manifest
+        } else {
+            self.write_encrypted(comm, children)?
+        };
+
+        children
+            .files2fs
+            .comm
+            .close(proto::writefs::RequestClose {})?;
+        comm.copystatusdone(proto::usbsas::ResponseCopyStatusDone {})?;
+
+        children.forward_bitvec()?;
+        match self.write_fs(comm, children) {
+            Ok(bad_sector_count) => {
+                let device_error = if bad_sector_count != 0 {
+                    error!("USB TRANSFER FAILED (faulty device) for user {}", self.id);
+                    faulty_device_error(bad_sector_count)
+                } else {
+                    info!("USB TRANSFER DONE for user {}", self.id);
+                    String::new()
+                };
+                comm.copydone(proto::usbsas::ResponseCopyDone {
+                    error_path: self.manifest.rejected(RejectStatus::Error),
+                    filtered_path: self.manifest.rejected(RejectStatus::Filtered),
+                    dirty_path: self.manifest.rejected(RejectStatus::Dirty),
+                    skipped_path: self.manifest.rejected(RejectStatus::Skipped),
+                    manifest,
+                    device_error,
+                    damaged_path: self.manifest.damaged(),
+                })?;
+            }
+            Err(err) => {
+                comm.error(proto::usbsas::ResponseError {
+                    err: format!("err writing fs: {}", err),
+                })?;
+                error!("USB TRANSFER FAILED for user {}", self.id);
+            }
+        }
+
+        Ok(State::TransferDone(TransferDoneState {}))
+    }
+
+    fn write_files(
+        &mut self,
+        comm: &mut Comm<proto::usbsas::Request>,
+        children: &mut Children,
+    ) -> Result<()> {
         trace!("copy usb");
 
         // Create directory tree
@@ -832,7 +1281,8 @@ impl WriteFilesState {
                 Ok(rep) => rep,
                 Err(err) => {
                     error!("{}", err);
-                    self.errors.push(path.clone());
+                    self.manifest
+                        .reject(path, RejectStatus::Error, &format!("{}", err));
                     continue;
                 }
             };
@@ -848,36 +1298,142 @@ impl WriteFilesState {
                 Ok(_) => (),
                 Err(err) => {
                     warn!("didn't copy file {}: {}", path, err);
-                    self.errors.push(path.clone());
+                    self.manifest
+                        .reject(path, RejectStatus::Error, &format!("{}", err));
                 }
             }
         }
+        Ok(())
+    }
 
+    /// Write directories and files in a tar encrypted with the passphrase of
+    /// the user, stored as a single file at the root of the destination fs.
+    /// The manifest (and its signature) lists the files and their hashes, it
+    /// is written in the encrypted tar too. Returns the manifest.
+    fn write_encrypted(
+        &mut self,
+        comm: &mut Comm<proto::usbsas::Request>,
+        children: &mut Children,
+    ) -> Result<Vec<u8>> {
+        trace!("copy usb encrypted");
+        let path = format!("/{}", ENCRYPTED_FILE_NAME);
         children
             .files2fs
             .comm
-            .close(proto::writefs::RequestClose {})?;
-        comm.copystatusdone(proto::usbsas::ResponseCopyStatusDone {})?;
+            .newfile(proto::writefs::RequestNewFile {
+                path: path.clone(),
+                size: 0,
+                ftype: FileType::Regular.into(),
+                timestamp: now_timestamp(),
+            })?;
 
-        children.forward_bitvec()?;
-        match self.write_fs(comm, children) {
-            Ok(()) => {
-                comm.copydone(proto::usbsas::ResponseCopyDone {
-                    error_path: self.errors,
-                    filtered_path: self.filtered,
-                    dirty_path: self.dirty,
+        let tar2files = &mut children.tar2files.comm;
+        let writer = encrypt::passphrase_writer(
+            FsFileWriter {
+                comm: &mut children.files2fs.comm,
+                path: path.clone(),
+                offset: 0,
+            },
+            &self.usb.passphrase,
+        )?;
+        let mut builder = tar::Builder::new(writer);
+
+        for dir in &self.directories {
+            let timestamp = tar2files
+                .getattr(proto::files::RequestGetAttr { path: dir.clone() })?
+                .timestamp;
+            let mut header = tar::Header::new_ustar();
+            header.set_size(0);
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_mtime(timestamp as u64);
+            builder.append_data(&mut header, dir.trim_start_matches('/'), io::empty())?;
+        }
+
+        for path in &self.files {
+            let attrs = match tar2files.getattr(proto::files::RequestGetAttr { path: path.clone() })
+            {
+                Ok(rep) => rep,
+                Err(err) => {
+                    error!("{}", err);
+                    self.manifest
+                        .reject(path, RejectStatus::Error, &format!("{}", err));
+                    continue;
+                }
+            };
+            let mut header = tar::Header::new_ustar();
+            header.set_size(attrs.size);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_mtime(attrs.timestamp as u64);
+            builder.append_data(&mut header, path.trim_start_matches('/'), io::empty())?;
+
+            // Once the header is written, the entry can't be removed from the
+            // archive: after a read error, the rest of the file is zero-filled
+            // and the file is rejected.
+            let mut offset: u64 = 0;
+            let mut read_error = None;
+            while offset < attrs.size {
+                let size_todo = std::cmp::min(attrs.size - offset, READ_FILE_MAX_SIZE);
+                let mut data = vec![];
+                if read_error.is_none() {
+                    match tar2files.readfile(proto::files::RequestReadFile {
+                        path: path.to_string(),
+                        offset,
+                        size: size_todo,
+                    }) {
+                        Ok(rep) => data = rep.data,
+                        Err(err) => read_error = Some(err),
+                    }
+                }
+                if read_error.is_some() {
+                    data = vec![0; size_todo as usize];
+                }
+                builder.get_mut().write_all(&data)?;
+                offset += size_todo;
+                comm.copystatus(proto::usbsas::ResponseCopyStatus {
+                    current_size: size_todo,
                 })?;
-                info!("USB TRANSFER DONE for user {}", self.id);
             }
-            Err(err) => {
-                comm.error(proto::usbsas::ResponseError {
-                    err: format!("err writing fs: {}", err),
-                })?;
-                error!("USB TRANSFER FAILED for user {}", self.id);
+            let padding =
+                (TAR_BLOCK_SIZE - (attrs.size % TAR_BLOCK_SIZE as u64) as usize) % TAR_BLOCK_SIZE;
+            builder.get_mut().write_all(&vec![0; padding])?;
+            if let Some(err) = read_error {
+                warn!("didn't copy file {}: {}", path, err);
+                self.manifest.reject(
+                    path,
+                    RejectStatus::Error,
+                    &format!("{} (zero-filled in the archive)", err),
+                );
             }
         }
 
-        Ok(State::TransferDone(TransferDoneState {}))
+        let manifest = self.manifest.to_json()?;
+        for (name, data) in self.meta_files(&manifest) {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(data.len() as u64);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_mtime(now_timestamp() as u64);
+            builder.append_data(&mut header, name, data.as_slice())?;
+        }
+
+        builder.into_inner()?.finish()?;
+        children
+            .files2fs
+            .comm
+            .endfile(proto::writefs::RequestEndFile { path })?;
+        Ok(manifest)
+    }
+
+    /// Manifest and its signature (if a signing key is configured), written
+    /// at the root of the destination
+    fn meta_files(&self, manifest: &[u8]) -> Vec<(&'static str, Vec<u8>)> {
+        let mut files = vec![(MANIFEST_FILE_NAME, manifest.to_vec())];
+        if let Some(ref signing_key) = self.signing_key {
+            files.push((SIGNATURE_FILE_NAME, signing_key.sign(manifest).into_bytes()));
+        }
+        files
     }
 
     fn init_fs(&mut self, children: &mut Children) -> Result<()> {
@@ -885,13 +1441,13 @@ impl WriteFilesState {
         let dev_size = children
             .fs2dev
             .comm
-            .size(proto::fs2dev::RequestDevSize {})?
-            .size;
+            .size(proto::fs2dev::RequestDevSize {})?;
         children
             .files2fs
             .comm
             .setfsinfos(proto::writefs::RequestSetFsInfos {
-                dev_size,
+                dev_size: dev_size.size,
+                sector_size: dev_size.block_size,
                 fstype: self.usb.fstype,
             })?;
         Ok(())
@@ -921,11 +1477,26 @@ impl WriteFilesState {
                             "Analyzer status: clean: {:#?}, dirty: {:#?}",
                             &res.clean, &res.dirty
                         );
-                        self.files
-                            .retain(|x| res.clean.contains(&x.trim_start_matches('/').to_string()));
-                        res.dirty
-                            .iter()
-                            .for_each(|p| self.dirty.push(format!("/{}", p)));
+                        let (clean, rejected): (Vec<String>, Vec<String>) =
+                            self.files.drain(..).partition(|x| {
+                                res.clean.contains(&x.trim_start_matches('/').to_string())
+                            });
+                        self.files = clean;
+                        for path in rejected {
+                            if res
+                                .dirty
+                                .contains(&path.trim_start_matches('/').to_string())
+                            {
+                                self.manifest.reject(
+                                    &path,
+                                    RejectStatus::Dirty,
+                                    "detected by analyzer",
+                                );
+                            } else {
+                                self.manifest
+                                    .reject(&path, RejectStatus::Error, "not analyzed");
+                            }
+                        }
                         comm.analyzedone(proto::usbsas::ResponseAnalyzeDone {})?;
                         return Ok(());
                     }
@@ -1009,11 +1580,46 @@ impl WriteFilesState {
         Ok(())
     }
 
+    /// Write the transfer manifest or its signature at the root of the
+    /// destination fs
+    fn write_meta_file(&self, children: &mut Children, name: &str, data: &[u8]) -> Result<()> {
+        trace!("write {}", name);
+        let path = format!("/{}", name);
+        children
+            .files2fs
+            .comm
+            .newfile(proto::writefs::RequestNewFile {
+                path: path.clone(),
+                size: data.len() as u64,
+                ftype: FileType::Regular.into(),
+                timestamp: now_timestamp(),
+            })?;
+        let mut offset: u64 = 0;
+        for chunk in data.chunks(READ_FILE_MAX_SIZE as usize) {
+            children
+                .files2fs
+                .comm
+                .writefile(proto::writefs::RequestWriteFile {
+                    path: path.clone(),
+                    offset,
+                    data: chunk.to_vec(),
+                })?;
+            offset += chunk.len() as u64;
+        }
+        children
+            .files2fs
+            .comm
+            .endfile(proto::writefs::RequestEndFile { path })?;
+        Ok(())
+    }
+
+    /// Write the fs on the destination device, returns the number of sectors
+    /// that didn't match when read back (if verification is enabled)
     fn write_fs(
         &mut self,
         comm: &mut Comm<proto::usbsas::Request>,
         children: &mut Children,
-    ) -> Result<()> {
+    ) -> Result<u64> {
         use proto::fs2dev::response::Msg;
         children
             .fs2dev
@@ -1028,23 +1634,21 @@ impl WriteFilesState {
                         total_size: status.total_size,
                     })?;
                 }
-                Msg::CopyStatusDone(_) => {
+                Msg::CopyStatusDone(status) => {
                     comm.finalcopystatusdone(proto::usbsas::ResponseFinalCopyStatusDone {})?;
-                    break;
+                    return Ok(status.bad_sector_count);
                 }
                 Msg::Error(msg) => return Err(Error::Error(msg.err)),
                 _ => return Err(Error::Error("error writing fs".into())),
             }
         }
-        Ok(())
     }
 }
 
 struct UploadOrCmdState {
     destination: Destination,
-    errors: Vec<String>,
-    filtered: Vec<String>,
     id: String,
+    manifest: Manifest,
 }
 
 impl UploadOrCmdState {
@@ -1068,9 +1672,13 @@ impl UploadOrCmdState {
 
         comm.finalcopystatusdone(proto::usbsas::ResponseFinalCopyStatusDone {})?;
         comm.copydone(proto::usbsas::ResponseCopyDone {
-            error_path: self.errors,
-            filtered_path: self.filtered,
+            error_path: self.manifest.rejected(RejectStatus::Error),
+            filtered_path: self.manifest.rejected(RejectStatus::Filtered),
             dirty_path: Vec::new(),
+            skipped_path: self.manifest.rejected(RejectStatus::Skipped),
+            manifest: self.manifest.to_json()?,
+            device_error: String::new(),
+            damaged_path: self.manifest.damaged(),
         })?;
 
         info!("NET TRANSFER DONE for user {}", self.id);
@@ -1125,6 +1733,7 @@ struct WipeState {
     devnum: u64,
     quick: bool,
     fstype: i32,
+    mode: i32,
 }
 
 impl WipeState {
@@ -1136,6 +1745,12 @@ impl WipeState {
         use proto::fs2dev::response::Msg;
         trace!("req wipe");
 
+        // Wiped devices are written, they must be allowed as destinations
+        let device = children.listed_device(self.busnum as u32, self.devnum as u32, None)?;
+        if !device.is_dst {
+            return Err(device_refused(&device, "destination"));
+        }
+
         // Unlock fs2dev
         children
             .fs2dev
@@ -1143,9 +1758,18 @@ impl WipeState {
             .write_all(&((self.devnum << 32) | self.busnum).to_ne_bytes())?;
         children.fs2dev.locked = false;
 
+        // Mode and description of the wipe actually done (the device may not
+        // support the requested one)
+        let mut wipe_done = proto::usbsas::ResponseWipe::default();
+        // Sectors that didn't read back as written, by the wipe passes and
+        // by the new fs
+        let mut bad_sector_count = 0;
         if !self.quick {
             trace!("secure wipe");
-            children.fs2dev.comm.wipe(proto::fs2dev::RequestWipe {})?;
+            children
+                .fs2dev
+                .comm
+                .wipe(proto::fs2dev::RequestWipe { mode: self.mode })?;
             loop {
                 let rep: proto::fs2dev::Response = children.fs2dev.comm.recv()?;
                 match rep.msg.ok_or(Error::BadRequest)? {
@@ -1155,7 +1779,12 @@ impl WipeState {
                             total_size: status.total_size,
                         })?
                     }
-                    Msg::CopyStatusDone(_) => break,
+                    Msg::WipeDone(done) => {
+                        wipe_done.mode = done.mode;
+                        wipe_done.method = done.method;
+                        bad_sector_count += done.bad_sector_count;
+                        break;
+                    }
                     _ => {
                         return Err(Error::Error("fs2dev err while wiping".into()));
                     }
@@ -1168,13 +1797,13 @@ impl WipeState {
         let dev_size = children
             .fs2dev
             .comm
-            .size(proto::fs2dev::RequestDevSize {})?
-            .size;
+            .size(proto::fs2dev::RequestDevSize {})?;
         children
             .files2fs
             .comm
             .setfsinfos(proto::writefs::RequestSetFsInfos {
-                dev_size,
+                dev_size: dev_size.size,
+                sector_size: dev_size.block_size,
                 fstype: self.fstype,
             })?;
         children
@@ -1196,8 +1825,16 @@ impl WipeState {
                         total_size: status.total_size,
                     })?;
                 }
-                Msg::CopyStatusDone(_) => {
-                    comm.wipe(proto::usbsas::ResponseWipe {})?;
+                Msg::CopyStatusDone(status) => {
+                    bad_sector_count += status.bad_sector_count;
+                    if bad_sector_count != 0 {
+                        error!("faulty device, {} bad sectors", bad_sector_count);
+                        comm.error(proto::usbsas::ResponseError {
+                            err: faulty_device_error(bad_sector_count),
+                        })?;
+                    } else {
+                        comm.wipe(wipe_done)?;
+                    }
                     break;
                 }
                 _ => {
@@ -1211,8 +1848,8 @@ impl WipeState {
         }
 
         info!(
-            "WIPE DONE (bus/devnum: {}/{} - quick: {})",
-            self.busnum, self.devnum, self.quick
+            "WIPE DONE (bus/devnum: {}/{} - quick: {} - mode: {})",
+            self.busnum, self.devnum, self.quick, self.mode
         );
         Ok(State::WaitEnd(WaitEndState {}))
     }
@@ -1361,8 +1998,38 @@ struct Children {
     usbdev: UsbsasChild<proto::usbdev::Request>,
 }
 
+/// Error reported when a device isn't allowed in a role, with the reason
+/// given by usbdev if it was refused by the device policy
+fn device_refused(device: &Device, role: &str) -> Error {
+    let reason = if device.refused.is_empty() {
+        format!("not plugged on a {} port", role)
+    } else {
+        device.refused.clone()
+    };
+    Error::Error(format!(
+        "device {}/{} refused as {}: {}",
+        device.busnum, device.devnum, role, reason
+    ))
+}
+
 // Functions shared by multiple states are implementend on this struct.
 impl Children {
+    /// Get a device as listed by usbdev, on `lun` or as listed first (the
+    /// LUN written when it's a destination)
+    fn listed_device(&mut self, busnum: u32, devnum: u32, lun: Option<u32>) -> Result<Device> {
+        self.usbdev
+            .comm
+            .devices(proto::usbdev::RequestDevices {})?
+            .devices
+            .into_iter()
+            .find(|dev| {
+                dev.busnum == busnum
+                    && dev.devnum == devnum
+                    && lun.map_or(true, |lun| dev.lun == lun)
+            })
+            .ok_or_else(|| Error::Error(format!("device {}/{} not found", busnum, devnum)))
+    }
+
     fn id(
         &mut self,
         comm: &mut Comm<proto::usbsas::Request>,
@@ -1456,66 +2123,60 @@ impl Children {
         Ok(())
     }
 
-    fn wait_all(&mut self) -> Result<()> {
+    /// Wait the children, returns the ones which ended abnormally
+    fn wait_all(&self) -> Vec<String> {
         debug!("waiting children");
-        if let Some(ref mut analyzer) = self.analyzer {
-            trace!("waiting analyzer");
-            if let Err(err) = analyzer.wait() {
-                error!("Waiting analyzer failed: {}", err);
-            };
-        };
-        trace!("waiting identificator");
-        if let Err(err) = self.identificator.wait() {
-            error!("Waiting identificator failed: {}", err);
-        };
-        trace!("waiting cmdexec");
-        if let Err(err) = self.cmdexec.wait() {
-            error!("Waiting cmdexec failed: {}", err);
-        };
-        trace!("waiting files2fs");
-        if let Err(err) = self.files2fs.wait() {
-            error!("Waiting files2fs failed: {}", err);
-        };
-        trace!("waiting files2tar");
-        if let Err(err) = self.files2tar.wait() {
-            error!("Waiting files2tar failed: {}", err);
-        };
-        trace!("waiting filter");
-        if let Err(err) = self.filter.wait() {
-            error!("Waiting filter failed: {}", err);
-        };
-        trace!("waiting fs2dev");
-        if let Err(err) = self.fs2dev.wait() {
-            error!("Waiting fs2dev failed: {}", err);
-        };
-        trace!("waiting scsi2files");
-        if let Err(err) = self.scsi2files.wait() {
-            error!("Waiting scsi2files failed: {}", err);
+        let mut crashed = Vec::new();
+        if let Some(ref analyzer) = self.analyzer {
+            wait_child("analyzer", analyzer, &mut crashed);
         };
-        trace!("waiting tar2files");
-        if let Err(err) = self.tar2files.wait() {
-            error!("Waiting tar2files failed: {}", err);
-        };
-        trace!("waiting uploader");
-        if let Err(err) = self.uploader.wait() {
-            error!("Waiting uploader failed: {}", err);
-        };
-        trace!("waiting usbdev");
-        if let Err(err) = self.usbdev.wait() {
-            error!("Waiting usbdev failed: {}", err);
-        };
-        Ok(())
+        wait_child("identificator", &self.identificator, &mut crashed);
+        wait_child("cmdexec", &self.cmdexec, &mut crashed);
+        wait_child("files2fs", &self.files2fs, &mut crashed);
+        wait_child("files2tar", &self.files2tar, &mut crashed);
+        wait_child("filter", &self.filter, &mut crashed);
+        wait_child("fs2dev", &self.fs2dev, &mut crashed);
+        wait_child("scsi2files", &self.scsi2files, &mut crashed);
+        wait_child("tar2files", &self.tar2files, &mut crashed);
+        wait_child("uploader", &self.uploader, &mut crashed);
+        wait_child("usbdev", &self.usbdev, &mut crashed);
+        crashed
     }
 
     fn end_wait_all(&mut self, comm: &mut Comm<proto::usbsas::Request>) -> Result<()> {
         trace!("req end");
         self.end_all()?;
-        self.wait_all()?;
-        comm.end(proto::usbsas::ResponseEnd {})?;
+        let crashed = self.wait_all();
+        comm.end(proto::usbsas::ResponseEnd { crashed })?;
         Ok(())
     }
 }
 
+/// Wait a child, it is added to `crashed` if it was killed by a signal or
+/// exited with an error. The sandbox forwards the signal killing the process
+/// it runs, exit codes above 128 (128 + signal) are only its fallback.
+fn wait_child<R>(name: &str, child: &UsbsasChild<R>, crashed: &mut Vec<String>) {
+    trace!("waiting {}", name);
+    match child.wait() {
+        Ok(WaitStatus::Exited(_, 0)) => (),
+        Ok(status) => {
+            error!("{} ended abnormally: {:?}", name, status);
+            crashed.push(name.to_string());
+        }
+        Err(err) => error!("Waiting {} failed: {}", name, err),
+    }
+}
+
+/// Let usbdev and fs2dev read the registry of enrolled devices
+fn read_registry(spawner: UsbsasChildSpawner, config: &Config) -> UsbsasChildSpawner {
+    match config.enrollment {
+        Some(ref enrollment) => spawner
+            .read_path(&enrollment.registry_path)
+            .read_path(&format!("{}.sig", enrollment.registry_path)),
+        None => spawner,
+    }
+}
+
 pub struct Usbsas {
     comm: Comm<proto::usbsas::Request>,
     children: Children,
@@ -1531,6 +2192,20 @@ impl Usbsas {
         analyze: bool,
     ) -> Result<Self> {
         trace!("init");
+        let config = conf_parse(&conf_read(config_path)?)?;
+        // The signing key must be loaded before entering seccomp
+        let signing_key = match config.signature {
+            Some(ref signature) => Some(SigningKey::from_file(&signature.key_path)?),
+            None => None,
+        };
+        usbsas_process::sandbox::set_required(config.sandbox_required.unwrap_or(false));
+        // Inherited by the children, the environment variable is used otherwise
+        if let Some(audit) = config.seccomp_audit {
+            usbsas_privileges::audit::set_mode(Some(match audit {
+                SeccompAudit::Log => usbsas_privileges::audit::AuditMode::Log,
+                SeccompAudit::Trap => usbsas_privileges::audit::AuditMode::Trap,
+            }));
+        }
         let mut pipes_read = vec![];
         let mut pipes_write = vec![];
 
@@ -1546,23 +2221,34 @@ impl Usbsas {
             .arg(out_tar)
             .arg(out_fs)
             .arg(config_path)
+            .host_fs()
+            .network()
             .spawn::<usbsas_cmdexec::CmdExec, proto::cmdexec::Request>()?;
         pipes_read.push(cmdexec.comm.input_fd());
         pipes_write.push(cmdexec.comm.output_fd());
 
-        let usbdev = UsbsasChildSpawner::new()
+        let usbdev = read_registry(UsbsasChildSpawner::new(), &config)
             .arg(config_path)
+            .read_path(config_path)
+            .usb()
+            .hotplug()
             .spawn::<UsbDev, proto::usbdev::Request>()?;
         pipes_read.push(usbdev.comm.input_fd());
         pipes_write.push(usbdev.comm.output_fd());
 
         let scsi2files = UsbsasChildSpawner::new()
+            .usb()
             .spawn::<usbsas_scsi2files::Scsi2Files, proto::files::Request>()?;
         pipes_read.push(scsi2files.comm.input_fd());
         pipes_write.push(scsi2files.comm.output_fd());
 
-        let files2tar = UsbsasChildSpawner::new()
-            .arg(out_tar)
+        let mut files2tar_spawner = UsbsasChildSpawner::new().arg(out_tar).write_path(out_tar);
+        if let Some(ref signature) = config.signature {
+            files2tar_spawner = files2tar_spawner
+                .arg(&signature.key_path)
+                .read_path(&signature.key_path);
+        }
+        let files2tar = files2tar_spawner
             .wait_on_startup()
             .spawn::<usbsas_files2tar::Files2Tar, proto::writetar::Request>()?;
         pipes_read.push(files2tar.comm.input_fd());
@@ -1570,18 +2256,24 @@ impl Usbsas {
 
         let files2fs = UsbsasChildSpawner::new()
             .arg(out_fs)
+            .write_path(out_fs)
             .spawn::<usbsas_files2fs::Files2Fs, proto::writefs::Request>()?;
         pipes_read.push(files2fs.comm.input_fd());
         pipes_write.push(files2fs.comm.output_fd());
 
         let filter = UsbsasChildSpawner::new()
             .arg(config_path)
+            .read_path(config_path)
             .spawn::<usbsas_filter::Filter, proto::filter::Request>()?;
         pipes_read.push(filter.comm.input_fd());
         pipes_write.push(filter.comm.output_fd());
 
-        let fs2dev = UsbsasChildSpawner::new()
+        let fs2dev = read_registry(UsbsasChildSpawner::new(), &config)
             .arg(out_fs)
+            .arg(config_path)
+            .read_path(out_fs)
+            .read_path(config_path)
+            .usb()
             .wait_on_startup()
             .spawn::<usbsas_fs2dev::Fs2Dev, proto::fs2dev::Request>()?;
         pipes_read.push(fs2dev.comm.input_fd());
@@ -1589,6 +2281,7 @@ impl Usbsas {
 
         let tar2files = UsbsasChildSpawner::new()
             .arg(out_tar)
+            .read_path(out_tar)
             .wait_on_startup()
             .spawn::<usbsas_tar2files::Tar2Files, proto::files::Request>()?;
         pipes_read.push(tar2files.comm.input_fd());
@@ -1597,6 +2290,9 @@ impl Usbsas {
         let uploader = UsbsasChildSpawner::new()
             .arg(out_tar)
             .arg(config_path)
+            .read_path(out_tar)
+            .read_path(config_path)
+            .network()
             .spawn::<usbsas_net::Uploader, proto::uploader::Request>()?;
         pipes_read.push(uploader.comm.input_fd());
         pipes_write.push(uploader.comm.output_fd());
@@ -1605,6 +2301,9 @@ impl Usbsas {
             let analyzer = UsbsasChildSpawner::new()
                 .arg(out_tar)
                 .arg(config_path)
+                .read_path(out_tar)
+                .read_path(config_path)
+                .network()
                 .spawn::<usbsas_net::Analyzer, proto::analyzer::Request>()?;
             pipes_read.push(analyzer.comm.input_fd());
             pipes_write.push(analyzer.comm.output_fd());
@@ -1634,7 +2333,11 @@ impl Usbsas {
         Ok(Usbsas {
             comm,
             children,
-            state: State::Init(InitState {}),
+            state: State::Init(InitState {
+                signing_key,
+                symlink_policy: config.symlink_policy.unwrap_or_default(),
+                damaged_file_policy: config.damaged_file_policy.unwrap_or_default(),
+            }),
         })
     }
 
@@ -1726,3 +2429,251 @@ fn main() -> Result<()> {
     trace!("stop");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        follow_links, manifest, proto, resolve_link_target, Children, Comm, CopyFilesState,
+        DamagedFilePolicy, Destination, FileType, Manifest, Result, SourceLinks, SymlinkPolicy,
+        UsbDevice, UsbsasChild,
+    };
+    use std::{
+        collections::HashMap,
+        fs::File,
+        os::unix::{io::OwnedFd, net::UnixStream},
+        thread,
+    };
+    use usbsas_process::Pid;
+
+    /// Source with links (path -> target) and regular files (path -> size)
+    struct Source {
+        links: HashMap<&'static str, &'static str>,
+        files: HashMap<&'static str, u64>,
+        read_links: usize,
+    }
+
+    impl Source {
+        fn new(links: &[(&'static str, &'static str)], files: &[(&'static str, u64)]) -> Self {
+            Source {
+                links: links.iter().cloned().collect(),
+                files: files.iter().cloned().collect(),
+                read_links: 0,
+            }
+        }
+    }
+
+    impl SourceLinks for Source {
+        fn read_link(&mut self, path: &str) -> Result<String> {
+            self.read_links += 1;
+            Ok(self.links[path].to_string())
+        }
+
+        fn file_attrs(&mut self, path: &str) -> Result<(Option<FileType>, u64)> {
+            if self.links.contains_key(path) {
+                Ok((Some(FileType::Symlink), 0))
+            } else if let Some(size) = self.files.get(path) {
+                Ok((Some(FileType::Regular), *size))
+            } else {
+                Ok((Some(FileType::Directory), 0))
+            }
+        }
+    }
+
+    #[test]
+    fn test_resolve_link_target() {
+        assert_eq!(
+            resolve_link_target("/dir/link", "file"),
+            Some("/dir/file".to_string())
+        );
+        assert_eq!(
+            resolve_link_target("/dir/link", "../other/./file"),
+            Some("/other/file".to_string())
+        );
+        assert_eq!(
+            resolve_link_target("/a/b/link", "../../c/file"),
+            Some("/c/file".to_string())
+        );
+        assert_eq!(resolve_link_target("/dir/link", "../../file"), None);
+        assert_eq!(resolve_link_target("/link", "../file"), None);
+        assert_eq!(resolve_link_target("/dir/link", "/etc/passwd"), None);
+        assert_eq!(resolve_link_target("/dir/link", "/??/C:/file"), None);
+    }
+
+    #[test]
+    fn test_follow_links() {
+        let mut source = Source::new(
+            &[
+                ("/dir/link", "../file"),
+                ("/chain1", "chain2"),
+                ("/chain2", "dir/link"),
+                ("/absolute", "/file"),
+                ("/escape", "dir/../../file"),
+                ("/to_dir", "dir"),
+            ],
+            &[("/file", 42)],
+        );
+        assert_eq!(
+            follow_links(&mut source, "/dir/link").unwrap(),
+            Some(("/file".to_string(), 42))
+        );
+        assert_eq!(
+            follow_links(&mut source, "/chain1").unwrap(),
+            Some(("/file".to_string(), 42))
+        );
+        assert_eq!(follow_links(&mut source, "/absolute").unwrap(), None);
+        assert_eq!(follow_links(&mut source, "/escape").unwrap(), None);
+        assert_eq!(follow_links(&mut source, "/to_dir").unwrap(), None);
+    }
+
+    #[test]
+    fn test_follow_links_loop() {
+        let mut source = Source::new(&[("/a", "b"), ("/b", "a")], &[]);
+        assert_eq!(follow_links(&mut source, "/a").unwrap(), None);
+        assert_eq!(source.read_links, super::MAX_LINK_HOPS);
+
+        let mut source = Source::new(&[("/self", "./self")], &[]);
+        assert_eq!(follow_links(&mut source, "/self").unwrap(), None);
+        assert_eq!(source.read_links, super::MAX_LINK_HOPS);
+    }
+
+    fn comm<R>(stream: UnixStream) -> Comm<R> {
+        Comm::new(
+            File::from(OwnedFd::from(stream.try_clone().unwrap())),
+            File::from(OwnedFd::from(stream)),
+        )
+    }
+
+    /// Child not spawned, its requests are answered on the returned comm
+    fn child<R>() -> (UsbsasChild<R>, Comm<R>) {
+        let (parent, child) = UnixStream::pair().unwrap();
+        (
+            UsbsasChild {
+                child: Pid::from_raw(0),
+                comm: comm(parent),
+                locked: false,
+            },
+            comm(child),
+        )
+    }
+
+    #[test]
+    fn test_file_to_tar_manifest() {
+        use proto::{files, writetar};
+        const DATA: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+        // scsi2files with a single file
+        let (scsi2files, mut source) = child::<files::Request>();
+        let source_thread = thread::spawn(move || {
+            while let Ok(req) = source.recv::<files::Request>() {
+                let msg = match req.msg {
+                    Some(files::request::Msg::GetAttr(_)) => {
+                        files::response::Msg::GetAttr(files::ResponseGetAttr {
+                            ftype: FileType::Regular.into(),
+                            size: DATA.len() as u64,
+                            timestamp: 0,
+                        })
+                    }
+                    Some(files::request::Msg::ReadFile(req)) => {
+                        files::response::Msg::ReadFile(files::ResponseReadFile {
+                            data: DATA[req.offset as usize..(req.offset + req.size) as usize]
+                                .to_vec(),
+                            bad_sectors: vec![],
+                        })
+                    }
+                    _ => break,
+                };
+                source.send(files::Response { msg: Some(msg) }).unwrap();
+            }
+        });
+
+        // files2tar keeping what's written
+        let (files2tar, mut tar) = child::<writetar::Request>();
+        let tar_thread = thread::spawn(move || {
+            let mut written = Vec::new();
+            while let Ok(req) = tar.recv::<writetar::Request>() {
+                let msg = match req.msg {
+                    Some(writetar::request::Msg::NewFile(req)) => {
+                        written.push((req.path, Vec::new()));
+                        writetar::response::Msg::NewFile(writetar::ResponseNewFile {})
+                    }
+                    Some(writetar::request::Msg::WriteFile(req)) => {
+                        written.last_mut().unwrap().1.extend(req.data);
+                        writetar::response::Msg::WriteFile(writetar::ResponseWriteFile {})
+                    }
+                    Some(writetar::request::Msg::EndFile(_)) => {
+                        writetar::response::Msg::EndFile(writetar::ResponseEndFile {})
+                    }
+                    _ => break,
+                };
+                tar.send(writetar::Response { msg: Some(msg) }).unwrap();
+            }
+            written
+        });
+
+        let mut children = Children {
+            analyzer: None,
+            identificator: child().0,
+            cmdexec: child().0,
+            files2fs: child().0,
+            files2tar,
+            filter: child().0,
+            fs2dev: child().0,
+            scsi2files,
+            tar2files: child().0,
+            uploader: child().0,
+            usbdev: child().0,
+        };
+        // Copy status sent to the client
+        let (_client, mut comm) = child::<proto::usbsas::Request>();
+
+        let device = UsbDevice {
+            busnum: 1,
+            devnum: 2,
+            vendorid: 0x1234,
+            productid: 0x5678,
+            manufacturer: "manufacturer".into(),
+            serial: "serial".into(),
+            description: "description".into(),
+            sector_size: 512,
+            dev_size: 0,
+        };
+        let mut manifest = Manifest::new("user", &device, manifest::Destination::Net);
+        let mut state = CopyFilesState {
+            destination: Destination::Net(Default::default()),
+            device,
+            id: "user".into(),
+            selected: vec!["/link".into()],
+            signing_key: None,
+            symlink_policy: SymlinkPolicy::Dereference,
+            damaged_file_policy: DamagedFilePolicy::Skip,
+        };
+
+        // Dereferenced link
+        state
+            .file_to_tar(
+                &mut comm,
+                &mut children,
+                "/link",
+                "/dir/file",
+                None,
+                &mut manifest,
+            )
+            .unwrap();
+        drop(children);
+        source_thread.join().unwrap();
+        assert_eq!(
+            tar_thread.join().unwrap(),
+            [("/link".to_string(), DATA.to_vec())]
+        );
+
+        let json: serde_json::Value = serde_json::from_slice(&manifest.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json["files"]["/link"],
+            serde_json::json!({
+                "size": DATA.len(),
+                "sha256": "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592",
+                "source": "/dir/file"
+            })
+        );
+    }
+}
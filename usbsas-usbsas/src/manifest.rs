@@ -0,0 +1,243 @@
+//! Transfer manifest. It lists every file written to the destination with its
+//! size and SHA-256 and every rejected entry with the reason why, along with
+//! the user id and the descriptors of the devices.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, time::SystemTime};
+use usbsas_mass_storage::UsbDevice;
+use usbsas_proto::common::Device;
+
+#[derive(Serialize, Debug)]
+pub(crate) struct DeviceDesc {
+    vendorid: u32,
+    productid: u32,
+    manufacturer: String,
+    description: String,
+    serial: String,
+}
+
+impl From<&UsbDevice> for DeviceDesc {
+    fn from(device: &UsbDevice) -> Self {
+        DeviceDesc {
+            vendorid: device.vendorid,
+            productid: device.productid,
+            manufacturer: device.manufacturer.clone(),
+            description: device.description.clone(),
+            serial: device.serial.clone(),
+        }
+    }
+}
+
+impl From<&Device> for DeviceDesc {
+    fn from(device: &Device) -> Self {
+        DeviceDesc {
+            vendorid: device.vendorid,
+            productid: device.productid,
+            manufacturer: device.manufacturer.clone(),
+            description: device.description.clone(),
+            serial: device.serial.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Destination {
+    Usb(Option<DeviceDesc>),
+    Net,
+    Cmd,
+}
+
+#[derive(Serialize, Debug)]
+struct FileEntry {
+    size: u64,
+    sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_target: Option<String>,
+    /// Number of unreadable sectors replaced by zeros
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unreadable_sectors: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RejectStatus {
+    Filtered,
+    Dirty,
+    Skipped,
+    Damaged,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+struct RejectedEntry {
+    path: String,
+    status: RejectStatus,
+    reason: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Manifest {
+    id: String,
+    time: f64,
+    source: DeviceDesc,
+    destination: Destination,
+    files: BTreeMap<String, FileEntry>,
+    rejected: Vec<RejectedEntry>,
+}
+
+impl Manifest {
+    pub(crate) fn new(id: &str, source: &UsbDevice, destination: Destination) -> Self {
+        Manifest {
+            id: id.to_string(),
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|time| time.as_secs_f64())
+                .unwrap_or(0.0),
+            source: source.into(),
+            destination,
+            files: BTreeMap::new(),
+            rejected: Vec::new(),
+        }
+    }
+
+    pub(crate) fn usb_destination(device: Option<&Device>) -> Destination {
+        Destination::Usb(device.map(DeviceDesc::from))
+    }
+
+    /// Add a file copied from `source` (if it's a dereferenced link) with the
+    /// SHA-256 of its content.
+    pub(crate) fn add_file(&mut self, path: &str, size: u64, sha256: String, source: Option<&str>) {
+        self.files.insert(
+            path.to_string(),
+            FileEntry {
+                size,
+                sha256,
+                source: source.map(String::from),
+                link_target: None,
+                unreadable_sectors: None,
+            },
+        );
+    }
+
+    pub(crate) fn add_link(&mut self, path: &str, target: &str) {
+        self.files.insert(
+            path.to_string(),
+            FileEntry {
+                size: 0,
+                sha256: String::new(),
+                source: None,
+                link_target: Some(target.to_string()),
+                unreadable_sectors: None,
+            },
+        );
+    }
+
+    /// Mark a copied file as damaged, with `count` sectors replaced by zeros.
+    pub(crate) fn set_damaged(&mut self, path: &str, count: u64) {
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.unreadable_sectors = Some(count);
+        }
+    }
+
+    /// Damaged files, either rejected or copied zero-filled.
+    pub(crate) fn damaged(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|(_, entry)| entry.unreadable_sectors.is_some())
+            .map(|(path, _)| path.clone())
+            .chain(self.rejected(RejectStatus::Damaged))
+            .collect()
+    }
+
+    /// Mark an entry as rejected, removing it from the copied files if needed.
+    pub(crate) fn reject(&mut self, path: &str, status: RejectStatus, reason: &str) {
+        let _ = self.files.remove(path);
+        self.rejected.push(RejectedEntry {
+            path: path.to_string(),
+            status,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Paths rejected with `status`, in the order they were rejected.
+    pub(crate) fn rejected(&self, status: RejectStatus) -> Vec<String> {
+        self.rejected
+            .iter()
+            .filter(|entry| entry.status == status)
+            .map(|entry| entry.path.clone())
+            .collect()
+    }
+
+    pub(crate) fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, RejectStatus};
+    use usbsas_mass_storage::UsbDevice;
+
+    fn manifest() -> Manifest {
+        let source = UsbDevice {
+            busnum: 1,
+            devnum: 2,
+            vendorid: 0x1234,
+            productid: 0x5678,
+            manufacturer: "manufacturer".into(),
+            serial: "serial".into(),
+            description: "description".into(),
+            sector_size: 512,
+            dev_size: 0,
+        };
+        Manifest::new("user", &source, super::Destination::Net)
+    }
+
+    #[test]
+    fn test_manifest_json() {
+        let mut manifest = manifest();
+        manifest.add_file("/dir/file", 3, "sha".into(), None);
+        manifest.add_file("/link", 3, "sha".into(), Some("/dir/file"));
+        manifest.add_link("/kept_link", "dir/file");
+        manifest.reject("/dirty", RejectStatus::Dirty, "virus");
+
+        let json: serde_json::Value = serde_json::from_slice(&manifest.to_json().unwrap()).unwrap();
+        assert_eq!(json["id"], "user");
+        assert_eq!(json["source"]["vendorid"], 0x1234);
+        assert_eq!(json["source"]["serial"], "serial");
+        assert!(json["source"].get("busnum").is_none());
+        assert_eq!(json["destination"], "net");
+        assert_eq!(
+            json["files"]["/dir/file"],
+            serde_json::json!({"size": 3, "sha256": "sha"})
+        );
+        assert_eq!(json["files"]["/link"]["source"], "/dir/file");
+        assert_eq!(json["files"]["/kept_link"]["link_target"], "dir/file");
+        assert_eq!(
+            json["rejected"],
+            serde_json::json!([{"path": "/dirty", "status": "dirty", "reason": "virus"}])
+        );
+    }
+
+    #[test]
+    fn test_manifest_reject() {
+        let mut manifest = manifest();
+        manifest.add_file("/file", 3, "sha".into(), None);
+        manifest.add_file("/zerofilled", 3, "sha".into(), None);
+        manifest.set_damaged("/zerofilled", 2);
+        manifest.reject("/file", RejectStatus::Damaged, "1 unreadable sectors");
+        manifest.reject("/filtered", RejectStatus::Filtered, "file name filter");
+
+        assert_eq!(manifest.rejected(RejectStatus::Damaged), vec!["/file"]);
+        assert_eq!(manifest.rejected(RejectStatus::Filtered), vec!["/filtered"]);
+        assert!(manifest.rejected(RejectStatus::Error).is_empty());
+        assert_eq!(manifest.damaged(), vec!["/zerofilled", "/file"]);
+
+        let json: serde_json::Value = serde_json::from_slice(&manifest.to_json().unwrap()).unwrap();
+        assert!(json["files"].get("/file").is_none());
+        assert_eq!(json["files"]["/zerofilled"]["unreadable_sectors"], 2);
+    }
+}
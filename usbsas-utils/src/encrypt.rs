@@ -0,0 +1,215 @@
+//! age encryption of the transfer outputs, either for the recipients
+//! configured for a destination or with a passphrase typed by the user.
+
+use age::{secrecy::Secret, stream::StreamWriter, x25519, Encryptor, Recipient};
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// Upper bound of the size of an age header with a passphrase (scrypt stanza)
+const HEADER_MAX_SIZE: u64 = 1024;
+// Nonce of the payload, followed by chunks with a tag each
+const NONCE_SIZE: u64 = 16;
+const TAG_SIZE: u64 = 16;
+
+/// Upper bound of the size of `size` bytes encrypted with a passphrase
+pub fn encrypted_size(size: u64) -> u64 {
+    HEADER_MAX_SIZE + NONCE_SIZE + (size / CHUNK_SIZE as u64 + 1) * TAG_SIZE + size
+}
+
+fn invalid_data<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}", err))
+}
+
+fn parse_recipients(recipients: &[String]) -> io::Result<Vec<Box<dyn Recipient + Send>>> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            x25519::Recipient::from_str(recipient)
+                .map(|recipient| Box::new(recipient) as Box<dyn Recipient + Send>)
+                .map_err(|err| invalid_data(format!("bad recipient {}: {}", recipient, err)))
+        })
+        .collect()
+}
+
+/// Encrypt what's written in `output` for `recipients` (age public keys).
+/// `finish()` must be called on the returned writer.
+pub fn recipients_writer<W: Write>(
+    output: W,
+    recipients: &[String],
+) -> io::Result<StreamWriter<W>> {
+    Encryptor::with_recipients(parse_recipients(recipients)?)
+        .ok_or_else(|| invalid_data("no recipients"))?
+        .wrap_output(output)
+}
+
+/// Encrypt what's written in `output` with `passphrase`. `finish()` must be
+/// called on the returned writer.
+pub fn passphrase_writer<W: Write>(output: W, passphrase: &str) -> io::Result<StreamWriter<W>> {
+    if passphrase.is_empty() {
+        return Err(invalid_data("empty passphrase"));
+    }
+    Encryptor::with_user_passphrase(Secret::new(passphrase.to_owned())).wrap_output(output)
+}
+
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "poisoned lock"))?
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader of the encrypted content of `inner`, used to stream an archive
+/// to a remote server without writing it encrypted on disk first.
+pub struct EncryptReader<R: Read> {
+    inner: R,
+    chunk: Vec<u8>,
+    output: SharedBuf,
+    writer: Option<StreamWriter<SharedBuf>>,
+}
+
+impl<R: Read> EncryptReader<R> {
+    pub fn new(inner: R, recipients: &[String]) -> io::Result<Self> {
+        let output = SharedBuf::default();
+        Ok(EncryptReader {
+            inner,
+            chunk: vec![0; CHUNK_SIZE],
+            writer: Some(recipients_writer(output.clone(), recipients)?),
+            output,
+        })
+    }
+}
+
+impl<R: Read> Read for EncryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut output = self
+                    .output
+                    .0
+                    .lock()
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "poisoned lock"))?;
+                if !output.is_empty() || self.writer.is_none() {
+                    let len = buf.len().min(output.len());
+                    buf[..len].copy_from_slice(&output[..len]);
+                    output.drain(..len);
+                    return Ok(len);
+                }
+            }
+            match self.inner.read(&mut self.chunk)? {
+                0 => {
+                    if let Some(writer) = self.writer.take() {
+                        writer.finish()?;
+                    }
+                }
+                len => {
+                    if let Some(writer) = self.writer.as_mut() {
+                        writer.write_all(&self.chunk[..len])?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::{x25519::Identity, Decryptor};
+
+    fn data() -> Vec<u8> {
+        (0..3 * CHUNK_SIZE as u32 + 42).map(|i| i as u8).collect()
+    }
+
+    fn decrypt_with_passphrase(
+        encrypted: &[u8],
+        passphrase: &str,
+    ) -> Result<Vec<u8>, age::DecryptError> {
+        let decryptor = match Decryptor::new(encrypted)? {
+            Decryptor::Passphrase(decryptor) => decryptor,
+            _ => panic!("not encrypted with a passphrase"),
+        };
+        let mut decrypted = Vec::new();
+        decryptor
+            .decrypt(&Secret::new(passphrase.to_owned()), None)?
+            .read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    fn decrypt_with_identity(encrypted: &[u8], identity: &Identity) -> Vec<u8> {
+        let decryptor = match Decryptor::new(encrypted).unwrap() {
+            Decryptor::Recipients(decryptor) => decryptor,
+            _ => panic!("not encrypted for recipients"),
+        };
+        let mut decrypted = Vec::new();
+        decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        decrypted
+    }
+
+    #[test]
+    fn test_passphrase() {
+        for data in [Vec::new(), data()] {
+            let mut encrypted = Vec::new();
+            let mut writer = passphrase_writer(&mut encrypted, "passphrase").unwrap();
+            writer.write_all(&data).unwrap();
+            writer.finish().unwrap();
+            assert!(encrypted.len() as u64 <= encrypted_size(data.len() as u64));
+            assert!(decrypt_with_passphrase(&encrypted, "bad passphrase").is_err());
+            assert_eq!(
+                decrypt_with_passphrase(&encrypted, "passphrase").unwrap(),
+                data
+            );
+        }
+        assert!(passphrase_writer(Vec::new(), "").is_err());
+    }
+
+    #[test]
+    fn test_recipients() {
+        let identity = Identity::generate();
+        let recipients = vec![identity.to_public().to_string()];
+
+        let mut encrypted = Vec::new();
+        let mut writer = recipients_writer(&mut encrypted, &recipients).unwrap();
+        writer.write_all(&data()).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(decrypt_with_identity(&encrypted, &identity), data());
+
+        assert!(recipients_writer(Vec::new(), &[]).is_err());
+        assert!(recipients_writer(Vec::new(), &["age1bad".into()]).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_reader() {
+        let identity = Identity::generate();
+        let data = data();
+        let mut reader =
+            EncryptReader::new(&data[..], &[identity.to_public().to_string()]).unwrap();
+        let mut encrypted = Vec::new();
+        // Small reads, the output is consumed by parts
+        let mut buf = [0; 1000];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                len => encrypted.extend_from_slice(&buf[..len]),
+            }
+        }
+        assert_eq!(decrypt_with_identity(&encrypted, &identity), data);
+    }
+}
@@ -0,0 +1,87 @@
+//! Registry of the destination devices enrolled by the administrators. It is
+//! a JSON file signed with the Ed25519 key of the administrators, the detached
+//! hexadecimal signature being stored next to it (`<registry>.sig`).
+//!
+//! An enrolled device also has a marker holding a random token in a block of
+//! the gap between its MBR and its partition, a device spoofing the IDs and the
+//! serial number of an enrolled one won't have it.
+
+use crate::sign::{self, SigningKey};
+use serde::{Deserialize, Serialize};
+use std::{fs, io};
+
+/// Block of the marker, the last one before the partition written by usbsas
+/// (`usbsas_mbr::SECTOR_START`)
+pub const MARKER_SECTOR: u64 = 0x3e;
+pub const TOKEN_SIZE: usize = 32;
+const MARKER_MAGIC: &[u8; 16] = b"USBSAS-ENROLLED\0";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EnrolledDevice {
+    pub vendorid: u32,
+    pub productid: u32,
+    pub serial: String,
+    pub description: String,
+    /// Hexadecimal token of the marker of the device
+    pub token: String,
+    /// Unix timestamp of the enrollment
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Registry {
+    pub devices: Vec<EnrolledDevice>,
+}
+
+fn signature_path(path: &str) -> String {
+    format!("{}.sig", path)
+}
+
+impl Registry {
+    /// Read the registry and check its signature with the hexadecimal
+    /// `public_key`. This must be done before entering seccomp.
+    pub fn from_file(path: &str, public_key: &str) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        sign::verify(
+            public_key,
+            &data,
+            &fs::read_to_string(signature_path(path))?,
+        )?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the registry and its signature
+    pub fn to_file(&self, path: &str, key: &SigningKey) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(path, &data)?;
+        fs::write(signature_path(path), key.sign(&data))
+    }
+
+    pub fn find(&self, vendorid: u32, productid: u32, serial: &str) -> Option<&EnrolledDevice> {
+        self.devices.iter().find(|device| {
+            device.vendorid == vendorid && device.productid == productid && device.serial == serial
+        })
+    }
+
+    pub fn find_token(&self, token: &str) -> Option<&EnrolledDevice> {
+        self.devices.iter().find(|device| device.token == token)
+    }
+}
+
+/// Marker block (of `block_size` bytes) holding `token`
+pub fn marker(token: &[u8; TOKEN_SIZE], block_size: usize) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    block[..MARKER_MAGIC.len()].copy_from_slice(MARKER_MAGIC);
+    block[MARKER_MAGIC.len()..MARKER_MAGIC.len() + TOKEN_SIZE].copy_from_slice(token);
+    block
+}
+
+/// Hexadecimal token of a marker block, if it is one
+pub fn marker_token(block: &[u8]) -> Option<String> {
+    if block.len() < MARKER_MAGIC.len() + TOKEN_SIZE || !block.starts_with(MARKER_MAGIC) {
+        return None;
+    }
+    Some(hex::encode(
+        &block[MARKER_MAGIC.len()..MARKER_MAGIC.len() + TOKEN_SIZE],
+    ))
+}
@@ -1,13 +1,22 @@
-//! usbsas constants and logging utility.
+//! usbsas constants, logging, signing, encryption and enrollment utilities.
 
 use std::env;
 
+#[cfg(feature = "encrypt")]
+pub mod encrypt;
+#[cfg(feature = "enrollment")]
+pub mod enrollment;
 pub mod log;
+#[cfg(feature = "sign")]
+pub mod sign;
 
+pub const ENCRYPTED_FILE_NAME: &str = "usbsas-transfer.tar.age";
 pub const INPUT_PIPE_FD_VAR: &str = "INPUT_PIPE_FD";
+pub const MANIFEST_FILE_NAME: &str = "usbsas-manifest.json";
 pub const OUTPUT_PIPE_FD_VAR: &str = "OUTPUT_PIPE_FD";
 pub const READ_FILE_MAX_SIZE: u64 = 1024 * 1024 * 10;
 pub const SECTOR_SIZE: u64 = 512;
+pub const SIGNATURE_FILE_NAME: &str = "usbsas-manifest.json.sig";
 pub const TAR_BLOCK_SIZE: usize = 512;
 pub const TAR_DATA_DIR: &str = "data";
 pub const USBSAS_BIN_PATH: &str = env!("USBSAS_BIN_PATH");
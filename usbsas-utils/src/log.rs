@@ -1,15 +1,31 @@
-#[cfg(feature = "log-json")]
-use {
-    env_logger::{Builder, Env},
-    std::{
-        io::Write,
-        sync::{Arc, RwLock},
-    },
+//! Logging of usbsas processes on stderr with env_logger (JSON records with the
+//! `log-json` feature). With the `log-forward` feature, records can also be
+//! forwarded to journald or to a syslog collector.
+
+#[cfg(feature = "log-forward")]
+mod forward;
+#[cfg(feature = "log-forward")]
+mod journald;
+#[cfg(feature = "log-forward")]
+mod syslog;
+
+#[cfg(feature = "log-forward")]
+pub use forward::{
+    check_output, event, forwarding, init_logger_with_output, set_field, Output, Protocol,
+    SyslogConfig,
 };
 
+use env_logger::{Builder, Env};
 #[cfg(feature = "log-json")]
-pub fn init_logger(session_id: Arc<RwLock<String>>) {
+use std::{
+    io::Write,
+    sync::{Arc, RwLock},
+};
+
+fn builder(#[cfg(feature = "log-json")] session_id: Arc<RwLock<String>>) -> Builder {
+    #[cfg_attr(not(feature = "log-json"), allow(unused_mut))]
     let mut builder = Builder::from_env(Env::default().filter_or("RUST_LOG", "info"));
+    #[cfg(feature = "log-json")]
     builder.format(move |buf, record| {
         write!(buf, "{{")?;
         write!(
@@ -22,18 +38,20 @@ pub fn init_logger(session_id: Arc<RwLock<String>>) {
         write!(buf, " \"level\":\"{}\",", record.level())?;
         write!(buf, " \"target\":\"{}\",", record.target())?;
         write!(buf, " \"msg\":{},", serde_json::to_string(&record.args())?)?;
-        #[cfg(feature = "log-json")]
-        {
-            write!(buf, " \"transfer_id\":\"{}\"", session_id.read().unwrap())?;
-        }
+        write!(buf, " \"transfer_id\":\"{}\"", session_id.read().unwrap())?;
         writeln!(buf, "}}")?;
 
         Ok(())
     });
-    builder.init();
+    builder
+}
+
+#[cfg(feature = "log-json")]
+pub fn init_logger(session_id: Arc<RwLock<String>>) {
+    builder(session_id).init();
 }
 
 #[cfg(not(feature = "log-json"))]
 pub fn init_logger() {
-    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "info"));
+    builder().init();
 }
@@ -0,0 +1,226 @@
+//! Logger writing the records on stderr and forwarding them to journald or to
+//! a syslog collector, with structured fields: the ones of the session (set
+//! with `set_field()`) and the ones of the event (logged with `event()`).
+
+use super::{journald::Journald, syslog::Syslog};
+use log::{Level, Log, Metadata, Record};
+#[cfg(feature = "log-json")]
+use std::sync::{Arc, RwLock};
+use std::{
+    cell::RefCell,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// Fields added to all the forwarded records
+static FIELDS: Mutex<Vec<(&str, String)>> = Mutex::new(Vec::new());
+/// The logger forwards the records to journald or to a syslog collector
+static FORWARDING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Fields of the event being logged by the thread
+    static EVENT_FIELDS: RefCell<Vec<(String, String)>> = RefCell::new(Vec::new());
+}
+
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+pub struct SyslogConfig {
+    /// "host:port" of the collector
+    pub address: String,
+    pub protocol: Protocol,
+    /// PEM certificates of the CAs of the collector (TLS)
+    pub ca_file: Option<PathBuf>,
+    /// Directory of the messages kept while the collector is unreachable
+    pub spool_dir: Option<PathBuf>,
+    /// Maximum size of the spool, in bytes
+    pub spool_max_size: u64,
+}
+
+pub enum Output {
+    Stderr,
+    /// Records are only sent to journald, not written on stderr
+    Journald,
+    Syslog(SyslogConfig),
+}
+
+/// Check that the records could be forwarded to `output`, without forwarding
+/// them
+pub fn check_output(output: &Output) -> io::Result<()> {
+    match output {
+        Output::Stderr => Ok(()),
+        Output::Journald => Journald::new("usbsas").map(|_| ()),
+        Output::Syslog(config) => super::syslog::check_config(config),
+    }
+}
+
+/// Whether the logger forwards the records, the processes that can't forward
+/// their own records (sandboxed children) should then be given to it
+pub fn forwarding() -> bool {
+    FORWARDING.load(Ordering::Relaxed)
+}
+
+/// Set (or remove if `None`) a field added to all the forwarded records
+pub fn set_field(name: &'static str, value: Option<String>) {
+    let mut fields = FIELDS.lock().unwrap_or_else(|err| err.into_inner());
+    fields.retain(|(field, _)| *field != name);
+    if let Some(value) = value {
+        fields.push((name, value));
+    }
+}
+
+/// Log `message` with additional fields
+pub fn event(level: Level, target: &str, message: &str, fields: &[(&str, &str)]) {
+    if level > log::max_level() {
+        return;
+    }
+    EVENT_FIELDS.with(|event_fields| {
+        *event_fields.borrow_mut() = fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    });
+    log::logger().log(
+        &Record::builder()
+            .args(format_args!("{}", message))
+            .level(level)
+            .target(target)
+            .build(),
+    );
+    EVENT_FIELDS.with(|event_fields| event_fields.borrow_mut().clear());
+}
+
+/// Fields of the session then of the event
+fn record_fields() -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = FIELDS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect();
+    EVENT_FIELDS.with(|event_fields| fields.extend(event_fields.borrow().iter().cloned()));
+    fields
+}
+
+/// Syslog severity of a level
+pub(super) fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+enum Sink {
+    Journald(Journald),
+    Syslog(Syslog),
+}
+
+struct Logger {
+    stderr: env_logger::Logger,
+    write_stderr: bool,
+    sink: Option<Sink>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.stderr.matches(record) {
+            return;
+        }
+        if self.write_stderr {
+            self.stderr.log(record);
+        }
+        // The TLS library logs while the records are forwarded, they stay
+        // local not to loop
+        if record.target().starts_with("rustls") {
+            return;
+        }
+        match &self.sink {
+            Some(Sink::Journald(journald)) => journald.send(record, &record_fields()),
+            Some(Sink::Syslog(syslog)) => syslog.send(record, &record_fields()),
+            None => (),
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+    }
+}
+
+/// Init the logger with records forwarded to `output`, `app_name` identifying
+/// the process in the forwarded records
+pub fn init_logger_with_output(
+    #[cfg(feature = "log-json")] session_id: Arc<RwLock<String>>,
+    app_name: &str,
+    output: Output,
+) -> io::Result<()> {
+    let stderr = super::builder(
+        #[cfg(feature = "log-json")]
+        session_id,
+    )
+    .build();
+    let max_level = stderr.filter();
+    let (write_stderr, sink) = match output {
+        Output::Stderr => (true, None),
+        Output::Journald => (false, Some(Sink::Journald(Journald::new(app_name)?))),
+        Output::Syslog(config) => (true, Some(Sink::Syslog(Syslog::new(app_name, config)?))),
+    };
+    let forwarding = sink.is_some();
+    log::set_boxed_logger(Box::new(Logger {
+        stderr,
+        write_stderr,
+        sink,
+    }))
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    log::set_max_level(max_level);
+    FORWARDING.store(forwarding, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_severity() {
+        assert_eq!(severity(Level::Error), 3);
+        assert_eq!(severity(Level::Warn), 4);
+        assert_eq!(severity(Level::Info), 6);
+        assert_eq!(severity(Level::Debug), 7);
+        assert_eq!(severity(Level::Trace), 7);
+    }
+
+    #[test]
+    fn test_record_fields() {
+        set_field("session_id", Some("1".into()));
+        set_field("transfer_id", Some("2".into()));
+        set_field("session_id", Some("3".into()));
+        EVENT_FIELDS.with(|event_fields| {
+            *event_fields.borrow_mut() = vec![("path".into(), "/a".into())];
+        });
+        assert_eq!(
+            record_fields(),
+            [
+                ("transfer_id".to_string(), "2".to_string()),
+                ("session_id".to_string(), "3".to_string()),
+                ("path".to_string(), "/a".to_string()),
+            ]
+        );
+        EVENT_FIELDS.with(|event_fields| event_fields.borrow_mut().clear());
+        set_field("session_id", None);
+        set_field("transfer_id", None);
+        assert!(record_fields().is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! Records sent to journald with its native protocol: the fields of a record
+//! in a datagram on the socket of journald.
+
+use super::forward::severity;
+use log::Record;
+use std::{io, os::unix::net::UnixDatagram};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+// Longer messages are truncated, larger datagrams would need a memfd
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+pub(super) struct Journald {
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+impl Journald {
+    pub(super) fn new(identifier: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(Journald {
+            socket,
+            identifier: identifier.to_string(),
+        })
+    }
+
+    pub(super) fn send(&self, record: &Record, fields: &[(String, String)]) {
+        let mut message = record.args().to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut len = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(len) {
+                len -= 1;
+            }
+            message.truncate(len);
+        }
+        let mut datagram = Vec::new();
+        add_field(&mut datagram, "MESSAGE", &message);
+        add_field(
+            &mut datagram,
+            "PRIORITY",
+            &severity(record.level()).to_string(),
+        );
+        add_field(&mut datagram, "SYSLOG_IDENTIFIER", &self.identifier);
+        add_field(&mut datagram, "SYSLOG_PID", &std::process::id().to_string());
+        add_field(&mut datagram, "TARGET", record.target());
+        for (name, value) in fields {
+            add_field(&mut datagram, &field_name(name), value);
+        }
+        // Can't be logged, it would be sent to journald again
+        if let Err(err) = self.socket.send(&datagram) {
+            eprintln!("couldn't send log to journald: {}", err);
+        }
+    }
+}
+
+/// Journald field names are made of upper case letters, digits and
+/// underscores, and can't start with an underscore (trusted fields)
+fn field_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9') => c,
+            _ => '_',
+        })
+        .collect::<String>()
+        .trim_start_matches('_')
+        .to_string()
+}
+
+fn add_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Values with new lines are prefixed with their size
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_field_name() {
+        assert_eq!(field_name("session_id"), "SESSION_ID");
+        assert_eq!(field_name("_transfer-id"), "TRANSFER_ID");
+        assert_eq!(field_name("dév"), "D_V");
+    }
+
+    #[test]
+    fn test_send() {
+        let (socket, journal) = UnixDatagram::pair().unwrap();
+        let journald = Journald {
+            socket,
+            identifier: "usbsas-server".to_string(),
+        };
+        let fields = vec![
+            ("session_id".to_string(), "42".to_string()),
+            ("serial".to_string(), "a\nSESSION_ID=forged".to_string()),
+        ];
+        journald.send(
+            &Record::builder()
+                .args(format_args!("device plugged"))
+                .level(Level::Warn)
+                .target("usbsas_usbdev")
+                .build(),
+            &fields,
+        );
+        let mut datagram = vec![0; 4096];
+        let len = journal.recv(&mut datagram).unwrap();
+        let mut expected = format!(
+            "MESSAGE=device plugged\nPRIORITY=4\nSYSLOG_IDENTIFIER=usbsas-server\n\
+             SYSLOG_PID={}\nTARGET=usbsas_usbdev\nSESSION_ID=42\nSERIAL\n",
+            std::process::id()
+        )
+        .into_bytes();
+        // Values with new lines are prefixed with their size, they can't add
+        // fields
+        expected.extend_from_slice(&19u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nSESSION_ID=forged\n");
+        assert_eq!(datagram[..len], expected);
+    }
+}
@@ -0,0 +1,668 @@
+//! Records sent to a syslog collector as RFC 5424 messages over UDP, TCP or
+//! TLS (octet counting framing of RFC 6587 and RFC 5425). A thread sends them,
+//! and keeps them in a spool file while the collector is unreachable.
+
+use super::forward::{severity, Protocol, SyslogConfig};
+use log::Record;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Facility of the messages (daemon)
+const FACILITY: u8 = 3;
+// ID of the structured data of the messages, with the private enterprise
+// number reserved for documentation (RFC 5612)
+const SD_ID: &str = "usbsas@32473";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// Time between two attempts to reach the collector
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const SPOOL_FILE: &str = "usbsas-syslog.spool";
+// Messages waiting for the forwarder thread, newer ones are dropped when it
+// can't keep up
+const QUEUE_SIZE: usize = 4096;
+
+pub(super) struct Syslog {
+    sender: Mutex<mpsc::SyncSender<String>>,
+    hostname: String,
+    app_name: String,
+    /// Messages dropped since the queue is full
+    dropped: AtomicU64,
+}
+
+impl Syslog {
+    /// Start the forwarder thread. The address of the collector is resolved
+    /// by the thread, messages are spooled until it can be.
+    pub(super) fn new(app_name: &str, config: SyslogConfig) -> io::Result<Self> {
+        let tls_config = match config.protocol {
+            Protocol::Tls => Some(tls_config(&config)?),
+            _ => None,
+        };
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let mut forwarder = Forwarder {
+            config,
+            addr: None,
+            tls_config,
+            connection: None,
+            failed_at: None,
+            spool_len: 0,
+            dropped: 0,
+        };
+        thread::Builder::new()
+            .name("syslog".into())
+            .spawn(move || forwarder.run(receiver))?;
+        Ok(Syslog {
+            sender: Mutex::new(sender),
+            hostname: header_field(hostname.trim(), 255),
+            app_name: header_field(app_name, 48),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub(super) fn send(&self, record: &Record, fields: &[(String, String)]) {
+        let message = self.format(record, fields);
+        let sender = match self.sender.lock() {
+            Ok(sender) => sender,
+            Err(_) => return,
+        };
+        match sender.try_send(message) {
+            Ok(()) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    eprintln!("{} log messages were dropped, forwarder too slow", dropped);
+                }
+            }
+            Err(mpsc::TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    eprintln!("log forwarder too slow, dropping messages");
+                }
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG`
+    fn format(&self, record: &Record, fields: &[(String, String)]) -> String {
+        let timestamp = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_else(|_| "-".into());
+        let mut structured_data = String::new();
+        if fields.is_empty() {
+            structured_data.push('-');
+        } else {
+            structured_data.push('[');
+            structured_data.push_str(SD_ID);
+            for (name, value) in fields {
+                let _ = write!(
+                    structured_data,
+                    " {}=\"{}\"",
+                    param_name(name),
+                    escape_param(value)
+                );
+            }
+            structured_data.push(']');
+        }
+        format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            FACILITY * 8 + severity(record.level()),
+            timestamp,
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            header_field(record.target(), 32),
+            structured_data,
+            escape_message(&record.args().to_string())
+        )
+    }
+}
+
+/// Header fields are printable ASCII characters without spaces, "-" if empty
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| matches!(c, '!'..='~'))
+        .take(max_len)
+        .collect();
+    if field.is_empty() {
+        "-".into()
+    } else {
+        field
+    }
+}
+
+fn param_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| matches!(c, '!'..='~') && !matches!(c, '=' | ']' | '"'))
+        .take(32)
+        .collect()
+}
+
+/// Control characters (new lines in particular) are escaped like rsyslog does
+/// (`#` and their octal code): a message is a line of the spool, and some
+/// values come from the devices
+fn escape_control(c: char, escaped: &mut String) -> bool {
+    if c.is_control() && c.is_ascii() {
+        let _ = write!(escaped, "#{:03o}", c as u8);
+        return true;
+    }
+    false
+}
+
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if escape_control(c, &mut escaped) {
+            continue;
+        }
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_message(message: &str) -> String {
+    let mut escaped = String::with_capacity(message.len());
+    for c in message.chars() {
+        if !escape_control(c, &mut escaped) {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Check the configuration without connecting to the collector: the address
+/// has a port, the CAs can be read and the spool directory exists
+pub(super) fn check_config(config: &SyslogConfig) -> io::Result<()> {
+    let valid_address = match config.address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    };
+    if !valid_address {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address {}, expected host:port", config.address),
+        ));
+    }
+    if let Protocol::Tls = config.protocol {
+        tls_config(config)?;
+    }
+    if let Some(spool_dir) = &config.spool_dir {
+        if !spool_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("spool directory {} doesn't exist", spool_dir.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("couldn't resolve {}", address),
+        )
+    })
+}
+
+fn tls_config(config: &SyslogConfig) -> io::Result<Arc<ClientConfig>> {
+    let ca_file = config.ca_file.as_ref().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "ca_file is required for TLS")
+    })?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(fs::File::open(ca_file)?))? {
+        roots
+            .add(&rustls::Certificate(cert))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    }
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Tcp(stream) => send_framed(stream, message),
+            Connection::Tls(stream) => send_framed(stream, message),
+        }
+    }
+}
+
+fn send_framed(stream: &mut impl Write, message: &str) -> io::Result<()> {
+    write!(stream, "{} {}", message.len(), message)?;
+    stream.flush()
+}
+
+fn connect_tcp(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    Ok(stream)
+}
+
+struct Forwarder {
+    config: SyslogConfig,
+    /// Address of the collector, once resolved
+    addr: Option<SocketAddr>,
+    tls_config: Option<Arc<ClientConfig>>,
+    connection: Option<Connection>,
+    /// Last time the collector couldn't be reached
+    failed_at: Option<Instant>,
+    /// Size of the spool file
+    spool_len: u64,
+    /// Messages dropped since the spool is full
+    dropped: u64,
+}
+
+impl Forwarder {
+    fn run(&mut self, receiver: mpsc::Receiver<String>) {
+        // Messages spooled by a previous run
+        self.spool_len = self
+            .spool_path()
+            .and_then(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        loop {
+            match receiver.recv_timeout(RETRY_INTERVAL) {
+                Ok(message) => self.forward(message),
+                Err(mpsc::RecvTimeoutError::Timeout) => self.send_spool(),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn forward(&mut self, message: String) {
+        // Messages are sent in order, the spooled ones first
+        self.send_spool();
+        if self.spool_len == 0 && self.send(&message).is_ok() {
+            return;
+        }
+        self.spool(&message);
+    }
+
+    fn connect(&mut self) -> io::Result<Connection> {
+        let addr = match self.addr {
+            Some(addr) => addr,
+            None => *self.addr.insert(resolve(&self.config.address)?),
+        };
+        match self.config.protocol {
+            Protocol::Udp => {
+                let socket = match addr {
+                    SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+                    SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+                };
+                socket.connect(addr)?;
+                Ok(Connection::Udp(socket))
+            }
+            Protocol::Tcp => Ok(Connection::Tcp(connect_tcp(addr)?)),
+            Protocol::Tls => {
+                let tls_config = self.tls_config.clone().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no TLS configuration")
+                })?;
+                let host = self
+                    .config
+                    .address
+                    .rsplit_once(':')
+                    .map(|(host, _)| host)
+                    .unwrap_or(&self.config.address)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                let server_name = ServerName::try_from(host)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+                let connection = ClientConnection::new(tls_config, server_name)
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+                Ok(Connection::Tls(Box::new(StreamOwned::new(
+                    connection,
+                    connect_tcp(addr)?,
+                ))))
+            }
+        }
+    }
+
+    /// The collector couldn't be reached recently, wait before retrying
+    fn retry_pending(&self) -> bool {
+        self.failed_at
+            .map_or(false, |failed_at| failed_at.elapsed() < RETRY_INTERVAL)
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        if self.retry_pending() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "collector unreachable",
+            ));
+        }
+        let result = match self.connection.take() {
+            Some(connection) => Ok(connection),
+            None => self.connect(),
+        }
+        .and_then(|mut connection| {
+            connection.send(message)?;
+            Ok(connection)
+        });
+        match result {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                self.failed_at = None;
+                Ok(())
+            }
+            Err(err) => {
+                if self.failed_at.is_none() {
+                    eprintln!("couldn't send log to {}: {}", self.config.address, err);
+                }
+                self.failed_at = Some(Instant::now());
+                Err(err)
+            }
+        }
+    }
+
+    fn spool_path(&self) -> Option<PathBuf> {
+        self.config
+            .spool_dir
+            .as_ref()
+            .map(|dir| dir.join(SPOOL_FILE))
+    }
+
+    fn spool(&mut self, message: &str) {
+        // Messages are written with a new line
+        let len = message.len() as u64 + 1;
+        let path = match self.spool_path() {
+            Some(path) if self.spool_len + len <= self.config.spool_max_size => path,
+            _ => {
+                if self.dropped == 0 {
+                    eprintln!("log spool unavailable or full, dropping messages");
+                }
+                self.dropped += 1;
+                return;
+            }
+        };
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", message));
+        match result {
+            Ok(()) => self.spool_len += len,
+            Err(err) => eprintln!("couldn't write log spool: {}", err),
+        }
+    }
+
+    /// Send the spooled messages, the ones that couldn't be sent are kept
+    fn send_spool(&mut self) {
+        if self.spool_len == 0 || self.retry_pending() {
+            return;
+        }
+        let path = match self.spool_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let messages: Vec<String> = match fs::File::open(&path) {
+            Ok(file) => BufReader::new(file).lines().map_while(Result::ok).collect(),
+            Err(err) => {
+                // Removed behind our back
+                if err.kind() == io::ErrorKind::NotFound {
+                    self.spool_len = 0;
+                }
+                return;
+            }
+        };
+        let sent = messages
+            .iter()
+            .take_while(|message| self.send(message).is_ok())
+            .count();
+        if sent == 0 {
+            return;
+        }
+        if self.dropped > 0 {
+            eprintln!("{} log messages were dropped", self.dropped);
+            self.dropped = 0;
+        }
+        let (result, rest_len) = if sent == messages.len() {
+            (fs::remove_file(&path), 0)
+        } else {
+            let mut rest = messages[sent..].join("\n");
+            rest.push('\n');
+            let tmp_path = path.with_extension("tmp");
+            let rest_len = rest.len() as u64;
+            (
+                fs::write(&tmp_path, rest).and_then(|_| fs::rename(&tmp_path, &path)),
+                rest_len,
+            )
+        };
+        match result {
+            Ok(()) => self.spool_len = rest_len,
+            Err(err) => eprintln!("couldn't update log spool: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::{io::Read, net::TcpListener};
+
+    fn syslog(queue_size: usize) -> (Syslog, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let syslog = Syslog {
+            sender: Mutex::new(sender),
+            hostname: header_field("host name", 255),
+            app_name: header_field("usbsas-server", 48),
+            dropped: AtomicU64::new(0),
+        };
+        (syslog, receiver)
+    }
+
+    fn forwarder(address: String, spool_dir: PathBuf) -> Forwarder {
+        Forwarder {
+            config: SyslogConfig {
+                address,
+                protocol: Protocol::Tcp,
+                ca_file: None,
+                spool_dir: Some(spool_dir),
+                spool_max_size: 1024,
+            },
+            addr: None,
+            tls_config: None,
+            connection: None,
+            failed_at: None,
+            spool_len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("usbsas-syslog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_format() {
+        let (syslog, _receiver) = syslog(1);
+        let fields = vec![
+            ("session_id".to_string(), "42".to_string()),
+            ("product".to_string(), "a \"disk\" [x]\\".to_string()),
+        ];
+        let message = syslog.format(
+            &Record::builder()
+                .args(format_args!("device plugged"))
+                .level(Level::Warn)
+                .target("usbsas_usbdev")
+                .build(),
+            &fields,
+        );
+        // daemon.warning
+        let rest = message.strip_prefix("<28>1 ").unwrap();
+        let (timestamp, rest) = rest.split_once(' ').unwrap();
+        // RFC 3339, in UTC
+        assert_eq!(timestamp.as_bytes()[10], b'T');
+        assert!(timestamp.ends_with('Z'));
+        assert_eq!(
+            rest,
+            format!(
+                "hostname usbsas-server {} usbsas_usbdev \
+                 [usbsas@32473 session_id=\"42\" product=\"a \\\"disk\\\" [x\\]\\\\\"] \
+                 device plugged",
+                std::process::id()
+            )
+        );
+
+        // No fields
+        let message = syslog.format(
+            &Record::builder()
+                .args(format_args!("started"))
+                .level(Level::Info)
+                .target("")
+                .build(),
+            &[],
+        );
+        assert!(message.starts_with("<30>1 "));
+        assert!(message.ends_with(" - - started"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(header_field("", 4), "-");
+        assert_eq!(header_field("a b\nc-long", 4), "abc-");
+        assert_eq!(param_name("na=me\"]"), "name");
+        assert_eq!(escape_param("a\"b\\c]d"), "a\\\"b\\\\c\\]d");
+        assert_eq!(escape_message("a\nb\r\tc"), "a#012b#015#011c");
+
+        // A device can't forge records with new lines in its strings
+        let (syslog, _receiver) = syslog(1);
+        let fields = vec![(
+            "serial".to_string(),
+            "x\"]\n<11>1 - - - - - - forged\r\n".to_string(),
+        )];
+        let message = syslog.format(
+            &Record::builder()
+                .args(format_args!("line\n<11>1 - - - - - - forged"))
+                .level(Level::Info)
+                .target("usbsas")
+                .build(),
+            &fields,
+        );
+        assert!(!message.contains(['\n', '\r']));
+        assert!(message.contains("serial=\"x\\\"\\]#012<11>1 - - - - - - forged#015#012\""));
+        assert!(message.ends_with(" line#012<11>1 - - - - - - forged"));
+    }
+
+    #[test]
+    fn test_queue() {
+        let (syslog, receiver) = syslog(2);
+        let record = |message| {
+            syslog.send(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(Level::Info)
+                    .target("usbsas")
+                    .build(),
+                &[],
+            )
+        };
+        for message in ["1", "2", "3", "4"] {
+            record(message);
+        }
+        // The newer messages are dropped when the queue is full
+        assert_eq!(syslog.dropped.load(Ordering::Relaxed), 2);
+        assert!(receiver.recv().unwrap().ends_with(" 1"));
+        assert!(receiver.recv().unwrap().ends_with(" 2"));
+        assert!(receiver.try_recv().is_err());
+        // And the count is reset once messages can be queued again
+        record("5");
+        assert_eq!(syslog.dropped.load(Ordering::Relaxed), 0);
+        assert!(receiver.recv().unwrap().ends_with(" 5"));
+    }
+
+    #[test]
+    fn test_spool() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dir = spool_dir("spool");
+        let mut forwarder = forwarder(listener.local_addr().unwrap().to_string(), dir.clone());
+        let spool_path = dir.join(SPOOL_FILE);
+
+        // Collector unreachable: the messages are spooled, one per line
+        forwarder.failed_at = Some(Instant::now());
+        forwarder.forward("first".into());
+        forwarder.forward("second".into());
+        assert_eq!(fs::read_to_string(&spool_path).unwrap(), "first\nsecond\n");
+        assert_eq!(forwarder.spool_len, 13);
+
+        // Until the spool is full
+        forwarder.forward("x".repeat(1024));
+        assert_eq!(forwarder.dropped, 1);
+        assert_eq!(forwarder.spool_len, 13);
+
+        // Replayed in order once the collector can be reached again, before
+        // the new messages
+        forwarder.failed_at = None;
+        forwarder.forward("third".into());
+        assert!(!spool_path.exists());
+        assert_eq!(forwarder.spool_len, 0);
+        assert_eq!(forwarder.dropped, 0);
+        drop(forwarder);
+        let mut received = String::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut received)
+            .unwrap();
+        assert_eq!(received, "5 first6 second5 third");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spool_unreachable() {
+        let dir = spool_dir("partial");
+        let spool_path = dir.join(SPOOL_FILE);
+        fs::write(&spool_path, "first\nsecond\n").unwrap();
+        // Nothing listens on the port of a closed listener
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut forwarder = forwarder(address, dir.clone());
+        forwarder.spool_len = 13;
+
+        forwarder.forward("third".into());
+        assert!(forwarder.failed_at.is_some());
+        assert_eq!(
+            fs::read_to_string(&spool_path).unwrap(),
+            "first\nsecond\nthird\n"
+        );
+        assert_eq!(forwarder.spool_len, 19);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
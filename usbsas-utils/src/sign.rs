@@ -0,0 +1,50 @@
+//! Ed25519 signature of the transfer manifests. The secret key of a station
+//! is stored as 64 hexadecimal characters, signatures are written as
+//! hexadecimal strings next to the manifest.
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use std::{convert::TryFrom, fs, io};
+
+fn invalid_data<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}", err))
+}
+
+pub struct SigningKey {
+    keypair: Keypair,
+}
+
+impl SigningKey {
+    /// Read the secret key from `path`. This must be done before entering
+    /// seccomp.
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let key = hex::decode(fs::read_to_string(path)?.trim()).map_err(invalid_data)?;
+        let secret = SecretKey::from_bytes(&key).map_err(invalid_data)?;
+        let public = PublicKey::from(&secret);
+        Ok(SigningKey {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.keypair.public.as_bytes())
+    }
+
+    /// Sign `data`, returns the hexadecimal signature
+    pub fn sign(&self, data: &[u8]) -> String {
+        hex::encode(self.keypair.sign(data).to_bytes())
+    }
+}
+
+/// Verify the hexadecimal `signature` of `data` with the hexadecimal
+/// `public_key`
+pub fn verify(public_key: &str, data: &[u8], signature: &str) -> io::Result<()> {
+    let public_key = PublicKey::from_bytes(&hex::decode(public_key.trim()).map_err(invalid_data)?)
+        .map_err(invalid_data)?;
+    let signature = Signature::try_from(
+        hex::decode(signature.trim())
+            .map_err(invalid_data)?
+            .as_slice(),
+    )
+    .map_err(invalid_data)?;
+    public_key.verify(data, &signature).map_err(invalid_data)
+}